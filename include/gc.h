@@ -0,0 +1,32 @@
+/* C interface to the gc crate, see src/ffi.rs. */
+#ifndef GC_H
+#define GC_H
+
+#include <stddef.h>
+#include <stdint.h>
+
+typedef struct Vm Vm;
+
+typedef enum {
+    GC_OK = 0,
+    GC_NULL_VM,
+    GC_STACK_OVERFLOW,
+    GC_STACK_UNDERFLOW,
+    GC_TYPE_MISMATCH,
+    GC_INVALID_HANDLE,
+    GC_ERROR,
+} GcStatus;
+
+Vm *gc_vm_new(void);
+void gc_vm_free(Vm *vm);
+GcStatus gc_vm_push_int(Vm *vm, int64_t value);
+GcStatus gc_vm_push_pair(Vm *vm);
+GcStatus gc_vm_pop_int(Vm *vm, int64_t *out);
+GcStatus gc_vm_pop(Vm *vm);
+GcStatus gc_vm_pop_handle(Vm *vm, uint64_t *out);
+GcStatus gc_vm_push_handle(Vm *vm, uint64_t handle);
+GcStatus gc_handle_release(Vm *vm, uint64_t handle);
+GcStatus gc_vm_collect(Vm *vm);
+size_t gc_vm_num_objects(const Vm *vm);
+
+#endif
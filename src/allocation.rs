@@ -0,0 +1,912 @@
+//! Where heap objects come from and go back to. An object's box is turned
+//! into a raw pointer once, and every handle is a copy of that pointer, so
+//! handles carry the provenance of the allocation itself rather than of a
+//! reference that goes out of scope while they're still around. Keeping all
+//! of it here keeps the rest of the crate clean under Miri.
+//!
+//! In debug builds, and in any build once `Vm::set_quarantine` is used,
+//! freed objects aren't given back right away. Their memory is overwritten
+//! with `POISON`, stamped with the collection that freed them, and held in a
+//! [`Quarantine`] for a while, so a stale handle reads the poison, which
+//! `GcPtr` checks for, instead of whatever was allocated there next.
+//!
+//! With `LayoutPolicy::Clustered`, objects come from [`Chunks`] the VM owns
+//! instead, each a run of slots allocated together, and a pair goes in the
+//! chunk of one of its children when that has a slot free. Freed objects go
+//! back to their chunk, through the quarantine like any other, and a chunk
+//! is given back once nothing's left in it. Each chunk starts with a
+//! [`ChunkMeta`] holding its objects' mark bits and types side by side, and
+//! is aligned so that's found from an object's address alone: marking,
+//! sweeping and stats read those instead of the objects. A chunk none of
+//! whose objects was marked, and none of whose objects needs dropping, is
+//! freed whole, or quarantined whole, without visiting its objects. Slots
+//! freed elsewhere are only taken again by pairs next to their children, so
+//! [`Chunks::evacuation`] picks sparse chunks for `Vm::compact`, or a
+//! relocation from `Vm::start_relocation`, to move the objects out of, and
+//! [`Chunks::relocate`] moves them.
+//!
+//! With the `canaries` feature every object sits between two guard words,
+//! which sweeping checks, so a write running off either end of an object is
+//! caught at the next collection.
+//!
+//! With the `asan` feature, for builds run under AddressSanitizer, quarantined
+//! memory is also poisoned in the sanitizer's shadow memory, so touching it
+//! gets a precise report from the sanitizer rather than a panic from us.
+
+use std::alloc::Layout;
+use std::collections::{BTreeMap, HashSet, VecDeque};
+use std::ptr::NonNull;
+use std::sync::atomic::{AtomicBool, Ordering};
+
+use crate::{Header, Object, TypeTag};
+
+#[cfg(not(feature = "canaries"))]
+type Allocation = Object;
+
+/// An object with its guard words.
+#[cfg(feature = "canaries")]
+#[repr(C)]
+struct Allocation {
+    front: u64,
+    object: Object,
+    back: u64,
+}
+
+/// What an intact guard word of the object at `ptr` holds. Mixing in the
+/// address means a guard copied from elsewhere doesn't pass.
+#[cfg(feature = "canaries")]
+fn canary(ptr: NonNull<Object>) -> u64 {
+    0x5afe_c0de_5afe_c0de ^ ptr.as_ptr().addr() as u64
+}
+
+pub(crate) fn allocate(object: Object) -> NonNull<Object> {
+    try_allocate(object)
+        .unwrap_or_else(|| std::alloc::handle_alloc_error(Layout::new::<Allocation>()))
+}
+
+/// Like `allocate`, but returns `None` instead of aborting when the
+/// allocator is out of memory. Memory from here is what `Box` would have
+/// allocated, so it's given back through `Box` too.
+pub(crate) fn try_allocate(object: Object) -> Option<NonNull<Object>> {
+    let allocation = NonNull::new(unsafe { std::alloc::alloc(Layout::new::<Allocation>()) })?
+        .cast::<Allocation>();
+    Some(unsafe { emplace(allocation, object) })
+}
+
+/// Writes `object` into `allocation`, returning the pointer to it.
+///
+/// # Safety
+///
+/// `allocation` has to be valid for writes and hold nothing that needs
+/// dropping.
+unsafe fn emplace(allocation: NonNull<Allocation>, object: Object) -> NonNull<Object> {
+    #[cfg(not(feature = "canaries"))]
+    {
+        allocation.write(object);
+        allocation
+    }
+    #[cfg(feature = "canaries")]
+    {
+        allocation.write(Allocation {
+            front: 0,
+            object,
+            back: 0,
+        });
+        // derived from the pointer to the whole allocation rather than a
+        // reference to the field, so `allocation_of` can step back out
+        let allocation = allocation.as_ptr();
+        let ptr = NonNull::new_unchecked(std::ptr::addr_of_mut!((*allocation).object));
+        (*allocation).front = canary(ptr);
+        (*allocation).back = canary(ptr);
+        ptr
+    }
+}
+
+/// The object in `allocation`.
+fn object_in(allocation: NonNull<Allocation>) -> NonNull<Object> {
+    #[cfg(feature = "canaries")]
+    let allocation = unsafe { allocation.byte_add(std::mem::offset_of!(Allocation, object)) };
+    allocation.cast()
+}
+
+/// The allocation the object at `ptr` lives in.
+fn allocation_of(ptr: NonNull<Object>) -> NonNull<Allocation> {
+    #[cfg(feature = "canaries")]
+    let ptr = unsafe { ptr.byte_sub(std::mem::offset_of!(Allocation, object)) };
+    ptr.cast()
+}
+
+/// Panics if either guard word of the object at `ptr` has been overwritten.
+/// Does nothing without the `canaries` feature.
+pub(crate) fn check_canaries(ptr: NonNull<Object>) {
+    #[cfg(feature = "canaries")]
+    {
+        let allocation = allocation_of(ptr).as_ptr();
+        let (front, back) = unsafe {
+            (
+                std::ptr::addr_of!((*allocation).front).read(),
+                std::ptr::addr_of!((*allocation).back).read(),
+            )
+        };
+        assert!(
+            front == canary(ptr) && back == canary(ptr),
+            "the guard words around {ptr:?} were overwritten"
+        );
+    }
+    #[cfg(not(feature = "canaries"))]
+    let _ = ptr;
+}
+
+/// Byte freed objects are filled with. Its top bit is set, which the low
+/// byte of a live object's header never has, so a header can't hold it.
+const POISON: u8 = 0xde;
+
+/// How many freed objects a debug build holds on to before giving the
+/// oldest one back.
+pub(crate) const QUARANTINE_LEN: usize = 64;
+
+/// Set once any VM quarantines, after which every access through a handle
+/// checks for poison in release builds too.
+static CHECK_POISON: AtomicBool = AtomicBool::new(false);
+
+/// Memory in quarantine.
+enum Quarantined {
+    Object(NonNull<Object>),
+    /// a chunk none of whose objects were marked, swept all at once
+    Chunk(Chunk),
+}
+
+/// Freed objects whose memory hasn't been given back yet.
+pub(crate) struct Quarantine {
+    /// oldest first, each with the collection that freed it
+    objects: VecDeque<(Quarantined, usize)>,
+    /// objects in quarantine, counting each of a chunk's
+    len: usize,
+    /// collections finished so far
+    collections: usize,
+    /// how many collections objects stay quarantined for, if set by
+    /// `Vm::set_quarantine`; otherwise only debug builds quarantine, and
+    /// only the last `QUARANTINE_LEN` objects
+    keep_for: Option<usize>,
+}
+
+// SAFETY: quarantined memory belongs to the quarantine alone; nothing reads
+// it but the quarantine and the checks on dead handles
+unsafe impl Send for Quarantine {}
+
+impl Quarantine {
+    pub(crate) fn new() -> Self {
+        Self {
+            objects: VecDeque::new(),
+            len: 0,
+            collections: 0,
+            keep_for: None,
+        }
+    }
+
+    pub(crate) fn keep_for(&mut self, collections: Option<usize>, chunks: &mut Chunks) {
+        if collections.is_some() {
+            CHECK_POISON.store(true, Ordering::Relaxed);
+        }
+        self.keep_for = collections;
+        self.release_expired(chunks);
+    }
+
+    /// Drops the object, then poisons and quarantines its memory, or gives it
+    /// back right away in release builds that don't quarantine. Memory from
+    /// one of `chunks` goes back to its chunk.
+    ///
+    /// # Safety
+    ///
+    /// `ptr` has to come from `allocate` or `chunks` and not have been freed
+    /// yet.
+    pub(crate) unsafe fn free(&mut self, ptr: NonNull<Object>, chunks: &mut Chunks) {
+        check_canaries(ptr);
+        ptr.as_ptr().drop_in_place();
+        if !self.retains() {
+            release(ptr, chunks);
+            return;
+        }
+        // counting from 1, so this is the collection running now
+        let freed_by = self.collections + 1;
+        poison(ptr, freed_by);
+        self.objects.push_back((Quarantined::Object(ptr), freed_by));
+        self.len += 1;
+        self.release_expired(chunks);
+    }
+
+    /// Frees a chunk taken by `Chunks::take_unmarked` and everything in it,
+    /// without dropping the objects, which needn't be: its memory is given
+    /// back in one go, or poisoned and quarantined as a whole.
+    pub(crate) fn free_chunk(&mut self, chunk: Chunk, chunks: &mut Chunks) {
+        if !self.retains() {
+            unsafe { dealloc_chunk(chunk) }
+            return;
+        }
+        let freed_by = self.collections + 1;
+        for slot in bits(chunk.occupied()) {
+            unsafe { poison(object_in(chunk.slot(slot)), freed_by) }
+        }
+        self.len += chunk.occupied().count_ones() as usize;
+        self.objects
+            .push_back((Quarantined::Chunk(chunk), freed_by));
+        self.release_expired(chunks);
+    }
+
+    /// Whether freed objects are quarantined rather than given back right
+    /// away, which only release builds that don't quarantine do.
+    pub(crate) fn retains(&self) -> bool {
+        cfg!(debug_assertions) || self.keep_for.is_some()
+    }
+
+    /// Counts a finished collection, giving back what's served its time.
+    pub(crate) fn collected(&mut self, chunks: &mut Chunks) {
+        self.collections += 1;
+        self.release_expired(chunks);
+    }
+
+    fn release_expired(&mut self, chunks: &mut Chunks) {
+        while let Some(&(_, freed_by)) = self.objects.front() {
+            let expired = match self.keep_for {
+                Some(collections) => self.collections.saturating_sub(freed_by) >= collections,
+                None => self.len > QUARANTINE_LEN || !cfg!(debug_assertions),
+            };
+            if !expired {
+                return;
+            }
+            let (quarantined, _) = self.objects.pop_front().unwrap();
+            self.release(quarantined, chunks);
+        }
+    }
+
+    fn release(&mut self, quarantined: Quarantined, chunks: &mut Chunks) {
+        match quarantined {
+            Quarantined::Object(ptr) => {
+                self.len -= 1;
+                unsafe { release(ptr, chunks) }
+            }
+            Quarantined::Chunk(chunk) => {
+                for slot in bits(chunk.occupied()) {
+                    sanitizer_poison(object_in(chunk.slot(slot)), false);
+                }
+                self.len -= chunk.occupied().count_ones() as usize;
+                unsafe { dealloc_chunk(chunk) }
+            }
+        }
+    }
+
+    pub(crate) fn len(&self) -> usize {
+        self.len
+    }
+
+    /// Gives back the memory of everything in quarantine.
+    pub(crate) fn clear(&mut self, chunks: &mut Chunks) {
+        while let Some((quarantined, _)) = self.objects.pop_front() {
+            self.release(quarantined, chunks);
+        }
+    }
+}
+
+/// Fills the memory of the already dropped object at `ptr` with `POISON`,
+/// stamped with the collection that freed it, for `check_live` to find.
+unsafe fn poison(ptr: NonNull<Object>, freed_by: usize) {
+    ptr.as_ptr()
+        .cast::<u8>()
+        .write_bytes(POISON, size_of::<Object>());
+    std::ptr::addr_of_mut!((*ptr.as_ptr()).value)
+        .cast::<u64>()
+        .write(freed_by as u64);
+    sanitizer_poison(ptr, true);
+}
+
+/// Objects in a chunk, one per bit of a mark word.
+const CHUNK_SLOTS: usize = 64;
+
+/// The start of every chunk, ahead of its slots.
+#[repr(C)]
+struct ChunkMeta {
+    /// bit `i` is set once the object in slot `i` has been marked; an
+    /// object in a chunk never has its header's mark bit set
+    marks: u64,
+    /// bit `i` is set while the object in slot `i` is out of the heap but
+    /// its slot isn't free yet, being quarantined or finalized
+    retired: u64,
+    /// the type of the object in each occupied slot, as allocated
+    types: [TypeTag; CHUNK_SLOTS],
+}
+
+/// Bytes from the start of a chunk to its first slot.
+const SLOTS_OFFSET: usize = size_of::<ChunkMeta>().next_multiple_of(align_of::<Allocation>());
+
+/// Bytes from the start of a chunk to the end of its last slot.
+const CHUNK_BYTES: usize = SLOTS_OFFSET + CHUNK_SLOTS * size_of::<Allocation>();
+
+/// What chunks are aligned to, so masking an address inside one gives its
+/// start.
+const CHUNK_ALIGN: usize = CHUNK_BYTES.next_power_of_two();
+
+fn chunk_layout() -> Layout {
+    Layout::from_size_align(CHUNK_BYTES, CHUNK_ALIGN).unwrap()
+}
+
+/// Slots allocated together, which objects are allocated from one by one.
+pub(crate) struct Chunk {
+    meta: NonNull<ChunkMeta>,
+    /// bit `i` set while slot `i` holds nothing; the lowest is used next
+    free: u64,
+}
+
+impl Chunk {
+    fn slot(&self, slot: usize) -> NonNull<Allocation> {
+        unsafe {
+            self.meta
+                .byte_add(SLOTS_OFFSET)
+                .cast::<Allocation>()
+                .add(slot)
+        }
+    }
+
+    /// Bit `i` set for each slot `i` holding an object.
+    fn occupied(&self) -> u64 {
+        !self.free
+    }
+
+    /// Bit `i` set for each slot `i` not holding an object of the heap:
+    /// those free, and those whose object was swept but isn't released yet.
+    fn vacant(&self) -> u64 {
+        self.free | unsafe { self.meta.as_ref() }.retired
+    }
+
+    /// Objects of the heap in the chunk.
+    fn live(&self) -> usize {
+        self.vacant().count_zeros() as usize
+    }
+
+    /// Moves `object` into the lowest free slot, which there has to be.
+    fn take(&mut self, object: Object) -> NonNull<Object> {
+        let slot = self.free.trailing_zeros() as usize;
+        self.free &= self.free - 1;
+        unsafe {
+            (*self.meta.as_ptr()).types[slot] = object.header.type_tag();
+            emplace(self.slot(slot), object)
+        }
+    }
+}
+
+/// The chunks a compaction empties, and those it fills.
+pub(crate) struct Evacuation {
+    sources: HashSet<usize>,
+    /// starts of the chunks with room, the one to fill next last
+    targets: Vec<usize>,
+}
+
+impl Evacuation {
+    pub(crate) fn is_empty(&self) -> bool {
+        self.sources.is_empty()
+    }
+
+    /// Whether the object at `ptr`, which has to be in a chunk, is in one
+    /// being emptied.
+    pub(crate) fn moves(&self, ptr: NonNull<Object>) -> bool {
+        self.sources.contains(&chunk_start(ptr))
+    }
+
+    /// Leaves the chunk starting at `start` where it is, if it was to be
+    /// emptied.
+    pub(crate) fn keep(&mut self, start: usize) {
+        self.sources.remove(&start);
+    }
+}
+
+/// Indices of the bits set in `word`, lowest first.
+fn bits(mut word: u64) -> impl Iterator<Item = usize> {
+    std::iter::from_fn(move || {
+        let bit = word.trailing_zeros() as usize;
+        word &= word.wrapping_sub(1);
+        (bit < 64).then_some(bit)
+    })
+}
+
+/// Start of the chunk the object at `ptr` is in, if it's in one at all.
+pub(crate) fn chunk_start(ptr: NonNull<Object>) -> usize {
+    allocation_of(ptr).as_ptr().addr() & !(CHUNK_ALIGN - 1)
+}
+
+/// The meta of the chunk the object at `ptr` is in, which it has to be,
+/// and the object's slot in it.
+fn meta_of(ptr: NonNull<Object>) -> (NonNull<ChunkMeta>, usize) {
+    let allocation = allocation_of(ptr);
+    let addr = allocation.as_ptr().addr();
+    let start = addr & !(CHUNK_ALIGN - 1);
+    let slot = (addr - start - SLOTS_OFFSET) / size_of::<Allocation>();
+    (allocation.with_addr(start.try_into().unwrap()).cast(), slot)
+}
+
+/// Whether the object at `ptr`, which has to be in a chunk, is marked.
+pub(crate) fn chunk_marked(ptr: NonNull<Object>) -> bool {
+    let (meta, slot) = meta_of(ptr);
+    unsafe { (*meta.as_ptr()).marks & 1 << slot != 0 }
+}
+
+/// Marks the object at `ptr`, which has to be in a chunk.
+pub(crate) fn mark_in_chunk(ptr: NonNull<Object>) {
+    let (meta, slot) = meta_of(ptr);
+    unsafe { (*meta.as_ptr()).marks |= 1 << slot }
+}
+
+/// Notes that the object at `ptr`, which has to be in a chunk, has been
+/// swept, until its slot is free again.
+pub(crate) fn retire(ptr: NonNull<Object>) {
+    let (meta, slot) = meta_of(ptr);
+    unsafe { (*meta.as_ptr()).retired |= 1 << slot }
+}
+
+/// The chunks a VM with `LayoutPolicy::Clustered` allocates from, by the
+/// address they start at.
+pub(crate) struct Chunks {
+    chunks: BTreeMap<usize, Chunk>,
+    /// start of the chunk objects with nowhere better to go are allocated
+    /// from, until it's full
+    current: Option<usize>,
+}
+
+// SAFETY: a chunk's memory belongs to the VM that owns the chunks, and is
+// only reached through its objects
+unsafe impl Send for Chunks {}
+
+impl Chunks {
+    pub(crate) fn new() -> Self {
+        Self {
+            chunks: BTreeMap::new(),
+            current: None,
+        }
+    }
+
+    /// Allocates `object` in the chunk of the first of `near` that's in one
+    /// with a slot free, or else in the current chunk, starting a new one
+    /// once that's full. Returns `None` when the allocator is out of memory.
+    pub(crate) fn try_allocate(
+        &mut self,
+        mut object: Object,
+        near: &[NonNull<Object>],
+    ) -> Option<NonNull<Object>> {
+        let nearby = near
+            .iter()
+            .filter_map(|&ptr| self.chunk_of(ptr))
+            .find(|start| self.chunks[start].free != 0);
+        let start = match nearby.or(self.current) {
+            Some(start) if self.chunks[&start].free != 0 => start,
+            _ => self.grow()?,
+        };
+        object.header.0 |= Header::CHUNKED;
+        Some(self.chunks.get_mut(&start).unwrap().take(object))
+    }
+
+    /// Allocates a new chunk and makes it the current one.
+    fn grow(&mut self) -> Option<usize> {
+        let meta = NonNull::new(unsafe { std::alloc::alloc(chunk_layout()) })?.cast::<ChunkMeta>();
+        unsafe {
+            meta.write(ChunkMeta {
+                marks: 0,
+                retired: 0,
+                types: [TypeTag::Nil; CHUNK_SLOTS],
+            })
+        };
+        let start = meta.as_ptr().addr();
+        self.chunks.insert(start, Chunk { meta, free: !0 });
+        self.current = Some(start);
+        Some(start)
+    }
+
+    /// Start of the chunk the object at `ptr` is in, if it's in one. Past
+    /// its last slot, a chunk's alignment can take in other allocations.
+    fn chunk_of(&self, ptr: NonNull<Object>) -> Option<usize> {
+        let addr = allocation_of(ptr).as_ptr().addr();
+        let start = addr & !(CHUNK_ALIGN - 1);
+        (self.chunks.contains_key(&start) && addr < start + CHUNK_BYTES).then_some(start)
+    }
+
+    /// Takes out the chunks with objects in the heap but none marked, each
+    /// of which holds nothing that needs dropping and nothing retired, so
+    /// they can be freed whole with `Quarantine::free_chunk` rather than
+    /// object by object. They come in address order.
+    pub(crate) fn take_unmarked(&mut self) -> Vec<(usize, Chunk)> {
+        let unmarked: Vec<_> = self
+            .chunks
+            .iter()
+            .filter(|(_, chunk)| {
+                let meta = unsafe { chunk.meta.as_ref() };
+                let occupied = chunk.occupied();
+                meta.marks == 0
+                    && meta.retired == 0
+                    && occupied != 0
+                    && bits(occupied).all(|slot| !meta.types[slot].needs_drop())
+            })
+            .map(|(&start, _)| start)
+            .collect();
+        if self.current.is_some_and(|start| unmarked.contains(&start)) {
+            self.current = None;
+        }
+        unmarked
+            .into_iter()
+            .map(|start| (start, self.chunks.remove(&start).unwrap()))
+            .collect()
+    }
+
+    /// Unmarks every object in a chunk, for the next collection.
+    pub(crate) fn clear_marks(&mut self) {
+        for chunk in self.chunks.values_mut() {
+            unsafe { (*chunk.meta.as_ptr()).marks = 0 }
+        }
+    }
+
+    /// Adds how many objects of each type the chunks hold to `counts`,
+    /// indexed by `TypeTag`, from the chunks' metas alone.
+    pub(crate) fn count_types(&self, counts: &mut [usize]) {
+        for chunk in self.chunks.values() {
+            let meta = unsafe { chunk.meta.as_ref() };
+            for slot in bits(chunk.occupied() & !meta.retired) {
+                counts[meta.types[slot] as usize] += 1;
+            }
+        }
+    }
+
+    /// Chunks objects are allocated from.
+    pub(crate) fn len(&self) -> usize {
+        self.chunks.len()
+    }
+
+    /// Slots free in every chunk but the current one, counting those whose
+    /// object is only waiting to be released.
+    pub(crate) fn free_slots(&self) -> usize {
+        self.chunks
+            .iter()
+            .filter(|&(&start, _)| self.current != Some(start))
+            .map(|(_, chunk)| chunk.vacant().count_ones() as usize)
+            .sum()
+    }
+
+    /// `free_slots` over the slots of every chunk, or 0 without chunks.
+    pub(crate) fn fragmentation(&self) -> f64 {
+        if self.chunks.is_empty() {
+            return 0.0;
+        }
+        self.free_slots() as f64 / (self.chunks.len() * CHUNK_SLOTS) as f64
+    }
+
+    /// Picks the chunks to empty into the free slots of the others: the
+    /// sparsest, at most half full, for as long as the rest have room for
+    /// their objects. The current chunk and those starting at one of
+    /// `pinned` stay put.
+    pub(crate) fn evacuation(&self, pinned: &HashSet<usize>) -> Evacuation {
+        let mut candidates: Vec<_> = self
+            .chunks
+            .iter()
+            .filter(|&(start, chunk)| {
+                self.current != Some(*start)
+                    && !pinned.contains(start)
+                    && (1..=CHUNK_SLOTS / 2).contains(&chunk.live())
+            })
+            .collect();
+        candidates.sort_by_key(|(_, chunk)| chunk.live());
+        let mut room: usize = self
+            .chunks
+            .values()
+            .map(|chunk| chunk.free.count_ones() as usize)
+            .sum();
+        let mut sources = HashSet::new();
+        for (&start, chunk) in candidates {
+            // emptying the chunk takes its own room away too
+            let needs = chunk.live() + chunk.free.count_ones() as usize;
+            if needs > room {
+                break;
+            }
+            room -= needs;
+            sources.insert(start);
+        }
+        let mut targets: Vec<_> = self
+            .chunks
+            .iter()
+            .filter(|&(start, chunk)| !sources.contains(start) && chunk.free != 0)
+            .collect();
+        // the fullest are filled first, keeping the emptier ones for later
+        targets.sort_by_key(|(_, chunk)| std::cmp::Reverse(chunk.free.count_ones()));
+        Evacuation {
+            sources,
+            targets: targets.into_iter().map(|(&start, _)| start).collect(),
+        }
+    }
+
+    /// Moves the object at `ptr`, in a chunk `plan` empties, into a free
+    /// slot of one it fills, and returns where it is now. The old slot is
+    /// put back, and its chunk given back once nothing's left in it. Returns
+    /// `None`, leaving the object where it is, once the chunks `plan` fills
+    /// have no slot left, which only happens when something else has taken
+    /// the room it made since it was planned.
+    ///
+    /// # Safety
+    ///
+    /// `ptr` has to be an object of the heap in one of `plan`'s sources.
+    pub(crate) unsafe fn relocate(
+        &mut self,
+        ptr: NonNull<Object>,
+        plan: &mut Evacuation,
+    ) -> Option<NonNull<Object>> {
+        let start = loop {
+            let start = *plan.targets.last()?;
+            if self.chunks.get(&start).is_some_and(|chunk| chunk.free != 0) {
+                break start;
+            }
+            plan.targets.pop();
+        };
+        check_canaries(ptr);
+        let moved = self.chunks.get_mut(&start).unwrap().take(ptr.read());
+        self.release(ptr);
+        Some(moved)
+    }
+
+    /// Puts the slot of the already dropped object at `ptr` back, giving
+    /// back its chunk if that was the last thing in it, unless it's the
+    /// current one. Returns whether `ptr` was in a chunk at all.
+    fn release(&mut self, ptr: NonNull<Object>) -> bool {
+        let Some(start) = self.chunk_of(ptr) else {
+            return false;
+        };
+        let chunk = self.chunks.get_mut(&start).unwrap();
+        let (meta, slot) = meta_of(ptr);
+        unsafe { (*meta.as_ptr()).retired &= !(1 << slot) }
+        chunk.free |= 1 << slot;
+        if chunk.free == !0 && self.current != Some(start) {
+            let chunk = self.chunks.remove(&start).unwrap();
+            unsafe { dealloc_chunk(chunk) }
+        }
+        true
+    }
+}
+
+impl Drop for Chunks {
+    fn drop(&mut self) {
+        for (_, chunk) in std::mem::take(&mut self.chunks) {
+            unsafe { dealloc_chunk(chunk) }
+        }
+    }
+}
+
+unsafe fn dealloc_chunk(chunk: Chunk) {
+    std::alloc::dealloc(chunk.meta.as_ptr().cast(), chunk_layout());
+}
+
+/// Drops the object and gives its memory back, for when nothing is
+/// quarantined. Unlike `Quarantine::free` this can run on any thread, but
+/// it can't give back memory from a chunk.
+///
+/// # Safety
+///
+/// `ptr` has to come from `allocate` and not have been freed yet.
+pub(crate) unsafe fn deallocate(ptr: NonNull<Object>) {
+    check_canaries(ptr);
+    drop(Box::from_raw(allocation_of(ptr).as_ptr()));
+}
+
+#[cfg(feature = "asan")]
+extern "C" {
+    fn __asan_poison_memory_region(addr: *const u8, size: usize);
+    fn __asan_unpoison_memory_region(addr: *const u8, size: usize);
+}
+
+/// Tells AddressSanitizer that the object at `ptr` must not be touched, or
+/// with `poisoned` false that it can be again. Does nothing without the
+/// `asan` feature.
+fn sanitizer_poison(ptr: NonNull<Object>, poisoned: bool) {
+    #[cfg(feature = "asan")]
+    unsafe {
+        let addr = ptr.as_ptr().cast::<u8>();
+        if poisoned {
+            __asan_poison_memory_region(addr, size_of::<Object>());
+        } else {
+            __asan_unpoison_memory_region(addr, size_of::<Object>());
+        }
+    }
+    #[cfg(not(feature = "asan"))]
+    let _ = (ptr, poisoned);
+}
+
+/// Gives back the memory of an object that's already been dropped, to its
+/// chunk if it's in one of `chunks`.
+unsafe fn release(ptr: NonNull<Object>, chunks: &mut Chunks) {
+    sanitizer_poison(ptr, false);
+    if chunks.release(ptr) {
+        return;
+    }
+    std::alloc::dealloc(
+        allocation_of(ptr).as_ptr().cast(),
+        Layout::new::<Allocation>(),
+    );
+}
+
+/// Whether `ptr` points at a freed object still in quarantine. Only
+/// meaningful while freed memory is being poisoned.
+pub(crate) fn is_poisoned(ptr: NonNull<Object>) -> bool {
+    unsafe {
+        std::ptr::addr_of!((*ptr.as_ptr()).header)
+            .cast::<u64>()
+            .read() as u8
+            == POISON
+    }
+}
+
+/// Panics if `ptr` points at a quarantined object, naming the collection
+/// that freed it. Checks in debug builds, and in release builds once
+/// something has quarantined.
+pub(crate) fn check_live(ptr: NonNull<Object>) {
+    if !cfg!(debug_assertions) && !CHECK_POISON.load(Ordering::Relaxed) {
+        return;
+    }
+    if is_poisoned(ptr) {
+        let freed_by = unsafe {
+            std::ptr::addr_of!((*ptr.as_ptr()).value)
+                .cast::<u64>()
+                .read()
+        };
+        panic!("{ptr:?} was used after collection {freed_by} freed it");
+    }
+}
+
+/// Turns a pointer into an address to store in a `Value`, exposing its
+/// provenance so [`from_addr`] can pick it back up.
+pub(crate) fn to_addr(ptr: NonNull<Object>) -> u64 {
+    ptr.as_ptr().expose_provenance() as u64
+}
+
+/// Turns an address from [`to_addr`] back into the pointer it came from.
+pub(crate) fn from_addr(addr: u64) -> NonNull<Object> {
+    NonNull::new(std::ptr::with_exposed_provenance_mut(addr as usize)).unwrap()
+}
+
+#[test]
+fn layout_test() {
+    use crate::{LayoutPolicy, Vm, VmConfig};
+
+    println!("Layout Test: Clustered pairs sit in the chunk of their head.");
+    let mut vm = Vm::with_config(VmConfig {
+        layout: LayoutPolicy::Clustered,
+        ..VmConfig::default()
+    });
+    vm.set_sweep_threads(4);
+    let list = vm.push_list(0..10_000);
+    let mut pair = list;
+    let mut near = 0;
+    while let Some((head, tail)) = pair.as_pair(&vm) {
+        let head = head.unwrap();
+        if vm.chunks.chunk_of(pair.0).is_some()
+            && vm.chunks.chunk_of(pair.0) == vm.chunks.chunk_of(head.0)
+        {
+            near += 1;
+        }
+        pair = tail.unwrap();
+    }
+    assert!(
+        near > 9_000,
+        "Should have put most pairs next to their head."
+    );
+
+    vm.gc();
+    assert!(vm.num_objs == 20_000, "Should have kept the list.");
+    vm.clear_stack();
+    vm.gc();
+    vm.shrink_to_fit();
+    assert!(
+        vm.chunks.chunks.len() <= 1,
+        "Should have given back every chunk but the current one."
+    );
+}
+
+#[test]
+fn chunk_meta_test() {
+    use crate::{HeapStats, LayoutPolicy, Vm, VmConfig};
+
+    println!("Chunk Meta Test: Marks and types of chunked objects live in the chunk.");
+    let build = |vm: &mut Vm| {
+        vm.push_list(0..100);
+        vm.push_str("kept");
+        vm.push_bytes(&[1, 2, 3]);
+        vm.push_float(1.5);
+        vm.push_str("garbage");
+        vm.drop_top();
+    };
+    let mut individual = Vm::new();
+    let mut clustered = Vm::with_config(VmConfig {
+        layout: LayoutPolicy::Clustered,
+        ..VmConfig::default()
+    });
+    build(&mut individual);
+    build(&mut clustered);
+    let same = |a: HeapStats, b: HeapStats| {
+        (a.objects, a.bytes, a.by_type) == (b.objects, b.bytes, b.by_type)
+    };
+    assert!(same(clustered.heap_stats(), individual.heap_stats()));
+    individual.gc();
+    clustered.gc();
+    let stats = clustered.heap_stats();
+    assert!(
+        same(stats.clone(), individual.heap_stats()),
+        "Should count the same types either way."
+    );
+    assert!(
+        stats.chunks == clustered.chunks.chunks.len() && individual.heap_stats().chunks == 0,
+        "Should count the chunks of a clustered layout alone."
+    );
+    assert!(stats.by_type["pair"] == 100 && stats.by_type["string"] == 1);
+    assert!(
+        !stats.by_type.contains_key("symbol"),
+        "Should leave out types with no objects."
+    );
+
+    let chunked = clustered.heap.iter().all(|obj| {
+        let header = unsafe { obj.0.as_ref() }.header;
+        header.in_chunk() && !header.is_marked()
+    });
+    assert!(
+        chunked,
+        "Should never set the mark bit in a chunked header."
+    );
+    let unmarked = clustered
+        .chunks
+        .chunks
+        .values()
+        .all(|chunk| unsafe { chunk.meta.as_ref() }.marks == 0);
+    assert!(unmarked, "Should clear every chunk's marks after sweeping.");
+    let occupied: u32 = clustered
+        .chunks
+        .chunks
+        .values()
+        .map(|chunk| chunk.occupied().count_ones())
+        .sum();
+    assert!(
+        occupied as usize == clustered.num_objs + clustered.quarantined(),
+        "Should have a slot taken for everything not given back."
+    );
+}
+
+#[test]
+fn unmarked_chunk_test() {
+    use crate::{LayoutPolicy, Vm, VmConfig};
+
+    println!("Unmarked Chunk Test: Chunks with nothing marked are freed whole.");
+    let mut vm = Vm::with_config(VmConfig {
+        layout: LayoutPolicy::Clustered,
+        ..VmConfig::default()
+    });
+    vm.set_quarantine(Some(1));
+    vm.push_str("kept");
+    let list = vm.push_list(0..1000);
+    vm.drop_top();
+    vm.gc();
+    assert!(
+        vm.num_objs == 1 && vm.chunks.chunks.len() == 1,
+        "Should have taken out every chunk but the one still in use."
+    );
+    assert!(
+        vm.quarantined() == 2000,
+        "Should quarantine the objects of the chunks taken out too."
+    );
+    assert!(is_poisoned(list.0), "Should poison the chunks taken out.");
+
+    for i in 0..100 {
+        vm.push_str(&i.to_string());
+    }
+    vm.clear_stack();
+    vm.push_str("kept");
+    vm.gc();
+    assert!(
+        vm.chunks.chunks.len() == 3,
+        "Should free strings one by one, since they need dropping."
+    );
+    vm.gc();
+    vm.gc();
+    assert!(vm.quarantined() == 0 && vm.num_objs == 1);
+    assert!(
+        vm.chunks.chunks.len() == 1,
+        "Should give back the chunks emptied one by one too."
+    );
+}
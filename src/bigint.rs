@@ -0,0 +1,299 @@
+use std::cmp::Ordering;
+use std::fmt;
+use std::ops::{Add, Div, Mul, Neg, Sub};
+
+/// Arbitrary-precision integer, used by int arithmetic once a result no longer
+/// fits in an `i64`.
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub struct BigInt {
+    negative: bool,
+    /// magnitude as little-endian base 2^32 digits, without trailing zeros
+    limbs: Vec<u32>,
+}
+
+impl BigInt {
+    pub fn zero() -> Self {
+        Self {
+            negative: false,
+            limbs: vec![],
+        }
+    }
+
+    pub fn is_zero(&self) -> bool {
+        self.limbs.is_empty()
+    }
+
+    pub fn is_negative(&self) -> bool {
+        self.negative
+    }
+
+    /// Returns the value as an `i64` if it's in range.
+    pub fn to_i64(&self) -> Option<i64> {
+        if self.limbs.len() > 2 {
+            return None;
+        }
+        let mag = self
+            .limbs
+            .iter()
+            .rev()
+            .fold(0u64, |acc, &limb| acc << 32 | limb as u64);
+        if self.negative {
+            match mag.cmp(&(1 << 63)) {
+                Ordering::Less => Some(-(mag as i64)),
+                Ordering::Equal => Some(i64::MIN),
+                Ordering::Greater => None,
+            }
+        } else {
+            i64::try_from(mag).ok()
+        }
+    }
+
+    /// Nearest float, which loses precision past 53 significant bits.
+    pub fn to_f64(&self) -> f64 {
+        let mag = self
+            .limbs
+            .iter()
+            .rev()
+            .fold(0.0, |acc, &limb| acc * 4294967296.0 + limb as f64);
+        if self.negative {
+            -mag
+        } else {
+            mag
+        }
+    }
+
+    /// Bytes of out-of-line storage held by the digits.
+    pub(crate) fn heap_size(&self) -> usize {
+        self.limbs.capacity() * std::mem::size_of::<u32>()
+    }
+
+    /// The sign and the magnitude's digits, as `from_limbs` takes them.
+    pub(crate) fn parts(&self) -> (bool, &[u32]) {
+        (self.negative, &self.limbs)
+    }
+
+    pub(crate) fn from_limbs(negative: bool, mut limbs: Vec<u32>) -> Self {
+        while limbs.last() == Some(&0) {
+            limbs.pop();
+        }
+        let negative = negative && !limbs.is_empty();
+        Self { negative, limbs }
+    }
+
+    fn cmp_magnitude(a: &[u32], b: &[u32]) -> Ordering {
+        a.len()
+            .cmp(&b.len())
+            .then_with(|| a.iter().rev().cmp(b.iter().rev()))
+    }
+
+    fn add_magnitude(a: &[u32], b: &[u32]) -> Vec<u32> {
+        let mut out = Vec::with_capacity(a.len().max(b.len()) + 1);
+        let mut carry = 0u64;
+        for i in 0..a.len().max(b.len()) {
+            let sum = *a.get(i).unwrap_or(&0) as u64 + *b.get(i).unwrap_or(&0) as u64 + carry;
+            out.push(sum as u32);
+            carry = sum >> 32;
+        }
+        if carry != 0 {
+            out.push(carry as u32);
+        }
+        out
+    }
+
+    /// `a - b`, where `a`'s magnitude is at least `b`'s.
+    fn sub_magnitude(a: &[u32], b: &[u32]) -> Vec<u32> {
+        let mut out = Vec::with_capacity(a.len());
+        let mut borrow = 0i64;
+        for (i, &limb) in a.iter().enumerate() {
+            let mut diff = limb as i64 - *b.get(i).unwrap_or(&0) as i64 - borrow;
+            borrow = 0;
+            if diff < 0 {
+                diff += 1 << 32;
+                borrow = 1;
+            }
+            out.push(diff as u32);
+        }
+        out
+    }
+
+    fn signed_add(&self, other: &BigInt, other_negative: bool) -> BigInt {
+        if self.negative == other_negative {
+            return Self::from_limbs(
+                self.negative,
+                Self::add_magnitude(&self.limbs, &other.limbs),
+            );
+        }
+        match Self::cmp_magnitude(&self.limbs, &other.limbs) {
+            Ordering::Less => Self::from_limbs(
+                other_negative,
+                Self::sub_magnitude(&other.limbs, &self.limbs),
+            ),
+            _ => Self::from_limbs(
+                self.negative,
+                Self::sub_magnitude(&self.limbs, &other.limbs),
+            ),
+        }
+    }
+
+    /// Truncating division of magnitudes, one bit at a time. `b` must be
+    /// non-zero.
+    fn div_magnitude(a: &[u32], b: &[u32]) -> Vec<u32> {
+        let mut quotient = vec![0u32; a.len()];
+        let mut rem: Vec<u32> = vec![];
+        for bit in (0..a.len() * 32).rev() {
+            // rem = rem << 1 | next bit of a
+            let mut carry = a[bit / 32] >> (bit % 32) & 1;
+            for limb in rem.iter_mut() {
+                let next = *limb >> 31;
+                *limb = *limb << 1 | carry;
+                carry = next;
+            }
+            if carry != 0 {
+                rem.push(carry);
+            }
+            if Self::cmp_magnitude(&rem, b) != Ordering::Less {
+                rem = Self::sub_magnitude(&rem, b);
+                while rem.last() == Some(&0) {
+                    rem.pop();
+                }
+                quotient[bit / 32] |= 1 << (bit % 32);
+            }
+        }
+        quotient
+    }
+
+    /// Divides the magnitude by a single digit in place, returning the
+    /// remainder.
+    fn div_small(limbs: &mut [u32], divisor: u32) -> u32 {
+        let mut rem = 0u64;
+        for limb in limbs.iter_mut().rev() {
+            let cur = rem << 32 | *limb as u64;
+            *limb = (cur / divisor as u64) as u32;
+            rem = cur % divisor as u64;
+        }
+        rem as u32
+    }
+}
+
+impl From<i64> for BigInt {
+    fn from(value: i64) -> Self {
+        let mag = value.unsigned_abs();
+        Self::from_limbs(value < 0, vec![mag as u32, (mag >> 32) as u32])
+    }
+}
+
+impl Add for &BigInt {
+    type Output = BigInt;
+
+    fn add(self, other: &BigInt) -> BigInt {
+        self.signed_add(other, other.negative)
+    }
+}
+
+impl Sub for &BigInt {
+    type Output = BigInt;
+
+    fn sub(self, other: &BigInt) -> BigInt {
+        self.signed_add(other, !other.negative)
+    }
+}
+
+impl Mul for &BigInt {
+    type Output = BigInt;
+
+    fn mul(self, other: &BigInt) -> BigInt {
+        let mut out = vec![0u32; self.limbs.len() + other.limbs.len()];
+        for (i, &a) in self.limbs.iter().enumerate() {
+            let mut carry = 0u64;
+            for (j, &b) in other.limbs.iter().enumerate() {
+                let cur = out[i + j] as u64 + a as u64 * b as u64 + carry;
+                out[i + j] = cur as u32;
+                carry = cur >> 32;
+            }
+            out[i + other.limbs.len()] = carry as u32;
+        }
+        BigInt::from_limbs(self.negative != other.negative, out)
+    }
+}
+
+/// Division truncates toward zero, like it does for `i64`.
+///
+/// # Panics
+///
+/// Panics if `other` is zero.
+impl Div for &BigInt {
+    type Output = BigInt;
+
+    fn div(self, other: &BigInt) -> BigInt {
+        assert!(!other.is_zero(), "attempt to divide by zero");
+        BigInt::from_limbs(
+            self.negative != other.negative,
+            BigInt::div_magnitude(&self.limbs, &other.limbs),
+        )
+    }
+}
+
+impl Neg for &BigInt {
+    type Output = BigInt;
+
+    fn neg(self) -> BigInt {
+        BigInt::from_limbs(!self.negative, self.limbs.clone())
+    }
+}
+
+impl fmt::Display for BigInt {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.is_zero() {
+            return write!(f, "0");
+        }
+        // peel off base 10^9 chunks, least significant first
+        let mut limbs = self.limbs.clone();
+        let mut chunks = vec![];
+        while !limbs.is_empty() {
+            chunks.push(BigInt::div_small(&mut limbs, 1_000_000_000));
+            while limbs.last() == Some(&0) {
+                limbs.pop();
+            }
+        }
+        if self.negative {
+            write!(f, "-")?;
+        }
+        write!(f, "{}", chunks.pop().unwrap())?;
+        for chunk in chunks.iter().rev() {
+            write!(f, "{:09}", chunk)?;
+        }
+        Ok(())
+    }
+}
+
+#[test]
+fn bigint_test() {
+    println!("BigInt Test: Arithmetic carries past 64 bits and back.");
+    let max = BigInt::from(i64::MAX);
+    let one = BigInt::from(1);
+    let big = &max + &one;
+    assert!(big.to_i64().is_none(), "Should have overflowed i64.");
+    assert!(big.to_string() == "9223372036854775808");
+    assert!(
+        (&big - &one).to_i64() == Some(i64::MAX),
+        "Should fit again."
+    );
+
+    let square = &big * &big;
+    assert!(square.to_string() == "85070591730234615865843651857942052864");
+    let min = &(-&big) * &one;
+    assert!(
+        min.to_i64() == Some(i64::MIN),
+        "Should reach i64::MIN exactly."
+    );
+    assert!((&one - &one).is_zero() && !(&one - &one).is_negative());
+
+    assert!(&square / &big == big, "Should divide exactly.");
+    let seven = BigInt::from(-7);
+    assert!(
+        (&seven / &BigInt::from(2)).to_i64() == Some(-3),
+        "Should truncate."
+    );
+    assert!((&one / &square).is_zero());
+    assert!(big.to_f64() == 9223372036854775808.0);
+}
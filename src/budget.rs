@@ -0,0 +1,225 @@
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex, MutexGuard};
+
+use crate::Vm;
+
+/// A limit on the bytes owned by all the VMs that join it, for processes
+/// running many VMs, such as one per tenant. Once they together own nine
+/// tenths of the limit, the VMs owning the most are asked to collect at
+/// their next safepoint, biggest first, as many as it takes for those asked
+/// to own at least half the total. The next round is asked for once usage
+/// has grown by a quarter over what that round left, or crossed nine tenths
+/// again, whichever is more.
+///
+/// The interpreters and native calls reach safepoints on their own; an
+/// embedder pushing and popping directly calls [`Vm::safepoint`]. VMs
+/// report their usage at safepoints and collections, so the total can lag
+/// behind what's been allocated since. A budget doesn't refuse allocations:
+/// it only decides when to collect.
+#[derive(Clone)]
+pub struct MemoryBudget {
+    inner: Arc<Budget>,
+}
+
+struct Budget {
+    limit: usize,
+    /// bytes owned by the members, as last reported
+    used: AtomicUsize,
+    /// usage at which the next round of collections is asked for
+    trigger: AtomicUsize,
+    members: Mutex<Members>,
+}
+
+#[derive(Default)]
+struct Members {
+    /// by VM id
+    slots: HashMap<u64, Arc<Slot>>,
+    /// members asked to collect that haven't yet
+    outstanding: usize,
+}
+
+/// One member's usage and request, shared between the VM and the budget.
+#[derive(Default)]
+pub(crate) struct Slot {
+    bytes: AtomicUsize,
+    requested: AtomicBool,
+}
+
+/// A VM's membership in a budget.
+pub(crate) struct Membership {
+    budget: MemoryBudget,
+    slot: Arc<Slot>,
+}
+
+impl MemoryBudget {
+    pub fn new(limit: usize) -> Self {
+        MemoryBudget {
+            inner: Arc::new(Budget {
+                limit,
+                used: AtomicUsize::new(0),
+                trigger: AtomicUsize::new(pressure(limit)),
+                members: Mutex::new(Members::default()),
+            }),
+        }
+    }
+
+    pub fn limit(&self) -> usize {
+        self.inner.limit
+    }
+
+    /// Bytes owned by the VMs in the budget, as they last reported.
+    pub fn used(&self) -> usize {
+        self.inner.used.load(Ordering::Relaxed)
+    }
+
+    /// Asks the biggest members to collect, unless a round is under way.
+    fn request_collections(&self) {
+        let mut members = lock(&self.inner.members);
+        if members.outstanding > 0 {
+            return;
+        }
+        let mut slots: Vec<_> = members.slots.values().cloned().collect();
+        slots.sort_by_key(|slot| std::cmp::Reverse(slot.bytes.load(Ordering::Relaxed)));
+        let (mut asked, used) = (0, self.used());
+        for slot in slots {
+            if asked >= used / 2 {
+                break;
+            }
+            asked += slot.bytes.load(Ordering::Relaxed);
+            slot.requested.store(true, Ordering::Relaxed);
+            members.outstanding += 1;
+        }
+    }
+
+    /// Counts a requested collection as done, arming the next round once
+    /// the last one of this round is.
+    fn collected(&self, members: &mut Members) {
+        members.outstanding -= 1;
+        if members.outstanding == 0 {
+            let used = self.used();
+            let trigger = pressure(self.inner.limit).max(used + used / 4);
+            self.inner.trigger.store(trigger, Ordering::Relaxed);
+        }
+    }
+}
+
+/// Usage at which a budget of `limit` bytes starts asking for collections.
+fn pressure(limit: usize) -> usize {
+    limit / 10 * 9
+}
+
+impl Vm {
+    /// Makes this VM count towards `budget`, leaving the one it was in
+    /// before, if any, and reports its usage right away.
+    pub fn join_budget(&mut self, budget: &MemoryBudget) {
+        self.leave_budget();
+        let slot = Arc::new(Slot::default());
+        lock(&budget.inner.members)
+            .slots
+            .insert(self.id, slot.clone());
+        self.budget = Some(Membership {
+            budget: budget.clone(),
+            slot,
+        });
+        self.report_usage();
+    }
+
+    /// Stops counting towards the budget the VM is in, if any. Dropping the
+    /// VM does this too.
+    pub fn leave_budget(&mut self) {
+        let Some(Membership { budget, slot }) = self.budget.take() else {
+            return;
+        };
+        let mut members = lock(&budget.inner.members);
+        members.slots.remove(&self.id);
+        budget
+            .inner
+            .used
+            .fetch_sub(slot.bytes.load(Ordering::Relaxed), Ordering::Relaxed);
+        if slot.requested.load(Ordering::Relaxed) {
+            budget.collected(&mut members);
+        }
+    }
+
+    /// Whether the VM's budget has asked it to collect. Reports the VM's
+    /// usage to the budget first.
+    pub(crate) fn budget_requested(&self) -> bool {
+        self.report_usage();
+        self.budget
+            .as_ref()
+            .is_some_and(|membership| membership.slot.requested.load(Ordering::Relaxed))
+    }
+
+    /// Updates the budget with what the VM owns now, asking for collections
+    /// if that puts it over the trigger.
+    pub(crate) fn report_usage(&self) {
+        let Some(Membership { budget, slot }) = &self.budget else {
+            return;
+        };
+        let before = slot.bytes.swap(self.num_bytes, Ordering::Relaxed);
+        let used = &budget.inner.used;
+        let used = if self.num_bytes >= before {
+            used.fetch_add(self.num_bytes - before, Ordering::Relaxed) + self.num_bytes - before
+        } else {
+            used.fetch_sub(before - self.num_bytes, Ordering::Relaxed) - (before - self.num_bytes)
+        };
+        if used >= budget.inner.trigger.load(Ordering::Relaxed) {
+            budget.request_collections();
+        }
+    }
+
+    /// Reports a finished collection to the budget, counting it as the one
+    /// asked for if there was a request.
+    pub(crate) fn budget_collected(&self) {
+        let Some(Membership { budget, slot }) = &self.budget else {
+            return;
+        };
+        if slot.requested.swap(false, Ordering::Relaxed) {
+            budget.collected(&mut lock(&budget.inner.members));
+        }
+        self.report_usage();
+    }
+}
+
+/// Nothing panics while holding the lock, so poisoning is ignored.
+fn lock(members: &Mutex<Members>) -> MutexGuard<'_, Members> {
+    members.lock().unwrap_or_else(|err| err.into_inner())
+}
+
+#[test]
+fn memory_budget_test() {
+    println!("Memory Budget Test: The biggest VMs collect when the budget runs low.");
+    let budget = MemoryBudget::new(64 * 1024);
+    let mut big = Vm::new();
+    let mut small = Vm::new();
+    big.join_budget(&budget);
+    small.join_budget(&budget);
+    small.push_str("small but kept");
+
+    let garbage = "x".repeat(1024);
+    let mut collected = 0;
+    for _ in 0..100 {
+        // keeps the heap threshold out of the way
+        big.max_objs = usize::MAX;
+        big.push_str(&garbage);
+        big.drop_top();
+        collected += big.safepoint() as usize;
+        small.safepoint();
+    }
+    assert!(collected > 0, "Should have asked the big VM to collect.");
+    assert!(
+        budget.used() < budget.limit(),
+        "Should have kept the VMs under budget."
+    );
+    assert!(!small.safepoint(), "Should have left the small VM alone.");
+
+    let used = budget.used();
+    drop(big);
+    assert!(
+        budget.used() < used,
+        "Should stop counting a VM once it's dropped."
+    );
+    small.leave_budget();
+    assert!(budget.used() == 0);
+}
@@ -0,0 +1,262 @@
+//! Compaction of a clustered heap. Sweeping leaves holes in the chunks of
+//! `LayoutPolicy::Clustered`, which only pairs placed next to their children
+//! fill again, so a heap that shrank after a peak keeps most of its chunks.
+//! Compacting moves the objects of the sparsest chunks into the free slots
+//! of fuller ones, emptying the sparse chunks so they're given back.
+//!
+//! Everything the VM holds is pointed at the objects' new places on the
+//! spot. Handles held outside it aren't, so the VM keeps where each moved
+//! object went, by epoch, and every method taking a handle looks it up
+//! there when the handle isn't to a live object: that lookup, in
+//! `Vm::resolve`, is the read barrier handles go through.
+
+use std::collections::{HashMap, HashSet};
+
+use crate::allocation;
+use crate::copy::remap_children;
+use crate::{ConsKey, GcPtr, LayoutPolicy, Object, Trace, TypeTag, Value, Visitor, Vm};
+
+impl Vm {
+    /// Moves the objects of sparse chunks into the free slots of fuller
+    /// ones, giving the emptied chunks back, and returns how many objects
+    /// moved. Only a clustered layout has chunks to compact. Objects that
+    /// foreign and custom objects or the finalizer thread refer to stay
+    /// where they are, and so does everything in their chunks.
+    ///
+    /// A handle taken before keeps working for as long as its object lives,
+    /// since every method taking one finds where it went, see
+    /// [`Vm::resolve`]; a `Value` held outside the VM, being only an
+    /// address, doesn't. Does nothing while the VM records, which the log
+    /// can't express, or while a borrow has left an object referring to one
+    /// that isn't live, see [`ObjMut`](crate::ObjMut).
+    pub fn compact(&mut self) -> usize {
+        self.compaction_due = false;
+        self.relocation = None;
+        if self.config.layout != LayoutPolicy::Clustered
+            || self.recorder.is_some()
+            || !self.settle_writes()
+        {
+            return 0;
+        }
+        let mut plan = self.chunks.evacuation(&self.pinned_chunks());
+        if plan.is_empty() {
+            return 0;
+        }
+        let mut moved = HashMap::new();
+        for i in 0..self.heap.len() {
+            if !plan.moves(self.heap[i].0) {
+                continue;
+            }
+            let room = self.move_object(i, &mut plan, &mut moved);
+            assert!(room, "the evacuation made room");
+        }
+        self.forward_roots(&moved);
+        for i in 0..self.heap.len() {
+            let obj = self.heap[i].clone();
+            remap_children(&mut self.object_mut(&obj).value, forwarder(&moved));
+        }
+        #[cfg(debug_assertions)]
+        self.verify_heap();
+        moved.len()
+    }
+
+    /// The handle to where `obj`'s object is now, if it's a live object of
+    /// this VM: `obj` itself, unless [`Vm::compact`] has moved the object
+    /// since `obj` was taken. Every method taking a handle goes through
+    /// this first, so handles held across a compaction keep working.
+    pub fn resolve(&self, obj: &GcPtr<Object>) -> Option<GcPtr<Object>> {
+        if self.live.contains(obj) {
+            return Some(obj.clone());
+        }
+        self.forwarded.get(&obj.1).cloned()
+    }
+
+    /// Runs the compaction the last collection scheduled, if it did, see
+    /// [`Vm::set_auto_compact`].
+    pub(crate) fn compact_if_due(&mut self) {
+        if self.compaction_due {
+            self.compact();
+        }
+    }
+
+    /// Moves the heap's `i`th object, in a chunk `plan` empties, and adds
+    /// where it went to `moved`, by the address it was at. Returns `false`,
+    /// moving nothing, if `plan` is out of room. Nothing referring to it is
+    /// pointed at it yet.
+    pub(crate) fn move_object(
+        &mut self,
+        i: usize,
+        plan: &mut allocation::Evacuation,
+        moved: &mut HashMap<u64, GcPtr<Object>>,
+    ) -> bool {
+        let obj = self.heap[i].clone();
+        let Some(ptr) = (unsafe { self.chunks.relocate(obj.0, plan) }) else {
+            return false;
+        };
+        let to = GcPtr(ptr, obj.1);
+        self.live.remove(&obj);
+        self.live.insert(&to);
+        self.forwarded.insert(obj.1, to.clone());
+        moved.insert(allocation::to_addr(obj.0), to.clone());
+        self.heap[i] = to;
+        true
+    }
+
+    /// Forgets where the objects collected since moved to.
+    pub(crate) fn sweep_forwarded(&mut self) {
+        let live = &self.live;
+        self.forwarded.retain(|_, obj| live.contains(obj));
+    }
+
+    /// Starts of the chunks holding objects compaction mustn't move: those
+    /// foreign and custom objects refer to, which it can't point elsewhere,
+    /// and those kept for the finalizer thread, which it doesn't look at.
+    pub(crate) fn pinned_chunks(&self) -> HashSet<usize> {
+        struct Pins(HashSet<usize>);
+        impl Visitor for Pins {
+            fn visit(&mut self, obj: &GcPtr<Object>) {
+                self.0.insert(allocation::chunk_start(obj.0));
+            }
+        }
+
+        let mut pins = Pins(HashSet::new());
+        for obj in &self.heap {
+            let tag = unsafe { obj.0.as_ref() }.header.type_tag();
+            if matches!(tag, TypeTag::Foreign | TypeTag::Custom) {
+                obj.value().trace(&mut pins);
+            }
+        }
+        for obj in self.finalizing_objects() {
+            pins.visit(&obj);
+        }
+        pins.0
+    }
+
+    /// Points every reference the VM holds outside the heap to an object in
+    /// `moved`, by the address it was at, at where it is now.
+    pub(crate) fn forward_roots(&mut self, moved: &HashMap<u64, GcPtr<Object>>) {
+        let forward = forwarder(moved);
+        let forward_value = |value: &mut Value| {
+            if let Some(to) = value.addr().and_then(|addr| moved.get(&addr)) {
+                *value = Value::from_obj(to.clone());
+            }
+        };
+
+        self.stack
+            .slots_mut(0..self.stack_size)
+            .for_each(forward_value);
+        for frame in &mut self.frames {
+            frame.locals.iter_mut().for_each(forward_value);
+        }
+        let roots = self.globals.values_mut().chain(&mut self.constants);
+        let tables = self.strings.values_mut().chain(self.symbols.values_mut());
+        roots
+            .chain(self.handles.values_mut())
+            .chain(tables)
+            .for_each(forward);
+        let conses = std::mem::take(&mut self.conses);
+        self.conses = conses
+            .into_iter()
+            .map(|((mut head, mut tail), mut pair)| {
+                for child in [&mut head, &mut tail] {
+                    if let ConsKey::Obj(obj) = child {
+                        forward(obj);
+                    }
+                }
+                forward(&mut pair);
+                ((head, tail), pair)
+            })
+            .collect();
+        self.forward_identity_keys(forward);
+    }
+}
+
+/// Points a reference to an object in `moved`, by the address it was at,
+/// at where it is now.
+pub(crate) fn forwarder(
+    moved: &HashMap<u64, GcPtr<Object>>,
+) -> impl Fn(&mut GcPtr<Object>) + Copy + '_ {
+    |obj| {
+        if let Some(to) = moved.get(&allocation::to_addr(obj.0)) {
+            *obj = to.clone();
+        }
+    }
+}
+
+#[test]
+fn compact_test() {
+    use crate::VmConfig;
+
+    println!("Compact Test: Sparse chunks are emptied, and old handles still work.");
+    let mut vm = Vm::with_config(VmConfig {
+        layout: LayoutPolicy::Clustered,
+        max_stack: Some(1000),
+        ..VmConfig::default()
+    });
+    // keeps every eighth int, leaving each chunk about an eighth full
+    let sparse = |vm: &mut Vm| {
+        for i in 0..2560 {
+            vm.push_int(i);
+            if i % 8 != 0 {
+                vm.drop_top();
+            }
+        }
+    };
+    sparse(&mut vm);
+    let ints: Vec<_> = (0..320).rev().map(|depth| vm.peek(depth)).collect();
+    let hashes: Vec<_> = ints.iter().map(|obj| vm.identity_hash(obj)).collect();
+    let (first, last) = (ints[0].clone(), ints[319].clone());
+    vm.define_global("first", first.clone());
+    let handle = vm.register_handle(last.clone());
+    let pair = vm.push_pair_with(first.clone(), last.clone());
+    vm.gc();
+    let before = vm.heap_stats();
+    assert!(
+        before.fragmentation > 0.5 && before.fragmentation == vm.fragmentation(),
+        "Should count the slots left free between survivors."
+    );
+
+    let moved = vm.compact();
+    let after = vm.heap_stats();
+    assert!(moved > 0 && after.objects == before.objects);
+    assert!(
+        after.chunks < before.chunks / 2,
+        "Should have given back the emptied chunks."
+    );
+    assert!(after.fragmentation < before.fragmentation);
+    assert!(after.by_type == before.by_type);
+
+    let stale = ints
+        .iter()
+        .filter(|obj| vm.resolve(obj).unwrap().0 != obj.0);
+    assert!(stale.count() > 0, "Should have moved some of the ints.");
+    assert!(
+        ints.iter()
+            .map(|obj| obj.as_int(&vm).unwrap())
+            .eq((0..2560).step_by(8)),
+        "Should still reach the objects through the old handles."
+    );
+    assert!(ints.iter().map(|obj| vm.identity_hash(obj)).eq(hashes));
+    let (first, last) = (vm.resolve(&first).unwrap(), vm.resolve(&last).unwrap());
+    assert!(vm.get_global("first").unwrap().0 == first.0);
+    assert!(vm.handle(handle).unwrap().0 == last.0);
+    let (head, tail) = pair.as_pair(&vm).unwrap();
+    assert!(head.unwrap().0 == first.0 && tail.unwrap().0 == last.0);
+    vm.pop();
+    let popped = vm.pop_n(320);
+    assert!(
+        popped
+            .iter()
+            .zip(&ints)
+            .all(|(obj, int)| obj.0 == vm.resolve(int).unwrap().0),
+        "Should have pointed the stack at the moved objects."
+    );
+
+    vm.set_auto_compact(Some(0.5));
+    sparse(&mut vm);
+    vm.gc();
+    assert!(
+        vm.fragmentation() <= 0.5,
+        "Should compact once a collection leaves too much free."
+    );
+}
@@ -0,0 +1,295 @@
+use crate::{BigInt, GcError, GcPtr, ObjType, Object, Vm};
+
+/// A Rust value that can be moved into the heap, see [`Vm::alloc_from`].
+pub trait ToObject {
+    /// Allocates the object for `self` and pushes it.
+    fn push_onto(self, vm: &mut Vm);
+}
+
+/// A Rust value that can be read out of a heap object.
+pub trait FromObject: Sized {
+    fn try_from_obj(vm: &Vm, obj: &GcPtr<Object>) -> Result<Self, GcError>;
+}
+
+impl Vm {
+    /// Moves `value` into the heap, pushing the result and returning a handle
+    /// to it. Tuples become pairs, with the first element as the head, and
+    /// vectors become arrays.
+    pub fn alloc_from(&mut self, value: impl ToObject) -> GcPtr<Object> {
+        value.push_onto(self);
+        self.peek(0)
+    }
+}
+
+impl ToObject for i64 {
+    fn push_onto(self, vm: &mut Vm) {
+        vm.push_int(self);
+    }
+}
+
+impl ToObject for f64 {
+    fn push_onto(self, vm: &mut Vm) {
+        vm.push_float(self);
+    }
+}
+
+impl ToObject for bool {
+    fn push_onto(self, vm: &mut Vm) {
+        vm.push_bool(self);
+    }
+}
+
+impl ToObject for &str {
+    fn push_onto(self, vm: &mut Vm) {
+        vm.push_str(self);
+    }
+}
+
+impl ToObject for String {
+    fn push_onto(self, vm: &mut Vm) {
+        vm.push_str(&self);
+    }
+}
+
+impl ToObject for GcPtr<Object> {
+    fn push_onto(self, vm: &mut Vm) {
+        let obj = vm.assert_live(&self);
+        vm.push_ptr(obj);
+    }
+}
+
+impl<A: ToObject, B: ToObject> ToObject for (A, B) {
+    fn push_onto(self, vm: &mut Vm) {
+        self.1.push_onto(vm);
+        self.0.push_onto(vm);
+        vm.push_pair();
+    }
+}
+
+impl<T: ToObject> ToObject for Vec<T> {
+    fn push_onto(self, vm: &mut Vm) {
+        let len = self.len();
+        for elem in self {
+            elem.push_onto(vm);
+        }
+        vm.push_array(len);
+    }
+}
+
+fn mismatch(expected: &'static str, obj: &GcPtr<Object>) -> GcError {
+    GcError::TypeMismatch {
+        expected,
+        found: obj.value().type_name(),
+    }
+}
+
+impl FromObject for i64 {
+    fn try_from_obj(vm: &Vm, obj: &GcPtr<Object>) -> Result<Self, GcError> {
+        let obj = &vm.check_live(obj)?;
+        match obj.value() {
+            ObjType::Int(i) => Ok(*i),
+            _ => Err(mismatch("int", obj)),
+        }
+    }
+}
+
+impl FromObject for f64 {
+    fn try_from_obj(vm: &Vm, obj: &GcPtr<Object>) -> Result<Self, GcError> {
+        let obj = &vm.check_live(obj)?;
+        obj.value().as_f64().ok_or_else(|| mismatch("number", obj))
+    }
+}
+
+impl FromObject for bool {
+    fn try_from_obj(vm: &Vm, obj: &GcPtr<Object>) -> Result<Self, GcError> {
+        let obj = &vm.check_live(obj)?;
+        match obj.value() {
+            ObjType::Bool(b) => Ok(*b),
+            _ => Err(mismatch("bool", obj)),
+        }
+    }
+}
+
+impl FromObject for String {
+    fn try_from_obj(vm: &Vm, obj: &GcPtr<Object>) -> Result<Self, GcError> {
+        let obj = &vm.check_live(obj)?;
+        match obj.value() {
+            value if value.is_string() => Ok(crate::string_contents(obj)),
+            _ => Err(mismatch("string", obj)),
+        }
+    }
+}
+
+impl FromObject for GcPtr<Object> {
+    fn try_from_obj(vm: &Vm, obj: &GcPtr<Object>) -> Result<Self, GcError> {
+        vm.check_live(obj)
+    }
+}
+
+impl<A: FromObject, B: FromObject> FromObject for (A, B) {
+    fn try_from_obj(vm: &Vm, obj: &GcPtr<Object>) -> Result<Self, GcError> {
+        let obj = &vm.check_live(obj)?;
+        match obj.value() {
+            ObjType::Pair(crate::Pair {
+                head: Some(head),
+                tail: Some(tail),
+            }) => Ok((A::try_from_obj(vm, head)?, B::try_from_obj(vm, tail)?)),
+            _ => Err(mismatch("pair", obj)),
+        }
+    }
+}
+
+impl<T: FromObject> FromObject for Vec<T> {
+    fn try_from_obj(vm: &Vm, obj: &GcPtr<Object>) -> Result<Self, GcError> {
+        let obj = &vm.check_live(obj)?;
+        crate::as_array(obj)?
+            .iter()
+            .map(|elem| T::try_from_obj(vm, elem))
+            .collect()
+    }
+}
+
+/// A heap value copied out into plain Rust data, see [`Vm::to_rust`].
+#[derive(Clone, Debug, PartialEq)]
+pub enum RustValue {
+    Nil,
+    Bool(bool),
+    Int(i64),
+    BigInt(BigInt),
+    Float(f64),
+    Str(String),
+    Symbol(String),
+    Pair(Box<RustValue>, Box<RustValue>),
+    Array(Vec<RustValue>),
+    /// entries in no particular order
+    Map(Vec<(RustValue, RustValue)>),
+    Bytes(Vec<u8>),
+    /// a closure, foreign or custom object, which has no plain equivalent;
+    /// holds its type name
+    Opaque(&'static str),
+}
+
+impl Vm {
+    /// Copies the value of `obj` and everything it refers to out of the heap.
+    /// Structure shared within the graph is copied once per path reaching it,
+    /// and a cycle, which would make the copy infinite, fails with
+    /// `GcError::CyclicValue`.
+    pub fn to_rust(&self, obj: &GcPtr<Object>) -> Result<RustValue, GcError> {
+        to_rust(&self.check_live(obj)?, &mut vec![])
+    }
+}
+
+/// `path` holds the objects being copied around this one.
+fn to_rust(obj: &GcPtr<Object>, path: &mut Vec<GcPtr<Object>>) -> Result<RustValue, GcError> {
+    if path.contains(obj) {
+        return Err(GcError::CyclicValue);
+    }
+    path.push(obj.clone());
+    let mut child = |child: &Option<GcPtr<Object>>| match child {
+        Some(child) => to_rust(child, path).map(Box::new),
+        None => Ok(Box::new(RustValue::Nil)),
+    };
+    let value = match obj.value() {
+        ObjType::Nil => RustValue::Nil,
+        ObjType::Bool(b) => RustValue::Bool(*b),
+        ObjType::Int(i) => RustValue::Int(*i),
+        ObjType::BigInt(n) => RustValue::BigInt(n.clone()),
+        ObjType::Float(f) => RustValue::Float(*f),
+        ObjType::Str(_) | ObjType::Rope(_) => RustValue::Str(crate::string_contents(obj)),
+        ObjType::Symbol(name) => RustValue::Symbol(name.clone()),
+        ObjType::Pair(pair) => RustValue::Pair(child(&pair.head)?, child(&pair.tail)?),
+        ObjType::Array(elems) => RustValue::Array(
+            elems
+                .iter()
+                .map(|elem| to_rust(elem, path))
+                .collect::<Result<_, _>>()?,
+        ),
+        ObjType::Map(map) => RustValue::Map(
+            map.entries
+                .iter()
+                .map(|(key, value)| {
+                    let key = match key {
+                        crate::MapKey::Int(i) => RustValue::Int(*i),
+                        crate::MapKey::Str(s) => RustValue::Str(s.clone()),
+                        crate::MapKey::Symbol(name) => RustValue::Symbol(name.clone()),
+                    };
+                    Ok((key, to_rust(value, path)?))
+                })
+                .collect::<Result<_, GcError>>()?,
+        ),
+        ObjType::Bytes(bytes) => RustValue::Bytes(bytes.clone()),
+        value => RustValue::Opaque(value.type_name()),
+    };
+    path.pop();
+    Ok(value)
+}
+
+#[test]
+fn convert_test() {
+    println!("Convert Test: Rust values round-trip through the heap.");
+    let mut vm = Vm::new();
+    let int = vm.alloc_from(42i64);
+    assert!(i64::try_from_obj(&vm, &int) == Ok(42));
+    assert!(vm.stack_size == 1, "Should have rooted the object.");
+
+    let pair = vm.alloc_from((1i64, "two"));
+    assert!(<(i64, String)>::try_from_obj(&vm, &pair) == Ok((1, "two".to_owned())));
+    assert!(
+        pair.as_pair(&vm).unwrap().0.unwrap().as_int(&vm) == Some(1),
+        "Should put the first half in the head."
+    );
+
+    let array = vm.alloc_from(vec![(true, 1.5), (false, 2.0)]);
+    assert!(<Vec<(bool, f64)>>::try_from_obj(&vm, &array) == Ok(vec![(true, 1.5), (false, 2.0)]));
+
+    let shared = vm.alloc_from((int.clone(), int.clone()));
+    let (a, b) = <(GcPtr<Object>, GcPtr<Object>)>::try_from_obj(&vm, &shared).unwrap();
+    assert!(a == int && b == int, "Should keep handles as they are.");
+    assert!(
+        bool::try_from_obj(&vm, &int)
+            == Err(GcError::TypeMismatch {
+                expected: "bool",
+                found: "int"
+            })
+    );
+    drop(vm);
+}
+
+#[test]
+fn to_rust_test() {
+    println!("To Rust Test: Heap graphs copy out into owned values.");
+    let mut vm = Vm::new();
+    let list = vm.push_list([1, 2]);
+    assert!(
+        vm.to_rust(&list)
+            == Ok(RustValue::Pair(
+                Box::new(RustValue::Int(1)),
+                Box::new(RustValue::Pair(
+                    Box::new(RustValue::Int(2)),
+                    Box::new(RustValue::Nil)
+                ))
+            ))
+    );
+
+    vm.push_ptr(list.clone());
+    vm.push_str("x");
+    vm.push_array(3);
+    let array = vm.peek(0);
+    match vm.to_rust(&array).unwrap() {
+        RustValue::Array(elems) => {
+            assert!(
+                elems.len() == 3 && elems[0] == elems[1],
+                "Should copy shared parts twice."
+            );
+            assert!(elems[2] == RustValue::Str("x".to_owned()));
+        }
+        other => panic!("Should be an array, not {:?}", other),
+    }
+
+    vm.set_tail(&list, list.clone()).unwrap();
+    assert!(
+        vm.to_rust(&list) == Err(GcError::CyclicValue),
+        "Should detect cycles."
+    );
+    drop(vm);
+}
@@ -0,0 +1,414 @@
+use std::collections::{HashMap, HashSet};
+
+use crate::{Event, GcError, GcPtr, ObjType, Object, Trace, Value, Visitor, Vm, VmConfig};
+
+impl Vm {
+    /// Copies everything reachable from `obj`, pushes the copy and returns
+    /// it. Objects shared by the original are shared by the copy too, cycles
+    /// included. Immortals and symbols stay shared with the original, and so
+    /// do foreign and custom objects, whose payloads can't be duplicated. The
+    /// copies start out unfrozen.
+    pub fn deep_clone(&mut self, obj: &GcPtr<Object>) -> GcPtr<Object> {
+        let obj = &self.assert_live(obj);
+        self.recorded(
+            |recorder| Event::DeepClone(recorder.id(obj)),
+            |vm| {
+                let copy = vm
+                    .copy_graph(std::slice::from_ref(obj), |vm, obj| {
+                        let shared =
+                            vm.immortals.contains(obj) || shallow_copy(obj.value()).is_none();
+                        shared.then(|| obj.clone())
+                    })
+                    .remove(0);
+                vm.push_ptr(copy.clone());
+                copy
+            },
+        )
+    }
+
+    /// Copies everything reachable from `obj`, which lives in this VM, into
+    /// `other`, pushes the copy there and returns it. Sharing and cycles are
+    /// kept like [`Vm::deep_clone`] keeps them. Nil, booleans, cached small
+    /// ints and symbols become `other`'s own; foreign and custom objects
+    /// belong to the VM that made them, so reaching one fails with
+    /// `GcError::TypeMismatch`. The whole graph is checked before anything is
+    /// allocated in `other`, so a failure, like `GcError::StackOverflow` when
+    /// its stack is full, leaves it exactly as it was. A recording `other`
+    /// can't log objects coming from elsewhere, so it refuses with
+    /// `GcError::Unrecordable`.
+    pub fn transfer_to(
+        &self,
+        other: &mut Vm,
+        obj: &GcPtr<Object>,
+    ) -> Result<GcPtr<Object>, GcError> {
+        other.refuse_recording("transfer_to")?;
+        let obj = &self.check_live(obj)?;
+        if other.stack_size >= other.stack_max {
+            return Err(GcError::StackOverflow);
+        }
+        other.stack.try_reserve(other.stack_size)?;
+        other.check_movable(std::slice::from_ref(obj))?;
+        let copy = other
+            .copy_graph(std::slice::from_ref(obj), share_across)
+            .remove(0);
+        other.push_ptr(copy.clone());
+        Ok(copy)
+    }
+
+    /// Deep clones the VM: returns a new one holding a copy of everything
+    /// this one can reach. The stack, frames, globals, constant pool and
+    /// handles are copied over like [`Vm::transfer_to`] copies, so structure
+    /// shared between any of them stays shared. The clone has the same
+    /// configuration, natives, kinds and mounted segments, whose objects it
+    /// shares rather than copies, doesn't record, and from then on is
+    /// independent of this VM.
+    ///
+    /// Nothing is shared copy-on-write: every reachable object is copied
+    /// right away, so this costs as much as the live heap. Foreign and custom
+    /// objects can't be copied, so reaching one fails with
+    /// `GcError::TypeMismatch`.
+    pub fn try_clone(&self) -> Result<Vm, GcError> {
+        let mut clone = Vm::with_config(VmConfig {
+            record: false,
+            ..self.config.clone()
+        });
+        clone.natives = self.natives.clone();
+        clone.kinds = self.kinds.clone();
+        for segment in &self.segments {
+            clone.attach(segment);
+        }
+
+        let locals = self.frames.iter().flat_map(|frame| &frame.locals).copied();
+        let values: Vec<_> = self.stack.slots(0..self.stack_size).chain(locals).collect();
+        let mut roots: Vec<_> = values.iter().filter_map(|value| value.as_obj()).collect();
+        roots.extend(self.globals.values().chain(&self.constants).cloned());
+        roots.extend(self.handles.values().cloned());
+        clone.check_movable(&roots)?;
+        let mut copies = clone.copy_graph(&roots, share_across).into_iter();
+
+        let mut copy = |value: Value, clone: &Vm| match value.as_obj() {
+            Some(_) => clone.to_value(copies.next().unwrap()),
+            None => value,
+        };
+        for &value in &values[..self.stack_size] {
+            let value = copy(value, &clone);
+            clone.push_slot(value);
+        }
+        let mut locals = values[self.stack_size..].iter();
+        let mut frames = self.frames.clone();
+        for frame in &mut frames {
+            for local in &mut frame.locals {
+                *local = copy(*locals.next().unwrap(), &clone);
+            }
+        }
+        clone.frames = frames;
+        for name in self.globals.keys() {
+            clone.globals.insert(name.clone(), copies.next().unwrap());
+        }
+        clone.constants = copies.by_ref().take(self.constants.len()).collect();
+        clone.handles = self.handles.keys().copied().zip(copies).collect();
+        clone.next_handle = self.next_handle;
+        clone.max_objs = self.max_objs;
+        Ok(clone)
+    }
+
+    /// Allocates copies of everything reachable from `roots` in this VM and
+    /// returns the copies of `roots`. `share` is asked about each object first,
+    /// and whatever it returns is used in place of a copy, without looking
+    /// inside. The copies aren't rooted, which is fine until the next
+    /// collection since allocating never collects.
+    fn copy_graph(
+        &mut self,
+        roots: &[GcPtr<Object>],
+        mut share: impl FnMut(&mut Vm, &GcPtr<Object>) -> Option<GcPtr<Object>>,
+    ) -> Vec<GcPtr<Object>> {
+        let mut replacements = HashMap::new();
+        let mut copies = vec![];
+        let mut pending = Pending(roots.to_vec());
+        while let Some(original) = pending.0.pop() {
+            if replacements.contains_key(&original) {
+                continue;
+            }
+            let replacement = match share(self, &original) {
+                Some(shared) => shared,
+                None => {
+                    let value = shallow_copy(original.value()).expect("uncopyable object");
+                    original.value().trace(&mut pending);
+                    let copy = self.alloc(value);
+                    let tag = original.user_tag();
+                    self.object_mut(&copy).header.set_user_tag(tag);
+                    copies.push(copy.clone());
+                    copy
+                }
+            };
+            replacements.insert(original, replacement);
+        }
+
+        for copy in &copies {
+            remap_children(&mut self.object_mut(copy).value, |child| {
+                *child = replacements[child].clone()
+            });
+        }
+        roots
+            .iter()
+            .map(|root| replacements[root].clone())
+            .collect()
+    }
+
+    /// Fails with `GcError::TypeMismatch` if anything reachable from `roots`
+    /// is a foreign or custom object, which `share_across` can't bring into
+    /// this VM. Objects of a segment this VM has mounted aren't looked
+    /// inside, since they're shared rather than copied.
+    fn check_movable(&self, roots: &[GcPtr<Object>]) -> Result<(), GcError> {
+        let mut seen = HashSet::new();
+        let mut pending = Pending(roots.to_vec());
+        while let Some(obj) = pending.0.pop() {
+            if self.is_shared(&obj) || !seen.insert(obj.clone()) {
+                continue;
+            }
+            if let ObjType::Foreign(_) | ObjType::Custom(_) = obj.value() {
+                return Err(GcError::TypeMismatch {
+                    expected: "value that can leave its VM",
+                    found: obj.value().type_name(),
+                });
+            }
+            obj.value().trace(&mut pending);
+        }
+        Ok(())
+    }
+}
+
+/// Objects reached but not looked at yet.
+struct Pending(Vec<GcPtr<Object>>);
+
+impl Visitor for Pending {
+    fn visit(&mut self, obj: &GcPtr<Object>) {
+        self.0.push(obj.clone());
+    }
+}
+
+/// How objects are shared when copying into another VM: nil, booleans, ints
+/// and symbols have their canonical instances there, and objects of a
+/// segment it has mounted are the same objects there. Foreign and custom
+/// objects can't be copied, which `Vm::check_movable` rules out first.
+fn share_across(other: &mut Vm, obj: &GcPtr<Object>) -> Option<GcPtr<Object>> {
+    if other.is_shared(obj) {
+        return Some(obj.clone());
+    }
+    match obj.value() {
+        ObjType::Nil => Some(other.nil.clone()),
+        ObjType::Bool(true) => Some(other.true_obj.clone()),
+        ObjType::Bool(false) => Some(other.false_obj.clone()),
+        ObjType::Int(i) => Some(other.alloc_int(*i)),
+        ObjType::Symbol(name) => Some(other.symbol_obj(name)),
+        ObjType::Foreign(_) | ObjType::Custom(_) => unreachable!("checked by check_movable"),
+        _ => None,
+    }
+}
+
+/// Calls `remap` on every child of `value` so it can point it elsewhere.
+/// Foreign and custom objects keep their children out of reach.
+pub(crate) fn remap_children(value: &mut ObjType, mut remap: impl FnMut(&mut GcPtr<Object>)) {
+    match value {
+        ObjType::Pair(pair) => pair.head.iter_mut().chain(&mut pair.tail).for_each(remap),
+        ObjType::Rope(rope) => {
+            remap(&mut rope.left);
+            remap(&mut rope.right);
+        }
+        ObjType::Array(elems) => elems.iter_mut().for_each(remap),
+        ObjType::Map(map) => map.entries.values_mut().for_each(remap),
+        ObjType::Closure(closure) => closure.upvalues.iter_mut().for_each(remap),
+        _ => {}
+    }
+}
+
+/// Returns a copy of `value` still pointing at the original children, or
+/// `None` for the kinds of objects that are never copied.
+pub(crate) fn shallow_copy(value: &ObjType) -> Option<ObjType> {
+    Some(match value {
+        ObjType::Int(i) => ObjType::Int(*i),
+        ObjType::BigInt(n) => ObjType::BigInt(n.clone()),
+        ObjType::Float(f) => ObjType::Float(*f),
+        ObjType::Pair(pair) => ObjType::Pair(pair.clone()),
+        ObjType::Str(s) => ObjType::Str(s.clone()),
+        ObjType::Rope(rope) => ObjType::Rope(rope.clone()),
+        ObjType::Array(elems) => ObjType::Array(elems.clone()),
+        ObjType::Map(map) => ObjType::Map(map.clone()),
+        ObjType::Closure(closure) => ObjType::Closure(closure.clone()),
+        ObjType::Bytes(bytes) => ObjType::Bytes(bytes.clone()),
+        ObjType::Nil
+        | ObjType::Bool(_)
+        | ObjType::Symbol(_)
+        | ObjType::Foreign(_)
+        | ObjType::Custom(_) => return None,
+    })
+}
+
+#[test]
+fn deep_clone_test() {
+    println!("Deep Clone Test: Copies keep the original's sharing and cycles.");
+    let mut vm = Vm::new();
+    let shared = vm.push_list([1, 2]);
+    vm.dup();
+    vm.push_array(2);
+    let array = vm.peek(0);
+    let ring = vm.push_list([3]);
+    vm.set_tail(&ring, ring.clone()).unwrap();
+    vm.array_set(&array, 1, ring).unwrap();
+    let before = vm.num_objs;
+
+    let copy = vm.deep_clone(&array);
+    assert!(vm.stack_size == 3, "Should have pushed the copy.");
+    assert!(copy != array && vm.deep_eq(&copy, &array));
+    assert!(
+        vm.num_objs == before + 7,
+        "Should have copied every object once."
+    );
+
+    let ring = vm.array_get(&copy, 1).unwrap();
+    assert!(
+        vm.iter_list(&ring).count() == 1,
+        "Should have kept the cycle."
+    );
+    let (_, tail) = ring.as_pair(&vm).unwrap();
+    assert!(
+        tail == Some(ring.clone()),
+        "Should point at the copied ring."
+    );
+
+    vm.set_head(&ring, shared).unwrap();
+    assert!(
+        !vm.deep_eq(&copy, &array),
+        "Should not share mutable objects."
+    );
+    vm.gc();
+    drop(vm);
+}
+
+#[test]
+fn transfer_test() {
+    #[derive(Debug)]
+    struct Texture;
+    impl Trace for Texture {
+        fn trace(&self, _: &mut dyn Visitor) {}
+    }
+    impl crate::ForeignObject for Texture {}
+
+    println!("Transfer Test: Graphs move between VMs with their sharing intact.");
+    let mut src = Vm::new();
+    let mut dst = Vm::with_config(VmConfig {
+        small_int_cache: true,
+        ..VmConfig::default()
+    });
+    let list = src.push_list([1, 2]);
+    src.push_ptr(list.clone());
+    src.symbol("tag");
+    src.push_array(3);
+    let array = src.peek(0);
+    src.set_tail(&list, list.clone()).unwrap();
+
+    let copy = src.transfer_to(&mut dst, &array).unwrap();
+    assert!(
+        dst.stack_size == 1,
+        "Should have pushed the copy in the destination."
+    );
+    assert!(dst.display(&copy) == src.display(&array));
+    let tag = dst.symbol("tag");
+    assert!(
+        dst.array_get(&copy, 2).unwrap() == tag,
+        "Should use the destination's symbols."
+    );
+    let one = dst
+        .array_get(&copy, 0)
+        .unwrap()
+        .as_pair(&dst)
+        .unwrap()
+        .0
+        .unwrap();
+    assert!(
+        dst.small_ints.contains(&one),
+        "Should use the destination's cached ints."
+    );
+
+    drop(src);
+    dst.gc();
+    assert!(
+        dst.display(&copy) == "#(#0=(1 . #0#) #0# tag)",
+        "Should own its copy."
+    );
+
+    let mut other = Vm::new();
+    dst.push_list([7, 8]);
+    dst.push_foreign(Texture);
+    dst.push_array(2);
+    let mixed = dst.peek(0);
+    let before = (other.num_objs, other.num_bytes, other.symbols.len());
+    assert!(
+        matches!(
+            dst.transfer_to(&mut other, &mixed),
+            Err(GcError::TypeMismatch { .. })
+        ),
+        "Should refuse foreign objects."
+    );
+    assert!(
+        (other.num_objs, other.num_bytes, other.symbols.len()) == before && other.stack_size == 0,
+        "Should have left the destination as it was."
+    );
+    let mut full = Vm::with_config(VmConfig {
+        max_stack: Some(0),
+        ..VmConfig::default()
+    });
+    assert!(dst.transfer_to(&mut full, &copy) == Err(GcError::StackOverflow));
+    assert!(full.num_objs == 0, "Should not copy with nowhere to push.");
+    drop(dst);
+}
+
+#[test]
+fn try_clone_test() {
+    println!("Try Clone Test: A clone can be changed without touching the original.");
+    let mut vm = Vm::new();
+    let list = vm.push_list([1, 2]);
+    vm.define_global("list", list.clone());
+    vm.push_float(0.5);
+    vm.store_local(0);
+    let index = vm.add_constant(list.clone());
+    let handle = vm.register_handle(list.clone());
+
+    let mut clone = vm.try_clone().unwrap();
+    let copy = clone.peek(0);
+    assert!(copy != list && clone.deep_eq_across(&copy, &vm, &list));
+    assert!(
+        clone.get_global("list") == Some(copy.clone()),
+        "Should keep roots shared."
+    );
+    clone.push_const(index).unwrap();
+    assert!(clone.peek(0) == copy);
+    clone.load_local(0).unwrap();
+    assert!(clone.pop().value() == &ObjType::Float(0.5));
+    assert!(clone.handle(handle) == Some(copy.clone()));
+
+    clone.set_head(&copy, clone.nil.clone()).unwrap();
+    assert!(
+        vm.display(&list) == "(1 2)",
+        "Should not affect the original."
+    );
+    clone.clear_stack();
+    clone.reset().unwrap();
+    vm.gc();
+    assert!(vm.num_objs == 5);
+    drop(clone);
+
+    #[derive(Debug)]
+    struct Texture;
+    impl Trace for Texture {
+        fn trace(&self, _: &mut dyn Visitor) {}
+    }
+    impl crate::ForeignObject for Texture {}
+
+    vm.push_foreign(Texture);
+    assert!(
+        matches!(vm.try_clone(), Err(GcError::TypeMismatch { .. })),
+        "Should refuse to copy foreign objects."
+    );
+    drop(vm);
+}
@@ -0,0 +1,299 @@
+//! The VM behind a C ABI. A `Vm` is an opaque pointer to C, made by
+//! `gc_vm_new` and released by `gc_vm_free`, and every other function takes
+//! it first and reports how it went with a [`GcStatus`]. Nothing here
+//! panics across the boundary: conditions the Rust API asserts on, like
+//! stack underflow, come back as status codes instead.
+//!
+//! Objects are never handed out as pointers. C refers to them by `u64`
+//! handles, which keep their object alive until `gc_handle_release`; a
+//! released or made-up handle is reported, not dereferenced.
+//!
+//! Built for `wasm32-unknown-unknown`, the cdylib exports the same functions
+//! to JavaScript, with handles and status codes as plain numbers:
+//!
+//! ```js
+//! const { instance } = await WebAssembly.instantiateStreaming(fetch("gc.wasm"));
+//! const { gc_vm_new, gc_vm_push_int, gc_vm_num_objects } = instance.exports;
+//! const vm = gc_vm_new();
+//! gc_vm_push_int(vm, 42n);
+//! ```
+
+use crate::{Event, GcError, GcPtr, ObjType, Object, Vm};
+
+/// What an FFI call did, `GC_OK` being success.
+#[repr(C)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum GcStatus {
+    Ok = 0,
+    /// the VM pointer was null
+    NullVm,
+    StackOverflow,
+    StackUnderflow,
+    TypeMismatch,
+    /// the handle was released or never handed out
+    InvalidHandle,
+    /// any other `GcError`
+    Error,
+}
+
+impl From<Result<(), GcError>> for GcStatus {
+    fn from(result: Result<(), GcError>) -> Self {
+        match result {
+            Ok(()) => GcStatus::Ok,
+            Err(GcError::StackOverflow) => GcStatus::StackOverflow,
+            Err(GcError::StackUnderflow) => GcStatus::StackUnderflow,
+            Err(GcError::TypeMismatch { .. }) => GcStatus::TypeMismatch,
+            Err(GcError::InvalidHandle(_)) => GcStatus::InvalidHandle,
+            Err(_) => GcStatus::Error,
+        }
+    }
+}
+
+impl Vm {
+    /// Roots `obj` until [`Vm::release_handle`] and returns the id it can be
+    /// looked up by. Ids aren't reused, and 0 is never one.
+    pub fn register_handle(&mut self, obj: GcPtr<Object>) -> u64 {
+        let obj = self.assert_live(&obj);
+        let obj_ref = obj.clone();
+        self.recorded(
+            |recorder| Event::RegisterHandle(recorder.id(&obj_ref)),
+            |vm| {
+                let id = vm.next_handle;
+                vm.next_handle += 1;
+                vm.handles.insert(id, obj);
+                id
+            },
+        )
+    }
+
+    pub fn handle(&self, id: u64) -> Option<GcPtr<Object>> {
+        self.handles.get(&id).cloned()
+    }
+
+    /// Unroots the object behind `id`, returning whether it was a handle.
+    pub fn release_handle(&mut self, id: u64) -> bool {
+        self.recorded(
+            |_| Event::ReleaseHandle(id),
+            |vm| vm.handles.remove(&id).is_some(),
+        )
+    }
+}
+
+/// Runs `f` on the VM `vm` points to, or fails with `GcStatus::NullVm`.
+///
+/// # Safety
+///
+/// `vm` has to be null or a live pointer from `gc_vm_new`.
+unsafe fn with_vm(vm: *mut Vm, f: impl FnOnce(&mut Vm) -> Result<(), GcError>) -> GcStatus {
+    match vm.as_mut() {
+        Some(vm) => f(vm).into(),
+        None => GcStatus::NullVm,
+    }
+}
+
+/// Makes a VM with the default configuration.
+#[no_mangle]
+pub extern "C" fn gc_vm_new() -> *mut Vm {
+    Box::into_raw(Box::new(Vm::new()))
+}
+
+/// Frees a VM and everything in its heap. Null is ignored.
+///
+/// # Safety
+///
+/// `vm` has to be null or a live pointer from `gc_vm_new`, and can't be used
+/// afterwards.
+#[no_mangle]
+pub unsafe extern "C" fn gc_vm_free(vm: *mut Vm) {
+    if !vm.is_null() {
+        drop(Box::from_raw(vm));
+    }
+}
+
+/// Pushes an int.
+///
+/// # Safety
+///
+/// `vm` has to be null or a live pointer from `gc_vm_new`.
+#[no_mangle]
+pub unsafe extern "C" fn gc_vm_push_int(vm: *mut Vm, value: i64) -> GcStatus {
+    with_vm(vm, |vm| {
+        if vm.stack_size >= vm.stack_max {
+            return Err(GcError::StackOverflow);
+        }
+        vm.push_int(value);
+        Ok(())
+    })
+}
+
+/// Pops two values and pushes a pair of them, the topmost being the head.
+///
+/// # Safety
+///
+/// `vm` has to be null or a live pointer from `gc_vm_new`.
+#[no_mangle]
+pub unsafe extern "C" fn gc_vm_push_pair(vm: *mut Vm) -> GcStatus {
+    with_vm(vm, |vm| {
+        if vm.stack_size < 2 {
+            return Err(GcError::StackUnderflow);
+        }
+        vm.push_pair();
+        Ok(())
+    })
+}
+
+/// Pops an int into `*out`. The top of the stack is left alone if it isn't
+/// an int.
+///
+/// # Safety
+///
+/// `vm` has to be null or a live pointer from `gc_vm_new`, and `out` has to
+/// be valid for writes.
+#[no_mangle]
+pub unsafe extern "C" fn gc_vm_pop_int(vm: *mut Vm, out: *mut i64) -> GcStatus {
+    with_vm(vm, |vm| {
+        let value = vm.peek_value(0).ok_or(GcError::StackUnderflow)?;
+        let i = match value.as_int() {
+            Some(i) => i,
+            None => match vm.peek(0).value() {
+                ObjType::Int(i) => *i,
+                value => {
+                    return Err(GcError::TypeMismatch {
+                        expected: "int",
+                        found: value.type_name(),
+                    })
+                }
+            },
+        };
+        vm.pop_value();
+        *out = i;
+        Ok(())
+    })
+}
+
+/// Pops and discards the top of the stack.
+///
+/// # Safety
+///
+/// `vm` has to be null or a live pointer from `gc_vm_new`.
+#[no_mangle]
+pub unsafe extern "C" fn gc_vm_pop(vm: *mut Vm) -> GcStatus {
+    with_vm(vm, |vm| vm.try_pop().map(drop))
+}
+
+/// Pops the top of the stack into a new handle, written to `*out`.
+///
+/// # Safety
+///
+/// `vm` has to be null or a live pointer from `gc_vm_new`, and `out` has to
+/// be valid for writes.
+#[no_mangle]
+pub unsafe extern "C" fn gc_vm_pop_handle(vm: *mut Vm, out: *mut u64) -> GcStatus {
+    with_vm(vm, |vm| {
+        let obj = vm.try_pop()?;
+        *out = vm.register_handle(obj);
+        Ok(())
+    })
+}
+
+/// Pushes the object behind `handle`, which stays valid.
+///
+/// # Safety
+///
+/// `vm` has to be null or a live pointer from `gc_vm_new`.
+#[no_mangle]
+pub unsafe extern "C" fn gc_vm_push_handle(vm: *mut Vm, handle: u64) -> GcStatus {
+    with_vm(vm, |vm| {
+        let obj = vm.handle(handle).ok_or(GcError::InvalidHandle(handle))?;
+        if vm.stack_size >= vm.stack_max {
+            return Err(GcError::StackOverflow);
+        }
+        vm.push_ptr(obj);
+        Ok(())
+    })
+}
+
+/// Releases `handle`, after which its object can be collected unless
+/// something else refers to it.
+///
+/// # Safety
+///
+/// `vm` has to be null or a live pointer from `gc_vm_new`.
+#[no_mangle]
+pub unsafe extern "C" fn gc_handle_release(vm: *mut Vm, handle: u64) -> GcStatus {
+    with_vm(vm, |vm| match vm.release_handle(handle) {
+        true => Ok(()),
+        false => Err(GcError::InvalidHandle(handle)),
+    })
+}
+
+/// Collects garbage.
+///
+/// # Safety
+///
+/// `vm` has to be null or a live pointer from `gc_vm_new`.
+#[no_mangle]
+pub unsafe extern "C" fn gc_vm_collect(vm: *mut Vm) -> GcStatus {
+    with_vm(vm, |vm| {
+        vm.gc();
+        Ok(())
+    })
+}
+
+/// Returns the number of live objects, or 0 for a null VM.
+///
+/// # Safety
+///
+/// `vm` has to be null or a live pointer from `gc_vm_new`.
+#[no_mangle]
+pub unsafe extern "C" fn gc_vm_num_objects(vm: *const Vm) -> usize {
+    vm.as_ref().map_or(0, |vm| vm.num_objs)
+}
+
+#[test]
+fn ffi_test() {
+    println!("FFI Test: The C API reports errors instead of panicking.");
+    unsafe {
+        let vm = gc_vm_new();
+        assert!(gc_vm_push_pair(vm) == GcStatus::StackUnderflow);
+        assert!(gc_vm_push_int(vm, 1) == GcStatus::Ok);
+        assert!(gc_vm_push_int(vm, 2) == GcStatus::Ok);
+        assert!(gc_vm_push_pair(vm) == GcStatus::Ok);
+        let mut out = 0;
+        assert!(gc_vm_pop_int(vm, &mut out) == GcStatus::TypeMismatch);
+        assert!(gc_vm_pop(vm) == GcStatus::Ok);
+        assert!(gc_vm_collect(vm) == GcStatus::Ok);
+        assert!(
+            gc_vm_num_objects(vm) == 0,
+            "Should have collected the pair."
+        );
+
+        assert!(gc_vm_push_int(vm, -7) == GcStatus::Ok);
+        assert!(gc_vm_pop_int(vm, &mut out) == GcStatus::Ok && out == -7);
+
+        assert!(gc_vm_push_int(vm, 1) == GcStatus::Ok);
+        assert!(gc_vm_push_int(vm, 2) == GcStatus::Ok);
+        assert!(gc_vm_push_pair(vm) == GcStatus::Ok);
+        let mut handle = 0;
+        assert!(gc_vm_pop_handle(vm, &mut handle) == GcStatus::Ok && handle != 0);
+        assert!(gc_vm_collect(vm) == GcStatus::Ok);
+        assert!(gc_vm_num_objects(vm) == 3, "Should keep handles alive.");
+        assert!(gc_vm_push_handle(vm, handle) == GcStatus::Ok);
+        assert!(gc_vm_pop(vm) == GcStatus::Ok);
+        assert!(gc_handle_release(vm, handle) == GcStatus::Ok);
+        assert!(gc_handle_release(vm, handle) == GcStatus::InvalidHandle);
+        assert!(gc_vm_push_handle(vm, handle) == GcStatus::InvalidHandle);
+        assert!(gc_vm_push_handle(vm, 12345) == GcStatus::InvalidHandle);
+        assert!(gc_vm_collect(vm) == GcStatus::Ok);
+        assert!(gc_vm_num_objects(vm) == 0, "Should free released handles.");
+        assert!(gc_vm_pop(vm) == GcStatus::StackUnderflow);
+        while gc_vm_push_int(vm, 0) == GcStatus::Ok {}
+        assert!(gc_vm_push_int(vm, 0) == GcStatus::StackOverflow);
+        gc_vm_free(vm);
+
+        let null = std::ptr::null_mut();
+        assert!(gc_vm_push_int(null, 1) == GcStatus::NullVm);
+        assert!(gc_vm_num_objects(null) == 0);
+        gc_vm_free(null);
+    }
+}
@@ -0,0 +1,316 @@
+use std::collections::{HashMap, HashSet};
+use std::sync::mpsc::{self, Receiver, Sender, TryRecvError};
+use std::thread::JoinHandle;
+
+use crate::{GcPtr, ObjType, Object, Trace, TypeTag, Visitor, Vm};
+
+/// A thread finalizing the values of dead objects, see
+/// [`Vm::set_background_finalizers`].
+pub(crate) struct Finalizer {
+    /// values to finalize, each with the id it's pending under
+    queue: Sender<(u64, ObjType)>,
+    /// ids of the values the thread is done with
+    done: Receiver<u64>,
+    thread: JoinHandle<()>,
+    /// objects the collection running now found dead, with the objects they
+    /// refer to, which have to outlive their finalizers
+    rescued: HashMap<GcPtr<Object>, Vec<GcPtr<Object>>>,
+    /// values sent to the thread and not reported done yet, by id: the
+    /// object each came out of, now holding nil, and the objects it referred
+    /// to, both of which are kept until then
+    pending: HashMap<u64, (GcPtr<Object>, Vec<GcPtr<Object>>)>,
+    next_id: u64,
+}
+
+impl Finalizer {
+    fn new() -> Self {
+        let (queue, values) = mpsc::channel::<(u64, ObjType)>();
+        let (finished, done) = mpsc::channel();
+        let thread = std::thread::spawn(move || {
+            for (id, mut value) in values {
+                value.finalize();
+                // the value is dropped here too, so a slow `Drop` doesn't
+                // hold up the VM either
+                drop(value);
+                if finished.send(id).is_err() {
+                    return;
+                }
+            }
+        });
+        Finalizer {
+            queue,
+            done,
+            thread,
+            rescued: HashMap::new(),
+            pending: HashMap::new(),
+            next_id: 0,
+        }
+    }
+
+    /// If `obj`, which the sweep found dead, was rescued, takes its value
+    /// out and sends it to the thread, returning whether it did. `obj`
+    /// itself stays allocated until the value is finalized, since what it
+    /// referred to may refer back to it.
+    pub(crate) fn queue(&mut self, obj: &GcPtr<Object>) -> bool {
+        let Some(children) = self.rescued.remove(obj) else {
+            return false;
+        };
+        let object = unsafe { &mut *obj.0.as_ptr() };
+        let value = std::mem::replace(&mut object.value, ObjType::Nil);
+        object.header.set_type_tag(TypeTag::Nil);
+        let id = self.next_id;
+        self.next_id += 1;
+        self.pending.insert(id, (obj.clone(), children));
+        // a send only fails once a finalizer has panicked on the thread,
+        // which joining it reports
+        let _ = self.queue.send((id, value));
+        true
+    }
+}
+
+/// Gathers the objects a value refers to.
+struct Children(Vec<GcPtr<Object>>);
+
+impl Visitor for Children {
+    fn visit(&mut self, obj: &GcPtr<Object>) {
+        self.0.push(obj.clone());
+    }
+}
+
+impl Vm {
+    /// Runs finalizers on a thread of their own instead of during sweeps,
+    /// so slow cleanup, such as closing sockets or flushing files, doesn't
+    /// make collections any longer. Each dead foreign or custom object's
+    /// value is sent to the thread, finalized and dropped there, and until
+    /// it has been, the objects it refers to are kept alive as if rooted.
+    /// What's done is noticed at the start of the next collection.
+    ///
+    /// Turning it off waits for the thread to finish everything sent to it,
+    /// and after that finalizers run during sweeps again. If a finalizer
+    /// panicked on the thread, the panic is resumed then, as it is when the
+    /// VM is dropped.
+    ///
+    /// wasm32 has no threads to spawn, so there finalizers always run during
+    /// sweeps and this does nothing.
+    pub fn set_background_finalizers(&mut self, enabled: bool) {
+        if cfg!(target_arch = "wasm32") {
+            return;
+        }
+        if enabled {
+            self.finalizer.get_or_insert_with(Finalizer::new);
+            return;
+        }
+        let Some(finalizer) = self.finalizer.take() else {
+            return;
+        };
+        let Finalizer {
+            queue,
+            done,
+            thread,
+            mut pending,
+            ..
+        } = finalizer;
+        drop(queue);
+        if let Err(err) = thread.join() {
+            std::panic::resume_unwind(err);
+        }
+        for id in done.try_iter() {
+            if let Some((obj, _)) = pending.remove(&id) {
+                unsafe { self.quarantine.free(obj.0, &mut self.chunks) }
+            }
+        }
+    }
+
+    /// Number of values sent to the finalizer thread that no collection has
+    /// seen it finish yet.
+    pub fn pending_finalizers(&self) -> usize {
+        self.finalizer
+            .as_ref()
+            .map_or(0, |finalizer| finalizer.pending.len())
+    }
+
+    /// Bounds how many values may wait on the finalizer thread. With more
+    /// than `limit` waiting, a collection first blocks until the thread has
+    /// worked the backlog down to `limit`, so allocating faster than
+    /// finalizers keep up slows down to their pace, instead of piling up
+    /// what the waiting values keep alive. `None`, the default, never waits.
+    pub fn set_finalizer_backlog(&mut self, limit: Option<usize>) {
+        self.finalizer_backlog = limit;
+    }
+
+    /// Frees the objects whose values the finalizer thread is done with,
+    /// which stops keeping alive what they referred to. Waits for the thread
+    /// first if it's further behind than the backlog allows.
+    pub(crate) fn reap_finalized(&mut self) {
+        let Some(finalizer) = &mut self.finalizer else {
+            return;
+        };
+        let limit = self.finalizer_backlog.unwrap_or(usize::MAX);
+        loop {
+            let done = match finalizer.done.try_recv() {
+                Err(TryRecvError::Empty) if finalizer.pending.len() > limit => {
+                    finalizer.done.recv().ok()
+                }
+                done => done.ok(),
+            };
+            // nothing's done yet, or a finalizer panicked on the thread
+            let Some(id) = done else {
+                return;
+            };
+            if let Some((obj, _)) = finalizer.pending.remove(&id) {
+                unsafe { self.quarantine.free(obj.0, &mut self.chunks) }
+            }
+        }
+    }
+
+    /// Marks what the values still being finalized refer to.
+    pub(crate) fn mark_finalizing(&mut self) {
+        let Some(finalizer) = &mut self.finalizer else {
+            return;
+        };
+        for (obj, children) in finalizer.pending.values_mut() {
+            unsafe { obj.mark() }
+            for child in children {
+                unsafe { child.mark() }
+            }
+        }
+    }
+
+    /// With background finalizers on, picks out the foreign and custom
+    /// objects the last mark didn't reach and marks what they refer to, so
+    /// it survives until they're finalized. They stay unmarked themselves,
+    /// for the sweep to hand them over, and so does anything reached only
+    /// through another of them, which is handed over too.
+    pub(crate) fn rescue_finalizable(&mut self) {
+        let Some(finalizer) = &mut self.finalizer else {
+            return;
+        };
+        let dying: HashSet<_> = self
+            .heap
+            .iter()
+            .filter(|obj| {
+                !obj.is_marked() && matches!(obj.value(), ObjType::Foreign(_) | ObjType::Custom(_))
+            })
+            .cloned()
+            .collect();
+        for obj in &dying {
+            let mut children = Children(vec![]);
+            obj.value().trace(&mut children);
+            // a plain mark would go through the dying objects too, and one
+            // referring back to itself would then never be finalized
+            let mut pending = Children(children.0.clone());
+            while let Some(mut child) = pending.0.pop() {
+                if child.is_marked() || dying.contains(&child) {
+                    continue;
+                }
+                child.set_marked();
+                child.value().trace(&mut pending);
+            }
+            finalizer.rescued.insert(obj.clone(), children.0);
+        }
+    }
+
+    /// The objects still waiting on the finalizer thread, and everything
+    /// they keep alive.
+    pub(crate) fn finalizing_objects(&self) -> Vec<GcPtr<Object>> {
+        let pending = self.finalizer.iter().flat_map(|f| f.pending.values());
+        pending
+            .flat_map(|(obj, children)| std::iter::once(obj).chain(children))
+            .cloned()
+            .collect()
+    }
+}
+
+#[test]
+fn background_finalizer_test() {
+    use std::sync::{Arc, Mutex};
+
+    use crate::ForeignObject;
+
+    #[derive(Debug)]
+    struct Socket {
+        peer: GcPtr<Object>,
+        closed: Arc<Mutex<Vec<std::thread::ThreadId>>>,
+    }
+    impl Trace for Socket {
+        fn trace(&self, visitor: &mut dyn Visitor) {
+            visitor.visit(&self.peer);
+        }
+    }
+    impl ForeignObject for Socket {
+        fn finalize(&mut self) {
+            std::thread::sleep(std::time::Duration::from_millis(10));
+            self.closed
+                .lock()
+                .unwrap()
+                .push(std::thread::current().id());
+        }
+    }
+
+    println!("Background Finalizer Test: Finalizers run off the collecting thread.");
+    let closed = Arc::new(Mutex::new(vec![]));
+    let mut vm = Vm::new();
+    vm.set_background_finalizers(true);
+    for _ in 0..3 {
+        let peer = vm.push_list([1, 2]);
+        vm.push_foreign(Socket {
+            peer,
+            closed: closed.clone(),
+        });
+        vm.pop_n(2);
+    }
+    vm.gc();
+    assert!(
+        vm.pending_finalizers() == 3,
+        "Should have queued every socket."
+    );
+    assert!(
+        vm.num_objs == 3 * 4,
+        "Should keep what the sockets refer to until they're closed."
+    );
+
+    vm.set_background_finalizers(false);
+    let closed = closed.lock().unwrap().clone();
+    assert!(closed.len() == 3, "Should have closed every socket.");
+    assert!(
+        closed.iter().all(|&id| id != std::thread::current().id()),
+        "Should have closed them on the finalizer thread."
+    );
+    vm.gc();
+    assert!(
+        vm.num_objs == 0,
+        "Should free the peers once they're closed."
+    );
+    drop(vm);
+}
+
+#[test]
+fn finalizer_backlog_test() {
+    #[derive(Debug)]
+    struct Slow;
+    impl Trace for Slow {
+        fn trace(&self, _: &mut dyn Visitor) {}
+    }
+    impl crate::ForeignObject for Slow {
+        fn finalize(&mut self) {
+            std::thread::sleep(std::time::Duration::from_millis(5));
+        }
+    }
+
+    println!("Finalizer Backlog Test: Collections wait for a lagging finalizer thread.");
+    let mut vm = Vm::new();
+    vm.set_background_finalizers(true);
+    vm.set_finalizer_backlog(Some(2));
+    for _ in 0..10 {
+        vm.push_foreign(Slow);
+        vm.drop_top();
+    }
+    vm.gc();
+    assert!(vm.pending_finalizers() == 10);
+    vm.gc();
+    assert!(
+        vm.pending_finalizers() <= 2,
+        "Should have waited for the backlog to shrink."
+    );
+    drop(vm);
+}
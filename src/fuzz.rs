@@ -0,0 +1,372 @@
+//! A model of VM programs for fuzzing the collector: a sequence of [`Op`]s
+//! is run against a fresh VM by [`apply`], alongside a shadow copy of what
+//! the stack and globals should hold, and after every operation everything
+//! reachable is checked against it. A collection that frees something still
+//! reachable, or scribbles over it, shows up as a panic naming the first
+//! operation after which they disagree.
+//!
+//! With the `arbitrary` feature, [`Op`] implements `arbitrary::Arbitrary`,
+//! so a cargo-fuzz target can take `ops: Vec<Op>` and call `apply(&ops)`.
+//! Without it, [`decode`] turns raw bytes into operations, and a target is
+//! `gc::fuzz::apply(&gc::fuzz::decode(data))` in a `fuzz_target!`.
+
+use std::collections::{HashMap, HashSet};
+
+use crate::{GcPtr, ObjType, Object, Value, Vm};
+
+/// Deepest the stack gets; pushes past it are skipped.
+const MAX_DEPTH: usize = 256;
+
+/// Number of distinct globals and strings operations refer to.
+const NAMES: u8 = 8;
+
+/// One step of a program. Operations referring to stack slots count down
+/// from the top, wrapping around the stack's depth, and ones the stack is
+/// too shallow or too deep for are skipped, so every sequence is valid.
+#[derive(Clone, Debug, PartialEq)]
+#[cfg_attr(feature = "arbitrary", derive(arbitrary::Arbitrary))]
+pub enum Op {
+    PushNil,
+    PushInt(i64),
+    /// pushes a fresh string, one of a few distinct texts
+    PushStr(u8),
+    /// `Vm::push_pair`, making the top the head and the one below the tail
+    Pair,
+    Pop,
+    Dup,
+    Swap,
+    /// makes the value at `value` the head of the pair at `pair`
+    SetHead {
+        pair: u8,
+        value: u8,
+    },
+    /// like `SetHead`, for the tail
+    SetTail {
+        pair: u8,
+        value: u8,
+    },
+    Freeze(u8),
+    /// binds the top of the stack to one of a few globals
+    Global(u8),
+    Gc,
+}
+
+/// Decodes `data` into operations, each from an opcode byte and the bytes
+/// after it that it needs. Missing trailing bytes read as 0.
+pub fn decode(data: &[u8]) -> Vec<Op> {
+    let mut bytes = data.iter().copied();
+    let mut ops = vec![];
+    while let Some(opcode) = bytes.next() {
+        let mut byte = || bytes.next().unwrap_or(0);
+        ops.push(match opcode % 12 {
+            0 => Op::PushNil,
+            1 => Op::PushInt(i64::from_le_bytes(std::array::from_fn(|_| byte()))),
+            2 => Op::PushStr(byte()),
+            3 => Op::Pair,
+            4 => Op::Pop,
+            5 => Op::Dup,
+            6 => Op::Swap,
+            7 => Op::SetHead {
+                pair: byte(),
+                value: byte(),
+            },
+            8 => Op::SetTail {
+                pair: byte(),
+                value: byte(),
+            },
+            9 => Op::Freeze(byte()),
+            10 => Op::Global(byte()),
+            _ => Op::Gc,
+        });
+    }
+    ops
+}
+
+/// What a stack slot, global or pair field should hold.
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Shadow {
+    Nil,
+    Int(i64),
+    Str(u8),
+    /// index into `Model::pairs`
+    Pair(usize),
+}
+
+#[derive(Debug)]
+struct ShadowPair {
+    obj: GcPtr<Object>,
+    head: Shadow,
+    tail: Shadow,
+    frozen: bool,
+}
+
+#[derive(Default)]
+struct Model {
+    stack: Vec<Shadow>,
+    globals: HashMap<u8, Shadow>,
+    /// every pair made so far, reachable or not
+    pairs: Vec<ShadowPair>,
+}
+
+impl Model {
+    /// Index into `stack` of the slot `depth` down from the top, wrapping.
+    fn slot(&self, depth: u8) -> Option<usize> {
+        let len = self.stack.len();
+        (len > 0).then(|| len - 1 - usize::from(depth) % len)
+    }
+}
+
+/// Runs `ops` on a new VM, panicking as soon as the VM disagrees with what
+/// the operations so far should have left.
+pub fn apply(ops: &[Op]) {
+    let mut vm = Vm::new();
+    let mut model = Model::default();
+    for (step, op) in ops.iter().enumerate() {
+        step_op(&mut vm, &mut model, op);
+        if let Err(problem) = check(&vm, &model) {
+            panic!("after {op:?}, operation {step}: {problem}");
+        }
+    }
+}
+
+fn step_op(vm: &mut Vm, model: &mut Model, op: &Op) {
+    let depth = model.stack.len();
+    let pushes = matches!(op, Op::PushNil | Op::PushInt(_) | Op::PushStr(_) | Op::Dup);
+    if pushes && depth >= MAX_DEPTH {
+        return;
+    }
+    match *op {
+        Op::PushNil => {
+            vm.push_nil();
+            model.stack.push(Shadow::Nil);
+        }
+        Op::PushInt(i) => {
+            vm.push_int(i);
+            model.stack.push(Shadow::Int(i));
+        }
+        Op::PushStr(n) => {
+            vm.push_str(&text(n));
+            model.stack.push(Shadow::Str(n % NAMES));
+        }
+        Op::Pair if depth >= 2 => {
+            vm.push_pair();
+            let head = model.stack.pop().unwrap();
+            let tail = model.stack.pop().unwrap();
+            model.pairs.push(ShadowPair {
+                obj: vm.peek(0),
+                head,
+                tail,
+                frozen: false,
+            });
+            model.stack.push(Shadow::Pair(model.pairs.len() - 1));
+        }
+        Op::Pop if depth >= 1 => {
+            vm.drop_top();
+            model.stack.pop();
+        }
+        Op::Dup if depth >= 1 => {
+            vm.dup();
+            model.stack.push(model.stack[depth - 1]);
+        }
+        Op::Swap if depth >= 2 => {
+            vm.swap();
+            model.stack.swap(depth - 1, depth - 2);
+        }
+        Op::SetHead { pair, value } | Op::SetTail { pair, value } => {
+            let (Some(pair), Some(value)) = (model.slot(pair), model.slot(value)) else {
+                return;
+            };
+            let Shadow::Pair(index) = model.stack[pair] else {
+                return;
+            };
+            let target = model.pairs[index].obj.clone();
+            let obj = vm.peek(depth - 1 - value);
+            let head = matches!(op, Op::SetHead { .. });
+            let result = if head {
+                vm.set_head(&target, obj)
+            } else {
+                vm.set_tail(&target, obj)
+            };
+            let shadow = &mut model.pairs[index];
+            assert!(
+                result.is_ok() != shadow.frozen,
+                "{op:?} on a pair frozen: {} returned {result:?}",
+                shadow.frozen
+            );
+            if result.is_ok() {
+                let field = if head {
+                    &mut shadow.head
+                } else {
+                    &mut shadow.tail
+                };
+                *field = model.stack[value];
+            }
+        }
+        Op::Freeze(slot) => {
+            let Some(slot) = model.slot(slot) else {
+                return;
+            };
+            if let Shadow::Pair(index) = model.stack[slot] {
+                vm.freeze(&model.pairs[index].obj);
+                model.pairs[index].frozen = true;
+            }
+        }
+        Op::Global(n) if depth >= 1 => {
+            let top = vm.peek(0);
+            vm.define_global(&text(n), top);
+            model.globals.insert(n % NAMES, model.stack[depth - 1]);
+        }
+        Op::Gc => vm.gc(),
+        _ => {}
+    }
+}
+
+fn text(n: u8) -> String {
+    format!("s{}", n % NAMES)
+}
+
+/// Checks the stack, the globals and every pair they reach against the
+/// model, and the heap's own bookkeeping.
+fn check(vm: &Vm, model: &Model) -> Result<(), String> {
+    if vm.num_objs != vm.heap.len() {
+        return Err(format!(
+            "counts {} objects with {} in the heap",
+            vm.num_objs,
+            vm.heap.len()
+        ));
+    }
+    let values: Vec<_> = vm.stack_values().collect();
+    if values.len() != model.stack.len() {
+        return Err(format!(
+            "stack is {} deep, should be {}",
+            values.len(),
+            model.stack.len()
+        ));
+    }
+    let mut pending = vec![];
+    for (slot, (&value, &shadow)) in values.iter().zip(&model.stack).enumerate() {
+        check_value(vm, model, value, shadow).map_err(|err| format!("stack slot {slot}: {err}"))?;
+        pending.push(shadow);
+    }
+    for (&n, &shadow) in &model.globals {
+        let obj = vm
+            .get_global(&text(n))
+            .ok_or_else(|| format!("global {} is gone", text(n)))?;
+        check_obj(vm, model, &obj, shadow).map_err(|err| format!("global {}: {err}", text(n)))?;
+        pending.push(shadow);
+    }
+
+    let mut seen = HashSet::new();
+    while let Some(shadow) = pending.pop() {
+        let Shadow::Pair(index) = shadow else {
+            continue;
+        };
+        if !seen.insert(index) {
+            continue;
+        }
+        let pair = &model.pairs[index];
+        if vm.is_frozen(&pair.obj) != pair.frozen {
+            return Err(format!("pair {index} should be frozen: {}", pair.frozen));
+        }
+        let ObjType::Pair(fields) = vm.get(&pair.obj) else {
+            return Err(format!(
+                "pair {index} is a {}",
+                vm.get(&pair.obj).type_name()
+            ));
+        };
+        for (field, shadow) in [(&fields.head, pair.head), (&fields.tail, pair.tail)] {
+            let field = field.clone().ok_or(format!("pair {index} lost a field"))?;
+            check_obj(vm, model, &field, shadow).map_err(|err| format!("pair {index}: {err}"))?;
+            pending.push(shadow);
+        }
+    }
+    Ok(())
+}
+
+fn check_value(vm: &Vm, model: &Model, value: Value, shadow: Shadow) -> Result<(), String> {
+    match value.as_obj() {
+        Some(obj) => check_obj(vm, model, &obj, shadow),
+        None if value.is_nil() && shadow == Shadow::Nil => Ok(()),
+        None if int(shadow).is_some() && value.as_int() == int(shadow) => Ok(()),
+        None => Err(format!("holds {value:?}, should hold {shadow:?}")),
+    }
+}
+
+fn check_obj(vm: &Vm, model: &Model, obj: &GcPtr<Object>, shadow: Shadow) -> Result<(), String> {
+    if !vm.is_live(obj) {
+        return Err(format!("{:?} should be {shadow:?} but was freed", obj.0));
+    }
+    let matches = match (vm.get(obj), shadow) {
+        (ObjType::Nil, Shadow::Nil) => true,
+        (ObjType::Int(i), Shadow::Int(j)) => *i == j,
+        (ObjType::Str(s), Shadow::Str(n)) => *s == text(n),
+        (ObjType::Pair(_), Shadow::Pair(index)) => *obj == model.pairs[index].obj,
+        _ => false,
+    };
+    if !matches {
+        return Err(format!("holds {:?}, should hold {shadow:?}", vm.get(obj)));
+    }
+    Ok(())
+}
+
+fn int(shadow: Shadow) -> Option<i64> {
+    match shadow {
+        Shadow::Int(i) => Some(i),
+        _ => None,
+    }
+}
+
+#[test]
+fn fuzz_test() {
+    println!("Fuzz Test: Random programs leave everything reachable intact.");
+    apply(&[
+        Op::PushInt(1),
+        Op::PushStr(0),
+        Op::Pair,
+        Op::Dup,
+        Op::SetTail { pair: 0, value: 1 },
+        Op::Global(3),
+        Op::Pop,
+        Op::Gc,
+        Op::PushInt(i64::MAX),
+        Op::PushNil,
+        Op::Pair,
+        Op::Freeze(0),
+        Op::SetHead { pair: 0, value: 0 },
+        Op::Gc,
+    ]);
+
+    // a fixed xorshift stream, so failures reproduce
+    let mut state = 0x9e37_79b9_7f4a_7c15_u64;
+    for _ in 0..20 {
+        let data: Vec<u8> = (0..2000)
+            .map(|_| {
+                state ^= state << 13;
+                state ^= state >> 7;
+                state ^= state << 17;
+                state as u8
+            })
+            .collect();
+        apply(&decode(&data));
+    }
+}
+
+#[cfg(feature = "arbitrary")]
+#[test]
+fn arbitrary_test() {
+    use arbitrary::{Arbitrary, Unstructured};
+
+    println!("Arbitrary Test: Generated operations run like decoded ones.");
+    let mut state = 0x9e37_79b9_7f4a_7c15_u64;
+    let data: Vec<u8> = (0..4000)
+        .map(|_| {
+            state ^= state << 13;
+            state ^= state >> 7;
+            state ^= state << 17;
+            state as u8
+        })
+        .collect();
+    let ops = Vec::<Op>::arbitrary(&mut Unstructured::new(&data)).unwrap();
+    assert!(!ops.is_empty(), "Should have generated some operations.");
+    apply(&ops);
+}
@@ -0,0 +1,532 @@
+use std::collections::HashMap;
+
+use crate::program::{Reader, Writer};
+use crate::{BigInt, Closure, Event, GcError, GcPtr, Map, MapKey, ObjType, Object, Pair, Vm};
+
+/// An object graph flattened into plain data, see [`Vm::to_graph`]. Every
+/// object reachable from the root is one node, referring to its children by
+/// their index in `nodes`, so shared and cyclic structure survives the trip.
+/// With the `serde` feature it can be serialized to any serde format.
+const MAGIC: &[u8; 4] = b"GCSN";
+const VERSION: u8 = 1;
+
+#[derive(Clone, Debug, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct Graph {
+    pub root: usize,
+    pub nodes: Vec<Node>,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum Node {
+    Nil,
+    Bool(bool),
+    Int(i64),
+    /// sign and base 2^32 digits, least significant first
+    BigInt {
+        negative: bool,
+        limbs: Vec<u32>,
+    },
+    Float(f64),
+    Str(String),
+    Symbol(String),
+    Pair(Option<usize>, Option<usize>),
+    Array(Vec<usize>),
+    Map(Vec<(Key, usize)>),
+    Closure {
+        code_id: usize,
+        upvalues: Vec<usize>,
+    },
+    Bytes(Vec<u8>),
+}
+
+#[derive(Clone, Debug, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum Key {
+    Int(i64),
+    Str(String),
+    Symbol(String),
+}
+
+impl Graph {
+    /// Encodes the graph as a snapshot: a header and the length of the rest,
+    /// then a table of every distinct string, symbol and key name, the node
+    /// count and root, and one tag byte per node followed by varints. Text is
+    /// written once and referred to by its table index, and pairs refer to
+    /// their children by node index plus one, 0 being none.
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut table = HashMap::new();
+        let mut text = Writer(vec![]);
+        let mut body = Writer(vec![]);
+        let mut intern = |body: &mut Writer, s: &str| {
+            let next = table.len();
+            let index = *table.entry(s.to_owned()).or_insert_with(|| {
+                text.str(s);
+                next
+            });
+            body.uint(index);
+        };
+        body.uint(self.nodes.len());
+        body.uint(self.root);
+        for node in &self.nodes {
+            match node {
+                Node::Nil => body.0.push(0),
+                Node::Bool(b) => body.0.extend([1, *b as u8]),
+                Node::Int(i) => {
+                    body.0.push(2);
+                    body.int(*i);
+                }
+                Node::BigInt { negative, limbs } => {
+                    body.0.extend([3, *negative as u8]);
+                    body.uint(limbs.len());
+                    for &limb in limbs {
+                        body.uint(limb as usize);
+                    }
+                }
+                Node::Float(f) => {
+                    body.0.push(4);
+                    body.0.extend(f.to_bits().to_le_bytes());
+                }
+                Node::Str(s) => {
+                    body.0.push(5);
+                    intern(&mut body, s);
+                }
+                Node::Symbol(name) => {
+                    body.0.push(6);
+                    intern(&mut body, name);
+                }
+                Node::Pair(head, tail) => {
+                    body.0.push(7);
+                    body.uint(head.map_or(0, |head| head + 1));
+                    body.uint(tail.map_or(0, |tail| tail + 1));
+                }
+                Node::Array(elems) => {
+                    body.0.push(8);
+                    body.uint(elems.len());
+                    for &elem in elems {
+                        body.uint(elem);
+                    }
+                }
+                Node::Map(entries) => {
+                    body.0.push(9);
+                    body.uint(entries.len());
+                    for (key, value) in entries {
+                        match key {
+                            Key::Int(i) => {
+                                body.0.push(0);
+                                body.int(*i);
+                            }
+                            Key::Str(s) => {
+                                body.0.push(1);
+                                intern(&mut body, s);
+                            }
+                            Key::Symbol(name) => {
+                                body.0.push(2);
+                                intern(&mut body, name);
+                            }
+                        }
+                        body.uint(*value);
+                    }
+                }
+                Node::Closure { code_id, upvalues } => {
+                    body.0.push(10);
+                    body.uint(*code_id);
+                    body.uint(upvalues.len());
+                    for &upvalue in upvalues {
+                        body.uint(upvalue);
+                    }
+                }
+                Node::Bytes(bytes) => {
+                    body.0.push(11);
+                    body.uint(bytes.len());
+                    body.0.extend(bytes);
+                }
+            }
+        }
+        let mut rest = Writer(vec![]);
+        rest.uint(table.len());
+        rest.0.extend(text.0);
+        rest.0.extend(body.0);
+        let mut out = Writer(MAGIC.to_vec());
+        out.0.push(VERSION);
+        out.uint(rest.0.len());
+        out.0.extend(rest.0);
+        out.0
+    }
+
+    /// Decodes a snapshot written by [`Graph::to_bytes`]. Anything malformed,
+    /// including indices past the node count or string table, fails with
+    /// `GcError::InvalidGraph`, so a decoded graph always loads.
+    pub fn from_bytes(bytes: &[u8]) -> Result<Graph, GcError> {
+        Self::decode(&mut Reader(bytes)).map_err(|err| match err {
+            GcError::InvalidBytecode(reason) => GcError::InvalidGraph(reason),
+            err => err,
+        })
+    }
+
+    fn decode(input: &mut Reader) -> Result<Graph, GcError> {
+        if input.take(4)? != MAGIC {
+            return Err(GcError::InvalidGraph("bad magic number"));
+        }
+        if input.byte()? != VERSION {
+            return Err(GcError::InvalidGraph("unsupported version"));
+        }
+        let len = input.uint()?;
+        if len != input.0.len() {
+            return Err(GcError::InvalidGraph("wrong length"));
+        }
+        let table = (0..input.uint()?)
+            .map(|_| input.str())
+            .collect::<Result<Vec<_>, _>>()?;
+        let text = |input: &mut Reader| {
+            let index = input.uint()?;
+            table
+                .get(index)
+                .cloned()
+                .ok_or(GcError::InvalidGraph("string index out of range"))
+        };
+        let count = input.uint()?;
+        let node = |input: &mut Reader| match input.uint()? {
+            index if index < count => Ok(index),
+            _ => Err(GcError::InvalidGraph("node index out of range")),
+        };
+        let nodes = |input: &mut Reader| -> Result<Vec<_>, GcError> {
+            (0..input.uint()?).map(|_| node(input)).collect()
+        };
+        let root = node(input)?;
+        let mut graph = Graph {
+            root,
+            nodes: vec![],
+        };
+        for _ in 0..count {
+            graph.nodes.push(match input.byte()? {
+                0 => Node::Nil,
+                1 => Node::Bool(input.bool()?),
+                2 => Node::Int(input.int()?),
+                3 => Node::BigInt {
+                    negative: input.bool()?,
+                    limbs: (0..input.uint()?)
+                        .map(|_| {
+                            u32::try_from(input.uint()?)
+                                .map_err(|_| GcError::InvalidGraph("digit too large"))
+                        })
+                        .collect::<Result<_, _>>()?,
+                },
+                4 => Node::Float(input.float()?),
+                5 => Node::Str(text(input)?),
+                6 => Node::Symbol(text(input)?),
+                7 => {
+                    let mut child = || match input.uint()? {
+                        0 => Ok(None),
+                        n if n <= count => Ok(Some(n - 1)),
+                        _ => Err(GcError::InvalidGraph("node index out of range")),
+                    };
+                    Node::Pair(child()?, child()?)
+                }
+                8 => Node::Array(nodes(input)?),
+                9 => Node::Map(
+                    (0..input.uint()?)
+                        .map(|_| {
+                            let key = match input.byte()? {
+                                0 => Key::Int(input.int()?),
+                                1 => Key::Str(text(input)?),
+                                2 => Key::Symbol(text(input)?),
+                                _ => return Err(GcError::InvalidGraph("unknown map key")),
+                            };
+                            Ok((key, node(input)?))
+                        })
+                        .collect::<Result<_, _>>()?,
+                ),
+                10 => Node::Closure {
+                    code_id: input.uint()?,
+                    upvalues: nodes(input)?,
+                },
+                11 => {
+                    let len = input.uint()?;
+                    Node::Bytes(input.take(len)?.to_vec())
+                }
+                _ => return Err(GcError::InvalidGraph("unknown node")),
+            });
+        }
+        if !input.0.is_empty() {
+            return Err(GcError::InvalidGraph("trailing bytes"));
+        }
+        Ok(graph)
+    }
+}
+
+impl Vm {
+    /// Flattens everything reachable from `obj` into a [`Graph`]. Ropes
+    /// become strings, and foreign and custom objects, which have no plain
+    /// form, fail with `GcError::TypeMismatch`.
+    pub fn to_graph(&self, obj: &GcPtr<Object>) -> Result<Graph, GcError> {
+        let obj = &self.check_live(obj)?;
+        let mut index = HashMap::from([(obj.clone(), 0)]);
+        let mut order = vec![obj.clone()];
+        let mut nodes = vec![];
+        while let Some(obj) = order.get(nodes.len()).cloned() {
+            let mut id = |child: &GcPtr<Object>| {
+                *index.entry(child.clone()).or_insert_with(|| {
+                    order.push(child.clone());
+                    order.len() - 1
+                })
+            };
+            nodes.push(match obj.value() {
+                ObjType::Nil => Node::Nil,
+                ObjType::Bool(b) => Node::Bool(*b),
+                ObjType::Int(i) => Node::Int(*i),
+                ObjType::BigInt(n) => {
+                    let (negative, limbs) = n.parts();
+                    Node::BigInt {
+                        negative,
+                        limbs: limbs.to_vec(),
+                    }
+                }
+                ObjType::Float(f) => Node::Float(*f),
+                ObjType::Str(_) | ObjType::Rope(_) => Node::Str(crate::string_contents(&obj)),
+                ObjType::Symbol(name) => Node::Symbol(name.clone()),
+                ObjType::Pair(pair) => Node::Pair(
+                    pair.head.as_ref().map(&mut id),
+                    pair.tail.as_ref().map(&mut id),
+                ),
+                ObjType::Array(elems) => Node::Array(elems.iter().map(id).collect()),
+                ObjType::Map(map) => Node::Map(
+                    map.entries
+                        .iter()
+                        .map(|(key, value)| {
+                            let key = match key {
+                                MapKey::Int(i) => Key::Int(*i),
+                                MapKey::Str(s) => Key::Str(s.clone()),
+                                MapKey::Symbol(name) => Key::Symbol(name.clone()),
+                            };
+                            (key, id(value))
+                        })
+                        .collect(),
+                ),
+                ObjType::Closure(closure) => Node::Closure {
+                    code_id: closure.code_id,
+                    upvalues: closure.upvalues.iter().map(id).collect(),
+                },
+                ObjType::Bytes(bytes) => Node::Bytes(bytes.clone()),
+                value @ (ObjType::Foreign(_) | ObjType::Custom(_)) => {
+                    return Err(GcError::TypeMismatch {
+                        expected: "value with a plain form",
+                        found: value.type_name(),
+                    })
+                }
+            });
+        }
+        Ok(Graph { root: 0, nodes })
+    }
+
+    /// Builds the objects of `graph` in this heap, pushing its root and
+    /// returning a handle to it. A graph referring to nodes it doesn't have
+    /// fails with `GcError::InvalidGraph` before anything is allocated.
+    pub fn from_graph(&mut self, graph: &Graph) -> Result<GcPtr<Object>, GcError> {
+        let len = graph.nodes.len();
+        let valid = |index: &usize| *index < len;
+        let all_valid = valid(&graph.root)
+            && graph.nodes.iter().all(|node| match node {
+                Node::Pair(head, tail) => head.iter().chain(tail).all(valid),
+                Node::Array(elems) => elems.iter().all(valid),
+                Node::Map(entries) => entries.iter().map(|(_, index)| index).all(valid),
+                Node::Closure { upvalues, .. } => upvalues.iter().all(valid),
+                _ => true,
+            });
+        if !all_valid {
+            return Err(GcError::InvalidGraph("node index out of range"));
+        }
+        Ok(self.recorded(
+            |_| Event::FromGraph(graph.clone()),
+            |vm| vm.build_graph(graph),
+        ))
+    }
+
+    /// Builds a graph `from_graph` has checked and pushes its root.
+    fn build_graph(&mut self, graph: &Graph) -> GcPtr<Object> {
+        // allocate every node with its children missing, then link them
+        let objects: Vec<_> = graph
+            .nodes
+            .iter()
+            .map(|node| match node {
+                Node::Nil => self.nil.clone(),
+                Node::Bool(true) => self.true_obj.clone(),
+                Node::Bool(false) => self.false_obj.clone(),
+                Node::Int(i) => self.alloc_int(*i),
+                Node::BigInt { negative, limbs } => self.alloc(crate::int_result(
+                    BigInt::from_limbs(*negative, limbs.clone()),
+                )),
+                Node::Float(f) => self.alloc(ObjType::Float(*f)),
+                Node::Str(s) => self.alloc(ObjType::Str(s.clone())),
+                Node::Symbol(name) => self.symbol_obj(name),
+                Node::Pair(..) => self.alloc(ObjType::Pair(Pair {
+                    head: None,
+                    tail: None,
+                })),
+                Node::Array(_) => self.alloc(ObjType::Array(vec![])),
+                Node::Map(_) => self.alloc(ObjType::Map(Map::default())),
+                Node::Closure { code_id, .. } => self.alloc(ObjType::Closure(Closure {
+                    code_id: *code_id,
+                    upvalues: vec![],
+                })),
+                Node::Bytes(bytes) => self.alloc(ObjType::Bytes(bytes.clone())),
+            })
+            .collect();
+        let child = |index: &usize| objects[*index].clone();
+        for (obj, node) in objects.iter().zip(&graph.nodes) {
+            let before = obj.size();
+            match (&mut self.object_mut(obj).value, node) {
+                (ObjType::Pair(pair), Node::Pair(head, tail)) => {
+                    pair.head = head.as_ref().map(child);
+                    pair.tail = tail.as_ref().map(child);
+                }
+                (ObjType::Array(elems), Node::Array(indices)) => {
+                    *elems = indices.iter().map(child).collect();
+                }
+                (ObjType::Map(map), Node::Map(entries)) => {
+                    map.entries = entries
+                        .iter()
+                        .map(|(key, index)| {
+                            let key = match key {
+                                Key::Int(i) => MapKey::Int(*i),
+                                Key::Str(s) => MapKey::Str(s.clone()),
+                                Key::Symbol(name) => MapKey::Symbol(name.clone()),
+                            };
+                            (key, child(index))
+                        })
+                        .collect();
+                }
+                (ObjType::Closure(closure), Node::Closure { upvalues, .. }) => {
+                    closure.upvalues = upvalues.iter().map(child).collect();
+                }
+                _ => continue,
+            }
+            self.num_bytes = self.num_bytes - before + obj.size();
+        }
+        let root = child(&graph.root);
+        self.push_ptr(root.clone());
+        root
+    }
+
+    /// Serializes `obj` as its [`Graph`].
+    #[cfg(feature = "serde")]
+    pub fn serialize<S: serde::Serializer>(
+        &self,
+        obj: &GcPtr<Object>,
+        serializer: S,
+    ) -> Result<S::Ok, S::Error> {
+        use serde::ser::Error;
+        let graph = self.to_graph(obj).map_err(S::Error::custom)?;
+        serde::Serialize::serialize(&graph, serializer)
+    }
+
+    /// Deserializes a [`Graph`] into this heap like [`Vm::from_graph`].
+    #[cfg(feature = "serde")]
+    pub fn deserialize<'de, D: serde::Deserializer<'de>>(
+        &mut self,
+        deserializer: D,
+    ) -> Result<GcPtr<Object>, D::Error> {
+        use serde::de::Error;
+        let graph: Graph = serde::Deserialize::deserialize(deserializer)?;
+        self.from_graph(&graph).map_err(D::Error::custom)
+    }
+}
+
+#[test]
+fn graph_test() {
+    println!("Graph Test: Shared and cyclic graphs round-trip through plain data.");
+    let mut vm = Vm::new();
+    let shared = vm.push_list([1, 2]);
+    vm.set_tail(&shared, shared.clone()).unwrap();
+    vm.push_int(i64::MIN);
+    vm.push_int(-1);
+    vm.mul().unwrap();
+    vm.dup();
+    vm.push_array(2);
+    let array = vm.pop();
+    vm.symbol("key");
+    let key = vm.pop();
+    vm.map_new();
+    let map = vm.pop();
+    vm.map_set(&map, &key, array.clone()).unwrap();
+    let root = vm.push_pair_with(map, shared.clone());
+    let graph = vm.to_graph(&root).unwrap();
+    assert!(graph.nodes[graph.root] == Node::Pair(Some(1), Some(2)));
+
+    let mut other = Vm::new();
+    let copy = other.from_graph(&graph).unwrap();
+    assert!(other.stack_size == 1, "Should have rooted the copy.");
+    assert!(vm.deep_eq_across(&root, &other, &copy));
+    assert!(other.display(&copy) == vm.display(&root));
+    assert!(
+        other.to_graph(&copy).unwrap() == graph,
+        "Should keep the sharing."
+    );
+    let (objs, bytes) = (other.num_objs, other.num_bytes);
+    other.gc();
+    assert!(other.num_objs == objs && other.num_bytes == bytes);
+
+    let bad = Graph {
+        root: 0,
+        nodes: vec![Node::Array(vec![1])],
+    };
+    assert!(other.from_graph(&bad) == Err(GcError::InvalidGraph("node index out of range")));
+    drop(vm);
+}
+
+#[test]
+fn snapshot_test() {
+    println!("Snapshot Test: Snapshots round-trip and malformed ones are rejected.");
+    let mut vm = Vm::new();
+    vm.push_str("repeated");
+    vm.push_str("repeated");
+    vm.symbol("repeated");
+    vm.push_bytes(&[0, 255]);
+    vm.push_float(-0.5);
+    vm.push_int(i64::MAX);
+    vm.push_int(2);
+    vm.mul().unwrap();
+    vm.push_array(6);
+    let array = vm.pop();
+    let list = vm.push_list([1, 2, 3]);
+    vm.set_tail(&list, list.clone()).unwrap();
+    let root = vm.push_pair_with(array, list);
+    let graph = vm.to_graph(&root).unwrap();
+    let bytes = graph.to_bytes();
+    assert!(Graph::from_bytes(&bytes) == Ok(graph.clone()));
+    assert!(
+        bytes.windows(8).filter(|w| w == b"repeated").count() == 1,
+        "Should write each string once."
+    );
+
+    // flip, drop and insert bytes at random, checking that whatever decodes
+    // still loads into a heap
+    let mut seed = 0x2545_f491_4f6c_dd1du64;
+    let mut random = move |n: usize| {
+        seed ^= seed << 13;
+        seed ^= seed >> 7;
+        seed ^= seed << 17;
+        seed as usize % n
+    };
+    let mut decoded = 0;
+    for _ in 0..2000 {
+        let mut bad = bytes.clone();
+        for _ in 0..1 + random(3) {
+            let at = random(bad.len());
+            match random(3) {
+                0 => bad[at] ^= 1 << random(8),
+                1 => drop(bad.remove(at)),
+                _ => bad.insert(at, random(256) as u8),
+            }
+        }
+        if let Ok(graph) = Graph::from_bytes(&bad) {
+            decoded += 1;
+            vm.from_graph(&graph).unwrap();
+            vm.pop_value();
+        }
+    }
+    assert!(decoded < 2000, "Should reject most corrupted snapshots.");
+    assert!(Graph::from_bytes(&bytes[..bytes.len() - 1]).is_err());
+    drop(vm);
+}
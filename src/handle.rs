@@ -0,0 +1,104 @@
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::mpsc::Sender;
+
+use crate::{GcError, GcPtr, Object, Vm};
+
+/// Ids of the VMs created so far, so a [`SendHandle`] knows which one it
+/// belongs to.
+static VMS: AtomicU64 = AtomicU64::new(0);
+
+pub(crate) fn next_vm_id() -> u64 {
+    VMS.fetch_add(1, Ordering::Relaxed)
+}
+
+/// A rooted reference to an object that, unlike a [`GcPtr`], can be held by
+/// any thread, such as a task in an async pipeline, while the VM stays on its
+/// own. It keeps its object alive until it's redeemed with [`Vm::redeem`] or
+/// dropped, which can happen on any thread: the VM unroots the object at its
+/// next collection.
+#[derive(Debug)]
+pub struct SendHandle {
+    vm: u64,
+    /// the handle rooting the object, see `Vm::register_handle`
+    id: u64,
+    /// where the id is sent once the handle is dropped
+    released: Sender<u64>,
+}
+
+impl Drop for SendHandle {
+    fn drop(&mut self) {
+        // the VM may be gone already, and its roots with it
+        let _ = self.released.send(self.id);
+    }
+}
+
+impl Vm {
+    /// Roots `obj` behind a handle that can be sent to other threads.
+    /// Panics if `obj` isn't live, or if the VM records, since the handle is
+    /// released from whichever thread drops it.
+    pub fn send_handle(&mut self, obj: &GcPtr<Object>) -> SendHandle {
+        assert!(!self.recording(), "`send_handle` can't be recorded");
+        SendHandle {
+            vm: self.id,
+            id: self.register_handle(obj.clone()),
+            released: self.release.clone(),
+        }
+    }
+
+    /// Pushes the object behind `handle` and returns it, consuming the
+    /// handle, so from then on it's rooted by the stack instead. Fails with
+    /// `GcError::DeadObject` if the handle belongs to another VM, and with
+    /// `GcError::InvalidHandle` if the VM has been reset since it was made.
+    pub fn redeem(&mut self, handle: SendHandle) -> Result<GcPtr<Object>, GcError> {
+        if handle.vm != self.id {
+            return Err(GcError::DeadObject);
+        }
+        let obj = self
+            .handle(handle.id)
+            .ok_or(GcError::InvalidHandle(handle.id))?;
+        self.push_ptr(obj.clone());
+        Ok(obj)
+    }
+
+    /// Unroots the objects of the send handles dropped since the last
+    /// collection.
+    pub(crate) fn release_send_handles(&mut self) {
+        while let Ok(id) = self.released.try_recv() {
+            self.handles.remove(&id);
+        }
+    }
+}
+
+#[test]
+fn send_handle_test() {
+    println!("Send Handle Test: Handles keep their objects alive on other threads.");
+    let mut vm = Vm::new();
+    let list = vm.push_list([1, 2, 3]);
+    let handle = vm.send_handle(&list);
+    vm.clear_stack();
+
+    let (sender, receiver) = std::sync::mpsc::channel();
+    let thread = std::thread::spawn(move || sender.send(handle).unwrap());
+    vm.gc();
+    assert!(vm.num_objs == 6, "Should keep the list rooted meanwhile.");
+    thread.join().unwrap();
+    let handle = receiver.recv().unwrap();
+
+    let mut other = Vm::new();
+    assert!(
+        other.redeem(vm.send_handle(&list)) == Err(GcError::DeadObject),
+        "Should only redeem handles on their own VM."
+    );
+    drop(other);
+
+    assert!(vm.redeem(handle) == Ok(list.clone()));
+    assert!(vm.peek(0) == list, "Should have pushed the list.");
+    vm.clear_stack();
+    let dropped = vm.send_handle(&list);
+    std::thread::spawn(move || drop(dropped)).join().unwrap();
+    vm.gc();
+    assert!(
+        vm.num_objs == 0,
+        "Should unroot redeemed and dropped handles."
+    );
+}
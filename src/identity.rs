@@ -0,0 +1,223 @@
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex, MutexGuard, Weak};
+
+use crate::{GcPtr, Object, Vm};
+
+/// What an [`IdentityMap`] does to the objects it's keyed by.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum KeyMode {
+    /// keys don't keep their objects alive; once one is collected its entry
+    /// is gone
+    Weak,
+    /// keys are roots, alive for as long as they're in the map
+    Rooted,
+}
+
+/// The keys of one map, shared with the VM so collections can see them.
+pub(crate) struct Keys {
+    mode: KeyMode,
+    objects: HashMap<u64, GcPtr<Object>>,
+}
+
+/// Locks `keys`. Nothing panics while holding the lock, but if something
+/// did, the keys would still be consistent, so poisoning is ignored.
+fn lock(keys: &Mutex<Keys>) -> MutexGuard<'_, Keys> {
+    keys.lock().unwrap_or_else(|err| err.into_inner())
+}
+
+/// A map from heap objects, by identity, to Rust values. Entries are found
+/// by the objects' [`Vm::identity_hash`] rather than their addresses, so the
+/// map stays valid across collections whatever the [`KeyMode`].
+///
+/// The values aren't traced: a handle stored in one is not a root. A map
+/// only takes the VM that created it, since that's the one whose
+/// collections see its keys; its methods panic when given another.
+pub struct IdentityMap<V> {
+    /// id of the VM that created the map
+    vm: u64,
+    keys: Arc<Mutex<Keys>>,
+    values: HashMap<u64, V>,
+}
+
+impl<V> IdentityMap<V> {
+    pub fn insert(&mut self, vm: &mut Vm, key: &GcPtr<Object>, value: V) -> Option<V> {
+        self.check_owner(vm);
+        self.prune();
+        let hash = vm.identity_hash(key);
+        self.keys().objects.insert(hash, key.clone());
+        self.values.insert(hash, value)
+    }
+
+    pub fn get(&self, vm: &Vm, key: &GcPtr<Object>) -> Option<&V> {
+        self.check_owner(vm);
+        let key = &vm.assert_live(key);
+        self.values.get(&key.identity_hash()?)
+    }
+
+    pub fn get_mut(&mut self, vm: &Vm, key: &GcPtr<Object>) -> Option<&mut V> {
+        self.check_owner(vm);
+        let key = &vm.assert_live(key);
+        self.values.get_mut(&key.identity_hash()?)
+    }
+
+    pub fn remove(&mut self, vm: &Vm, key: &GcPtr<Object>) -> Option<V> {
+        self.check_owner(vm);
+        let key = &vm.assert_live(key);
+        self.prune();
+        let hash = key.identity_hash()?;
+        self.keys().objects.remove(&hash);
+        self.values.remove(&hash)
+    }
+
+    /// Panics unless `vm` created the map.
+    fn check_owner(&self, vm: &Vm) {
+        assert!(
+            vm.id == self.vm,
+            "An identity map only takes the VM that created it"
+        );
+    }
+
+    fn keys(&self) -> MutexGuard<'_, Keys> {
+        lock(&self.keys)
+    }
+
+    pub fn len(&self) -> usize {
+        self.keys().objects.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Drops the values of weak keys that have been collected. Collections
+    /// only get to see the keys, so their values linger until the next
+    /// insertion or removal.
+    fn prune(&mut self) {
+        let keys = lock(&self.keys);
+        if keys.objects.len() < self.values.len() {
+            self.values
+                .retain(|hash, _| keys.objects.contains_key(hash));
+        }
+    }
+}
+
+impl Vm {
+    /// Creates an empty [`IdentityMap`] whose keys behave as `mode` says.
+    /// Rooted keys would keep objects alive behind the log's back, so
+    /// creating a rooted map panics if the VM records.
+    pub fn identity_map<V>(&mut self, mode: KeyMode) -> IdentityMap<V> {
+        assert!(
+            !(mode == KeyMode::Rooted && self.recording()),
+            "A rooted identity map can't be recorded"
+        );
+        let keys = Arc::new(Mutex::new(Keys {
+            mode,
+            objects: HashMap::new(),
+        }));
+        self.identity_keys.push(Arc::downgrade(&keys));
+        IdentityMap {
+            vm: self.id,
+            keys,
+            values: HashMap::new(),
+        }
+    }
+
+    /// Marks the keys of every rooted identity map still around.
+    pub(crate) fn mark_identity_keys(&mut self) {
+        self.identity_keys.retain(|keys| keys.strong_count() > 0);
+        for keys in self.identity_keys.iter().filter_map(Weak::upgrade) {
+            let mut keys = lock(&keys);
+            if keys.mode == KeyMode::Rooted {
+                for obj in keys.objects.values_mut() {
+                    unsafe { obj.mark() }
+                }
+            }
+        }
+    }
+
+    /// Empties every identity map, which drops their values the next time
+    /// they're modified.
+    pub(crate) fn clear_identity_keys(&mut self) {
+        for keys in self.identity_keys.iter().filter_map(Weak::upgrade) {
+            lock(&keys).objects.clear();
+        }
+    }
+
+    /// Drops the weak identity map keys the last mark didn't reach.
+    pub(crate) fn sweep_identity_keys(&mut self) {
+        for keys in self.identity_keys.iter().filter_map(Weak::upgrade) {
+            let mut keys = lock(&keys);
+            if keys.mode == KeyMode::Weak {
+                keys.objects.retain(|_, obj| obj.is_marked());
+            }
+        }
+    }
+
+    /// Calls `forward` on every key of every identity map still around, so
+    /// it can point it at where its object was moved.
+    pub(crate) fn forward_identity_keys(&mut self, mut forward: impl FnMut(&mut GcPtr<Object>)) {
+        for keys in self.identity_keys.iter().filter_map(Weak::upgrade) {
+            lock(&keys).objects.values_mut().for_each(&mut forward);
+        }
+    }
+
+    /// Every key of every identity map still around, rooted or not.
+    #[cfg(debug_assertions)]
+    pub(crate) fn identity_key_objects(&self) -> Vec<GcPtr<Object>> {
+        let keys = self.identity_keys.iter().filter_map(Weak::upgrade);
+        keys.flat_map(|keys| lock(&keys).objects.values().cloned().collect::<Vec<_>>())
+            .collect()
+    }
+}
+
+#[test]
+fn identity_map_test() {
+    println!("Identity Map Test: Weak keys die with their objects, rooted ones keep them.");
+    let mut vm = Vm::new();
+    let mut weak = vm.identity_map(KeyMode::Weak);
+    let mut rooted = vm.identity_map(KeyMode::Rooted);
+    vm.push_str("kept");
+    vm.push_str("dropped");
+    let (dropped, kept) = (vm.pop(), vm.peek(0));
+    weak.insert(&mut vm, &kept, 1);
+    weak.insert(&mut vm, &dropped, 2);
+    assert!(weak.insert(&mut vm, &kept, 3) == Some(1), "Should replace.");
+    assert!(weak.get(&vm, &kept) == Some(&3) && weak.len() == 2);
+
+    vm.push_int(4);
+    let int = vm.pop();
+    rooted.insert(&mut vm, &int, "four");
+    vm.gc();
+    assert!(weak.len() == 1, "Should have dropped the collected key.");
+    assert!(vm.num_objs == 2, "Should have rooted the int.");
+    assert!(rooted.get(&vm, &int) == Some(&"four"));
+    *weak.get_mut(&vm, &kept).unwrap() += 1;
+    assert!(weak.remove(&vm, &kept) == Some(4) && weak.is_empty());
+
+    drop(rooted);
+    vm.gc();
+    assert!(
+        vm.num_objs == 1,
+        "Should stop rooting once the map is gone."
+    );
+    assert!(vm.identity_keys.len() == 1);
+    drop(vm);
+}
+
+#[test]
+fn identity_map_owner_test() {
+    println!("Identity Map Owner Test: Maps refuse other VMs' objects.");
+    let mut a = Vm::new();
+    let mut b = Vm::new();
+    let mut rooted = a.identity_map(KeyMode::Rooted);
+    let list = b.push_list([1, 2]);
+    let insert = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| {
+        rooted.insert(&mut b, &list, ());
+    }));
+    assert!(insert.is_err(), "Should refuse another VM.");
+    assert!(rooted.is_empty(), "Should not have rooted the list in A.");
+    b.clear_stack();
+    b.gc();
+    a.gc();
+    assert!(b.num_objs == 0);
+}
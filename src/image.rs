@@ -0,0 +1,578 @@
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{Read, Write};
+use std::path::Path;
+
+use crate::program::{Reader, Writer};
+use crate::{
+    BigInt, Closure, GcError, GcPtr, LayoutPolicy, Map, MapKey, ObjType, Object, Pair, Value, Vm,
+    VmConfig,
+};
+
+const MAGIC: &[u8; 4] = b"GCIM";
+const VERSION: u8 = 1;
+
+/// Children of an object being loaded, filled in once every object exists.
+enum Links {
+    Pair(Option<usize>, Option<usize>),
+    Array(Vec<usize>),
+    Map(Vec<(MapKey, usize)>),
+    Closure(Vec<usize>),
+}
+
+impl Vm {
+    /// Writes everything reachable from the roots to `writer`: the objects,
+    /// then the stack, frames, globals and constant pool referring to them,
+    /// to be brought back by [`Vm::load_image`]. Ropes are written flattened.
+    /// Foreign and custom objects can't be saved, so reaching one fails with
+    /// `GcError::TypeMismatch` before anything is written.
+    pub fn save_image(&self, mut writer: impl Write) -> Result<(), GcError> {
+        let locals = self.frames.iter().flat_map(|frame| &frame.locals).copied();
+        let mut objects: Vec<GcPtr<Object>> = self
+            .stack
+            .slots(0..self.stack_size)
+            .chain(locals)
+            .filter_map(Value::as_obj)
+            .collect();
+        objects.extend(self.globals.values().chain(&self.constants).cloned());
+        let mut index = HashMap::new();
+        let mut order = vec![];
+        while let Some(obj) = objects.pop() {
+            if index.contains_key(&obj) {
+                continue;
+            }
+            match obj.value() {
+                ObjType::Pair(pair) => objects.extend(pair.head.iter().chain(&pair.tail).cloned()),
+                ObjType::Array(elems) => objects.extend(elems.iter().cloned()),
+                ObjType::Map(map) => objects.extend(map.entries.values().cloned()),
+                ObjType::Closure(closure) => objects.extend(closure.upvalues.iter().cloned()),
+                ObjType::Foreign(_) | ObjType::Custom(_) => {
+                    return Err(GcError::TypeMismatch {
+                        expected: "value that can be saved",
+                        found: obj.value().type_name(),
+                    })
+                }
+                _ => {}
+            }
+            index.insert(obj.clone(), order.len());
+            order.push(obj);
+        }
+
+        let mut out = Writer(MAGIC.to_vec());
+        out.0.push(VERSION);
+        out.0.push(
+            self.config.unboxed_ints as u8
+                | (self.config.small_int_cache as u8) << 1
+                | ((self.config.layout == LayoutPolicy::Clustered) as u8) << 2,
+        );
+        out.uint(self.config.max_stack.unwrap_or(0));
+        out.uint(order.len());
+        for obj in &order {
+            out.object(obj, &index);
+        }
+        out.uint(self.stack_size);
+        for value in self.stack.slots(0..self.stack_size) {
+            out.value(value, &index);
+        }
+        out.uint(self.frames.len());
+        for frame in &self.frames {
+            out.uint(frame.return_pc);
+            out.uint(frame.base);
+            out.uint(frame.locals.len());
+            for &value in &frame.locals {
+                out.value(value, &index);
+            }
+        }
+        out.uint(self.globals.len());
+        for (name, obj) in &self.globals {
+            out.str(name);
+            out.uint(index[obj]);
+        }
+        out.uint(self.constants.len());
+        for obj in &self.constants {
+            out.uint(index[obj]);
+        }
+        writer
+            .write_all(&out.0)
+            .map_err(|err| GcError::Io(err.kind()))
+    }
+
+    /// Reads an image written by [`Vm::save_image`] into a new VM with the
+    /// configuration it was saved with, rejecting malformed images with
+    /// `GcError::InvalidImage` instead of building a corrupt heap. Natives
+    /// and kinds aren't part of an image and need registering again.
+    pub fn load_image(mut reader: impl Read) -> Result<Vm, GcError> {
+        let mut bytes = vec![];
+        reader
+            .read_to_end(&mut bytes)
+            .map_err(|err| GcError::Io(err.kind()))?;
+        Self::decode_image(&mut Reader(&bytes)).map_err(|err| match err {
+            GcError::InvalidBytecode(reason) => GcError::InvalidImage(reason),
+            err => err,
+        })
+    }
+
+    /// Opens the heap persisted at `path`, or starts a new VM configured with
+    /// `config` if there's nothing there yet, so that [`Vm::sync`] saves the
+    /// heap back to it. Objects aren't allocated out of one region the file
+    /// could be mapped onto, so the file holds an image: opening relinks its
+    /// objects and syncing rewrites it.
+    pub fn open(path: impl AsRef<Path>, config: VmConfig) -> Result<Vm, GcError> {
+        let path = path.as_ref();
+        let mut vm = match File::open(path) {
+            Ok(file) => Vm::load_image(std::io::BufReader::new(file))?,
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => Vm::with_config(config),
+            Err(err) => return Err(GcError::Io(err.kind())),
+        };
+        vm.backing = Some(path.to_owned());
+        Ok(vm)
+    }
+
+    /// Saves the heap to the file the VM was opened from, doing nothing for
+    /// VMs that weren't made by [`Vm::open`]. The image goes to a temporary
+    /// file first and replaces the old one once it's on disk, so a failed or
+    /// interrupted sync leaves the last one intact.
+    pub fn sync(&self) -> Result<(), GcError> {
+        let Some(path) = &self.backing else {
+            return Ok(());
+        };
+        let mut tmp = path.clone().into_os_string();
+        tmp.push(".tmp");
+        let io = |err: std::io::Error| GcError::Io(err.kind());
+        let mut file = File::create(&tmp).map_err(io)?;
+        let mut image = vec![];
+        self.save_image(&mut image)?;
+        file.write_all(&image).map_err(io)?;
+        file.sync_all().map_err(io)?;
+        std::fs::rename(&tmp, path).map_err(io)
+    }
+
+    fn decode_image(input: &mut Reader) -> Result<Vm, GcError> {
+        if input.take(4)? != MAGIC {
+            return Err(GcError::InvalidImage("bad magic number"));
+        }
+        if input.byte()? != VERSION {
+            return Err(GcError::InvalidImage("unsupported version"));
+        }
+        let flags = input.byte()?;
+        let max_stack = input.uint()?;
+        let mut vm = Vm::with_config(VmConfig {
+            unboxed_ints: flags & 1 != 0,
+            small_int_cache: flags & 2 != 0,
+            record: false,
+            max_stack: (max_stack != 0).then_some(max_stack),
+            layout: if flags & 4 != 0 {
+                LayoutPolicy::Clustered
+            } else {
+                LayoutPolicy::Individual
+            },
+        });
+
+        // allocate every object with its children missing, then link them
+        let count = input.uint()?;
+        let mut objects = vec![];
+        let mut links = vec![];
+        for _ in 0..count {
+            let (obj, obj_links) = input.object(&mut vm, count)?;
+            if let Some(obj_links) = obj_links {
+                links.push((obj.clone(), obj_links));
+            }
+            objects.push(obj);
+        }
+        for (obj, obj_links) in links {
+            let child = |index: usize| objects[index].clone();
+            let before = obj.size();
+            match (&mut vm.object_mut(&obj).value, obj_links) {
+                (ObjType::Pair(pair), Links::Pair(head, tail)) => {
+                    pair.head = head.map(child);
+                    pair.tail = tail.map(child);
+                }
+                (ObjType::Array(elems), Links::Array(indices)) => {
+                    *elems = indices.into_iter().map(child).collect();
+                }
+                (ObjType::Map(map), Links::Map(entries)) => {
+                    map.entries = entries
+                        .into_iter()
+                        .map(|(key, index)| (key, child(index)))
+                        .collect();
+                }
+                (ObjType::Closure(closure), Links::Closure(indices)) => {
+                    closure.upvalues = indices.into_iter().map(child).collect();
+                }
+                _ => unreachable!(),
+            }
+            vm.num_bytes = vm.num_bytes - before + obj.size();
+        }
+
+        let stack_size = input.uint()?;
+        if stack_size > vm.stack_max {
+            return Err(GcError::InvalidImage("stack too deep"));
+        }
+        for _ in 0..stack_size {
+            let value = input.value(&vm, &objects)?;
+            vm.push_slot(value);
+        }
+        let frames = input.uint()?;
+        if frames == 0 {
+            return Err(GcError::InvalidImage("missing root frame"));
+        }
+        vm.frames.clear();
+        for _ in 0..frames {
+            let mut frame = crate::Frame::root();
+            frame.return_pc = input.uint()?;
+            frame.base = input.uint()?;
+            if frame.base > stack_size {
+                return Err(GcError::InvalidImage("frame base past the stack"));
+            }
+            for _ in 0..input.uint()? {
+                frame.locals.push(input.value(&vm, &objects)?);
+            }
+            vm.frames.push(frame);
+        }
+        for _ in 0..input.uint()? {
+            let name = input.str()?;
+            let obj = objects[input.index(count)?].clone();
+            vm.globals.insert(name, obj);
+        }
+        for _ in 0..input.uint()? {
+            let obj = objects[input.index(count)?].clone();
+            vm.constants.push(obj);
+        }
+        if !input.0.is_empty() {
+            return Err(GcError::InvalidImage("trailing bytes"));
+        }
+        Ok(vm)
+    }
+}
+
+impl Writer {
+    fn object(&mut self, obj: &GcPtr<Object>, index: &HashMap<GcPtr<Object>, usize>) {
+        let header = unsafe { obj.0.as_ref() }.header;
+        let indices = |out: &mut Writer, objs: &[GcPtr<Object>]| {
+            out.uint(objs.len());
+            for obj in objs {
+                out.uint(index[obj]);
+            }
+        };
+        match obj.value() {
+            ObjType::Nil => self.0.push(0),
+            ObjType::Bool(b) => self.0.extend([1, *b as u8]),
+            ObjType::Int(i) => {
+                self.0.push(2);
+                self.int(*i);
+            }
+            ObjType::BigInt(n) => {
+                let (negative, limbs) = n.parts();
+                self.0.extend([3, negative as u8]);
+                self.uint(limbs.len());
+                for &limb in limbs {
+                    self.uint(limb as usize);
+                }
+            }
+            ObjType::Float(f) => {
+                self.0.push(4);
+                self.0.extend(f.to_bits().to_le_bytes());
+            }
+            ObjType::Str(_) | ObjType::Rope(_) => {
+                self.0.push(5);
+                self.str(&crate::string_contents(obj));
+            }
+            ObjType::Symbol(name) => {
+                self.0.push(6);
+                self.str(name);
+            }
+            ObjType::Pair(pair) => {
+                self.0.push(7);
+                for child in [&pair.head, &pair.tail] {
+                    self.uint(child.as_ref().map_or(0, |child| index[child] + 1));
+                }
+            }
+            ObjType::Array(elems) => {
+                self.0.push(8);
+                indices(self, elems);
+            }
+            ObjType::Map(map) => {
+                self.0.push(9);
+                self.uint(map.entries.len());
+                for (key, value) in &map.entries {
+                    match key {
+                        MapKey::Int(i) => {
+                            self.0.push(0);
+                            self.int(*i);
+                        }
+                        MapKey::Str(s) => {
+                            self.0.push(1);
+                            self.str(s);
+                        }
+                        MapKey::Symbol(name) => {
+                            self.0.push(2);
+                            self.str(name);
+                        }
+                    }
+                    self.uint(index[value]);
+                }
+            }
+            ObjType::Closure(closure) => {
+                self.0.push(10);
+                self.uint(closure.code_id);
+                indices(self, &closure.upvalues);
+            }
+            ObjType::Bytes(bytes) => {
+                self.0.push(11);
+                self.uint(bytes.len());
+                self.0.extend(bytes);
+            }
+            ObjType::Foreign(_) | ObjType::Custom(_) => unreachable!(),
+        }
+        self.0.push(header.is_frozen() as u8);
+        self.uint(header.user_tag() as usize);
+    }
+
+    fn value(&mut self, value: Value, index: &HashMap<GcPtr<Object>, usize>) {
+        if let Some(obj) = value.as_obj() {
+            self.0.push(5);
+            self.uint(index[&obj]);
+        } else if value.is_nil() {
+            self.0.push(0);
+        } else if let Some(b) = value.as_bool() {
+            self.0.push(1 + b as u8);
+        } else if let Some(i) = value.as_int() {
+            self.0.push(3);
+            self.int(i);
+        } else {
+            self.0.push(4);
+            self.0
+                .extend(value.as_float().unwrap().to_bits().to_le_bytes());
+        }
+    }
+}
+
+impl Reader<'_> {
+    /// Reads an object index, which has to be below `count`.
+    fn index(&mut self, count: usize) -> Result<usize, GcError> {
+        let index = self.uint()?;
+        if index >= count {
+            return Err(GcError::InvalidImage("object index out of range"));
+        }
+        Ok(index)
+    }
+
+    fn indices(&mut self, count: usize) -> Result<Vec<usize>, GcError> {
+        (0..self.uint()?).map(|_| self.index(count)).collect()
+    }
+
+    /// Reads an object into `vm`, returning it along with the children it
+    /// still needs.
+    fn object(
+        &mut self,
+        vm: &mut Vm,
+        count: usize,
+    ) -> Result<(GcPtr<Object>, Option<Links>), GcError> {
+        let (obj, links) = match self.byte()? {
+            0 => (vm.nil.clone(), None),
+            1 => match self.bool()? {
+                true => (vm.true_obj.clone(), None),
+                false => (vm.false_obj.clone(), None),
+            },
+            2 => (vm.alloc_int(self.int()?), None),
+            3 => {
+                let negative = self.bool()?;
+                let limbs = (0..self.uint()?)
+                    .map(|_| {
+                        u32::try_from(self.uint()?)
+                            .map_err(|_| GcError::InvalidImage("digit too large"))
+                    })
+                    .collect::<Result<_, _>>()?;
+                let n = BigInt::from_limbs(negative, limbs);
+                (vm.alloc(crate::int_result(n)), None)
+            }
+            4 => (vm.alloc(ObjType::Float(self.float()?)), None),
+            5 => (vm.alloc(ObjType::Str(self.str()?)), None),
+            6 => {
+                let name = self.str()?;
+                if vm.symbols.contains_key(&name) {
+                    return Err(GcError::InvalidImage("duplicate symbol"));
+                }
+                (vm.symbol_obj(&name), None)
+            }
+            7 => {
+                let mut child = || match self.uint()? {
+                    0 => Ok(None),
+                    n if n <= count => Ok(Some(n - 1)),
+                    _ => Err(GcError::InvalidImage("object index out of range")),
+                };
+                let links = Links::Pair(child()?, child()?);
+                let pair = Pair {
+                    head: None,
+                    tail: None,
+                };
+                (vm.alloc(ObjType::Pair(pair)), Some(links))
+            }
+            8 => {
+                let links = Links::Array(self.indices(count)?);
+                (vm.alloc(ObjType::Array(vec![])), Some(links))
+            }
+            9 => {
+                let mut entries = vec![];
+                for _ in 0..self.uint()? {
+                    let key = match self.byte()? {
+                        0 => MapKey::Int(self.int()?),
+                        1 => MapKey::Str(self.str()?),
+                        2 => MapKey::Symbol(self.str()?),
+                        _ => return Err(GcError::InvalidImage("unknown map key")),
+                    };
+                    entries.push((key, self.index(count)?));
+                }
+                (
+                    vm.alloc(ObjType::Map(Map::default())),
+                    Some(Links::Map(entries)),
+                )
+            }
+            10 => {
+                let code_id = self.uint()?;
+                let links = Links::Closure(self.indices(count)?);
+                let closure = Closure {
+                    code_id,
+                    upvalues: vec![],
+                };
+                (vm.alloc(ObjType::Closure(closure)), Some(links))
+            }
+            11 => {
+                let len = self.uint()?;
+                (vm.alloc(ObjType::Bytes(self.take(len)?.to_vec())), None)
+            }
+            _ => return Err(GcError::InvalidImage("unknown object")),
+        };
+        let frozen = self.bool()?;
+        let user_tag =
+            u32::try_from(self.uint()?).map_err(|_| GcError::InvalidImage("user tag too large"))?;
+        if !vm.immortals.contains(&obj) {
+            let header = &mut vm.object_mut(&obj).header;
+            // symbols are frozen already, whatever the image says
+            if frozen {
+                header.set_frozen(true);
+            }
+            header.set_user_tag(user_tag);
+        }
+        Ok((obj, links))
+    }
+
+    fn value(&mut self, vm: &Vm, objects: &[GcPtr<Object>]) -> Result<Value, GcError> {
+        Ok(match self.byte()? {
+            0 => Value::NIL,
+            1 => Value::FALSE,
+            2 => Value::TRUE,
+            3 => Value::int(self.int()?).ok_or(GcError::InvalidImage("int too large"))?,
+            4 => Value::float(self.float()?),
+            5 => vm.to_value(objects[self.index(objects.len())?].clone()),
+            _ => return Err(GcError::InvalidImage("unknown value")),
+        })
+    }
+}
+
+#[test]
+fn image_test() {
+    println!("Image Test: A saved heap loads back with its roots and sharing.");
+    let mut vm = Vm::with_config(VmConfig {
+        unboxed_ints: true,
+        ..VmConfig::default()
+    });
+    let list = vm.push_list([1, 2]);
+    vm.set_tail(&list, list.clone()).unwrap();
+    vm.push_int(i64::MAX);
+    vm.push_int(1);
+    vm.add().unwrap();
+    vm.push_str("a");
+    vm.push_str("b");
+    vm.concat().unwrap();
+    vm.symbol("sym");
+    vm.map_new();
+    let map = vm.peek(0);
+    let sym = vm.peek(1);
+    vm.map_set(&map, &sym, list.clone()).unwrap();
+    vm.freeze(&map);
+    vm.push_float(0.25);
+    vm.store_local(3);
+    vm.push_bytes(b"raw");
+    vm.store_local(4);
+    vm.define_global("list", list.clone());
+    vm.add_constant(map.clone());
+    vm.push_int(5);
+
+    let mut image = vec![];
+    vm.save_image(&mut image).unwrap();
+    let mut loaded = Vm::load_image(&image[..]).unwrap();
+    assert!(loaded.stack_size == vm.stack_size);
+    let (objs, bytes) = (loaded.num_objs, loaded.num_bytes);
+    loaded.gc();
+    assert!(
+        loaded.num_objs == objs && loaded.num_bytes == bytes,
+        "Should load only live objects and account for all of them."
+    );
+    let shown: Vec<_> = (0..vm.stack_size)
+        .map(|depth| {
+            let (a, b) = (vm.peek(depth), loaded.peek(depth));
+            assert!(vm.display(&a) == loaded.display(&b));
+            vm.deep_eq_across(&a, &loaded, &b)
+        })
+        .collect();
+    assert!(shown.iter().all(|&eq| eq), "Should load an equal stack.");
+
+    let global = loaded.get_global("list").unwrap();
+    assert!(loaded.display(&global) == "#0=(1 . #0#)");
+    loaded.push_const(0).unwrap();
+    let map = loaded.pop();
+    assert!(loaded.is_frozen(&map), "Should keep headers.");
+    let sym = loaded.symbol("sym");
+    assert!(loaded.map_get(&map, &sym).unwrap() == Some(global));
+    loaded.load_local(3).unwrap();
+    assert!(loaded.pop().value() == &ObjType::Float(0.25));
+    loaded.load_local(4).unwrap();
+    assert!(loaded.pop().value() == &ObjType::Bytes(b"raw".to_vec()));
+
+    assert!(Vm::load_image(&image[..image.len() - 1]).is_err());
+    for len in 0..image.len() {
+        let mut bad = image.clone();
+        bad[len] ^= 0x55;
+        let _ = Vm::load_image(&bad[..]);
+    }
+    assert!(matches!(
+        Vm::load_image(&b"nope"[..]),
+        Err(GcError::InvalidImage(_))
+    ));
+    drop(loaded);
+    drop(vm);
+}
+
+#[test]
+#[cfg_attr(miri, ignore = "Miri isolates tests from the filesystem")]
+fn persistent_heap_test() {
+    println!("Persistent Heap Test: A synced heap survives reopening its file.");
+    let path = std::env::temp_dir().join(format!("gc-heap-{}", std::process::id()));
+    let _ = std::fs::remove_file(&path);
+    let mut vm = Vm::open(&path, VmConfig::default()).unwrap();
+    assert!(
+        vm.stack_size == 0 && vm.globals.is_empty(),
+        "Should start out empty."
+    );
+    vm.push_list([1, 2, 3]);
+    let list = vm.pop();
+    vm.define_global("list", list);
+    vm.sync().unwrap();
+    vm.push_str("unsynced");
+    drop(vm);
+
+    let mut vm = Vm::open(&path, VmConfig::default()).unwrap();
+    assert!(vm.stack_size == 0, "Should only keep what was synced.");
+    let list = vm.get_global("list").unwrap();
+    assert!(vm.display(&list) == "(1 2 3)");
+    vm.define_global("more", list);
+    vm.sync().unwrap();
+    assert!(Vm::open(&path, VmConfig::default()).unwrap().globals.len() == 2);
+    std::fs::remove_file(&path).unwrap();
+    assert!(
+        Vm::new().sync().is_ok(),
+        "Should not sync a VM without a file."
+    );
+    drop(vm);
+}
@@ -0,0 +1,930 @@
+use std::fmt;
+
+use crate::record::calls_native;
+use crate::{Event, GcError, GcPtr, ObjType, Object, Value, Vm};
+
+/// how deeply traces describe nested objects
+const DESCRIBE_DEPTH: usize = 8;
+
+/// An active call: where to resume once it returns, where its stack region
+/// starts, and its local variable slots, which are GC roots.
+#[derive(Clone, Debug)]
+pub(crate) struct Frame {
+    pub(crate) return_pc: usize,
+    pub(crate) base: usize,
+    pub(crate) locals: Vec<Value>,
+}
+
+impl Frame {
+    /// The frame at the bottom of the frame stack, whose locals are usable
+    /// outside of any call.
+    pub(crate) fn root() -> Self {
+        Self {
+            return_pc: 0,
+            base: 0,
+            locals: vec![],
+        }
+    }
+}
+
+/// One bytecode instruction. Operands come from and results go to the VM's
+/// stack, with the topmost value as the right-hand operand.
+#[derive(Clone, Debug, PartialEq)]
+pub enum Op {
+    PushNil,
+    PushBool(bool),
+    PushInt(i64),
+    PushFloat(f64),
+    PushStr(String),
+    /// pushes an entry of the constant pool, see `Vm::add_constant`
+    PushConst(usize),
+    /// discards the top of the stack
+    Pop,
+    Dup,
+    Over,
+    Swap,
+    Rot,
+    /// pops a head, then a tail, and pushes the pair, like `Vm::push_pair`
+    MakePair,
+    /// replaces a pair with its head, see `Vm::car`
+    Car,
+    /// replaces a pair with its tail, see `Vm::cdr`
+    Cdr,
+    /// pops `n` values into an array, like `Vm::push_array`
+    MakeArray(usize),
+    Add,
+    Sub,
+    Mul,
+    Div,
+    /// pops two values and pushes whether they're equal by `Vm::values_eq`
+    Eq,
+    /// pops two numbers and pushes whether the deeper one is smaller
+    Lt,
+    /// continues at the given instruction index
+    Jump(usize),
+    /// pops a value and jumps if it's `false` or nil
+    JumpIfFalse(usize),
+    /// calls the function starting at `target`, popping the top `argc`
+    /// values into its first locals, the deepest becoming local 0
+    Call {
+        target: usize,
+        argc: usize,
+    },
+    /// like `Call`, but replaces the current frame instead of pushing a new
+    /// one, so the callee returns straight to the current frame's caller;
+    /// at top level it's a plain `Call`
+    TailCall {
+        target: usize,
+        argc: usize,
+    },
+    /// pops the return value, discards everything the current frame pushed,
+    /// pushes the return value back and resumes the caller; at top level,
+    /// stops execution
+    Return,
+    /// pushes a local of the current frame, see `Vm::load_local`
+    LoadLocal(usize),
+    /// pops into a local of the current frame, see `Vm::store_local`
+    StoreLocal(usize),
+    /// calls a registered native, see `Vm::call_native`
+    CallNative {
+        name: String,
+        argc: usize,
+    },
+    /// installs a handler at the given instruction index for `Throw`s until
+    /// the matching `EndTry`, or until the current frame returns
+    Try(usize),
+    /// removes the innermost handler
+    EndTry,
+    /// pops a value and unwinds to the innermost handler, dropping the frames
+    /// and stack slots above the point where it was installed, then pushes
+    /// the value back and continues at the handler
+    Throw,
+}
+
+/// State of an interpreter loop between two instructions.
+pub(crate) struct Run {
+    pc: usize,
+    handlers: Vec<Handler>,
+    /// frames below this belong to whoever started the loop
+    depth: usize,
+    /// stack height to unwind to on an uncaught throw
+    stack_size: usize,
+}
+
+impl Run {
+    fn new(vm: &Vm, depth: usize) -> Self {
+        Self {
+            pc: 0,
+            handlers: vec![],
+            depth,
+            stack_size: vm.stack_size,
+        }
+    }
+}
+
+/// Code being run one instruction at a time, see `Vm::start`.
+pub(crate) struct Stepper {
+    code: Vec<Op>,
+    run: Run,
+}
+
+/// What one instruction did, as reported by `Vm::step` and tracing.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Step {
+    /// index of the instruction
+    pub pc: usize,
+    pub op: Op,
+    /// number of objects the instruction allocated
+    pub allocated: usize,
+    /// the stack afterwards, bottom first, with cyclic structure elided
+    pub stack: Vec<String>,
+}
+
+impl fmt::Display for Step {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{:>4} {:<24} +{} [{}]",
+            self.pc,
+            format!("{:?}", self.op),
+            self.allocated,
+            self.stack.join(", ")
+        )
+    }
+}
+
+impl Op {
+    /// How many values the instruction takes off the stack. A `Return` at
+    /// top level takes none, which `exec` handles on its own.
+    fn operands(&self) -> usize {
+        match self {
+            Op::Pop
+            | Op::Dup
+            | Op::Car
+            | Op::Cdr
+            | Op::JumpIfFalse(_)
+            | Op::Return
+            | Op::StoreLocal(_)
+            | Op::Throw => 1,
+            Op::Over
+            | Op::Swap
+            | Op::MakePair
+            | Op::Add
+            | Op::Sub
+            | Op::Mul
+            | Op::Div
+            | Op::Eq
+            | Op::Lt => 2,
+            Op::Rot => 3,
+            Op::MakeArray(n) => *n,
+            Op::Call { argc, .. } | Op::TailCall { argc, .. } | Op::CallNative { argc, .. } => {
+                *argc
+            }
+            _ => 0,
+        }
+    }
+
+    /// How many values the instruction leaves on the stack in place of its
+    /// operands.
+    fn results(&self) -> usize {
+        match self {
+            Op::Pop
+            | Op::Jump(_)
+            | Op::JumpIfFalse(_)
+            | Op::Call { .. }
+            | Op::TailCall { .. }
+            | Op::StoreLocal(_)
+            | Op::Try(_)
+            | Op::EndTry => 0,
+            Op::Dup | Op::Swap => 2,
+            Op::Over | Op::Rot => 3,
+            _ => 1,
+        }
+    }
+}
+
+/// An installed `Try` handler and the state to unwind back to.
+struct Handler {
+    pc: usize,
+    frames: usize,
+    stack_size: usize,
+}
+
+impl Vm {
+    /// Runs `code` from its first instruction until it falls off the end or
+    /// returns at top level.
+    ///
+    /// A `Throw` without a handler unwinds everything this call pushed and
+    /// fails with `GcError::UncaughtThrow`, leaving the thrown value on top of
+    /// the stack.
+    ///
+    /// Between instructions every live value is reachable from the roots
+    /// (the stack, frame locals, globals and the constant pool), so that's
+    /// where the interpreter collects once the allocation threshold is hit.
+    ///
+    /// A recording VM logs the whole run as one event, and refuses code that
+    /// calls natives with `GcError::Unrecordable`.
+    pub fn execute(&mut self, code: &[Op]) -> Result<(), GcError> {
+        if calls_native(code) {
+            self.refuse_recording("execute")?;
+        }
+        self.recorded(
+            |_| Event::Execute(code.to_vec()),
+            |vm| {
+                let depth = vm.frames.len();
+                let result = vm.run(code, depth);
+                vm.frames.truncate(depth);
+                result
+            },
+        )
+    }
+
+    /// Interpreter loop; frames below `depth` belong to an outer `execute`.
+    fn run(&mut self, code: &[Op], depth: usize) -> Result<(), GcError> {
+        let mut run = Run::new(self, depth);
+        while run.pc < code.len() {
+            let more = if self.trace {
+                self.traced_exec(code, &mut run)?.1
+            } else {
+                self.exec(code, &mut run)?
+            };
+            if !more {
+                break;
+            }
+        }
+        Ok(())
+    }
+
+    /// Like `exec`, additionally describing what the instruction did, which
+    /// is printed if tracing is on.
+    fn traced_exec(&mut self, code: &[Op], run: &mut Run) -> Result<(Step, bool), GcError> {
+        let pc = run.pc;
+        let allocations = self.allocations;
+        let more = self.exec(code, run)?;
+        let step = Step {
+            pc,
+            op: code[pc].clone(),
+            allocated: self.allocations - allocations,
+            stack: self.stack.slots(0..self.stack_size).map(describe).collect(),
+        };
+        #[cfg(all(feature = "std", not(target_arch = "wasm32")))]
+        if self.trace {
+            println!("{}", step);
+        }
+        Ok((step, more))
+    }
+
+    /// Executes the instruction at `run.pc`, returning `false` once a
+    /// top-level `Return` stops execution.
+    fn exec(&mut self, code: &[Op], run: &mut Run) -> Result<bool, GcError> {
+        self.collect_if_due();
+
+        let depth = run.depth;
+        let handlers = &mut run.handlers;
+        let mut pc = run.pc + 1;
+        let op = &code[run.pc];
+        let top_level_return = *op == Op::Return && self.frames.len() == depth;
+        if self.stack_size < op.operands() && !top_level_return {
+            return Err(GcError::StackUnderflow);
+        }
+        if self.stack_size + op.results() > self.stack_max + op.operands() {
+            return Err(GcError::StackOverflow);
+        }
+        match op {
+            Op::PushNil => self.push_nil(),
+            Op::PushBool(b) => self.push_bool(*b),
+            Op::PushInt(i) => self.push_int(*i),
+            Op::PushFloat(f) => self.push_float(*f),
+            Op::PushStr(s) => self.push_str(s),
+            Op::PushConst(index) => self.push_const(*index)?,
+            Op::Pop => self.drop_top(),
+            Op::Dup => self.dup(),
+            Op::Over => self.over(),
+            Op::Swap => self.swap(),
+            Op::Rot => self.rot(),
+            Op::MakePair => self.push_pair(),
+            Op::Car => self.car()?,
+            Op::Cdr => self.cdr()?,
+            Op::MakeArray(n) => self.push_array(*n),
+            Op::Add => self.add()?,
+            Op::Sub => self.sub()?,
+            Op::Mul => self.mul()?,
+            Op::Div => self.div()?,
+            Op::Eq => {
+                let b = self.peek(0);
+                let a = self.peek(1);
+                let eq = self.values_eq(&a, &b);
+                self.pop_value();
+                self.pop_value();
+                self.push_bool(eq);
+            }
+            Op::Lt => self.less_than()?,
+            Op::Jump(target) => pc = jump_target(code, *target)?,
+            Op::JumpIfFalse(target) => {
+                let target = jump_target(code, *target)?;
+                let cond = self.pop_value();
+                if cond.is_nil() || cond.as_bool() == Some(false) {
+                    pc = target;
+                }
+            }
+            Op::TailCall { target, argc } if self.frames.len() > depth => {
+                let target = jump_target(code, *target)?;
+                let args = self
+                    .stack
+                    .slots(self.stack_size - argc..self.stack_size)
+                    .collect();
+                let frame = self.frames.last_mut().unwrap();
+                frame.locals = args;
+                let base = frame.base;
+                handlers.retain(|handler| handler.frames < self.frames.len());
+                self.truncate_stack(base);
+                pc = target;
+            }
+            Op::Call { target, argc } | Op::TailCall { target, argc } => {
+                let target = jump_target(code, *target)?;
+                // each frame takes a slot of the stack's limit, so how deep
+                // calls go is up to `VmConfig::max_stack`
+                if self.stack_size + self.frames.len() >= self.stack_max {
+                    return Err(GcError::StackOverflow);
+                }
+                let base = self.stack_size - argc;
+                let locals = self.stack.slots(base..self.stack_size).collect();
+                self.truncate_stack(base);
+                self.frames.push(Frame {
+                    return_pc: pc,
+                    base,
+                    locals,
+                });
+                pc = target;
+            }
+            Op::Return => {
+                if top_level_return {
+                    return Ok(false);
+                }
+                let frame = self.frames.pop().unwrap();
+                handlers.retain(|handler| handler.frames <= self.frames.len());
+                let result = self.pop_value();
+                self.truncate_stack(frame.base);
+                self.push_slot(result);
+                pc = frame.return_pc;
+            }
+            Op::LoadLocal(index) => self.load_local(*index)?,
+            Op::StoreLocal(index) => self.store_local(*index),
+            Op::CallNative { name, argc } => self.call_native(name, *argc)?,
+            Op::Try(target) => handlers.push(Handler {
+                pc: jump_target(code, *target)?,
+                frames: self.frames.len(),
+                stack_size: self.stack_size,
+            }),
+            Op::EndTry => {
+                handlers.pop();
+            }
+            Op::Throw => {
+                let thrown = self.pop_value();
+                match handlers.pop() {
+                    Some(handler) => {
+                        self.unwind(handler.frames, handler.stack_size, thrown);
+                        pc = handler.pc;
+                    }
+                    None => {
+                        self.unwind(depth, run.stack_size, thrown);
+                        return Err(GcError::UncaughtThrow);
+                    }
+                }
+            }
+        }
+        run.pc = pc;
+        Ok(true)
+    }
+
+    /// Prints every instruction `execute` and `step` run, with the
+    /// allocations it made and the stack it left behind, while `on`.
+    pub fn set_trace(&mut self, on: bool) {
+        self.trace = on;
+    }
+
+    /// Loads `code` to be run one instruction at a time by [`Vm::step`],
+    /// abandoning whatever was being stepped before. Panics if the VM
+    /// records and `code` calls natives.
+    pub fn start(&mut self, code: Vec<Op>) {
+        assert!(
+            !(self.recording() && calls_native(&code)),
+            "`start` can't be recorded with code that calls natives"
+        );
+        let logged = code.clone();
+        self.recorded(
+            |_| Event::Start(logged),
+            |vm| {
+                if let Some(stepper) = vm.stepper.take() {
+                    vm.frames.truncate(stepper.run.depth);
+                }
+                let run = Run::new(vm, vm.frames.len());
+                vm.stepper = Some(Stepper { code, run });
+            },
+        );
+    }
+
+    /// Runs the next instruction of the code passed to [`Vm::start`] and
+    /// reports what it did, or returns `None` once there's nothing left to
+    /// run. An error ends the program like it would end `execute`.
+    pub fn step(&mut self) -> Result<Option<Step>, GcError> {
+        self.recorded(|_| Event::Step, Self::step_unrecorded)
+    }
+
+    fn step_unrecorded(&mut self) -> Result<Option<Step>, GcError> {
+        let Some(mut stepper) = self.stepper.take() else {
+            return Ok(None);
+        };
+        if stepper.run.pc >= stepper.code.len() {
+            self.frames.truncate(stepper.run.depth);
+            return Ok(None);
+        }
+        match self.traced_exec(&stepper.code, &mut stepper.run) {
+            Ok((step, more)) => {
+                if more {
+                    self.stepper = Some(stepper);
+                } else {
+                    self.frames.truncate(stepper.run.depth);
+                }
+                Ok(Some(step))
+            }
+            Err(err) => {
+                self.frames.truncate(stepper.run.depth);
+                Err(err)
+            }
+        }
+    }
+
+    /// Appends `obj` to the constant pool and returns its index for
+    /// `Op::PushConst`. Pool entries are roots for as long as the VM lives,
+    /// so a compiler can build its literals once and have every execution
+    /// push the same objects.
+    pub fn add_constant(&mut self, obj: GcPtr<Object>) -> usize {
+        let obj = self.assert_live(&obj);
+        let obj_ref = obj.clone();
+        self.recorded(
+            |recorder| Event::AddConstant(recorder.id(&obj_ref)),
+            |vm| {
+                vm.constants.push(obj);
+                vm.constants.len() - 1
+            },
+        )
+    }
+
+    /// Pushes constant pool entry `index`.
+    pub fn push_const(&mut self, index: usize) -> Result<(), GcError> {
+        let obj = self
+            .constants
+            .get(index)
+            .ok_or(GcError::IndexOutOfBounds {
+                index,
+                len: self.constants.len(),
+            })?
+            .clone();
+        self.recorded(|_| Event::PushConst(index), |vm| vm.push_ptr(obj));
+        Ok(())
+    }
+
+    /// Pushes the value of local slot `index` of the current frame.
+    pub fn load_local(&mut self, index: usize) -> Result<(), GcError> {
+        let locals = &self.frames.last().unwrap().locals;
+        let value = *locals.get(index).ok_or(GcError::IndexOutOfBounds {
+            index,
+            len: locals.len(),
+        })?;
+        self.recorded(|_| Event::LoadLocal(index), |vm| vm.push_slot(value));
+        Ok(())
+    }
+
+    /// Pops the top of the stack into local slot `index` of the current
+    /// frame, growing the frame's slots with nils as needed. Outside of any
+    /// call this is the root frame, whose locals live until overwritten.
+    pub fn store_local(&mut self, index: usize) {
+        assert!(self.stack_size >= 1, "Stack underflow!");
+        self.recorded(
+            |_| Event::StoreLocal(index),
+            |vm| {
+                let value = vm.pop_value();
+                let locals = &mut vm.frames.last_mut().unwrap().locals;
+                if index >= locals.len() {
+                    locals.resize(index + 1, Value::NIL);
+                }
+                locals[index] = value;
+            },
+        );
+    }
+
+    /// Drops the frames above `frames` and the stack slots above
+    /// `stack_size`, then pushes `thrown`. The abandoned slots are cleared, so
+    /// whatever only they referred to is collectable.
+    fn unwind(&mut self, frames: usize, stack_size: usize, thrown: Value) {
+        self.frames.truncate(frames);
+        self.truncate_stack(stack_size);
+        self.push_slot(thrown);
+    }
+
+    /// Pops values until only `len` are left on the stack.
+    pub(crate) fn truncate_stack(&mut self, len: usize) {
+        while self.stack_size > len {
+            self.stack_size -= 1;
+            self.stack[self.stack_size] = Value::NIL;
+        }
+    }
+
+    pub(crate) fn less_than(&mut self) -> Result<(), GcError> {
+        let b = self.peek(0);
+        let a = self.peek(1);
+        let lt = match (a.value(), b.value()) {
+            (ObjType::Int(x), ObjType::Int(y)) => x < y,
+            (x, y) => match (x.as_f64(), y.as_f64()) {
+                (Some(x), Some(y)) => x < y,
+                (None, _) => return Err(not_a_number(x)),
+                (_, None) => return Err(not_a_number(y)),
+            },
+        };
+        self.pop_value();
+        self.pop_value();
+        self.push_bool(lt);
+        Ok(())
+    }
+}
+
+/// Renders a stack value for traces, stopping at cycles and deep nesting.
+fn describe(value: Value) -> String {
+    match value.as_obj() {
+        Some(obj) => describe_obj(&obj, &mut vec![]),
+        None => format!("{:?}", value),
+    }
+}
+
+/// `path` holds the objects being described around this one.
+fn describe_obj(obj: &GcPtr<Object>, path: &mut Vec<GcPtr<Object>>) -> String {
+    if path.contains(obj) {
+        return "<cycle>".to_owned();
+    }
+    if path.len() >= DESCRIBE_DEPTH {
+        return "...".to_owned();
+    }
+    path.push(obj.clone());
+    let child = |child: &Option<GcPtr<Object>>, path: &mut Vec<_>| match child {
+        Some(child) => describe_obj(child, path),
+        None => "nil".to_owned(),
+    };
+    let out = match obj.value() {
+        ObjType::Nil => "nil".to_owned(),
+        ObjType::Bool(b) => b.to_string(),
+        ObjType::Int(i) => i.to_string(),
+        ObjType::BigInt(n) => n.to_string(),
+        ObjType::Float(f) => format!("{:?}", f),
+        ObjType::Str(_) | ObjType::Rope(_) => format!("{:?}", crate::string_contents(obj)),
+        ObjType::Symbol(name) => name.clone(),
+        ObjType::Pair(pair) => format!(
+            "({} . {})",
+            child(&pair.head, path),
+            child(&pair.tail, path)
+        ),
+        ObjType::Array(items) => {
+            let items: Vec<_> = items.iter().map(|item| describe_obj(item, path)).collect();
+            format!("[{}]", items.join(", "))
+        }
+        value => format!("<{}>", value.type_name()),
+    };
+    path.pop();
+    out
+}
+
+fn jump_target(code: &[Op], target: usize) -> Result<usize, GcError> {
+    if target > code.len() {
+        return Err(GcError::InvalidJump(target));
+    }
+    Ok(target)
+}
+
+fn not_a_number(value: &ObjType) -> GcError {
+    GcError::TypeMismatch {
+        expected: "number",
+        found: value.type_name(),
+    }
+}
+
+#[test]
+fn execute_test() {
+    println!("Execute Test: Bytecode runs with jumps and arithmetic.");
+    let mut vm = Vm::new();
+    vm.execute(&[
+        Op::PushInt(1),
+        Op::PushInt(2),
+        Op::Lt,
+        Op::JumpIfFalse(6),
+        Op::PushInt(10),
+        Op::Jump(7),
+        Op::PushInt(20),
+        Op::PushInt(32),
+        Op::Add,
+    ])
+    .unwrap();
+    assert!(
+        matches!(vm.pop().value(), ObjType::Int(42)),
+        "Should have taken the branch."
+    );
+    assert!(vm.stack_size == 0, "Should have consumed all operands.");
+
+    vm.execute(&[
+        Op::PushInt(4),
+        Op::PushFloat(2.5),
+        Op::Lt,
+        Op::PushBool(false),
+        Op::Eq,
+    ])
+    .unwrap();
+    assert!(
+        vm.pop_value() == crate::Value::TRUE,
+        "4 < 2.5 should be false."
+    );
+
+    assert!(vm.execute(&[Op::Jump(9)]) == Err(GcError::InvalidJump(9)));
+    assert!(vm
+        .execute(&[Op::PushStr("a".into()), Op::PushInt(1), Op::Lt])
+        .is_err());
+    drop(vm);
+}
+
+#[test]
+fn execute_loop_test() {
+    println!("Execute Loop Test: Stack shuffling makes loops expressible.");
+    let mut vm = Vm::new();
+    // ( sum i ) while 0 < i: sum += i, i -= 1
+    vm.execute(&[
+        Op::PushInt(0),
+        Op::PushInt(10),
+        Op::PushInt(0),
+        Op::Over,
+        Op::Lt,
+        Op::JumpIfFalse(13),
+        Op::Swap,
+        Op::Over,
+        Op::Add,
+        Op::Swap,
+        Op::PushInt(1),
+        Op::Sub,
+        Op::Jump(2),
+        Op::Pop,
+    ])
+    .unwrap();
+    assert!(
+        matches!(vm.pop().value(), ObjType::Int(55)),
+        "Should have summed 1..=10."
+    );
+    assert!(vm.stack_size == 0, "Should have consumed all operands.");
+    drop(vm);
+}
+
+#[test]
+fn call_test() {
+    println!("Call Test: Calls return values and clean up their frames.");
+    let mut vm = Vm::new();
+    // square(x) = x * x, called on 3 and then on 4, results summed
+    let code = [
+        Op::PushInt(3),
+        Op::Call { target: 8, argc: 1 },
+        Op::PushInt(4),
+        Op::Call { target: 8, argc: 1 },
+        Op::Add,
+        Op::Return,
+        Op::PushStr("unreachable".into()),
+        Op::Return,
+        Op::PushStr("garbage".into()),
+        Op::LoadLocal(0),
+        Op::Dup,
+        Op::Mul,
+        Op::Return,
+    ];
+    vm.execute(&code).unwrap();
+    assert!(
+        matches!(vm.pop().value(), ObjType::Int(25)),
+        "Should have returned 9 + 16."
+    );
+    assert!(vm.stack_size == 0 && vm.frames.len() == 1);
+
+    // runaway recursion
+    assert!(vm.execute(&[Op::Call { target: 0, argc: 0 }]) == Err(GcError::StackOverflow));
+    assert!(vm.frames.len() == 1, "Should have unwound the frames.");
+    drop(vm);
+}
+
+#[test]
+fn tail_call_test() {
+    println!("Tail Call Test: Tail recursion runs in constant frame space.");
+    let mut vm = Vm::new();
+    // sum(n, acc) = if 0 < n { sum(n - 1, acc + n) } else { acc }
+    let mut code = vec![
+        Op::PushInt(1000),
+        Op::PushInt(0),
+        Op::Call { target: 4, argc: 2 },
+        Op::Return,
+        Op::PushInt(0),
+        Op::LoadLocal(0),
+        Op::Lt,
+        Op::JumpIfFalse(15),
+        Op::LoadLocal(0),
+        Op::PushInt(1),
+        Op::Sub,
+        Op::LoadLocal(1),
+        Op::LoadLocal(0),
+        Op::Add,
+        Op::TailCall { target: 4, argc: 2 },
+        Op::LoadLocal(1),
+        Op::Return,
+    ];
+    vm.execute(&code).unwrap();
+    assert!(
+        matches!(vm.pop().value(), ObjType::Int(500500)),
+        "Should have summed 1..=1000."
+    );
+    assert!(vm.stack_size == 0 && vm.frames.len() == 1);
+
+    code[14] = Op::Call { target: 4, argc: 2 };
+    assert!(
+        vm.execute(&code) == Err(GcError::StackOverflow),
+        "Plain calls should run out of frames."
+    );
+    drop(vm);
+
+    // returning what the call does instead of falling through to `acc`
+    code[7] = Op::JumpIfFalse(16);
+    code.insert(15, Op::Return);
+    let mut vm = Vm::with_config(crate::VmConfig {
+        max_stack: Some(10_000),
+        ..crate::VmConfig::default()
+    });
+    vm.execute(&code).unwrap();
+    assert!(
+        matches!(vm.pop().value(), ObjType::Int(500500)),
+        "Should have made 1000 plain calls within a bigger stack."
+    );
+    assert!(vm.stack_size == 0 && vm.frames.len() == 1);
+    drop(vm);
+}
+
+#[test]
+fn locals_test() {
+    println!("Locals Test: Frame locals hold values across calls and GCs.");
+    let mut vm = Vm::new();
+    // f(a, b) = b - a, keeping a scratch string in local 2 across a GC
+    let code = [
+        Op::PushInt(10),
+        Op::PushInt(3),
+        Op::Call { target: 4, argc: 2 },
+        Op::Return,
+        Op::PushStr("scratch".into()),
+        Op::StoreLocal(2),
+        Op::LoadLocal(1),
+        Op::LoadLocal(0),
+        Op::Sub,
+        Op::Return,
+    ];
+    vm.execute(&code).unwrap();
+    assert!(
+        matches!(vm.pop().value(), ObjType::Int(-7)),
+        "Should have read args."
+    );
+    assert!(
+        vm.execute(&[Op::LoadLocal(0)]).is_err(),
+        "Root has no locals yet."
+    );
+
+    vm.push_str("kept");
+    vm.store_local(0);
+    vm.gc();
+    assert!(
+        vm.num_objs == 1,
+        "Should have rooted the root frame's local."
+    );
+    vm.load_local(0).unwrap();
+    assert!(vm.stack_size == 1);
+    drop(vm);
+}
+
+#[test]
+fn throw_test() {
+    println!("Throw Test: Throws unwind frames and release what they held.");
+    let mut vm = Vm::new();
+    // try { f("x") } catch, where f leaves garbage behind and throws
+    let code = [
+        Op::PushStr("outer".into()),
+        Op::Try(6),
+        Op::PushStr("x".into()),
+        Op::Call { target: 7, argc: 1 },
+        Op::EndTry,
+        Op::PushStr("not thrown".into()),
+        Op::Return,
+        Op::PushStr("garbage".into()),
+        Op::PushInt(1),
+        Op::PushInt(2),
+        Op::MakePair,
+        Op::Throw,
+    ];
+    vm.execute(&code).unwrap();
+    assert!(vm.stack_size == 2 && vm.frames.len() == 1);
+    vm.gc();
+    assert!(
+        vm.num_objs == 4,
+        "Should have kept only the outer string and the thrown pair."
+    );
+    assert!(matches!(vm.pop().value(), ObjType::Pair(_)));
+
+    assert!(
+        vm.execute(&[Op::Try(3), Op::EndTry, Op::PushInt(7), Op::Throw])
+            == Err(GcError::UncaughtThrow)
+    );
+    assert!(
+        matches!(vm.pop().value(), ObjType::Int(7)),
+        "Should leave the uncaught value on the stack."
+    );
+    assert!(vm.stack_size == 1);
+    drop(vm);
+}
+
+#[test]
+fn constants_test() {
+    println!("Constants Test: Pooled literals are shared and rooted.");
+    let mut vm = Vm::new();
+    let s = vm.intern("literal");
+    vm.push_int(1);
+    vm.push_int(2);
+    vm.push_pair();
+    let pair = vm.pop();
+    vm.pop();
+    let s_index = vm.add_constant(s.clone());
+    let pair_index = vm.add_constant(pair.clone());
+    vm.gc();
+    assert!(vm.num_objs == 4, "Should have rooted the pool.");
+
+    let code = [Op::PushConst(s_index), Op::PushConst(pair_index)];
+    vm.execute(&code).unwrap();
+    vm.execute(&code).unwrap();
+    assert!(
+        vm.num_objs == 4,
+        "Should not have allocated the literals again."
+    );
+    assert!(vm.pop() == pair && vm.pop() == s && vm.pop() == pair);
+    assert!(vm.execute(&[Op::PushConst(2)]) == Err(GcError::IndexOutOfBounds { index: 2, len: 2 }));
+    drop(vm);
+}
+
+#[test]
+fn step_test() {
+    println!("Step Test: Stepping reports each instruction and its effects.");
+    let mut vm = Vm::new();
+    vm.set_trace(true);
+    vm.start(vec![
+        Op::PushInt(1),
+        Op::PushInt(2),
+        Op::MakePair,
+        Op::Dup,
+        Op::Add,
+    ]);
+    let step = vm.step().unwrap().unwrap();
+    assert!(step.pc == 0 && step.op == Op::PushInt(1) && step.allocated == 1);
+    vm.step().unwrap();
+    let step = vm.step().unwrap().unwrap();
+    assert!(step.allocated == 1 && step.stack == ["(2 . 1)"]);
+    vm.step().unwrap();
+    assert!(
+        vm.step().is_err(),
+        "Should report errors like execute does."
+    );
+    assert!(
+        vm.step().unwrap().is_none(),
+        "Should have ended the program."
+    );
+
+    // an array holding itself
+    vm.push_int(1);
+    vm.push_array(1);
+    let array = vm.pop();
+    vm.array_set(&array, 0, array.clone()).unwrap();
+    vm.push_ptr(array);
+    assert!(
+        describe(vm.stack[vm.stack_size - 1]) == "[<cycle>]",
+        "Should elide cycles."
+    );
+    drop(vm);
+}
+
+#[test]
+fn execute_gc_test() {
+    println!("Execute GC Test: Running bytecode collects garbage as it goes.");
+    let mut vm = Vm::new();
+    let mut code = vec![Op::PushInt(1), Op::PushInt(2), Op::MakePair];
+    for i in 0..1000 {
+        code.push(Op::PushInt(i));
+        code.push(Op::Pop);
+    }
+    vm.execute(&code).unwrap();
+    assert!(vm.num_objs < 100, "Should have collected along the way.");
+
+    vm.gc();
+    assert!(vm.num_objs == 3, "Should have kept the rooted pair.");
+    drop(vm);
+}
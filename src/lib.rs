@@ -1,254 +1,5214 @@
+mod allocation;
+mod bigint;
+mod budget;
+mod compact;
+mod convert;
+mod copy;
+pub mod ffi;
+mod finalizer;
+pub mod fuzz;
+mod graph;
+mod handle;
+mod identity;
+#[cfg(feature = "std")]
+mod image;
+mod interp;
+pub mod lisp;
+mod mark;
+mod native;
+#[cfg(feature = "pressure")]
+mod pressure;
+mod print;
+mod program;
+mod record;
+mod relocate;
+mod segment;
+// wasm32 has no threads for mutators to run on
+#[cfg(all(feature = "std", not(target_arch = "wasm32")))]
+mod shared;
+mod stack;
+mod value;
+
+pub use bigint::BigInt;
+pub use budget::MemoryBudget;
+pub use convert::{FromObject, RustValue, ToObject};
+pub use graph::{Graph, Key, Node};
+pub use handle::SendHandle;
+pub use identity::{IdentityMap, KeyMode};
+pub use interp::{Op, Step};
+pub use native::{NativeCtx, NativeFn};
+#[cfg(feature = "pressure")]
+pub use pressure::{cgroup_pressure, PressureCheck};
+pub use program::{Constant, Program};
+pub use record::{replay, Event, Log};
+pub use segment::Segment;
+#[cfg(feature = "std")]
+#[cfg(all(feature = "std", not(target_arch = "wasm32")))]
+pub use shared::{Mutator, SharedHeap};
+
+use allocation::{Chunks, Quarantine};
+use budget::Membership;
+use finalizer::Finalizer;
+use identity::Keys;
+use interp::{Frame, Stepper};
+use record::Recorder;
+use stack::Stack;
+pub use value::Value;
+
+use std::any::Any;
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::fmt::Debug;
+use std::hash::{Hash, Hasher};
+use std::ops::Range;
 use std::ptr::NonNull;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
 
-#[derive(Clone, Debug)]
-pub struct GcPtr<T>(NonNull<T>);
+/// A handle to a heap object: its address, and the epoch of the object
+/// there when the handle was made, which tells it apart from any object
+/// allocated at the same address once this one is collected.
+#[derive(Debug)]
+pub struct GcPtr<T>(NonNull<T>, u64);
 
-impl GcPtr<Object> {
-    unsafe fn mark(&mut self) {
-        if self.0.as_ref().marked {
-            return;
-        }
+// SAFETY: outside the VM a handle is just an address. It's only dereferenced
+// by the VM that owns its object, which checks that it is one of its own (see
+// `Vm::is_live`) and can't be shared between threads, so sending a handle to
+// a thread without that VM gives it nothing to read. What objects own moves
+// with their VM, which is why foreign objects and custom payloads are `Send`.
+unsafe impl Send for GcPtr<Object> {}
+
+impl<T> Clone for GcPtr<T> {
+    fn clone(&self) -> Self {
+        GcPtr(self.0, self.1)
+    }
+}
+
+/// Handles compare by identity: two `GcPtr`s are equal when they point at the
+/// same heap object. That's when their epochs are, which no two objects
+/// share: a handle to a collected object never equals one to the object
+/// allocated at its address after it, and a handle taken before
+/// [`Vm::compact`] moved its object still equals one taken after.
+impl<T> PartialEq for GcPtr<T> {
+    fn eq(&self, other: &Self) -> bool {
+        self.1 == other.1
+    }
+}
 
-        self.0.as_mut().marked = true;
+impl<T> Eq for GcPtr<T> {}
 
-        if let ObjType::Pair(pair) = &mut self.0.as_mut().value {
-            if let Some(ref mut head) = &mut pair.head {
-                head.mark();
-            }
-            if let Some(ref mut tail) = &mut pair.tail {
-                tail.mark();
-            }
-        }
+impl<T> Hash for GcPtr<T> {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        self.1.hash(state);
+    }
+}
+
+/// The head and tail of a pair, as returned by [`GcPtr::as_pair`].
+pub type PairParts = (Option<GcPtr<Object>>, Option<GcPtr<Object>>);
+
+/// Called with the stack depth and limit when the stack grows past the
+/// point given to [`Vm::set_stack_warning`].
+pub type StackWarning = fn(depth: usize, max: usize);
+
+impl GcPtr<Object> {
+    /// A handle to the object at `ptr`, which has to be live.
+    pub(crate) fn from_live(ptr: NonNull<Object>) -> Self {
+        GcPtr(ptr, unsafe { ptr.as_ref().epoch })
     }
 
     fn is_marked(&self) -> bool {
-        unsafe { self.0.as_ref().marked }
+        let header = unsafe { self.0.as_ref().header };
+        if header.in_chunk() {
+            allocation::chunk_marked(self.0)
+        } else {
+            header.is_marked()
+        }
     }
 
+    /// Marks the object, which has to be one of a VM's own: the objects of
+    /// mounted segments are marked already, and other threads read them.
+    fn set_marked(&mut self) {
+        if unsafe { self.0.as_ref().header.in_chunk() } {
+            allocation::mark_in_chunk(self.0);
+        } else {
+            unsafe { self.0.as_mut().header.set_marked(true) }
+        }
+    }
+
+    /// Unmarks an object marked in its header. Those in chunks are unmarked
+    /// a chunk at a time, see `Chunks::clear_marks`.
     fn unmark(&mut self) {
         unsafe {
-            self.0.as_mut().marked = false;
+            self.0.as_mut().header.set_marked(false);
+        }
+    }
+
+    /// The identity hash, if one has been assigned yet.
+    fn identity_hash(&self) -> Option<u64> {
+        unsafe { self.0.as_ref().identity_hash() }
+    }
+
+    /// Borrows the object's value. The handle has to point at a live object,
+    /// which debug and quarantining builds check.
+    fn value(&self) -> &ObjType {
+        allocation::check_live(self.0);
+        unsafe { &self.0.as_ref().value }
+    }
+
+    /// Returns the value of an int object. Taking the VM that owns the
+    /// object means the read can't overlap with a collection, and lets it
+    /// check the object is live, panicking if not.
+    pub fn as_int(&self, vm: &Vm) -> Option<i64> {
+        match vm.get(self) {
+            ObjType::Int(i) => Some(*i),
+            _ => None,
+        }
+    }
+
+    /// Returns the head and tail of a pair object, like [`GcPtr::as_int`].
+    pub fn as_pair(&self, vm: &Vm) -> Option<PairParts> {
+        match vm.get(self) {
+            ObjType::Pair(pair) => Some((pair.head.clone(), pair.tail.clone())),
+            _ => None,
+        }
+    }
+
+    fn is_frozen(&self) -> bool {
+        unsafe { self.0.as_ref().header.is_frozen() }
+    }
+
+    fn user_tag(&self) -> u32 {
+        unsafe { self.0.as_ref().header.user_tag() }
+    }
+
+    /// Fails with `FrozenObject` if the object has been frozen.
+    fn ensure_mutable(&self) -> Result<(), GcError> {
+        if self.is_frozen() {
+            return Err(GcError::FrozenObject);
         }
+        Ok(())
+    }
+
+    /// Bytes owned by the object: its header plus any out-of-line storage.
+    fn size(&self) -> usize {
+        self.value().size()
+    }
+
+    unsafe fn finalize(&mut self) {
+        self.0.as_mut().value.finalize();
     }
 
-    unsafe fn free(&mut self) {
-        let unreached = self.0.as_mut();
-        let _ = Box::from_raw(unreached); // drop
+    unsafe fn free(&mut self, quarantine: &mut Quarantine, chunks: &mut Chunks) {
+        self.finalize();
+        quarantine.free(self.0, chunks);
     }
 }
 
-#[derive(Clone, Debug)]
+/// A heap object: 80 bytes on 64-bit targets, a 24-byte prefix of header,
+/// identity hash and epoch ahead of a 56-byte `ObjType`, which keeps a
+/// discriminant of its own besides the copy in the header.
+#[derive(Debug)]
 pub struct Object {
-    marked: bool,
+    header: Header,
+    /// assigned by `Vm::identity_hash` the first time it's asked for, and
+    /// kept with the object wherever it lives from then on; only meaningful
+    /// once the header says it's been
+    identity_hash: u64,
+    /// unique to this object among every object of every VM, so a handle
+    /// made before its address was reused is told apart from one to it
+    epoch: u64,
     value: ObjType,
 }
 
-#[derive(Clone, Debug)]
-pub enum ObjType {
-    Int(i64),
-    Pair(Pair),
+/// Source of object epochs, shared by all VMs so handles can't collide
+/// across them either.
+static EPOCHS: AtomicU64 = AtomicU64::new(0);
+
+impl Object {
+    fn new(mut header: Header, value: ObjType) -> Self {
+        header.set_type_tag(value.tag());
+        Object {
+            header,
+            identity_hash: 0,
+            epoch: EPOCHS.fetch_add(1, Ordering::Relaxed),
+            value,
+        }
+    }
+
+    fn identity_hash(&self) -> Option<u64> {
+        self.header.is_hashed().then_some(self.identity_hash)
+    }
+
+    fn set_identity_hash(&mut self, hash: u64) {
+        self.identity_hash = hash;
+        self.header.0 |= Header::HASHED;
+    }
 }
 
-#[derive(Clone, Debug)]
-pub struct Pair {
-    head: Option<GcPtr<Object>>,
-    tail: Option<GcPtr<Object>>,
+/// An object's flags, type and user tag, packed into one word: the flags in
+/// the low byte, the [`TypeTag`] of its value in the next one, and the user
+/// tag in the high half. The low byte's top bit is never set, so a header
+/// never reads as `allocation`'s poison, and bits 16 to 31 are free for
+/// more flags.
+#[derive(Clone, Copy, Debug, Default)]
+struct Header(u64);
+
+impl Header {
+    const MARKED: u64 = 1 << 0;
+    /// set by `Vm::freeze`; mutation APIs refuse to touch frozen objects
+    const FROZEN: u64 = 1 << 1;
+    /// whether the object's identity hash has been assigned
+    const HASHED: u64 = 1 << 2;
+    /// set for objects allocated in a chunk, whose mark bit is in the
+    /// chunk's meta rather than here
+    const CHUNKED: u64 = 1 << 3;
+    const TYPE_SHIFT: u32 = 8;
+    const TYPE_MASK: u64 = 0xff << Self::TYPE_SHIFT;
+    const TAG_SHIFT: u32 = 32;
+
+    fn is_marked(self) -> bool {
+        self.0 & Self::MARKED != 0
+    }
+
+    fn set_marked(&mut self, marked: bool) {
+        if marked {
+            self.0 |= Self::MARKED;
+        } else {
+            self.0 &= !Self::MARKED;
+        }
+    }
+
+    fn is_frozen(self) -> bool {
+        self.0 & Self::FROZEN != 0
+    }
+
+    fn set_frozen(&mut self, frozen: bool) {
+        if frozen {
+            self.0 |= Self::FROZEN;
+        } else {
+            self.0 &= !Self::FROZEN;
+        }
+    }
+
+    fn is_hashed(self) -> bool {
+        self.0 & Self::HASHED != 0
+    }
+
+    fn in_chunk(self) -> bool {
+        self.0 & Self::CHUNKED != 0
+    }
+
+    /// which `ObjType` variant the object holds, readable without touching
+    /// the value
+    fn type_tag(self) -> TypeTag {
+        TypeTag::ALL[((self.0 & Self::TYPE_MASK) >> Self::TYPE_SHIFT) as usize]
+    }
+
+    fn set_type_tag(&mut self, tag: TypeTag) {
+        self.0 = (self.0 & !Self::TYPE_MASK) | (tag as u64) << Self::TYPE_SHIFT;
+    }
+
+    /// free for embedders to use, see `Vm::set_user_tag`
+    fn user_tag(self) -> u32 {
+        (self.0 >> Self::TAG_SHIFT) as u32
+    }
+
+    fn set_user_tag(&mut self, tag: u32) {
+        self.0 = (self.0 & u32::MAX as u64) | (tag as u64) << Self::TAG_SHIFT;
+    }
 }
 
-const STACK_MAX: usize = 256;
-const INITIAL_GC_THRESHOLD: usize = 8;
+/// The variant of an `ObjType`, as kept in its object's header.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[repr(u8)]
+enum TypeTag {
+    Nil,
+    Bool,
+    Int,
+    BigInt,
+    Float,
+    Pair,
+    Str,
+    Symbol,
+    Rope,
+    Array,
+    Map,
+    Closure,
+    Foreign,
+    Custom,
+    Bytes,
+}
 
-pub struct Vm {
-    stack: [Option<GcPtr<Object>>; STACK_MAX],
-    stack_size: usize,
-    heap: Vec<GcPtr<Object>>,
-    /// currently total number of objects allocated
-    num_objs: usize,
-    /// number of objects required to trigger a GC
-    max_objs: usize,
+impl TypeTag {
+    /// every tag, indexed by its value
+    const ALL: [TypeTag; 15] = [
+        TypeTag::Nil,
+        TypeTag::Bool,
+        TypeTag::Int,
+        TypeTag::BigInt,
+        TypeTag::Float,
+        TypeTag::Pair,
+        TypeTag::Str,
+        TypeTag::Symbol,
+        TypeTag::Rope,
+        TypeTag::Array,
+        TypeTag::Map,
+        TypeTag::Closure,
+        TypeTag::Foreign,
+        TypeTag::Custom,
+        TypeTag::Bytes,
+    ];
+
+    fn name(self) -> &'static str {
+        match self {
+            TypeTag::Nil => "nil",
+            TypeTag::Bool => "bool",
+            TypeTag::Int => "int",
+            TypeTag::BigInt => "bigint",
+            TypeTag::Float => "float",
+            TypeTag::Pair => "pair",
+            TypeTag::Str => "string",
+            TypeTag::Symbol => "symbol",
+            TypeTag::Rope => "rope",
+            TypeTag::Array => "array",
+            TypeTag::Map => "map",
+            TypeTag::Closure => "closure",
+            TypeTag::Foreign => "foreign",
+            TypeTag::Custom => "custom",
+            TypeTag::Bytes => "bytes",
+        }
+    }
+
+    /// Whether dropping a value of this type does anything, so freeing its
+    /// object can't skip that.
+    fn needs_drop(self) -> bool {
+        !matches!(
+            self,
+            TypeTag::Nil
+                | TypeTag::Bool
+                | TypeTag::Int
+                | TypeTag::Float
+                | TypeTag::Pair
+                | TypeTag::Rope
+        )
+    }
+
+    /// Whether values of this type can refer to other objects, so marking
+    /// has to look inside them.
+    fn traces(self) -> bool {
+        matches!(
+            self,
+            TypeTag::Pair
+                | TypeTag::Rope
+                | TypeTag::Array
+                | TypeTag::Map
+                | TypeTag::Closure
+                | TypeTag::Foreign
+                | TypeTag::Custom
+        )
+    }
 }
 
-impl Vm {
-    pub fn new() -> Self {
-        Self {
-            stack: std::array::from_fn(|_| None),
-            stack_size: 0,
-            heap: vec![],
-            num_objs: 0,
-            max_objs: INITIAL_GC_THRESHOLD,
+#[derive(Debug)]
+pub enum ObjType {
+    Nil,
+    Bool(bool),
+    Int(i64),
+    /// integer too large for `Int`; arithmetic produces these on overflow
+    /// and shrinks results back to `Int` whenever they fit again
+    BigInt(BigInt),
+    Float(f64),
+    Pair(Pair),
+    Str(String),
+    Symbol(String),
+    Rope(Rope),
+    Array(Vec<GcPtr<Object>>),
+    Map(Map),
+    Closure(Closure),
+    /// native data owned by the embedder
+    Foreign(Box<dyn ForeignObject>),
+    /// value of a kind registered at runtime with [`Vm::register_kind`]
+    Custom(Custom),
+    /// raw binary data; holds no references, so marking never looks inside
+    Bytes(Vec<u8>),
+}
+
+impl ObjType {
+    fn tag(&self) -> TypeTag {
+        match self {
+            ObjType::Nil => TypeTag::Nil,
+            ObjType::Bool(_) => TypeTag::Bool,
+            ObjType::Int(_) => TypeTag::Int,
+            ObjType::BigInt(_) => TypeTag::BigInt,
+            ObjType::Float(_) => TypeTag::Float,
+            ObjType::Pair(_) => TypeTag::Pair,
+            ObjType::Str(_) => TypeTag::Str,
+            ObjType::Symbol(_) => TypeTag::Symbol,
+            ObjType::Rope(_) => TypeTag::Rope,
+            ObjType::Array(_) => TypeTag::Array,
+            ObjType::Map(_) => TypeTag::Map,
+            ObjType::Closure(_) => TypeTag::Closure,
+            ObjType::Foreign(_) => TypeTag::Foreign,
+            ObjType::Custom(_) => TypeTag::Custom,
+            ObjType::Bytes(_) => TypeTag::Bytes,
         }
     }
 
-    pub fn push(&mut self, value: ObjType) {
-        assert!(self.stack_size < STACK_MAX, "Stack overflow!");
-        let mut box_obj = Box::new(Object {
-            marked: false,
-            value,
-        });
-        let gc_ptr = GcPtr(NonNull::new(&mut *box_obj).unwrap());
-        self.stack[self.stack_size] = Some(gc_ptr.clone());
-        std::mem::forget(box_obj);
-        self.heap.push(gc_ptr);
-        self.stack_size += 1;
-        self.num_objs += 1;
+    /// Bytes an object holding this value owns, see `GcPtr::size`.
+    fn size(&self) -> usize {
+        let payload = match self {
+            ObjType::Str(s) | ObjType::Symbol(s) => s.capacity(),
+            ObjType::Bytes(bytes) => bytes.capacity(),
+            ObjType::BigInt(n) => n.heap_size(),
+            ObjType::Custom(custom) => (custom.kind.size)(&*custom.data),
+            ObjType::Array(elems) => elems.capacity() * std::mem::size_of::<GcPtr<Object>>(),
+            ObjType::Closure(closure) => {
+                closure.upvalues.capacity() * std::mem::size_of::<GcPtr<Object>>()
+            }
+            ObjType::Map(map) => {
+                map.entries.capacity() * std::mem::size_of::<(MapKey, GcPtr<Object>)>()
+            }
+            _ => 0,
+        };
+        std::mem::size_of::<Object>() + payload
     }
 
-    pub fn pop(&mut self) -> GcPtr<Object> {
-        self.stack_size -= 1;
-        let obj = self.stack[self.stack_size].take().unwrap();
-        obj
+    /// Runs the finalizer of a foreign or custom value.
+    fn finalize(&mut self) {
+        match self {
+            ObjType::Foreign(foreign) => foreign.finalize(),
+            ObjType::Custom(custom) => (custom.kind.finalize)(&mut *custom.data),
+            _ => {}
+        }
     }
 
-    pub fn push_int(&mut self, value: i64) {
-        self.push(ObjType::Int(value));
+    fn type_name(&self) -> &'static str {
+        match self {
+            ObjType::Nil => "nil",
+            ObjType::Bool(_) => "bool",
+            ObjType::Int(_) => "int",
+            ObjType::BigInt(_) => "bigint",
+            ObjType::Float(_) => "float",
+            ObjType::Pair(_) => "pair",
+            ObjType::Str(_) => "string",
+            ObjType::Symbol(_) => "symbol",
+            ObjType::Rope(_) => "rope",
+            ObjType::Array(_) => "array",
+            ObjType::Map(_) => "map",
+            ObjType::Closure(_) => "closure",
+            ObjType::Foreign(_) => "foreign",
+            ObjType::Custom(custom) => custom.kind.name,
+            ObjType::Bytes(_) => "bytes",
+        }
     }
 
-    pub fn push_pair(&mut self) {
-        let head = Some(self.pop());
-        let tail = Some(self.pop());
-        self.push(ObjType::Pair(Pair { head, tail }));
+    fn is_string(&self) -> bool {
+        matches!(self, ObjType::Str(_) | ObjType::Rope(_))
     }
 
-    pub fn mark_all(&mut self) {
-        for obj in &mut self.stack {
-            if let Some(obj) = obj {
-                unsafe {
-                    obj.mark();
-                }
-            }
+    /// Text of a string or rope, and the empty string for anything else.
+    fn contents(&self) -> String {
+        match self {
+            ObjType::Str(s) => s.clone(),
+            ObjType::Rope(rope) => string_contents(&rope.left) + &string_contents(&rope.right),
+            _ => String::new(),
         }
     }
 
-    pub fn sweep(&mut self) {
-        let mut live_objects = vec![];
+    /// Numeric value of ints and floats, used to compare across the two.
+    fn as_f64(&self) -> Option<f64> {
+        match *self {
+            ObjType::Int(i) => Some(i as f64),
+            ObjType::Float(f) => Some(f),
+            _ => None,
+        }
+    }
+}
 
-        for obj in &mut self.heap {
-            if !obj.is_marked() {
-                unsafe { obj.free() }
-                self.num_objs -= 1;
-            } else {
-                obj.unmark();
-                live_objects.push(obj.clone()); // ptr clone
+/// Compares values structurally: strings and ropes by content, floats by
+/// their bits, pairs and arrays by comparing their children the same way,
+/// and maps, closures, foreign and custom objects by identity. Unlike
+/// [`Vm::values_eq`], ints never equal floats. The children are followed
+/// without any cycle detection, so this only terminates on acyclic values;
+/// use [`Vm::deep_eq`] for graphs that may have cycles.
+impl PartialEq for ObjType {
+    fn eq(&self, other: &Self) -> bool {
+        fn child(child: &Option<GcPtr<Object>>) -> Option<&ObjType> {
+            child.as_ref().map(GcPtr::value)
+        }
+        match (self, other) {
+            (ObjType::Nil, ObjType::Nil) => true,
+            (ObjType::Bool(a), ObjType::Bool(b)) => a == b,
+            (ObjType::Int(a), ObjType::Int(b)) => a == b,
+            (ObjType::BigInt(a), ObjType::BigInt(b)) => a == b,
+            (ObjType::Float(a), ObjType::Float(b)) => a.to_bits() == b.to_bits(),
+            (a, b) if a.is_string() && b.is_string() => a.contents() == b.contents(),
+            (ObjType::Symbol(a), ObjType::Symbol(b)) => a == b,
+            (ObjType::Pair(a), ObjType::Pair(b)) => {
+                child(&a.head) == child(&b.head) && child(&a.tail) == child(&b.tail)
             }
+            (ObjType::Array(a), ObjType::Array(b)) => {
+                a.len() == b.len() && a.iter().zip(b).all(|(x, y)| x.value() == y.value())
+            }
+            (ObjType::Bytes(a), ObjType::Bytes(b)) => a == b,
+            (a, b) => std::ptr::eq(a, b),
         }
+    }
+}
 
-        self.heap = live_objects;
+impl Eq for ObjType {}
+
+impl Hash for ObjType {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        if self.is_string() {
+            "string".hash(state);
+        } else {
+            self.type_name().hash(state);
+        }
+        match self {
+            ObjType::Nil => {}
+            ObjType::Bool(b) => b.hash(state),
+            ObjType::Int(i) => i.hash(state),
+            ObjType::BigInt(n) => n.hash(state),
+            ObjType::Float(f) => f.to_bits().hash(state),
+            ObjType::Str(_) | ObjType::Rope(_) => self.contents().hash(state),
+            ObjType::Symbol(name) => name.hash(state),
+            ObjType::Pair(pair) => {
+                pair.head.as_ref().map(GcPtr::value).hash(state);
+                pair.tail.as_ref().map(GcPtr::value).hash(state);
+            }
+            ObjType::Array(elems) => {
+                elems.len().hash(state);
+                elems.iter().for_each(|elem| elem.value().hash(state));
+            }
+            ObjType::Bytes(bytes) => bytes.hash(state),
+            other => std::ptr::hash(other, state),
+        }
     }
+}
 
-    pub fn gc(&mut self) {
-        let num_objs = self.num_objs;
+#[derive(Clone, Debug)]
+pub struct Pair {
+    head: Option<GcPtr<Object>>,
+    tail: Option<GcPtr<Object>>,
+}
 
-        self.mark_all();
-        self.sweep();
+/// Iterator over the heads of a list, see [`Vm::iter_list`].
+pub struct ListIter<'a> {
+    // ties the iteration to a borrow of the VM, so nothing collects meanwhile
+    vm: &'a Vm,
+    next: Option<GcPtr<Object>>,
+    seen: HashSet<GcPtr<Object>>,
+}
 
-        self.max_objs = if self.num_objs == 0 {
-            INITIAL_GC_THRESHOLD
-        } else {
-            self.num_objs * 2
-        };
+impl Iterator for ListIter<'_> {
+    type Item = GcPtr<Object>;
 
-        println!("Collected {} objects, {} remaining.", num_objs - self.num_objs, self.num_objs);
+    fn next(&mut self) -> Option<GcPtr<Object>> {
+        let pair = self.next.take()?;
+        if !self.seen.insert(pair.clone()) {
+            return None;
+        }
+        match pair.value() {
+            ObjType::Pair(Pair { head, tail }) => {
+                self.next = tail.clone();
+                // a pair without a head still continues the list
+                Some(head.clone().unwrap_or_else(|| self.vm.nil.clone()))
+            }
+            _ => None,
+        }
     }
 }
 
-impl Drop for Vm {
-    fn drop(&mut self) {
-        self.stack_size = 0;
-        self.stack = std::array::from_fn(|_| None);
-        self.gc();
+/// A mutable borrow of part of an object, from [`Vm::get_mut`],
+/// [`Vm::foreign_mut`] or [`Vm::custom_mut`]. Like those borrows, it keeps
+/// the VM from collecting while it's alive.
+///
+/// Dropping it accounts for any change in the object's size. Only foreign
+/// and custom payloads can be made to refer to other objects through it. If
+/// one is left referring to an object that isn't live, nothing is undone,
+/// but collections are put off and borrowing any other object fails with
+/// `GcError::DeadObject` until a borrow of that one puts it right.
+pub struct ObjMut<'a, T: ?Sized> {
+    vm: &'a mut Vm,
+    obj: GcPtr<Object>,
+    value: NonNull<T>,
+    size: usize,
+}
+
+impl<'a, T: ?Sized> ObjMut<'a, T> {
+    /// `value` has to point into `obj`, which has to be live.
+    fn new(vm: &'a mut Vm, obj: &GcPtr<Object>, value: NonNull<T>) -> Self {
+        ObjMut {
+            vm,
+            obj: obj.clone(),
+            value,
+            size: obj.size(),
+        }
     }
 }
 
-#[test]
-fn test1() {
-    println!("Test 1: Objects on stack are preserved.");
-    let mut vm = Vm::new();
-    vm.push_int(1);
-    vm.push_int(2);
+impl<T: ?Sized> std::ops::Deref for ObjMut<'_, T> {
+    type Target = T;
 
-    vm.gc();
-    assert!(vm.num_objs == 2, "Should have preserved objects.");
-    drop(vm);
+    fn deref(&self) -> &T {
+        unsafe { self.value.as_ref() }
+    }
 }
 
-#[test]
-fn test2() {
-    println!("Test 2: Unreached objects are collected.");
-    let mut vm = Vm::new();
-    vm.push_int(1);
-    vm.push_int(2);
-    vm.pop();
-    vm.pop();
+impl<T: ?Sized> std::ops::DerefMut for ObjMut<'_, T> {
+    fn deref_mut(&mut self) -> &mut T {
+        unsafe { self.value.as_mut() }
+    }
+}
 
-    vm.gc();
-    assert!(vm.num_objs == 0, "Should have collected objects.");
-    drop(vm);
+impl<T: ?Sized> Drop for ObjMut<'_, T> {
+    fn drop(&mut self) {
+        self.vm.num_bytes = self.vm.num_bytes - self.size + self.obj.size();
+        let dead = self.vm.check_children(self.obj.value()).is_err();
+        if dead && !self.vm.dead_writes.contains(&self.obj) {
+            self.vm.dead_writes.push(self.obj.clone());
+        }
+    }
 }
 
-#[test]
-fn test3() {
-  println!("Test 3: Reach nested objects.");
-  let mut vm = Vm::new();
-  vm.push_int(1);
-  vm.push_int(2);
-  vm.push_pair();
-  vm.push_int(3);
-  vm.push_int(4);
-  vm.push_pair();
-  vm.push_pair();
+mod sealed {
+    pub trait Sealed {}
+}
 
-  vm.gc();
-  assert!(vm.num_objs == 7, "Should have reached objects.");
-  drop(vm);
+/// A value that holds no references, which [`Vm::get_mut`] lends out as is:
+/// nothing written to it can leave a dead handle in the heap.
+pub trait Plain: sealed::Sealed {
+    /// type name of the objects holding one, as errors give it
+    const NAME: &'static str;
+
+    fn plain_mut(value: &mut ObjType) -> Option<&mut Self>;
 }
 
-#[test]
-#[ignore]
-fn test4() {
-    println!("Test 4: Handle cycles.");
-    let mut vm = Vm::new();
-    vm.push_int(1);
-    vm.push_int(2);
-    vm.push_pair();
-    vm.push_int(3);
-    vm.push_int(4);
-    vm.push_pair();
+impl sealed::Sealed for i64 {}
 
-    /* Set up a cycle, and also make 2 and 4 unreachable and collectible. */
-    unsafe {
-        let b = vm.heap[1].clone();
-        let pair_a = &mut vm.heap[0];
-        if let ObjType::Pair(ref mut p) = &mut pair_a.0.as_mut().value {
-            p.tail = Some(b);
+impl Plain for i64 {
+    const NAME: &'static str = "int";
+
+    fn plain_mut(value: &mut ObjType) -> Option<&mut Self> {
+        match value {
+            ObjType::Int(i) => Some(i),
+            _ => None,
         }
     }
-    unsafe {
-        let a = vm.heap[0].clone();
-        let pair_b = &mut vm.heap[1];
-        if let ObjType::Pair(ref mut p) = &mut pair_b.0.as_mut().value {
-            p.tail = Some(a);
+}
+
+impl sealed::Sealed for BigInt {}
+
+impl Plain for BigInt {
+    const NAME: &'static str = "bigint";
+
+    fn plain_mut(value: &mut ObjType) -> Option<&mut Self> {
+        match value {
+            ObjType::BigInt(n) => Some(n),
+            _ => None,
         }
     }
-
-    vm.gc();
-    assert!(dbg!(vm.num_objs) == 4, "Should have collected objects.");
-    drop(vm);
 }
 
-#[test]
-fn perf_test() {
-    println!("Performance Test.");
-    let mut vm = Vm::new();
+impl sealed::Sealed for f64 {}
 
-    for i in 0..1000 {
-        for _j in 0..20 {
-            vm.push_int(i);
+impl Plain for f64 {
+    const NAME: &'static str = "float";
+
+    fn plain_mut(value: &mut ObjType) -> Option<&mut Self> {
+        match value {
+            ObjType::Float(f) => Some(f),
+            _ => None,
         }
+    }
+}
 
-        for _k in 0..20 {
-            vm.pop();
+impl sealed::Sealed for String {}
+
+impl Plain for String {
+    const NAME: &'static str = "string";
+
+    fn plain_mut(value: &mut ObjType) -> Option<&mut Self> {
+        match value {
+            ObjType::Str(s) => Some(s),
+            _ => None,
         }
     }
+}
+
+impl sealed::Sealed for Vec<u8> {}
+
+impl Plain for Vec<u8> {
+    const NAME: &'static str = "bytes";
+
+    fn plain_mut(value: &mut ObjType) -> Option<&mut Self> {
+        match value {
+            ObjType::Bytes(bytes) => Some(bytes),
+            _ => None,
+        }
+    }
+}
+
+/// A lazily concatenated string: `left` followed by `right`, each of which is
+/// either a flat string or another rope.
+#[derive(Clone, Debug)]
+pub struct Rope {
+    left: GcPtr<Object>,
+    right: GcPtr<Object>,
+    /// length in bytes of the whole concatenation
+    len: usize,
+}
+
+/// Something that walks the object graph, one reference at a time.
+pub trait Visitor {
+    fn visit(&mut self, obj: &GcPtr<Object>);
+}
+
+/// Enumerates the heap objects a value refers to. This is the only place an
+/// object kind needs to describe its children: marking and every other graph
+/// walk are visitors driven through it.
+pub trait Trace {
+    fn trace(&self, visitor: &mut dyn Visitor);
+}
+
+impl Trace for ObjType {
+    fn trace(&self, visitor: &mut dyn Visitor) {
+        match self {
+            ObjType::Pair(pair) => {
+                if let Some(head) = &pair.head {
+                    visitor.visit(head);
+                }
+                if let Some(tail) = &pair.tail {
+                    visitor.visit(tail);
+                }
+            }
+            ObjType::Rope(rope) => {
+                visitor.visit(&rope.left);
+                visitor.visit(&rope.right);
+            }
+            ObjType::Array(elems) => elems.iter().for_each(|elem| visitor.visit(elem)),
+            ObjType::Map(map) => map.entries.values().for_each(|value| visitor.visit(value)),
+            ObjType::Closure(closure) => closure
+                .upvalues
+                .iter()
+                .for_each(|upvalue| visitor.visit(upvalue)),
+            ObjType::Foreign(foreign) => foreign.trace(visitor),
+            ObjType::Custom(custom) => (custom.kind.trace)(&*custom.data, visitor),
+            _ => {}
+        }
+    }
+}
+
+/// A native Rust value stored in the heap, such as a texture or a database
+/// handle. The collector traces it for the heap objects it refers to, and
+/// gives it a chance to release its resources right before it's freed.
+///
+/// Foreign objects move with their VM, so they have to be `Send`.
+pub trait ForeignObject: Trace + Any + Debug + Send {
+    /// Called once, when the object is collected or the VM is dropped.
+    fn finalize(&mut self) {}
+}
+
+/// Behavior of an object kind registered at runtime. Each function receives
+/// the object's payload, as given to [`Vm::push_custom`].
+#[derive(Debug)]
+pub struct ObjKind {
+    pub name: &'static str,
+    /// reports every heap object the payload refers to
+    pub trace: fn(&dyn Any, &mut dyn Visitor),
+    /// bytes of out-of-line storage owned by the payload
+    pub size: fn(&dyn Any) -> usize,
+    /// runs right before the object is freed
+    pub finalize: fn(&mut dyn Any),
+}
+
+/// Type tag of a registered [`ObjKind`].
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct KindId(u32);
+
+#[derive(Debug)]
+pub struct Custom {
+    tag: KindId,
+    kind: Arc<ObjKind>,
+    data: Box<dyn Any + Send>,
+}
+
+/// A function value: the code it runs, identified by an id meaningful to
+/// whatever frontend compiled it, plus the environment values it captured.
+#[derive(Clone, Debug)]
+pub struct Closure {
+    code_id: usize,
+    upvalues: Vec<GcPtr<Object>>,
+}
+
+#[derive(Clone, Debug, Default)]
+pub struct Map {
+    entries: HashMap<MapKey, GcPtr<Object>>,
+}
+
+/// A hash-consed pair's child, as far as deduplication is concerned.
+#[derive(Debug, PartialEq, Eq, Hash)]
+enum ConsKey {
+    Int(i64),
+    Float(u64),
+    Obj(GcPtr<Object>),
+}
+
+impl ConsKey {
+    fn new(obj: &GcPtr<Object>) -> Self {
+        match obj.value() {
+            ObjType::Int(i) => ConsKey::Int(*i),
+            ObjType::Float(f) => ConsKey::Float(f.to_bits()),
+            _ => ConsKey::Obj(obj.clone()),
+        }
+    }
+}
+
+/// Keys are stored by value, so only the map's values need tracing.
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+enum MapKey {
+    Int(i64),
+    Str(String),
+    Symbol(String),
+}
+
+impl MapKey {
+    fn from_obj(obj: &GcPtr<Object>) -> Result<Self, GcError> {
+        match obj.value() {
+            ObjType::Int(i) => Ok(MapKey::Int(*i)),
+            ObjType::Symbol(name) => Ok(MapKey::Symbol(name.clone())),
+            value if value.is_string() => Ok(MapKey::Str(string_contents(obj))),
+            other => Err(GcError::TypeMismatch {
+                expected: "int, string or symbol key",
+                found: other.type_name(),
+            }),
+        }
+    }
+}
+
+/// Every way a fallible VM operation can fail.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum GcError {
+    TypeMismatch {
+        expected: &'static str,
+        found: &'static str,
+    },
+    IndexOutOfBounds {
+        index: usize,
+        len: usize,
+    },
+    UnknownKind(KindId),
+    FrozenObject,
+    InvalidJump(usize),
+    DivisionByZero,
+    StackOverflow,
+    UnknownNative(String),
+    UncaughtThrow,
+    InvalidBytecode(&'static str),
+    InvalidLog(&'static str),
+    /// the value refers back to itself, so it has no finite copy
+    CyclicValue,
+    /// an operation needed more values than the stack holds
+    StackUnderflow,
+    InvalidImage(&'static str),
+    InvalidGraph(&'static str),
+    /// no object is registered under the handle
+    InvalidHandle(u64),
+    /// the allocator couldn't provide memory for an object or stack segment
+    OutOfMemory,
+    /// a handle passed in points at an object that has been collected, or
+    /// that belongs to another VM
+    DeadObject,
+    /// a segment being mounted has a symbol of a name the VM already has its
+    /// own symbol for
+    DuplicateSymbol(String),
+    /// the operation can't be logged, and the VM records
+    Unrecordable(&'static str),
+    /// reading or writing failed
+    #[cfg(feature = "std")]
+    Io(std::io::ErrorKind),
+}
+
+impl std::fmt::Display for GcError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            GcError::TypeMismatch { expected, found } => {
+                write!(f, "expected {expected}, found {found}")
+            }
+            GcError::IndexOutOfBounds { index, len } => {
+                write!(f, "index {index} out of bounds for length {len}")
+            }
+            GcError::UnknownKind(kind) => write!(f, "unknown object kind {}", kind.0),
+            GcError::FrozenObject => write!(f, "object is frozen"),
+            GcError::InvalidJump(target) => write!(f, "jump to invalid target {target}"),
+            GcError::DivisionByZero => write!(f, "division by zero"),
+            GcError::StackOverflow => write!(f, "stack overflow"),
+            GcError::UnknownNative(name) => write!(f, "unknown native function `{name}`"),
+            GcError::UncaughtThrow => write!(f, "uncaught throw"),
+            GcError::InvalidBytecode(reason) => write!(f, "invalid bytecode: {reason}"),
+            GcError::InvalidLog(reason) => write!(f, "invalid log: {reason}"),
+            GcError::CyclicValue => write!(f, "value is cyclic"),
+            GcError::StackUnderflow => write!(f, "stack underflow"),
+            GcError::InvalidImage(reason) => write!(f, "invalid image: {reason}"),
+            GcError::InvalidGraph(reason) => write!(f, "invalid graph: {reason}"),
+            GcError::InvalidHandle(handle) => write!(f, "invalid handle {handle}"),
+            GcError::OutOfMemory => write!(f, "out of memory"),
+            GcError::DeadObject => write!(f, "object is not live in this VM"),
+            GcError::DuplicateSymbol(name) => write!(f, "symbol `{name}` already exists"),
+            GcError::Unrecordable(op) => write!(f, "`{op}` can't be recorded"),
+            #[cfg(feature = "std")]
+            GcError::Io(kind) => write!(f, "I/O error: {kind}"),
+        }
+    }
+}
+
+impl std::error::Error for GcError {}
+
+/// stack limit of VMs whose configuration doesn't set one
+const STACK_MAX: usize = 256;
+const INITIAL_GC_THRESHOLD: usize = 8;
+/// range of ints the small-int cache preallocates
+const SMALL_INT_MIN: i64 = -128;
+const SMALL_INT_MAX: i64 = 1024;
+
+/// Tunables for a [`Vm`]. The default configuration is what [`Vm::new`] uses.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct VmConfig {
+    /// keep ints that fit in a [`Value`] unboxed in their stack slot, so
+    /// `push_int` and int arithmetic don't allocate
+    pub unboxed_ints: bool,
+    /// preallocate immortal objects for the ints in `-128..=1024` and reuse
+    /// them instead of allocating whenever one of those ints is boxed
+    pub small_int_cache: bool,
+    /// log every operation that changes the VM for [`Vm::log`], so the
+    /// session can be [`replay`]ed; operations the log can't express are
+    /// refused, see [`Event`]
+    pub record: bool,
+    /// how many slots the stack may grow to, 256 if unset, with each active
+    /// call taking one, so this bounds how deeply calls nest too; the stack
+    /// only holds on to as much memory as it has needed so far
+    pub max_stack: Option<usize>,
+    /// where objects are put in memory
+    pub layout: LayoutPolicy,
+}
+
+/// Where a [`Vm`] puts the objects it allocates.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum LayoutPolicy {
+    /// every object is an allocation of its own
+    #[default]
+    Individual,
+    /// objects are allocated from chunks of a few dozen slots the VM owns,
+    /// and a pair goes in the chunk of its head or tail when that has room,
+    /// so walking a list touches a few chunks rather than memory all over;
+    /// sweeps then free on the collecting thread alone
+    Clustered,
+}
+
+/// What a [`Vm`]'s heap holds, from [`Vm::heap_stats`].
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct HeapStats {
+    /// objects in the heap, including garbage not collected yet
+    pub objects: usize,
+    /// bytes they own, see `num_bytes`
+    pub bytes: usize,
+    /// how many of the objects there are of each type, by the name errors
+    /// give it, with every custom kind counted as `"custom"`
+    pub by_type: BTreeMap<&'static str, usize>,
+    /// chunks a clustered layout allocates from
+    pub chunks: usize,
+    /// slots free in chunks other than the one being allocated from
+    pub free_slots: usize,
+    /// see [`Vm::fragmentation`]
+    pub fragmentation: f64,
+}
+
+/// Addresses of live objects, each with the epoch of the object there, so a
+/// handle is only taken for live if it's to the very object at its address.
+#[derive(Default)]
+struct LiveSet(HashMap<u64, u64>);
+
+impl LiveSet {
+    fn insert(&mut self, obj: &GcPtr<Object>) {
+        self.0.insert(allocation::to_addr(obj.0), obj.1);
+    }
+
+    fn remove(&mut self, obj: &GcPtr<Object>) {
+        self.0.remove(&allocation::to_addr(obj.0));
+    }
+
+    fn contains(&self, obj: &GcPtr<Object>) -> bool {
+        self.0.get(&allocation::to_addr(obj.0)) == Some(&obj.1)
+    }
+
+    /// The live object at `addr`, if there is one.
+    fn at(&self, addr: u64) -> Option<GcPtr<Object>> {
+        let epoch = *self.0.get(&addr)?;
+        Some(GcPtr(allocation::from_addr(addr), epoch))
+    }
+}
+
+impl Extend<GcPtr<Object>> for LiveSet {
+    fn extend<I: IntoIterator<Item = GcPtr<Object>>>(&mut self, objs: I) {
+        for obj in objs {
+            self.insert(&obj);
+        }
+    }
+}
+
+pub struct Vm {
+    /// distinguishes this VM from every other, see `SendHandle`
+    id: u64,
+    config: VmConfig,
+    /// grown a segment at a time as needed; slots past `stack_size` hold nil
+    stack: Stack,
+    stack_size: usize,
+    /// the most slots `stack` may grow to
+    stack_max: usize,
+    /// deepest the stack has been
+    stack_high_water: usize,
+    /// depth at which to call the warning hook, and the hook
+    stack_warning: Option<(usize, StackWarning)>,
+    heap: Vec<GcPtr<Object>>,
+    /// the heap, the immortals and the objects of mounted segments, which is
+    /// what handles passed in are checked against
+    live: LiveSet,
+    /// objects a borrow left referring to one that isn't live, which would
+    /// have marking read freed memory, so collections wait until they don't
+    dead_writes: Vec<GcPtr<Object>>,
+    /// currently total number of objects allocated
+    num_objs: usize,
+    /// currently total number of bytes owned by allocated objects
+    num_bytes: usize,
+    /// number of objects required to trigger a GC
+    max_objs: usize,
+    /// interned strings, held weakly: entries whose object wasn't marked are
+    /// dropped before sweeping
+    strings: HashMap<String, GcPtr<Object>>,
+    /// symbol table, held weakly like `strings`
+    symbols: HashMap<String, GcPtr<Object>>,
+    /// hash-consed pairs keyed by their children, held weakly like `strings`
+    conses: HashMap<(ConsKey, ConsKey), GcPtr<Object>>,
+    /// objects that live as long as the VM: allocated pre-marked so the
+    /// marker skips them, never swept, and freed on drop
+    immortals: Vec<GcPtr<Object>>,
+    /// object kinds registered at runtime, indexed by `KindId`
+    kinds: Vec<Arc<ObjKind>>,
+    nil: GcPtr<Object>,
+    true_obj: GcPtr<Object>,
+    false_obj: GcPtr<Object>,
+    /// canonical objects for `SMALL_INT_MIN..=SMALL_INT_MAX`, if enabled
+    small_ints: Vec<GcPtr<Object>>,
+    /// call frames, innermost last, above a root frame that's always there
+    frames: Vec<Frame>,
+    /// global variables, which are roots: unlike the weak tables above, an
+    /// object bound here lives until it's rebound or the VM is dropped
+    globals: HashMap<String, GcPtr<Object>>,
+    /// host functions callable from VM code, by name
+    natives: HashMap<String, NativeFn>,
+    /// literals referenced by `Op::PushConst`, which are roots
+    constants: Vec<GcPtr<Object>>,
+    /// number of objects allocated over the VM's lifetime
+    allocations: usize,
+    /// number of identity hashes handed out, which seeds the next one
+    identity_hashes: u64,
+    /// keys of the identity maps handed out; rooted ones are roots and weak
+    /// ones are swept like `strings`
+    identity_keys: Vec<std::sync::Weak<std::sync::Mutex<Keys>>>,
+    /// whether the interpreter prints every instruction it runs
+    trace: bool,
+    /// code being run instruction by instruction by `Vm::step`
+    stepper: Option<Stepper>,
+    /// the log, if this VM records
+    recorder: Option<Recorder>,
+    /// objects handed out to foreign code by id, which are roots until their
+    /// handle is released
+    handles: HashMap<u64, GcPtr<Object>>,
+    /// id of the next handle, 0 never being one
+    next_handle: u64,
+    /// where dropped `SendHandle`s send their handle's id, and where the VM
+    /// picks them up to release them
+    release: std::sync::mpsc::Sender<u64>,
+    released: std::sync::mpsc::Receiver<u64>,
+    /// objects freed but not yet given back, see `allocation`
+    quarantine: Quarantine,
+    /// what objects are allocated from with `LayoutPolicy::Clustered`
+    chunks: Chunks,
+    /// whether collections shrink the VM, see `Vm::set_auto_shrink`
+    auto_shrink: bool,
+    /// fragmentation above which collections schedule a compaction, see
+    /// `Vm::set_auto_compact`
+    auto_compact: Option<f64>,
+    /// set by a collection that left the heap too fragmented, for the next
+    /// `Vm::gc` or `Vm::safepoint` to compact
+    compaction_due: bool,
+    /// where compactions moved objects to, by epoch, so handles to where
+    /// they were still find them, see `Vm::resolve`
+    forwarded: HashMap<u64, GcPtr<Object>>,
+    /// the relocation under way, see `Vm::start_relocation`
+    relocation: Option<relocate::Relocation>,
+    /// most threads a sweep is split over, see `Vm::set_sweep_threads`
+    sweep_threads: usize,
+    /// the thread finalizers run on, if `Vm::set_background_finalizers`
+    /// turned it on
+    finalizer: Option<Finalizer>,
+    /// most values allowed to wait on the finalizer thread, see
+    /// `Vm::set_finalizer_backlog`
+    finalizer_backlog: Option<usize>,
+    /// how `Vm::set_pressure_check` checks for memory pressure, and the
+    /// allocation count it last checked at
+    #[cfg(feature = "pressure")]
+    pressure_check: Option<(PressureCheck, usize)>,
+    /// set when memory pressure asked for the next collection, which then
+    /// also gives back what it can
+    #[cfg(feature = "pressure")]
+    pressured: bool,
+    /// the process-wide budget the VM counts towards, if it joined one
+    budget: Option<Membership>,
+    /// read-only segments mounted by `Vm::mount`, whose objects are never
+    /// marked or swept by this VM
+    segments: Vec<Segment>,
+    /// file `Vm::sync` writes the heap to, if the VM was made by `Vm::open`
+    #[cfg(feature = "std")]
+    backing: Option<std::path::PathBuf>,
+}
+
+impl Default for Vm {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Vm {
+    pub fn new() -> Self {
+        Self::with_config(VmConfig::default())
+    }
+
+    pub fn with_config(config: VmConfig) -> Self {
+        let nil = immortal(ObjType::Nil);
+        let true_obj = immortal(ObjType::Bool(true));
+        let false_obj = immortal(ObjType::Bool(false));
+        let small_ints: Vec<_> = if config.small_int_cache {
+            (SMALL_INT_MIN..=SMALL_INT_MAX)
+                .map(|i| immortal(ObjType::Int(i)))
+                .collect()
+        } else {
+            vec![]
+        };
+        let mut immortals = vec![nil.clone(), true_obj.clone(), false_obj.clone()];
+        immortals.extend(small_ints.iter().cloned());
+        let recorder = config.record.then(|| Recorder::new(&immortals));
+        let mut live = LiveSet::default();
+        live.extend(immortals.iter().cloned());
+        let stack_max = config.max_stack.unwrap_or(STACK_MAX);
+        let (release, released) = std::sync::mpsc::channel();
+        Self {
+            id: handle::next_vm_id(),
+            config,
+            stack: Stack::new(),
+            stack_size: 0,
+            stack_max,
+            stack_high_water: 0,
+            stack_warning: None,
+            heap: vec![],
+            live,
+            dead_writes: vec![],
+            num_objs: 0,
+            num_bytes: 0,
+            max_objs: INITIAL_GC_THRESHOLD,
+            strings: HashMap::new(),
+            symbols: HashMap::new(),
+            conses: HashMap::new(),
+            immortals,
+            kinds: vec![],
+            nil,
+            true_obj,
+            false_obj,
+            small_ints,
+            frames: vec![Frame::root()],
+            globals: HashMap::new(),
+            natives: HashMap::new(),
+            constants: vec![],
+            allocations: 0,
+            identity_hashes: 0,
+            identity_keys: vec![],
+            trace: false,
+            stepper: None,
+            recorder,
+            handles: HashMap::new(),
+            next_handle: 1,
+            release,
+            released,
+            quarantine: Quarantine::new(),
+            chunks: Chunks::new(),
+            auto_shrink: false,
+            auto_compact: None,
+            compaction_due: false,
+            forwarded: HashMap::new(),
+            relocation: None,
+            sweep_threads: 1,
+            finalizer: None,
+            finalizer_backlog: None,
+            #[cfg(feature = "pressure")]
+            pressure_check: None,
+            #[cfg(feature = "pressure")]
+            pressured: false,
+            budget: None,
+            segments: vec![],
+            #[cfg(feature = "std")]
+            backing: None,
+        }
+    }
+
+    /// Pushes `value`, allocating a new object for it unless it's a boolean
+    /// or nil, which always reuse their canonical instance (as do small ints
+    /// when the small-int cache is enabled).
+    ///
+    /// Panics if `value` refers to an object that isn't live.
+    pub fn push(&mut self, value: ObjType) {
+        assert!(self.stack_size < self.stack_max, "Stack overflow!");
+        match self.try_push(value) {
+            Ok(()) => {}
+            Err(GcError::OutOfMemory) => out_of_memory(),
+            Err(err) => panic!("{err}"),
+        }
+    }
+
+    /// Like [`Vm::push`], but fails with `GcError::StackOverflow` instead of
+    /// panicking when the stack is full, with `GcError::DeadObject` when
+    /// `value` refers to an object that isn't live, and with
+    /// `GcError::OutOfMemory` instead of aborting when the allocator gives
+    /// out. Arbitrary values can't be logged, so while recording only the
+    /// dedicated push operations work, and this fails with
+    /// `GcError::Unrecordable`.
+    pub fn try_push(&mut self, mut value: ObjType) -> Result<(), GcError> {
+        self.refuse_recording("push")?;
+        if self.stack_size >= self.stack_max {
+            return Err(GcError::StackOverflow);
+        }
+        copy::remap_children(&mut value, |child| {
+            if let Some(live) = self.resolve(child) {
+                *child = live;
+            }
+        });
+        self.check_children(&value)?;
+        // the slot is made first, so the push can't fail once the object is
+        // allocated
+        self.stack.try_reserve(self.stack_size)?;
+        let obj = match value {
+            ObjType::Nil => self.nil.clone(),
+            ObjType::Bool(true) => self.true_obj.clone(),
+            ObjType::Bool(false) => self.false_obj.clone(),
+            ObjType::Int(i) => match self.cached_int(i) {
+                Some(obj) => obj,
+                None => self.try_alloc(ObjType::Int(i))?,
+            },
+            value => self.try_alloc(value)?,
+        };
+        self.push_ptr(obj);
+        Ok(())
+    }
+
+    /// Returns the object the small-int cache holds for `i`, if it's enabled
+    /// and covers `i`.
+    fn cached_int(&self, i: i64) -> Option<GcPtr<Object>> {
+        let index = usize::try_from(i.checked_sub(SMALL_INT_MIN)?).ok()?;
+        self.small_ints.get(index).cloned()
+    }
+
+    /// Returns the cached object for `i` if there is one, and otherwise
+    /// allocates a new int.
+    fn alloc_int(&mut self, i: i64) -> GcPtr<Object> {
+        match self.cached_int(i) {
+            Some(obj) => obj,
+            None => self.alloc(ObjType::Int(i)),
+        }
+    }
+
+    fn alloc(&mut self, value: ObjType) -> GcPtr<Object> {
+        self.try_alloc(value).unwrap_or_else(|_| out_of_memory())
+    }
+
+    /// Like `alloc`, but fails with `GcError::OutOfMemory` instead of
+    /// aborting when the allocator gives out.
+    fn try_alloc(&mut self, value: ObjType) -> Result<GcPtr<Object>, GcError> {
+        // everything that can panic, including a custom kind's size function,
+        // runs before the object exists or after it's in the heap and counted,
+        // so a panic can't leak it or leave the counts off
+        let size = value.size();
+        self.heap.try_reserve(1).map_err(|_| GcError::OutOfMemory)?;
+        let object = Object::new(Header::default(), value);
+        let ptr = match self.config.layout {
+            LayoutPolicy::Individual => allocation::try_allocate(object),
+            LayoutPolicy::Clustered => {
+                let near: Vec<_> = match &object.value {
+                    ObjType::Pair(pair) => pair
+                        .head
+                        .iter()
+                        .chain(&pair.tail)
+                        .map(|obj| obj.0)
+                        .filter(|&ptr| !self.relocation.as_ref().is_some_and(|r| r.moves(ptr)))
+                        .collect(),
+                    _ => vec![],
+                };
+                self.chunks.try_allocate(object, &near)
+            }
+        };
+        let gc_ptr = GcPtr::from_live(ptr.ok_or(GcError::OutOfMemory)?);
+        self.heap.push(gc_ptr.clone());
+        self.live.insert(&gc_ptr);
+        self.num_objs += 1;
+        self.num_bytes += size;
+        self.allocations += 1;
+        if let Some(recorder) = &mut self.recorder {
+            recorder.track(&gc_ptr);
+        }
+        if let Some(relocation) = &mut self.relocation {
+            relocation.written(&gc_ptr);
+        }
+        Ok(gc_ptr)
+    }
+
+    /// Roots an already allocated object by pushing it onto the stack.
+    fn push_ptr(&mut self, obj: GcPtr<Object>) {
+        let value = self.to_value(obj);
+        self.push_slot(value);
+    }
+
+    /// Returns the value to store for `obj` in a stack slot. Nil and
+    /// booleans, whether this VM's singletons or a mounted segment's, are
+    /// stored as immediates, so a slot holding one of them always looks the
+    /// same.
+    fn to_value(&self, obj: GcPtr<Object>) -> Value {
+        match obj.value() {
+            ObjType::Nil => Value::NIL,
+            ObjType::Bool(b) => Value::bool(*b),
+            _ => Value::from_obj(obj),
+        }
+    }
+
+    /// Pushes a value as is. Immediates stay off the heap until something
+    /// needs them as an object. Panics if `value` is an object that isn't
+    /// live.
+    pub fn push_value(&mut self, value: Value) {
+        if let Some(addr) = value.addr() {
+            assert!(
+                self.live.at(addr).is_some(),
+                "{value:?} is not a live object of this VM"
+            );
+        }
+        self.recorded(
+            |recorder| match value.as_obj() {
+                Some(obj) => Event::PushObject(recorder.id(&obj)),
+                None => Event::PushValue(value.to_bits()),
+            },
+            |vm| vm.push_slot(value),
+        );
+    }
+
+    /// Pushes a value taken from this VM, which needs no checking.
+    pub(crate) fn push_slot(&mut self, value: Value) {
+        assert!(self.stack_size < self.stack_max, "Stack overflow!");
+        self.stack.reserve(self.stack_size);
+        self.stack[self.stack_size] = value;
+        self.stack_size += 1;
+        if self.stack_size > self.stack_high_water {
+            self.stack_high_water = self.stack_size;
+        }
+        if let Some((depth, warn)) = self.stack_warning {
+            if self.stack_size == depth {
+                warn(depth, self.stack_max);
+            }
+        }
+    }
+
+    /// Returns the deepest the stack has been since the VM was created or
+    /// last reset.
+    pub fn stack_high_water(&self) -> usize {
+        self.stack_high_water
+    }
+
+    /// Calls `warn` whenever a push takes the stack to `fraction` of its
+    /// limit, so runaway recursion can be noticed before pushes start
+    /// failing. Replaces any previous hook; `None` removes it.
+    pub fn set_stack_warning(&mut self, fraction: f64, warn: Option<StackWarning>) {
+        let depth = ((self.stack_max as f64 * fraction).ceil() as usize).max(1);
+        self.stack_warning = warn.map(|warn| (depth, warn));
+    }
+
+    /// Keeps swept objects poisoned in quarantine for `collections` more
+    /// collections before giving their memory back, in any build, and checks
+    /// every access through a handle for them, so a handle that outlived its
+    /// object fails at its next use, naming the collection that freed it,
+    /// instead of reading memory that may have been reused. `None` goes back
+    /// to the default of quarantining only briefly, and only in debug builds.
+    pub fn set_quarantine(&mut self, collections: Option<usize>) {
+        self.quarantine.keep_for(collections, &mut self.chunks);
+    }
+
+    /// Number of freed objects in quarantine, whose memory hasn't been given
+    /// back yet.
+    pub fn quarantined(&self) -> usize {
+        self.quarantine.len()
+    }
+
+    /// Pops the top of the stack, allocating an object for it if it's an
+    /// immediate.
+    pub fn pop(&mut self) -> GcPtr<Object> {
+        self.recorded(
+            |_| Event::Pop,
+            |vm| {
+                let value = vm.pop_value();
+                vm.box_value(value)
+            },
+        )
+    }
+
+    /// Like [`Vm::pop`], but fails with `GcError::StackUnderflow` instead of
+    /// panicking when the stack is empty.
+    pub fn try_pop(&mut self) -> Result<GcPtr<Object>, GcError> {
+        if self.stack_size == 0 {
+            return Err(GcError::StackUnderflow);
+        }
+        Ok(self.pop())
+    }
+
+    /// Pops the top of the stack without boxing immediates.
+    pub fn pop_value(&mut self) -> Value {
+        assert!(self.stack_size >= 1, "Stack underflow!");
+        self.recorded(
+            |_| Event::Drop,
+            |vm| {
+                vm.stack_size -= 1;
+                std::mem::replace(&mut vm.stack[vm.stack_size], Value::NIL)
+            },
+        )
+    }
+
+    /// Returns the object `value` stands for, allocating one for immediates.
+    fn box_value(&mut self, value: Value) -> GcPtr<Object> {
+        if let Some(obj) = value.as_obj() {
+            obj
+        } else if value.is_nil() {
+            self.nil.clone()
+        } else if let Some(b) = value.as_bool() {
+            if b {
+                self.true_obj.clone()
+            } else {
+                self.false_obj.clone()
+            }
+        } else if let Some(i) = value.as_int() {
+            self.alloc_int(i)
+        } else {
+            self.alloc(ObjType::Float(value.as_float().unwrap()))
+        }
+    }
+
+    pub fn push_nil(&mut self) {
+        self.recorded(|_| Event::PushNil, |vm| vm.push(ObjType::Nil));
+    }
+
+    pub fn push_bool(&mut self, value: bool) {
+        self.recorded(
+            |_| Event::PushBool(value),
+            |vm| vm.push(ObjType::Bool(value)),
+        );
+    }
+
+    pub fn push_int(&mut self, value: i64) {
+        self.recorded(
+            |_| Event::PushInt(value),
+            |vm| match Value::int(value) {
+                Some(immediate) if vm.config.unboxed_ints => vm.push_slot(immediate),
+                _ => vm.push(ObjType::Int(value)),
+            },
+        );
+    }
+
+    /// Pushes every int in `values`, the first one deepest, checking up
+    /// front that they all fit, growing the stack once and writing the slots
+    /// a segment at a time. While recording, each push is logged as a
+    /// [`Vm::push_int`] of its own.
+    pub fn push_ints(&mut self, values: &[i64]) {
+        assert!(
+            values.len() <= self.stack_max - self.stack_size,
+            "Stack overflow!"
+        );
+        if self.recording() {
+            for &value in values {
+                self.push_int(value);
+            }
+            return;
+        }
+        let (base, top) = (self.stack_size, self.stack_size + values.len());
+        if top > base {
+            self.stack.reserve(top - 1);
+        }
+        // nothing collects until the slots are written, so the ints
+        // allocated meanwhile needn't be rooted yet
+        let slots: Vec<_> = values
+            .iter()
+            .map(|&value| match Value::int(value) {
+                Some(immediate) if self.config.unboxed_ints => immediate,
+                _ => {
+                    let obj = self.alloc_int(value);
+                    self.to_value(obj)
+                }
+            })
+            .collect();
+        for (slot, value) in self.stack.slots_mut(base..top).zip(slots) {
+            *slot = value;
+        }
+        self.stack_size = top;
+        self.stack_high_water = self.stack_high_water.max(top);
+        if let Some((depth, warn)) = self.stack_warning {
+            if (base + 1..=top).contains(&depth) {
+                warn(depth, self.stack_max);
+            }
+        }
+    }
+
+    /// Pops the top `count` values, returning their objects deepest first.
+    /// The slots are read a segment at a time once the count is checked.
+    /// While recording, each pop is logged as a [`Vm::pop`] of its own.
+    pub fn pop_n(&mut self, count: usize) -> Vec<GcPtr<Object>> {
+        assert!(count <= self.stack_size, "Stack underflow!");
+        if self.recording() {
+            let mut objs: Vec<_> = (0..count).map(|_| self.pop()).collect();
+            objs.reverse();
+            return objs;
+        }
+        let base = self.stack_size - count;
+        let values: Vec<_> = self
+            .stack
+            .slots_mut(base..self.stack_size)
+            .map(|slot| std::mem::replace(slot, Value::NIL))
+            .collect();
+        self.stack_size = base;
+        values
+            .into_iter()
+            .map(|value| self.box_value(value))
+            .collect()
+    }
+
+    pub fn push_float(&mut self, value: f64) {
+        self.recorded(
+            |_| Event::PushFloat(value),
+            |vm| vm.push(ObjType::Float(value)),
+        );
+    }
+
+    /// Pops a head and then a tail and pushes a pair of them, so the head is
+    /// the value pushed last: `( tail head -- pair )`. This is the shape
+    /// stack-machine code wants; to build a pair from handles you already
+    /// hold use [`Vm::push_pair_with`].
+    pub fn push_pair(&mut self) {
+        self.recorded(
+            |_| Event::PushPair,
+            |vm| {
+                let head = Some(vm.pop());
+                let tail = Some(vm.pop());
+                vm.push(ObjType::Pair(Pair { head, tail }));
+            },
+        );
+    }
+
+    /// Pops the pair on top of the stack and pushes its tail and then its
+    /// head, undoing [`Vm::push_pair`]: `( pair -- tail head )`. The returned
+    /// `(head, tail)` handles are rooted by those slots. Errors without
+    /// popping if the top isn't a pair.
+    pub fn pop_pair(&mut self) -> Result<(GcPtr<Object>, GcPtr<Object>), GcError> {
+        self.check_pair()?;
+        let (head, tail) = match self.stack[self.stack_size - 1].as_obj().unwrap().value() {
+            ObjType::Pair(pair) => (pair.head.clone(), pair.tail.clone()),
+            _ => unreachable!(),
+        };
+        let head = head.unwrap_or_else(|| self.nil.clone());
+        let tail = tail.unwrap_or_else(|| self.nil.clone());
+        self.recorded(
+            |_| Event::PopPair,
+            |vm| {
+                vm.pop_value();
+                vm.push_ptr(tail.clone());
+                vm.push_ptr(head.clone());
+            },
+        );
+        Ok((head, tail))
+    }
+
+    /// Replaces the pair on top of the stack with its head:
+    /// `( pair -- head )`.
+    pub fn car(&mut self) -> Result<(), GcError> {
+        self.pop_pair()?;
+        self.swap();
+        self.drop_top();
+        Ok(())
+    }
+
+    /// Replaces the pair on top of the stack with its tail:
+    /// `( pair -- tail )`.
+    pub fn cdr(&mut self) -> Result<(), GcError> {
+        self.pop_pair()?;
+        self.drop_top();
+        Ok(())
+    }
+
+    /// Pushes the head of the pair on top of the stack, keeping the pair:
+    /// `( pair -- pair head )`.
+    pub fn push_head(&mut self) -> Result<(), GcError> {
+        self.check_pair()?;
+        self.dup();
+        self.car()
+    }
+
+    /// Pushes the tail of the pair on top of the stack, keeping the pair:
+    /// `( pair -- pair tail )`.
+    pub fn push_tail(&mut self) -> Result<(), GcError> {
+        self.check_pair()?;
+        self.dup();
+        self.cdr()
+    }
+
+    fn check_pair(&self) -> Result<(), GcError> {
+        if self.stack_size == 0 {
+            return Err(GcError::StackUnderflow);
+        }
+        let top = self.stack[self.stack_size - 1];
+        match top.as_obj().as_ref().map(GcPtr::value) {
+            Some(ObjType::Pair(_)) => Ok(()),
+            _ => Err(GcError::TypeMismatch {
+                expected: "pair",
+                found: top.type_name(),
+            }),
+        }
+    }
+
+    /// Pushes a list of `items`, built out of pairs whose heads are the items
+    /// and whose tails are the rest of the list, ending in nil. Returns a
+    /// handle to the list, which is also the object pushed.
+    pub fn push_list(&mut self, items: impl IntoIterator<Item = i64>) -> GcPtr<Object> {
+        let items: Vec<_> = items.into_iter().collect();
+        self.push_nil();
+        for &item in items.iter().rev() {
+            self.push_int(item);
+            self.push_pair();
+        }
+        self.peek(0)
+    }
+
+    /// Like [`Vm::push_list`] for existing objects, which must be live until
+    /// this returns. The list under construction stays on the stack, so it's
+    /// rooted throughout.
+    pub fn push_list_of(
+        &mut self,
+        items: impl IntoIterator<Item = GcPtr<Object>>,
+    ) -> GcPtr<Object> {
+        let items: Vec<_> = items.into_iter().collect();
+        self.push_nil();
+        let mut list = self.peek(0);
+        for item in items.into_iter().rev() {
+            list = self.push_pair_with(item, list);
+            self.swap();
+            self.drop_top();
+        }
+        list
+    }
+
+    /// Whether `obj` is an object of this VM that hasn't been collected.
+    ///
+    /// A handle on its own doesn't keep its object alive, so one held across
+    /// a collection may point at freed memory. Every API taking a handle
+    /// checks it with this before touching the object: the fallible ones
+    /// fail with `GcError::DeadObject`, and the rest panic. A handle to an
+    /// object [`Vm::compact`] has moved since is live, see [`Vm::resolve`].
+    pub fn is_live(&self, obj: &GcPtr<Object>) -> bool {
+        self.resolve(obj).is_some()
+    }
+
+    /// The live object `value` refers to, if it refers to one. A value is
+    /// only an address, so unlike a handle, one kept across a collection can
+    /// come to refer to whatever is allocated there next.
+    pub fn value_obj(&self, value: Value) -> Option<GcPtr<Object>> {
+        self.live.at(value.addr()?)
+    }
+
+    /// Resolves `obj`, failing with `GcError::DeadObject` if it isn't live.
+    fn check_live(&self, obj: &GcPtr<Object>) -> Result<GcPtr<Object>, GcError> {
+        self.resolve(obj).ok_or(GcError::DeadObject)
+    }
+
+    /// Resolves `obj`, panicking if it isn't live.
+    pub(crate) fn assert_live(&self, obj: &GcPtr<Object>) -> GcPtr<Object> {
+        self.resolve(obj)
+            .unwrap_or_else(|| panic!("{:?} is not a live object of this VM", obj.0))
+    }
+
+    /// Fails unless every object `value` refers to is live, so a dead handle
+    /// never makes it into the heap.
+    fn check_children(&self, value: &ObjType) -> Result<(), GcError> {
+        struct Check<'a> {
+            live: &'a LiveSet,
+            all_live: bool,
+        }
+        impl Visitor for Check<'_> {
+            fn visit(&mut self, obj: &GcPtr<Object>) {
+                self.all_live &= self.live.contains(obj);
+            }
+        }
+
+        let mut check = Check {
+            live: &self.live,
+            all_live: true,
+        };
+        value.trace(&mut check);
+        if !check.all_live {
+            return Err(GcError::DeadObject);
+        }
+        Ok(())
+    }
+
+    /// Borrows the value of `obj`. The borrow holds on to the VM, so nothing
+    /// can collect (which takes `&mut Vm`) while it's alive. Panics if `obj`
+    /// isn't live.
+    pub fn get(&self, obj: &GcPtr<Object>) -> &ObjType {
+        let obj = self.assert_live(obj);
+        unsafe { &(*obj.0.as_ptr()).value }
+    }
+
+    /// Mutably borrows the value of `obj`, failing if it's frozen, as the
+    /// shared nil, bools and small ints, interned strings and symbols always
+    /// are, not live, or not a `T`. Like [`Vm::get`], the borrow keeps the VM
+    /// from collecting meanwhile. Values that refer to other objects are
+    /// changed through the dedicated mutation APIs instead, which check
+    /// what they're given.
+    ///
+    /// Writes through this borrow can't be logged, so while recording this
+    /// fails with `GcError::Unrecordable`; the dedicated mutation APIs work
+    /// either way.
+    pub fn get_mut<T: Plain>(&mut self, obj: &GcPtr<Object>) -> Result<ObjMut<'_, T>, GcError> {
+        self.refuse_recording("get_mut")?;
+        let obj = &self.check_live(obj)?;
+        self.check_writes(obj)?;
+        obj.ensure_mutable()?;
+        let found = obj.value().type_name();
+        let value = T::plain_mut(&mut self.object_mut(obj).value).ok_or(GcError::TypeMismatch {
+            expected: T::NAME,
+            found,
+        })?;
+        let value = NonNull::from(value);
+        Ok(ObjMut::new(self, obj, value))
+    }
+
+    /// Fails with `GcError::DeadObject` while a borrow has left an object
+    /// other than `obj` referring to one that isn't live. Borrowing that
+    /// object itself still works, so the write can be put right.
+    fn check_writes(&mut self, obj: &GcPtr<Object>) -> Result<(), GcError> {
+        self.settle_writes();
+        if self.dead_writes.iter().any(|written| written != obj) {
+            return Err(GcError::DeadObject);
+        }
+        Ok(())
+    }
+
+    /// Forgets the objects borrows left referring to dead ones that have
+    /// since been put right, returning whether none are left.
+    fn settle_writes(&mut self) -> bool {
+        let mut dead_writes = std::mem::take(&mut self.dead_writes);
+        dead_writes.retain(|obj| self.check_children(obj.value()).is_err());
+        self.dead_writes = dead_writes;
+        self.dead_writes.is_empty()
+    }
+
+    /// The only way to write to a heap object: every mutation, public or
+    /// internal, goes through here, and handles on their own are read-only.
+    /// That makes this the write barrier: a relocation under way notes the
+    /// object, which may be about to refer to one it hasn't moved yet.
+    fn object_mut(&mut self, obj: &GcPtr<Object>) -> &mut Object {
+        if let Some(relocation) = &mut self.relocation {
+            relocation.written(obj);
+        }
+        unsafe { &mut *obj.0.as_ptr() }
+    }
+
+    /// Iterates over the heads of the list `list`, following tails until one
+    /// isn't a pair. A pair without a head yields nil. A cyclic list ends
+    /// once it comes back around, after yielding every head in the cycle once.
+    pub fn iter_list(&self, list: &GcPtr<Object>) -> ListIter<'_> {
+        let list = &self.assert_live(list);
+        ListIter {
+            vm: self,
+            next: Some(list.clone()),
+            seen: HashSet::new(),
+        }
+    }
+
+    /// Makes `value` the head of `pair`. Both must be live; from then on the
+    /// pair keeps `value` alive. Errors if `pair` isn't a pair or is frozen.
+    pub fn set_head(&mut self, pair: &GcPtr<Object>, value: GcPtr<Object>) -> Result<(), GcError> {
+        self.set_pair_field(pair, value, true)
+    }
+
+    /// Makes `value` the tail of `pair`, like [`Vm::set_head`].
+    pub fn set_tail(&mut self, pair: &GcPtr<Object>, value: GcPtr<Object>) -> Result<(), GcError> {
+        self.set_pair_field(pair, value, false)
+    }
+
+    /// Every pair mutation goes through here. Collections only happen
+    /// between operations and always trace the whole heap, so for now the
+    /// new edge needs no write barrier.
+    fn set_pair_field(
+        &mut self,
+        pair: &GcPtr<Object>,
+        value: GcPtr<Object>,
+        head: bool,
+    ) -> Result<(), GcError> {
+        let value = self.check_live(&value)?;
+        let pair = &self.check_live(pair)?;
+        if !matches!(pair.value(), ObjType::Pair(_)) {
+            return Err(GcError::TypeMismatch {
+                expected: "pair",
+                found: pair.value().type_name(),
+            });
+        }
+        pair.ensure_mutable()?;
+        let value_ref = value.clone();
+        self.recorded(
+            |recorder| {
+                let (pair, value) = (recorder.id(pair), recorder.id(&value_ref));
+                if head {
+                    Event::SetHead { pair, value }
+                } else {
+                    Event::SetTail { pair, value }
+                }
+            },
+            |vm| {
+                if let ObjType::Pair(p) = &mut vm.object_mut(pair).value {
+                    if head {
+                        p.head = Some(value);
+                    } else {
+                        p.tail = Some(value);
+                    }
+                }
+            },
+        );
+        Ok(())
+    }
+
+    /// Pushes a new pair of `head` and `tail` and returns a handle to it,
+    /// leaving the rest of the stack alone. Both must be live objects, which
+    /// the pair keeps alive from then on.
+    pub fn push_pair_with(&mut self, head: GcPtr<Object>, tail: GcPtr<Object>) -> GcPtr<Object> {
+        let head = self.assert_live(&head);
+        let tail = self.assert_live(&tail);
+        self.recorded(
+            |recorder| Event::PushPairWith {
+                head: recorder.id(&head),
+                tail: recorder.id(&tail),
+            },
+            |vm| {
+                let pair = vm.alloc(ObjType::Pair(Pair {
+                    head: Some(head.clone()),
+                    tail: Some(tail.clone()),
+                }));
+                vm.push_ptr(pair.clone());
+                pair
+            },
+        )
+    }
+
+    /// Like [`Vm::push_pair`], but pushes the existing pair if one with the
+    /// same head and tail was already built this way. Children are the same
+    /// when they're the same object, or numbers of equal value, so building
+    /// identical trees bottom-up shares every subtree.
+    ///
+    /// Hash-consed pairs are shared by everyone who built them, so they're
+    /// frozen.
+    pub fn push_pair_hashconsed(&mut self) {
+        assert!(self.stack_size >= 2, "Stack underflow!");
+        self.recorded(
+            |_| Event::PushPairHashconsed,
+            |vm| {
+                let head = vm.peek(0);
+                let tail = vm.peek(1);
+                let key = (ConsKey::new(&head), ConsKey::new(&tail));
+                let pair = match vm.conses.get(&key) {
+                    Some(pair) => pair.clone(),
+                    None => {
+                        let pair = vm.alloc(ObjType::Pair(Pair {
+                            head: Some(head),
+                            tail: Some(tail),
+                        }));
+                        vm.freeze(&pair);
+                        vm.conses.insert(key, pair.clone());
+                        pair
+                    }
+                };
+                vm.pop();
+                vm.pop();
+                vm.push_ptr(pair);
+            },
+        );
+    }
+
+    /// Makes `obj` immutable: from now on, mutation APIs called on it fail
+    /// with `GcError::FrozenObject`. There is no way to unfreeze an object.
+    pub fn freeze(&mut self, obj: &GcPtr<Object>) {
+        let obj = &self.assert_live(obj);
+        self.recorded(
+            |recorder| Event::Freeze(recorder.id(obj)),
+            |vm| {
+                // objects of mounted segments are frozen already, and other
+                // threads may be reading them
+                if !obj.is_frozen() {
+                    vm.object_mut(obj).header.set_frozen(true);
+                }
+            },
+        );
+    }
+
+    /// Freezes `obj` and everything reachable from it. Panics if `obj` isn't
+    /// live, or while a borrow has left an object referring to one that
+    /// isn't, see [`ObjMut`].
+    pub fn freeze_deep(&mut self, obj: &GcPtr<Object>) {
+        struct Pending(Vec<GcPtr<Object>>);
+        impl Visitor for Pending {
+            fn visit(&mut self, obj: &GcPtr<Object>) {
+                if !obj.is_frozen() {
+                    self.0.push(obj.clone());
+                }
+            }
+        }
+
+        let obj = &self.assert_live(obj);
+        assert!(
+            self.settle_writes(),
+            "An object refers to one that isn't live"
+        );
+        let mut pending = Pending(vec![obj.clone()]);
+        while let Some(obj) = pending.0.pop() {
+            self.freeze(&obj);
+            obj.value().trace(&mut pending);
+        }
+    }
+
+    pub fn is_frozen(&self, obj: &GcPtr<Object>) -> bool {
+        let obj = &self.assert_live(obj);
+        obj.is_frozen()
+    }
+
+    /// Attaches an embedder-defined tag to `obj`'s header, replacing the
+    /// previous one. Tags start out as 0, mean nothing to the VM, and can be
+    /// set even on frozen objects, though not on a mounted segment's.
+    pub fn set_user_tag(&mut self, obj: &GcPtr<Object>, tag: u32) {
+        let obj = &self.assert_live(obj);
+        assert!(
+            !self.is_shared(obj),
+            "{:?} is in a read-only segment",
+            obj.0
+        );
+        self.recorded(
+            |recorder| Event::SetUserTag {
+                obj: recorder.id(obj),
+                tag,
+            },
+            |vm| vm.object_mut(obj).header.set_user_tag(tag),
+        );
+    }
+
+    pub fn user_tag(&self, obj: &GcPtr<Object>) -> u32 {
+        let obj = &self.assert_live(obj);
+        obj.user_tag()
+    }
+
+    /// Returns a hash of `obj`'s identity, for tables keyed by object. It's
+    /// picked the first time it's asked for and stored in the header, so
+    /// unlike the object's address it stays the same if a collector ever
+    /// moves the object. Distinct live objects get distinct hashes.
+    pub fn identity_hash(&mut self, obj: &GcPtr<Object>) -> u64 {
+        let obj = &self.assert_live(obj);
+        if let Some(hash) = obj.identity_hash() {
+            return hash;
+        }
+        self.recorded(
+            |recorder| Event::IdentityHash(recorder.id(obj)),
+            |vm| {
+                vm.identity_hashes += 1;
+                let hash = mix_hash(vm.identity_hashes);
+                vm.object_mut(obj).set_identity_hash(hash);
+                hash
+            },
+        )
+    }
+
+    /// Pops the top `len` values into a new array and pushes it. The deepest
+    /// of them becomes element 0.
+    pub fn push_array(&mut self, len: usize) {
+        self.recorded(
+            |_| Event::PushArray(len),
+            |vm| {
+                let elems = vm.peek_top(len);
+                let array = vm.alloc(ObjType::Array(elems));
+                for _ in 0..len {
+                    vm.pop();
+                }
+                vm.push_ptr(array);
+            },
+        );
+    }
+
+    pub fn array_get(&self, array: &GcPtr<Object>, index: usize) -> Result<GcPtr<Object>, GcError> {
+        let array = &self.check_live(array)?;
+        let elems = as_array(array)?;
+        elems.get(index).cloned().ok_or(GcError::IndexOutOfBounds {
+            index,
+            len: elems.len(),
+        })
+    }
+
+    pub fn array_set(
+        &mut self,
+        array: &GcPtr<Object>,
+        index: usize,
+        value: GcPtr<Object>,
+    ) -> Result<(), GcError> {
+        let array = &self.check_live(array)?;
+        let value = self.check_live(&value)?;
+        let len = as_array(array)?.len();
+        array.ensure_mutable()?;
+        if index >= len {
+            return Err(GcError::IndexOutOfBounds { index, len });
+        }
+        let value_ref = value.clone();
+        self.recorded(
+            |recorder| Event::ArraySet {
+                array: recorder.id(array),
+                index,
+                value: recorder.id(&value_ref),
+            },
+            |vm| {
+                if let ObjType::Array(elems) = &mut vm.object_mut(array).value {
+                    elems[index] = value;
+                }
+            },
+        );
+        Ok(())
+    }
+
+    /// Pops the top `num_upvalues` values and pushes a closure over `code_id`
+    /// capturing them, the deepest becoming upvalue 0.
+    pub fn push_closure(&mut self, code_id: usize, num_upvalues: usize) {
+        assert!(num_upvalues <= self.stack_size, "Stack underflow!");
+        self.recorded(
+            |_| Event::PushClosure {
+                code_id,
+                upvalues: num_upvalues,
+            },
+            |vm| {
+                let upvalues = vm.peek_top(num_upvalues);
+                let closure = vm.alloc(ObjType::Closure(Closure { code_id, upvalues }));
+                for _ in 0..num_upvalues {
+                    vm.pop();
+                }
+                vm.push_ptr(closure);
+            },
+        );
+    }
+
+    pub fn closure_code_id(&self, closure: &GcPtr<Object>) -> Result<usize, GcError> {
+        let closure = &self.check_live(closure)?;
+        Ok(as_closure(closure)?.code_id)
+    }
+
+    pub fn closure_upvalue(
+        &self,
+        closure: &GcPtr<Object>,
+        index: usize,
+    ) -> Result<GcPtr<Object>, GcError> {
+        let closure = &self.check_live(closure)?;
+        let upvalues = &as_closure(closure)?.upvalues;
+        upvalues
+            .get(index)
+            .cloned()
+            .ok_or(GcError::IndexOutOfBounds {
+                index,
+                len: upvalues.len(),
+            })
+    }
+
+    /// Pushes a foreign object. Its payload can't be logged, so this panics
+    /// if the VM records.
+    pub fn push_foreign<T: ForeignObject>(&mut self, value: T) {
+        assert!(!self.recording(), "`push_foreign` can't be recorded");
+        self.push(ObjType::Foreign(Box::new(value)));
+    }
+
+    /// Borrows the native value of a foreign object, if it's a `T`.
+    pub fn foreign_ref<T: ForeignObject>(&self, obj: &GcPtr<Object>) -> Option<&T> {
+        match self.get(obj) {
+            ObjType::Foreign(foreign) => (&**foreign as &dyn Any).downcast_ref(),
+            _ => None,
+        }
+    }
+
+    /// Mutably borrows the native value of a foreign object, failing if it's
+    /// not live or not a `T`. See [`ObjMut`] for what happens if the value is
+    /// left referring to an object that isn't live.
+    pub fn foreign_mut<T: ForeignObject>(
+        &mut self,
+        obj: &GcPtr<Object>,
+    ) -> Result<ObjMut<'_, T>, GcError> {
+        let obj = &self.check_live(obj)?;
+        self.check_writes(obj)?;
+        let found = obj.value().type_name();
+        let value = match &mut self.object_mut(obj).value {
+            ObjType::Foreign(foreign) => (&mut **foreign as &mut dyn Any).downcast_mut(),
+            _ => None,
+        };
+        let value = value.ok_or(GcError::TypeMismatch {
+            expected: std::any::type_name::<T>(),
+            found,
+        })?;
+        let value = NonNull::from(value);
+        Ok(ObjMut::new(self, obj, value))
+    }
+
+    /// Registers a new object kind, returning the tag to allocate it with.
+    pub fn register_kind(&mut self, kind: ObjKind) -> KindId {
+        self.kinds.push(Arc::new(kind));
+        KindId(self.kinds.len() as u32 - 1)
+    }
+
+    /// Pushes an object of a registered kind holding `data`, which moves
+    /// with the VM like a foreign object does. Fails with
+    /// `GcError::Unrecordable` if the VM records.
+    pub fn push_custom(&mut self, tag: KindId, data: Box<dyn Any + Send>) -> Result<(), GcError> {
+        self.refuse_recording("push_custom")?;
+        let kind = self
+            .kinds
+            .get(tag.0 as usize)
+            .ok_or(GcError::UnknownKind(tag))?
+            .clone();
+        self.push(ObjType::Custom(Custom { tag, kind, data }));
+        Ok(())
+    }
+
+    pub fn custom_kind(&self, obj: &GcPtr<Object>) -> Option<KindId> {
+        match self.get(obj) {
+            ObjType::Custom(custom) => Some(custom.tag),
+            _ => None,
+        }
+    }
+
+    /// Borrows the payload of a custom object, if it's a `T`.
+    pub fn custom_ref<T: Any>(&self, obj: &GcPtr<Object>) -> Option<&T> {
+        match self.get(obj) {
+            ObjType::Custom(custom) => custom.data.downcast_ref(),
+            _ => None,
+        }
+    }
+
+    /// Mutably borrows the payload of a custom object, like
+    /// [`Vm::foreign_mut`].
+    pub fn custom_mut<T: Any>(&mut self, obj: &GcPtr<Object>) -> Result<ObjMut<'_, T>, GcError> {
+        let obj = &self.check_live(obj)?;
+        self.check_writes(obj)?;
+        let found = obj.value().type_name();
+        let value = match &mut self.object_mut(obj).value {
+            ObjType::Custom(custom) => custom.data.downcast_mut(),
+            _ => None,
+        };
+        let value = value.ok_or(GcError::TypeMismatch {
+            expected: std::any::type_name::<T>(),
+            found,
+        })?;
+        let value = NonNull::from(value);
+        Ok(ObjMut::new(self, obj, value))
+    }
+
+    /// Pushes a new, empty map.
+    pub fn map_new(&mut self) {
+        self.recorded(
+            |_| Event::MapNew,
+            |vm| vm.push(ObjType::Map(Map::default())),
+        );
+    }
+
+    pub fn map_get(
+        &self,
+        map: &GcPtr<Object>,
+        key: &GcPtr<Object>,
+    ) -> Result<Option<GcPtr<Object>>, GcError> {
+        let map = &self.check_live(map)?;
+        let key = &self.check_live(key)?;
+        let key = MapKey::from_obj(key)?;
+        Ok(as_map(map)?.entries.get(&key).cloned())
+    }
+
+    /// Associates `value` with `key`, returning the value it replaces.
+    pub fn map_set(
+        &mut self,
+        map: &GcPtr<Object>,
+        key: &GcPtr<Object>,
+        value: GcPtr<Object>,
+    ) -> Result<Option<GcPtr<Object>>, GcError> {
+        let map = &self.check_live(map)?;
+        let key = &self.check_live(key)?;
+        let value = self.check_live(&value)?;
+        let map_key = MapKey::from_obj(key)?;
+        as_map(map)?;
+        map.ensure_mutable()?;
+        let value_ref = value.clone();
+        let old = self.recorded(
+            |recorder| Event::MapSet {
+                map: recorder.id(map),
+                key: recorder.id(key),
+                value: recorder.id(&value_ref),
+            },
+            |vm| {
+                let before = map.size();
+                let old = match &mut vm.object_mut(map).value {
+                    ObjType::Map(m) => m.entries.insert(map_key, value),
+                    _ => unreachable!(),
+                };
+                vm.num_bytes = vm.num_bytes - before + map.size();
+                old
+            },
+        );
+        Ok(old)
+    }
+
+    pub fn map_remove(
+        &mut self,
+        map: &GcPtr<Object>,
+        key: &GcPtr<Object>,
+    ) -> Result<Option<GcPtr<Object>>, GcError> {
+        let map = &self.check_live(map)?;
+        let key = &self.check_live(key)?;
+        let map_key = MapKey::from_obj(key)?;
+        as_map(map)?;
+        map.ensure_mutable()?;
+        Ok(self.recorded(
+            |recorder| Event::MapRemove {
+                map: recorder.id(map),
+                key: recorder.id(key),
+            },
+            |vm| match &mut vm.object_mut(map).value {
+                ObjType::Map(m) => m.entries.remove(&map_key),
+                _ => unreachable!(),
+            },
+        ))
+    }
+
+    pub fn push_bytes(&mut self, value: &[u8]) {
+        self.recorded(
+            |_| Event::PushBytes(value.to_vec()),
+            |vm| vm.push(ObjType::Bytes(value.to_vec())),
+        );
+    }
+
+    pub fn bytes_get(&self, bytes: &GcPtr<Object>, index: usize) -> Result<u8, GcError> {
+        let bytes = &self.check_live(bytes)?;
+        let data = as_bytes(bytes)?;
+        data.get(index).copied().ok_or(GcError::IndexOutOfBounds {
+            index,
+            len: data.len(),
+        })
+    }
+
+    pub fn bytes_set(
+        &mut self,
+        bytes: &GcPtr<Object>,
+        index: usize,
+        value: u8,
+    ) -> Result<(), GcError> {
+        let bytes = &self.check_live(bytes)?;
+        let len = as_bytes(bytes)?.len();
+        bytes.ensure_mutable()?;
+        if index >= len {
+            return Err(GcError::IndexOutOfBounds { index, len });
+        }
+        self.recorded(
+            |recorder| Event::BytesSet {
+                bytes: recorder.id(bytes),
+                index,
+                value,
+            },
+            |vm| {
+                if let ObjType::Bytes(data) = &mut vm.object_mut(bytes).value {
+                    data[index] = value;
+                }
+            },
+        );
+        Ok(())
+    }
+
+    /// Pushes a new byte buffer holding a copy of `range` of `bytes`.
+    pub fn bytes_slice(
+        &mut self,
+        bytes: &GcPtr<Object>,
+        range: Range<usize>,
+    ) -> Result<(), GcError> {
+        let bytes = &self.check_live(bytes)?;
+        let data = as_bytes(bytes)?;
+        let slice = data.get(range.clone()).ok_or(GcError::IndexOutOfBounds {
+            index: range.end.max(range.start),
+            len: data.len(),
+        })?;
+        let slice = slice.to_vec();
+        self.recorded(
+            |recorder| Event::BytesSlice {
+                bytes: recorder.id(bytes),
+                start: range.start,
+                end: range.end,
+            },
+            |vm| vm.push(ObjType::Bytes(slice)),
+        );
+        Ok(())
+    }
+
+    /// Appends `extra` to the end of `bytes`, growing it in place.
+    pub fn bytes_extend(&mut self, bytes: &GcPtr<Object>, extra: &[u8]) -> Result<(), GcError> {
+        let bytes = &self.check_live(bytes)?;
+        as_bytes(bytes)?;
+        bytes.ensure_mutable()?;
+        self.recorded(
+            |recorder| Event::BytesExtend {
+                bytes: recorder.id(bytes),
+                extra: extra.to_vec(),
+            },
+            |vm| {
+                let before = bytes.size();
+                if let ObjType::Bytes(data) = &mut vm.object_mut(bytes).value {
+                    data.extend_from_slice(extra);
+                }
+                vm.num_bytes = vm.num_bytes - before + bytes.size();
+            },
+        );
+        Ok(())
+    }
+
+    pub fn push_str(&mut self, value: &str) {
+        self.recorded(
+            |_| Event::PushStr(value.to_owned()),
+            |vm| vm.push(ObjType::Str(value.to_owned())),
+        );
+    }
+
+    /// Concatenates the two strings on top of the stack, the topmost going
+    /// last. Instead of copying, this allocates a rope node pointing at both.
+    pub fn concat(&mut self) -> Result<(), GcError> {
+        self.check_string(0)?;
+        self.check_string(1)?;
+        self.recorded(
+            |_| Event::Concat,
+            |vm| {
+                let right = vm.peek(0);
+                let left = vm.peek(1);
+                let len = string_len(&left) + string_len(&right);
+                let rope = vm.alloc(ObjType::Rope(Rope { left, right, len }));
+                vm.pop();
+                vm.pop();
+                vm.push_ptr(rope);
+            },
+        );
+        Ok(())
+    }
+
+    /// Replaces the string on top of the stack with a flat copy of its
+    /// contents, collapsing any rope structure.
+    pub fn flatten(&mut self) -> Result<(), GcError> {
+        self.check_string(0)?;
+        self.recorded(
+            |_| Event::Flatten,
+            |vm| {
+                let top = vm.peek(0);
+                if let ObjType::Rope(_) = top.value() {
+                    let flat = vm.alloc(ObjType::Str(string_contents(&top)));
+                    vm.pop();
+                    vm.push_ptr(flat);
+                }
+            },
+        );
+        Ok(())
+    }
+
+    /// Compares two objects by value: numbers numerically (an int equals a
+    /// float of the same value, and NaN equals nothing, not even itself),
+    /// strings and ropes by content, and everything else by identity.
+    pub fn values_eq(&self, a: &GcPtr<Object>, b: &GcPtr<Object>) -> bool {
+        let a = &self.assert_live(a);
+        let b = &self.assert_live(b);
+        values_eq(a, b)
+    }
+
+    /// Compares two object graphs by value: pairs, arrays, maps and closures
+    /// are equal when their children are, nil, booleans and symbols by value,
+    /// and everything else like [`Vm::values_eq`]. Cyclic graphs are equal
+    /// when they unfold into the same infinite structure: each pair of
+    /// objects being compared is merged into one equivalence class up front,
+    /// so meeting it again ends the walk.
+    pub fn deep_eq(&self, a: &GcPtr<Object>, b: &GcPtr<Object>) -> bool {
+        self.deep_eq_across(a, self, b)
+    }
+
+    /// Like [`Vm::deep_eq`], but compares `a` in this VM with `b` in
+    /// `other`, which is why nil, booleans and symbols compare by value.
+    pub fn deep_eq_across(&self, a: &GcPtr<Object>, other: &Vm, b: &GcPtr<Object>) -> bool {
+        let a = &self.assert_live(a);
+        let b = &other.assert_live(b);
+        let mut classes = HashMap::new();
+        let mut pending = vec![(a.clone(), b.clone())];
+        while let Some((a, b)) = pending.pop() {
+            let (ra, rb) = (find(&mut classes, a.clone()), find(&mut classes, b.clone()));
+            if ra == rb {
+                continue;
+            }
+            classes.insert(ra, rb);
+            let same = match (a.value(), b.value()) {
+                (ObjType::Pair(x), ObjType::Pair(y)) => [(&x.head, &y.head), (&x.tail, &y.tail)]
+                    .into_iter()
+                    .all(|children| match children {
+                        (Some(x), Some(y)) => {
+                            pending.push((x.clone(), y.clone()));
+                            true
+                        }
+                        (x, y) => x.is_none() && y.is_none(),
+                    }),
+                (ObjType::Array(x), ObjType::Array(y)) => {
+                    pending.extend(x.iter().cloned().zip(y.iter().cloned()));
+                    x.len() == y.len()
+                }
+                (ObjType::Closure(x), ObjType::Closure(y)) => {
+                    pending.extend(x.upvalues.iter().cloned().zip(y.upvalues.iter().cloned()));
+                    x.code_id == y.code_id && x.upvalues.len() == y.upvalues.len()
+                }
+                (ObjType::Map(x), ObjType::Map(y)) => {
+                    x.entries.len() == y.entries.len()
+                        && x.entries
+                            .iter()
+                            .all(|(key, value)| match y.entries.get(key) {
+                                Some(other) => {
+                                    pending.push((value.clone(), other.clone()));
+                                    true
+                                }
+                                None => false,
+                            })
+                }
+                (ObjType::Nil, ObjType::Nil) => true,
+                (ObjType::Bool(x), ObjType::Bool(y)) => x == y,
+                (ObjType::Symbol(x), ObjType::Symbol(y)) => x == y,
+                _ => values_eq(&a, &b),
+            };
+            if !same {
+                return false;
+            }
+        }
+        true
+    }
+
+    /// Pops two numbers and pushes their sum, the topmost one being the right
+    /// operand. Ints stay ints, with overflowing results promoted to big
+    /// integers; if either operand is a float, so is the result.
+    pub fn add(&mut self) -> Result<(), GcError> {
+        self.arith(Event::Add, i64::checked_add, |a, b| a + b, |a, b| a + b)
+    }
+
+    pub fn sub(&mut self) -> Result<(), GcError> {
+        self.arith(Event::Sub, i64::checked_sub, |a, b| a - b, |a, b| a - b)
+    }
+
+    pub fn mul(&mut self) -> Result<(), GcError> {
+        self.arith(Event::Mul, i64::checked_mul, |a, b| a * b, |a, b| a * b)
+    }
+
+    /// Like [`Vm::add`], for division. Integer division truncates toward
+    /// zero, and dividing by zero, int or float, is a `DivisionByZero` error.
+    pub fn div(&mut self) -> Result<(), GcError> {
+        if self.stack_size < 2 {
+            return Err(GcError::StackUnderflow);
+        }
+        let divisor = self.stack[self.stack_size - 1];
+        let is_zero = match divisor.as_obj() {
+            Some(obj) => match obj.value() {
+                ObjType::Int(i) => *i == 0,
+                ObjType::Float(f) => *f == 0.0,
+                ObjType::BigInt(n) => n.is_zero(),
+                _ => false,
+            },
+            None => divisor.as_int() == Some(0) || divisor.as_float() == Some(0.0),
+        };
+        if is_zero {
+            return Err(GcError::DivisionByZero);
+        }
+        self.arith(Event::Div, i64::checked_div, |a, b| a / b, |a, b| a / b)
+    }
+
+    /// Applies an arithmetic operator to the two numbers on top of the stack:
+    /// `small` to two ints, falling back to `big` when either operand is
+    /// already big or `small` overflows, and `float` when either is a float.
+    /// Logged as `event`.
+    fn arith(
+        &mut self,
+        event: Event,
+        small: fn(i64, i64) -> Option<i64>,
+        big: fn(&BigInt, &BigInt) -> BigInt,
+        float: fn(f64, f64) -> f64,
+    ) -> Result<(), GcError> {
+        if self.stack_size < 2 {
+            return Err(GcError::StackUnderflow);
+        }
+        // both operands are checked before anything is boxed, so a failure
+        // leaves the stack as it was and logs nothing
+        self.check_number(1)?;
+        self.check_number(0)?;
+        self.recorded(
+            |_| event,
+            |vm| {
+                // fast path: two unboxed operands never need to touch the heap
+                let (x, y) = (vm.stack[vm.stack_size - 2], vm.stack[vm.stack_size - 1]);
+                if let Some(n) = x.as_int().zip(y.as_int()).and_then(|(x, y)| small(x, y)) {
+                    vm.pop_value();
+                    vm.pop_value();
+                    vm.push_int(n);
+                    return;
+                }
+
+                let b = vm.peek(0);
+                let a = vm.peek(1);
+                let result = match (a.value(), b.value()) {
+                    (ObjType::Int(x), ObjType::Int(y)) => match small(*x, *y) {
+                        Some(n) => ObjType::Int(n),
+                        None => ObjType::BigInt(big(&BigInt::from(*x), &BigInt::from(*y))),
+                    },
+                    (x @ ObjType::Float(_), y) | (x, y @ ObjType::Float(_)) => {
+                        ObjType::Float(float(as_float(x), as_float(y)))
+                    }
+                    (x, y) => int_result(big(&as_bigint(x), &as_bigint(y))),
+                };
+                vm.pop();
+                vm.pop();
+                match result {
+                    ObjType::Int(n) => vm.push_int(n),
+                    result => vm.push(result),
+                }
+            },
+        );
+        Ok(())
+    }
+
+    /// Fails unless the value `depth` slots below the top of the stack is a
+    /// number.
+    fn check_number(&self, depth: usize) -> Result<(), GcError> {
+        let value = self.peek_value(depth).ok_or(GcError::StackUnderflow)?;
+        let number = match value.as_obj() {
+            Some(obj) => matches!(
+                obj.value(),
+                ObjType::Int(_) | ObjType::BigInt(_) | ObjType::Float(_)
+            ),
+            None => value.as_int().is_some() || value.as_float().is_some(),
+        };
+        if !number {
+            return Err(GcError::TypeMismatch {
+                expected: "number",
+                found: value.type_name(),
+            });
+        }
+        Ok(())
+    }
+
+    /// Pushes a copy of the top of the stack: `( a -- a a )`.
+    pub fn dup(&mut self) {
+        assert!(self.stack_size >= 1, "Stack underflow!");
+        self.recorded(
+            |_| Event::Dup,
+            |vm| vm.push_slot(vm.stack[vm.stack_size - 1]),
+        );
+    }
+
+    /// Pushes a copy of the second value on the stack: `( a b -- a b a )`.
+    pub fn over(&mut self) {
+        assert!(self.stack_size >= 2, "Stack underflow!");
+        self.recorded(
+            |_| Event::Over,
+            |vm| vm.push_slot(vm.stack[vm.stack_size - 2]),
+        );
+    }
+
+    /// Exchanges the top two values: `( a b -- b a )`.
+    pub fn swap(&mut self) {
+        assert!(self.stack_size >= 2, "Stack underflow!");
+        self.recorded(
+            |_| Event::Swap,
+            |vm| vm.stack.swap(vm.stack_size - 1, vm.stack_size - 2),
+        );
+    }
+
+    /// Moves the third value to the top: `( a b c -- b c a )`.
+    pub fn rot(&mut self) {
+        assert!(self.stack_size >= 3, "Stack underflow!");
+        self.recorded(
+            |_| Event::Rot,
+            |vm| {
+                let top = vm.stack_size - 1;
+                vm.stack.swap(top - 2, top - 1);
+                vm.stack.swap(top - 1, top);
+            },
+        );
+    }
+
+    /// Discards the top of the stack: `( a -- )`. This is Forth's `drop`;
+    /// the value stays alive only if something else still refers to it.
+    pub fn drop_top(&mut self) {
+        self.pop_value();
+    }
+
+    /// Discards everything on the stack, so whatever only the stack kept
+    /// alive is collected by the next GC. Meant for between runs: frame
+    /// locals, globals and constants are left alone.
+    pub fn clear_stack(&mut self) {
+        while self.stack_size > 0 {
+            self.pop_value();
+        }
+    }
+
+    /// Returns the object `depth` slots below the top of the stack, leaving
+    /// it in place. An immediate in that slot is boxed, and the slot updated
+    /// to refer to the box, so its identity is stable from then on. Panics if
+    /// the stack isn't that deep.
+    pub fn peek(&mut self, depth: usize) -> GcPtr<Object> {
+        assert!(depth < self.stack_size, "Stack underflow!");
+        self.recorded(
+            |_| Event::Peek(depth),
+            |vm| {
+                let slot = vm.stack_size - 1 - depth;
+                let obj = vm.box_value(vm.stack[slot]);
+                vm.stack[slot] = vm.to_value(obj.clone());
+                obj
+            },
+        )
+    }
+
+    /// Returns the value `depth` slots below the top of the stack as it's
+    /// stored, without boxing it, or `None` if the stack isn't that deep.
+    pub fn peek_value(&self, depth: usize) -> Option<Value> {
+        let slot = self.stack_size.checked_sub(depth + 1)?;
+        Some(self.stack[slot])
+    }
+
+    /// Iterates over the live stack slots, bottom first.
+    pub fn stack_values(&self) -> impl DoubleEndedIterator<Item = Value> + '_ {
+        self.stack.slots(0..self.stack_size)
+    }
+
+    /// Returns the top `count` objects on the stack, deepest first, leaving
+    /// them in place.
+    fn peek_top(&mut self, count: usize) -> Vec<GcPtr<Object>> {
+        assert!(count <= self.stack_size, "Stack underflow!");
+        (0..count).rev().map(|depth| self.peek(depth)).collect()
+    }
+
+    /// Fails unless the value `depth` slots below the top of the stack is a
+    /// string or rope.
+    fn check_string(&self, depth: usize) -> Result<(), GcError> {
+        let value = self.peek_value(depth).ok_or(GcError::StackUnderflow)?;
+        match value.as_obj() {
+            Some(obj) if obj.value().is_string() => Ok(()),
+            _ => Err(GcError::TypeMismatch {
+                expected: "string",
+                found: value.type_name(),
+            }),
+        }
+    }
+
+    /// Pushes the canonical string object for `s`, allocating it on first use,
+    /// and returns a handle to it. Interned strings can be compared by
+    /// identity, and are frozen, since the table finds them by contents.
+    ///
+    /// The table doesn't keep its strings alive: once an interned string is
+    /// unreachable it gets collected like any other object, and interning the
+    /// same text afterwards allocates a new canonical object.
+    pub fn intern(&mut self, s: &str) -> GcPtr<Object> {
+        self.recorded(
+            |_| Event::Intern(s.to_owned()),
+            |vm| {
+                let obj = match vm.strings.get(s) {
+                    Some(obj) => obj.clone(),
+                    None => {
+                        let obj = vm.alloc(ObjType::Str(s.to_owned()));
+                        // the table finds it by its contents, so they can't
+                        // change
+                        vm.freeze(&obj);
+                        vm.strings.insert(s.to_owned(), obj.clone());
+                        obj
+                    }
+                };
+                vm.push_ptr(obj.clone());
+                obj
+            },
+        )
+    }
+
+    /// Pushes the symbol named `name` and returns a handle to it. There is
+    /// only ever one live symbol per name, so symbols compare by identity.
+    /// Like interned strings, symbols are frozen, and ones nothing refers to
+    /// anymore are collected.
+    pub fn symbol(&mut self, name: &str) -> GcPtr<Object> {
+        self.recorded(
+            |_| Event::Symbol(name.to_owned()),
+            |vm| {
+                let obj = vm.symbol_obj(name);
+                vm.push_ptr(obj.clone());
+                obj
+            },
+        )
+    }
+
+    /// Returns the symbol named `name`, allocating it if there's none yet.
+    fn symbol_obj(&mut self, name: &str) -> GcPtr<Object> {
+        match self.symbols.get(name) {
+            Some(obj) => obj.clone(),
+            None => {
+                let obj = self.alloc(ObjType::Symbol(name.to_owned()));
+                self.freeze(&obj);
+                self.symbols.insert(name.to_owned(), obj.clone());
+                obj
+            }
+        }
+    }
+
+    /// Binds the global `name` to `handle`, replacing any previous binding.
+    /// The object stays reachable through the binding, so it doesn't need a
+    /// stack slot to survive collections.
+    pub fn define_global(&mut self, name: &str, handle: GcPtr<Object>) {
+        let handle = self.assert_live(&handle);
+        let handle_ref = handle.clone();
+        self.recorded(
+            |recorder| Event::DefineGlobal {
+                name: name.to_owned(),
+                value: recorder.id(&handle_ref),
+            },
+            |vm| {
+                vm.globals.insert(name.to_owned(), handle);
+            },
+        );
+    }
+
+    /// Returns the object bound to the global `name`, if any.
+    pub fn get_global(&self, name: &str) -> Option<GcPtr<Object>> {
+        self.globals.get(name).cloned()
+    }
+
+    fn mark_all(&mut self) {
+        let locals = self.frames.iter().flat_map(|frame| &frame.locals);
+        let locals = locals.copied();
+        for value in self.stack.slots(0..self.stack_size).chain(locals) {
+            if let Some(mut obj) = value.as_obj() {
+                unsafe {
+                    obj.mark();
+                }
+            }
+        }
+        let handles = self.handles.values_mut();
+        for obj in self
+            .globals
+            .values_mut()
+            .chain(&mut self.constants)
+            .chain(handles)
+        {
+            unsafe {
+                obj.mark();
+            }
+        }
+        self.mark_identity_keys();
+        self.mark_finalizing();
+    }
+
+    /// Drops the intern, symbol and hash-consing table entries whose objects
+    /// weren't reached by the last mark, so the tables never point at freed objects.
+    fn sweep_tables(&mut self) {
+        self.strings.retain(|_, obj| obj.is_marked());
+        self.symbols.retain(|_, obj| obj.is_marked());
+        self.conses.retain(|_, obj| obj.is_marked());
+        self.sweep_identity_keys();
+    }
+
+    fn sweep(&mut self) {
+        // every object of a clustered heap is in a chunk, so the regions can
+        // read its mark bit from the chunk's meta without touching it
+        let clustered = self.config.layout == LayoutPolicy::Clustered;
+        // a handle in the heap twice would be freed twice, so debug builds
+        // check for one before anything is freed
+        #[cfg(debug_assertions)]
+        {
+            let mut seen = HashSet::new();
+            for obj in &self.heap {
+                assert!(
+                    seen.insert(obj),
+                    "{:?} is in the heap twice and would be freed twice",
+                    obj.0
+                );
+            }
+        }
+        // likewise, an overrun is reported while the heap is still intact
+        if cfg!(feature = "canaries") {
+            for obj in &self.heap {
+                allocation::check_canaries(obj.0);
+            }
+        }
+        let threads = self
+            .sweep_threads
+            .min(self.heap.len() / SWEEP_REGION)
+            .max(1);
+        // objects freed off this thread can't go through the quarantine or
+        // back to a chunk, so when it's keeping them or they came from one
+        // the regions leave the freeing to this one, as they do when it
+        // decides which go to the finalizer thread
+        let free_in_regions = threads > 1
+            && !self.quarantine.retains()
+            && self.config.layout == LayoutPolicy::Individual
+            && self.finalizer.is_none();
+        let regions = if threads > 1 {
+            let len = self.heap.len().div_ceil(threads);
+            std::thread::scope(|scope| {
+                let workers: Vec<_> = self
+                    .heap
+                    .chunks_mut(len)
+                    .map(|region| {
+                        scope.spawn(move || sweep_region(region, free_in_regions, clustered))
+                    })
+                    .collect();
+                workers
+                    .into_iter()
+                    .map(|worker| {
+                        worker
+                            .join()
+                            .unwrap_or_else(|err| std::panic::resume_unwind(err))
+                    })
+                    .collect()
+            })
+        } else {
+            vec![sweep_region(&mut self.heap, false, clustered)]
+        };
+        // chunks nothing in which survived go whole, their objects needing no
+        // dropping
+        let unmarked = if clustered {
+            self.chunks.take_unmarked()
+        } else {
+            vec![]
+        };
+        self.chunks.clear_marks();
+
+        let mut live_objects = Vec::with_capacity(regions.iter().map(|r| r.live.len()).sum());
+        for mut region in regions {
+            for obj in &mut region.dead {
+                if let Some(recorder) = &mut self.recorder {
+                    recorder.forget(obj);
+                }
+                self.live.remove(obj);
+                if clustered {
+                    let start = allocation::chunk_start(obj.0);
+                    let whole = unmarked.binary_search_by_key(&start, |&(start, _)| start);
+                    if whole.is_ok() {
+                        continue;
+                    }
+                    allocation::retire(obj.0);
+                }
+                let queued = self
+                    .finalizer
+                    .as_mut()
+                    .is_some_and(|finalizer| finalizer.queue(obj));
+                if !free_in_regions && !queued {
+                    unsafe { obj.free(&mut self.quarantine, &mut self.chunks) }
+                }
+            }
+            self.num_objs -= region.dead.len();
+            self.num_bytes -= region.dead_bytes;
+            live_objects.append(&mut region.live);
+        }
+        self.heap = live_objects;
+        for (_, chunk) in unmarked {
+            self.quarantine.free_chunk(chunk, &mut self.chunks);
+        }
+    }
+
+    /// Sweeps on up to `threads` threads, splitting the heap into that many
+    /// regions, each at least `SWEEP_REGION` objects long. Unless swept
+    /// objects are being quarantined, each thread also frees the dead objects
+    /// of its region, running their finalizers. The default of 1 sweeps on
+    /// the collecting thread alone, as every sweep does on wasm32, which has
+    /// no threads to spawn.
+    pub fn set_sweep_threads(&mut self, threads: usize) {
+        if cfg!(target_arch = "wasm32") {
+            return;
+        }
+        self.sweep_threads = threads.max(1);
+    }
+
+    /// Checks that every object the roots, the live objects and the tables
+    /// refer to is still in the heap, so a marking bug that freed something
+    /// reachable fails at the collection that made it rather than at some
+    /// later use of the freed object. Debug builds run this after every
+    /// collection.
+    #[cfg(debug_assertions)]
+    fn verify_heap(&self) {
+        struct Check<'a> {
+            live: &'a LiveSet,
+            from: &'static str,
+        }
+        impl Visitor for Check<'_> {
+            fn visit(&mut self, obj: &GcPtr<Object>) {
+                assert!(
+                    self.live.contains(obj),
+                    "{:?}, referred to by {}, was freed while still reachable",
+                    obj.0,
+                    self.from
+                );
+            }
+        }
+
+        // by address as well as epoch, so a reference left where an object
+        // was before it moved is caught too
+        let mut live = LiveSet::default();
+        live.extend(self.heap.iter().chain(&self.immortals).cloned());
+        for segment in &self.segments {
+            live.extend(segment.objects().cloned());
+        }
+        live.extend(self.finalizing_objects());
+        let mut check = Check {
+            live: &live,
+            from: "a root",
+        };
+        let locals = self.frames.iter().flat_map(|frame| &frame.locals).copied();
+        for value in self.stack.slots(0..self.stack_size).chain(locals) {
+            if let Some(addr) = value.addr() {
+                assert!(
+                    live.at(addr).is_some(),
+                    "{:?}, referred to by a root, was freed while still reachable",
+                    allocation::from_addr(addr)
+                );
+            }
+        }
+        let roots = self.globals.values().chain(&self.constants);
+        for obj in roots.chain(self.handles.values()) {
+            check.visit(obj);
+        }
+        check.from = "a live object";
+        for obj in &self.heap {
+            allocation::check_canaries(obj.0);
+            obj.value().trace(&mut check);
+        }
+        check.from = "a table";
+        let tables = self.strings.values().chain(self.symbols.values());
+        for obj in tables.chain(self.conses.values()) {
+            check.visit(obj);
+        }
+        for obj in self.identity_key_objects() {
+            check.visit(&obj);
+        }
+    }
+
+    /// Gives back memory the VM has been holding on to since it was at its
+    /// biggest: the heap index, the tables and handles are shrunk to what
+    /// they hold now, stack segments above the top of the stack are freed,
+    /// and quarantined objects are released. Returns roughly how many bytes
+    /// that gave back.
+    pub fn shrink_to_fit(&mut self) -> usize {
+        let mut freed = shrink_vec(&mut self.heap) + shrink_map(&mut self.live.0);
+        freed += shrink_map(&mut self.strings) + shrink_map(&mut self.symbols);
+        freed += shrink_map(&mut self.conses) + shrink_map(&mut self.globals);
+        freed += shrink_map(&mut self.handles);
+        freed += self.stack.shrink(self.stack_size);
+        freed += self.quarantine.len() * std::mem::size_of::<Object>();
+        self.quarantine.clear(&mut self.chunks);
+        freed
+    }
+
+    /// Share of the slots of a clustered layout's chunks that are free, or
+    /// will be once what was swept from them is released, outside the chunk
+    /// being allocated from. Only a pair whose child is next to the slot
+    /// takes one of those, so they're memory held for nothing until
+    /// [`Vm::compact`] empties their chunks. Objects allocated one by one
+    /// leave no gaps, so with `LayoutPolicy::Individual` this is 0.
+    pub fn fragmentation(&self) -> f64 {
+        self.chunks.fragmentation()
+    }
+
+    /// Counts what the heap holds. With a clustered layout the types come
+    /// from the chunks' metas, without touching the objects.
+    pub fn heap_stats(&self) -> HeapStats {
+        let mut counts = [0; TypeTag::ALL.len()];
+        match self.config.layout {
+            LayoutPolicy::Individual => {
+                for obj in &self.heap {
+                    counts[unsafe { obj.0.as_ref() }.header.type_tag() as usize] += 1;
+                }
+            }
+            LayoutPolicy::Clustered => self.chunks.count_types(&mut counts),
+        }
+        HeapStats {
+            objects: self.num_objs,
+            bytes: self.num_bytes,
+            by_type: TypeTag::ALL
+                .iter()
+                .zip(counts)
+                .filter(|&(_, count)| count > 0)
+                .map(|(tag, count)| (tag.name(), count))
+                .collect(),
+            chunks: self.chunks.len(),
+            free_slots: self.chunks.free_slots(),
+            fragmentation: self.fragmentation(),
+        }
+    }
+
+    /// Shrinks after every collection that leaves the heap index less than a
+    /// quarter full, so memory freed by a burst of allocation is given back
+    /// once it's garbage. Off by default.
+    pub fn set_auto_shrink(&mut self, enabled: bool) {
+        self.auto_shrink = enabled;
+    }
+
+    /// Schedules a compaction whenever a collection leaves the heap's
+    /// [`Vm::fragmentation`] above `threshold`. It runs at the end of the
+    /// next [`Vm::gc`] or [`Vm::safepoint`], which is the collection itself
+    /// when that's where it happened; collections the interpreter or a
+    /// native function start on their own leave it until then, since they
+    /// hold handles the compaction can't see. `None`, the default, never
+    /// does.
+    pub fn set_auto_compact(&mut self, threshold: Option<f64>) {
+        self.auto_compact = threshold;
+    }
+
+    /// Brings the VM back to how it was when created, minus the memory it
+    /// has grown into: the stack, frames, globals, constants and identity
+    /// map keys are cleared, everything but the immortals is collected and
+    /// the collection threshold starts over. The stack segments and tables
+    /// stay allocated for the next run, and registered natives and kinds
+    /// stay registered.
+    ///
+    /// Fails with `GcError::DeadObject`, changing nothing, while a borrow has
+    /// left an object referring to one that isn't live, since it couldn't
+    /// collect; see [`ObjMut`].
+    pub fn reset(&mut self) -> Result<(), GcError> {
+        if !self.settle_writes() {
+            return Err(GcError::DeadObject);
+        }
+        self.recorded(
+            |_| Event::Reset,
+            |vm| {
+                vm.clear_stack();
+                vm.stepper = None;
+                vm.frames.truncate(1);
+                vm.frames[0].locals.clear();
+                vm.globals.clear();
+                vm.constants.clear();
+                vm.handles.clear();
+                vm.clear_identity_keys();
+                vm.collect();
+                vm.max_objs = INITIAL_GC_THRESHOLD;
+                vm.stack_high_water = 0;
+            },
+        );
+        Ok(())
+    }
+
+    /// Collects now, then compacts if a collection scheduled it, see
+    /// [`Vm::set_auto_compact`]. Does nothing while a borrow has left an
+    /// object referring to one that isn't live, see [`ObjMut`].
+    pub fn gc(&mut self) {
+        self.recorded(|_| Event::Gc, Self::collect);
+        self.compact_if_due();
+    }
+
+    /// Whether a safepoint should collect: the heap has reached its
+    /// threshold, the VM's budget has asked it to, or the host is short of
+    /// memory.
+    pub(crate) fn collection_due(&mut self) -> bool {
+        #[cfg(feature = "pressure")]
+        if self.under_pressure() {
+            return true;
+        }
+        self.num_objs >= self.max_objs || self.budget_requested()
+    }
+
+    /// Collects if one is due: the heap has reached its threshold, the VM's
+    /// budget has asked it to, or the host is short of memory. Returns
+    /// whether it collected. Embedders driving the VM through
+    /// [`Vm::push`]/[`Vm::pop`] should call this between operations, at a
+    /// point where everything they still need is on the stack or registered
+    /// with [`Vm::register_handle`]: a bare `GcPtr` isn't a root. A
+    /// compaction scheduled by [`Vm::set_auto_compact`] runs here too, and
+    /// so does a step of a relocation under way, see
+    /// [`Vm::relocation_step`].
+    pub fn safepoint(&mut self) -> bool {
+        let collected = self.collect_if_due();
+        self.compact_if_due();
+        self.relocation_step(1);
+        collected
+    }
+
+    /// Like [`Vm::safepoint`], but leaves a scheduled compaction for later,
+    /// for the interpreter and native functions, which hold handles of their
+    /// own that compaction wouldn't point at the moved objects.
+    pub(crate) fn collect_if_due(&mut self) -> bool {
+        let due = self.collection_due();
+        if due {
+            self.recorded(|_| Event::Gc, Self::collect);
+        }
+        due
+    }
+
+    fn collect(&mut self) {
+        if !self.settle_writes() {
+            return;
+        }
+        #[cfg(all(feature = "std", not(target_arch = "wasm32")))]
+        let num_objs = self.num_objs;
+
+        self.relocation = None;
+        self.reap_finalized();
+        self.release_send_handles();
+        self.mark_all();
+        self.rescue_finalizable();
+        self.sweep_tables();
+        self.sweep();
+        self.sweep_forwarded();
+        self.quarantine.collected(&mut self.chunks);
+        #[cfg(feature = "pressure")]
+        self.relieve_pressure();
+        #[cfg(debug_assertions)]
+        self.verify_heap();
+
+        self.max_objs = if self.num_objs == 0 {
+            INITIAL_GC_THRESHOLD
+        } else {
+            self.num_objs * 2
+        };
+        if self.auto_shrink && self.heap.len() < self.heap.capacity() / 4 {
+            self.shrink_to_fit();
+        }
+        if self
+            .auto_compact
+            .is_some_and(|threshold| self.fragmentation() > threshold)
+        {
+            self.compaction_due = true;
+        }
+        self.budget_collected();
+
+        // without std, and on wasm32, there's no stdout to report to
+        #[cfg(all(feature = "std", not(target_arch = "wasm32")))]
+        println!("Collected {} objects, {} remaining.", num_objs - self.num_objs, self.num_objs);
+    }
+}
+
+/// splitmix64's finalizer: a bijection, so distinct counts never collide,
+/// that spreads consecutive ones over the whole range.
+fn mix_hash(count: u64) -> u64 {
+    let mut hash = count;
+    hash = (hash ^ (hash >> 30)).wrapping_mul(0xbf58476d1ce4e5b9);
+    hash = (hash ^ (hash >> 27)).wrapping_mul(0x94d049bb133111eb);
+    hash ^ (hash >> 31)
+}
+
+/// Reports that the allocator gave out, the way std's collections do.
+fn out_of_memory() -> ! {
+    std::alloc::handle_alloc_error(std::alloc::Layout::new::<Object>())
+}
+
+/// Allocates one of the VM's immortals. They're pre-marked so no collection
+/// frees them, and frozen since every nil, bool and small int shares them.
+fn immortal(value: ObjType) -> GcPtr<Object> {
+    let mut header = Header::default();
+    header.set_marked(true);
+    header.set_frozen(true);
+    GcPtr::from_live(allocation::allocate(Object::new(header, value)))
+}
+
+fn as_array(obj: &GcPtr<Object>) -> Result<&[GcPtr<Object>], GcError> {
+    match obj.value() {
+        ObjType::Array(elems) => Ok(elems),
+        other => Err(GcError::TypeMismatch {
+            expected: "array",
+            found: other.type_name(),
+        }),
+    }
+}
+
+fn as_closure(obj: &GcPtr<Object>) -> Result<&Closure, GcError> {
+    match obj.value() {
+        ObjType::Closure(closure) => Ok(closure),
+        other => Err(GcError::TypeMismatch {
+            expected: "closure",
+            found: other.type_name(),
+        }),
+    }
+}
+
+fn as_map(obj: &GcPtr<Object>) -> Result<&Map, GcError> {
+    match obj.value() {
+        ObjType::Map(map) => Ok(map),
+        other => Err(GcError::TypeMismatch {
+            expected: "map",
+            found: other.type_name(),
+        }),
+    }
+}
+
+fn as_bytes(obj: &GcPtr<Object>) -> Result<&[u8], GcError> {
+    match obj.value() {
+        ObjType::Bytes(data) => Ok(data),
+        other => Err(GcError::TypeMismatch {
+            expected: "bytes",
+            found: other.type_name(),
+        }),
+    }
+}
+
+/// The value of an int or big int, which `Vm::check_number` has checked
+/// `value` is.
+fn as_bigint(value: &ObjType) -> BigInt {
+    match value {
+        ObjType::Int(i) => BigInt::from(*i),
+        ObjType::BigInt(n) => n.clone(),
+        _ => unreachable!(),
+    }
+}
+
+/// The value of a number, which `Vm::check_number` has checked `value` is.
+fn as_float(value: &ObjType) -> f64 {
+    match value {
+        ObjType::Int(i) => *i as f64,
+        ObjType::BigInt(n) => n.to_f64(),
+        ObjType::Float(f) => *f,
+        _ => unreachable!(),
+    }
+}
+
+/// Stores an integer result as an `Int` if it fits, and as a `BigInt`
+/// otherwise.
+fn int_result(n: BigInt) -> ObjType {
+    match n.to_i64() {
+        Some(i) => ObjType::Int(i),
+        None => ObjType::BigInt(n),
+    }
+}
+
+/// Shrinks `vec` to its length, returning how many bytes that gave back.
+fn shrink_vec<T>(vec: &mut Vec<T>) -> usize {
+    let capacity = vec.capacity();
+    vec.shrink_to_fit();
+    (capacity - vec.capacity()) * std::mem::size_of::<T>()
+}
+
+/// Like `shrink_vec`, counting only the entries and not the hash table's
+/// control bytes.
+fn shrink_map<K: Eq + Hash, V>(map: &mut HashMap<K, V>) -> usize {
+    let capacity = map.capacity();
+    map.shrink_to_fit();
+    (capacity - map.capacity()) * std::mem::size_of::<(K, V)>()
+}
+
+/// fewest objects worth giving a sweeping thread of its own
+const SWEEP_REGION: usize = 4096;
+
+/// What sweeping one region of the heap found.
+struct Region {
+    /// marked objects, unmarked again for the next collection
+    live: Vec<GcPtr<Object>>,
+    /// unmarked objects, which have already been freed if the region was
+    /// told to free them
+    dead: Vec<GcPtr<Object>>,
+    /// bytes the dead objects owned
+    dead_bytes: usize,
+}
+
+/// Sweeps one region. Only looks at the objects in `objects`, so regions can
+/// be swept in parallel. With `clustered`, every object is in a chunk, and
+/// live ones aren't touched at all.
+fn sweep_region(objects: &mut [GcPtr<Object>], free: bool, clustered: bool) -> Region {
+    let mut region = Region {
+        live: vec![],
+        dead: vec![],
+        dead_bytes: 0,
+    };
+    for obj in objects {
+        if clustered && allocation::chunk_marked(obj.0) {
+            region.live.push(obj.clone()); // ptr clone
+        } else if !clustered && obj.is_marked() {
+            obj.unmark();
+            region.live.push(obj.clone()); // ptr clone
+        } else {
+            region.dead_bytes += obj.size();
+            if free {
+                unsafe {
+                    obj.finalize();
+                    allocation::deallocate(obj.0);
+                }
+            }
+            region.dead.push(obj.clone());
+        }
+    }
+    region
+}
+
+/// [`Vm::values_eq`] on objects already known to be live, which may belong
+/// to different VMs.
+fn values_eq(a: &GcPtr<Object>, b: &GcPtr<Object>) -> bool {
+    let (va, vb) = (a.value(), b.value());
+    match (va, vb) {
+        (ObjType::Int(x), ObjType::Int(y)) => x == y,
+        (ObjType::BigInt(x), ObjType::BigInt(y)) => x == y,
+        _ if va.as_f64().is_some() && vb.as_f64().is_some() => va.as_f64() == vb.as_f64(),
+        _ if va.is_string() && vb.is_string() => string_contents(a) == string_contents(b),
+        _ => a == b,
+    }
+}
+
+/// Returns the representative of `obj`'s class in the union-find forest
+/// `classes`, halving the path on the way.
+fn find(
+    classes: &mut HashMap<GcPtr<Object>, GcPtr<Object>>,
+    mut obj: GcPtr<Object>,
+) -> GcPtr<Object> {
+    while let Some(parent) = classes.get(&obj).cloned() {
+        if let Some(grandparent) = classes.get(&parent).cloned() {
+            classes.insert(obj, grandparent.clone());
+            obj = grandparent;
+        } else {
+            return parent;
+        }
+    }
+    obj
+}
+
+fn string_len(obj: &GcPtr<Object>) -> usize {
+    match obj.value() {
+        ObjType::Str(s) => s.len(),
+        ObjType::Rope(rope) => rope.len,
+        _ => 0,
+    }
+}
+
+/// Collects the text of a string or rope. Walks the rope with an explicit
+/// stack, since repeated concatenation builds arbitrarily deep left spines.
+fn string_contents(obj: &GcPtr<Object>) -> String {
+    let mut out = String::with_capacity(string_len(obj));
+    let mut pending = vec![obj.clone()];
+    while let Some(obj) = pending.pop() {
+        match obj.value() {
+            ObjType::Str(s) => out.push_str(s),
+            ObjType::Rope(rope) => {
+                pending.push(rope.right.clone());
+                pending.push(rope.left.clone());
+            }
+            _ => {}
+        }
+    }
+    out
+}
+
+impl Drop for Vm {
+    fn drop(&mut self) {
+        self.leave_budget();
+        self.stack_size = 0;
+        self.stack.clear();
+        self.frames.clear();
+        self.globals.clear();
+        self.constants.clear();
+        self.handles.clear();
+        self.identity_keys.clear();
+        self.set_background_finalizers(false);
+        // with every root gone and no finalizer thread, marking reaches
+        // nothing a dead write could have left behind, so everything is
+        // freed regardless
+        self.dead_writes.clear();
+        self.gc();
+        for obj in &mut self.immortals {
+            unsafe { obj.free(&mut self.quarantine, &mut self.chunks) }
+        }
+        self.quarantine.clear(&mut self.chunks);
+    }
+}
+
+#[test]
+fn test1() {
+    println!("Test 1: Objects on stack are preserved.");
+    let mut vm = Vm::new();
+    vm.push_int(1);
+    vm.push_int(2);
+
+    vm.gc();
+    assert!(vm.num_objs == 2, "Should have preserved objects.");
+    drop(vm);
+}
+
+#[test]
+fn test2() {
+    println!("Test 2: Unreached objects are collected.");
+    let mut vm = Vm::new();
+    vm.push_int(1);
+    vm.push_int(2);
+    vm.pop();
+    vm.pop();
+
+    vm.gc();
+    assert!(vm.num_objs == 0, "Should have collected objects.");
+    drop(vm);
+}
+
+#[test]
+fn test3() {
+  println!("Test 3: Reach nested objects.");
+  let mut vm = Vm::new();
+  vm.push_int(1);
+  vm.push_int(2);
+  vm.push_pair();
+  vm.push_int(3);
+  vm.push_int(4);
+  vm.push_pair();
+  vm.push_pair();
+
+  vm.gc();
+  assert!(vm.num_objs == 7, "Should have reached objects.");
+  drop(vm);
+}
+
+#[test]
+fn test4() {
+    println!("Test 4: Handle cycles.");
+    let mut vm = Vm::new();
+    vm.push_int(1);
+    vm.push_int(2);
+    vm.push_pair();
+    vm.push_int(3);
+    vm.push_int(4);
+    vm.push_pair();
+
+    /* Set up a cycle, and also make 2 and 4 unreachable and collectible. */
+    let a = vm.stack[0].as_obj().unwrap();
+    let b = vm.stack[1].as_obj().unwrap();
+    vm.set_head(&a, b.clone()).unwrap();
+    vm.set_head(&b, a).unwrap();
+
+    vm.gc();
+    assert!(vm.num_objs == 4, "Should have collected objects.");
+    drop(vm);
+}
+
+#[test]
+fn perf_test() {
+    println!("Performance Test.");
+    let mut vm = Vm::new();
+
+    for i in 0..1000 {
+        for _j in 0..20 {
+            vm.push_int(i);
+        }
+
+        for _k in 0..20 {
+            vm.pop();
+        }
+    }
+    drop(vm);
+}
+
+#[test]
+fn intern_test() {
+    println!("Intern Test: Interned strings are canonical and weakly held.");
+    let mut vm = Vm::new();
+    let a = vm.intern("hello");
+    let b = vm.intern("hello");
+    let c = vm.intern("world");
+    assert!(a == b, "Should have returned the same object.");
+    assert!(a != c, "Should have returned distinct objects.");
+
+    vm.gc();
+    assert!(vm.num_objs == 2, "Should have preserved interned strings.");
+
+    vm.pop();
+    vm.pop();
+    vm.pop();
+    vm.gc();
+    assert!(vm.num_objs == 0, "Should have collected interned strings.");
+    assert!(
+        vm.strings.is_empty(),
+        "Should have dropped dead table entries."
+    );
+    drop(vm);
+}
+
+#[test]
+fn rope_test() {
+    println!("Rope Test: Concatenation builds ropes that flatten correctly.");
+    let mut vm = Vm::new();
+    vm.push_str("foo");
+    vm.push_str("bar");
+    vm.concat().unwrap();
+    vm.push_str("baz");
+    vm.concat().unwrap();
+
+    vm.gc();
+    assert!(vm.num_objs == 5, "Should have reached rope children.");
+
+    vm.flatten().unwrap();
+    vm.gc();
+    assert!(vm.num_objs == 1, "Should have collected the rope.");
+    let flat = vm.pop();
+    assert!(matches!(flat.value(), ObjType::Str(s) if s == "foobarbaz"));
+
+    vm.push_int(1);
+    vm.push_str("x");
+    assert!(vm.concat().is_err(), "Should have rejected a non-string.");
+    drop(vm);
+}
+
+#[test]
+fn float_test() {
+    println!("Float Test: Floats compare numerically, NaN never equal.");
+    let mut vm = Vm::new();
+    vm.push_float(f64::NAN);
+    let nan = vm.pop();
+    vm.push_float(2.0);
+    let two = vm.pop();
+    vm.push_int(2);
+    let int_two = vm.pop();
+    vm.push_int(i64::MAX);
+    let big = vm.pop();
+    vm.push_int(i64::MAX - 1);
+    let big_minus_one = vm.pop();
+
+    assert!(!vm.values_eq(&nan, &nan), "NaN should not equal itself.");
+    assert!(
+        vm.values_eq(&two, &int_two),
+        "Should compare int and float."
+    );
+    assert!(
+        !vm.values_eq(&big, &big_minus_one),
+        "Should compare ints exactly."
+    );
+    drop(vm);
+}
+
+#[test]
+fn singleton_test() {
+    println!("Singleton Test: Booleans and nil never allocate.");
+    let mut vm = Vm::new();
+    vm.push_bool(true);
+    vm.push_bool(true);
+    vm.push_bool(false);
+    vm.push_nil();
+    assert!(vm.num_objs == 0, "Should not have allocated.");
+
+    let nil = vm.pop();
+    let f = vm.pop();
+    let t1 = vm.pop();
+    let t2 = vm.pop();
+    assert!(t1 == t2, "Should have reused the true instance.");
+    assert!(t1 != f && f != nil, "Should have distinct singletons.");
+
+    vm.gc();
+    assert!(
+        matches!(t1.value(), ObjType::Bool(true)),
+        "Singletons should survive GC."
+    );
+    drop(vm);
+}
+
+#[test]
+fn array_test() {
+    println!("Array Test: Array elements are traced and bounds checked.");
+    let mut vm = Vm::new();
+    vm.push_int(1);
+    vm.push_int(2);
+    vm.push_int(3);
+    vm.push_array(3);
+
+    vm.gc();
+    assert!(vm.num_objs == 4, "Should have reached array elements.");
+
+    let array = vm.pop();
+    let first = vm.array_get(&array, 0).unwrap();
+    assert!(
+        matches!(first.value(), ObjType::Int(1)),
+        "Should keep push order."
+    );
+    assert!(
+        vm.array_get(&array, 3) == Err(GcError::IndexOutOfBounds { index: 3, len: 3 }),
+        "Should have reported out of bounds."
+    );
+
+    vm.push_int(4);
+    vm.push_ptr(array.clone());
+    vm.array_set(&array, 0, vm.stack[0].as_obj().unwrap())
+        .unwrap();
+    vm.gc();
+    assert!(
+        vm.num_objs == 4,
+        "Should have collected the overwritten element."
+    );
+
+    vm.pop();
+    vm.pop();
+    vm.gc();
+    assert!(vm.num_bytes == 0, "Should have released all bytes.");
+    drop(vm);
+}
+
+#[test]
+fn map_test() {
+    println!("Map Test: Map values are traced, keys compare by value.");
+    let mut vm = Vm::new();
+    vm.map_new();
+    let map = vm.stack[0].as_obj().unwrap();
+    vm.push_str("key");
+    let key = vm.pop();
+    vm.push_int(7);
+    let value = vm.pop();
+    vm.map_set(&map, &key, value).unwrap();
+
+    vm.gc();
+    assert!(vm.num_objs == 2, "Should have reached the map value.");
+
+    vm.intern("key");
+    let same_key = vm.pop();
+    let found = vm.map_get(&map, &same_key).unwrap().unwrap();
+    assert!(
+        matches!(found.value(), ObjType::Int(7)),
+        "Should look keys up by content."
+    );
+    assert!(
+        vm.map_get(&map, &map).is_err(),
+        "Should have rejected a map key."
+    );
+
+    assert!(vm.map_remove(&map, &same_key).unwrap().is_some());
+    vm.gc();
+    assert!(vm.num_objs == 1, "Should have collected the removed value.");
+    drop(vm);
+}
+
+#[test]
+fn bytes_test() {
+    println!("Bytes Test: Byte buffers slice, mutate and account their size.");
+    let mut vm = Vm::new();
+    vm.push_bytes(&[1, 2, 3, 4]);
+    let bytes = vm.stack[0].as_obj().unwrap();
+
+    vm.bytes_set(&bytes, 0, 9).unwrap();
+    assert!(
+        vm.bytes_get(&bytes, 0) == Ok(9),
+        "Should have mutated in place."
+    );
+    assert!(
+        vm.bytes_set(&bytes, 4, 0).is_err(),
+        "Should have bounds checked."
+    );
+
+    vm.bytes_slice(&bytes, 1..3).unwrap();
+    let slice = vm.pop();
+    assert!(matches!(slice.value(), ObjType::Bytes(b) if b == &[2, 3]));
+    assert!(
+        vm.bytes_slice(&bytes, 2..5).is_err(),
+        "Should have bounds checked."
+    );
+
+    let before = vm.num_bytes;
+    vm.bytes_extend(&bytes, &[0; 64]).unwrap();
+    assert!(
+        vm.num_bytes >= before + 64,
+        "Should have accounted the growth."
+    );
+
+    vm.gc();
+    assert!(vm.num_objs == 1, "Should have collected the slice.");
+    vm.pop();
+    vm.gc();
+    assert!(vm.num_bytes == 0, "Should have released all bytes.");
+    drop(vm);
+}
+
+#[test]
+fn push_pair_with_test() {
+    println!("Push Pair With Test: Pairs are built from explicit handles.");
+    let mut vm = Vm::new();
+    vm.push_str("head");
+    vm.push_str("tail");
+    let tail = vm.pop();
+    let head = vm.pop();
+    vm.push_ptr(head.clone());
+    vm.push_ptr(tail.clone());
+    let pair = vm.push_pair_with(head.clone(), tail.clone());
+    assert!(vm.stack_size == 3, "Should not have popped anything.");
+    match pair.value() {
+        ObjType::Pair(p) => assert!(p.head == Some(head) && p.tail == Some(tail)),
+        _ => panic!("Should have pushed a pair."),
+    }
+
+    vm.swap();
+    vm.pop();
+    vm.swap();
+    vm.pop();
+    vm.gc();
+    assert!(
+        vm.num_objs == 3,
+        "Should have kept the children via the pair."
+    );
+    drop(vm);
+}
+
+#[test]
+fn pop_pair_test() {
+    println!("Pop Pair Test: Popping a pair roots both of its halves.");
+    let mut vm = Vm::new();
+    vm.push_int(1);
+    vm.push_int(2);
+    vm.push_pair();
+    let (head, tail) = vm.pop_pair().unwrap();
+    assert!(matches!(head.value(), ObjType::Int(2)) && matches!(tail.value(), ObjType::Int(1)));
+    assert!(vm.stack_size == 2);
+
+    vm.gc();
+    assert!(
+        vm.num_objs == 2,
+        "Should have freed the pair but kept its halves."
+    );
+    assert!(vm.pop() == head && vm.pop() == tail);
+    vm.push_int(3);
+    assert!(
+        vm.pop_pair()
+            == Err(GcError::TypeMismatch {
+                expected: "pair",
+                found: "int"
+            })
+    );
+    assert!(vm.stack_size == 1, "Should have left a non-pair in place.");
+    drop(vm);
+}
+
+#[test]
+fn car_cdr_test() {
+    println!("Car Cdr Test: Lists can be walked from safe code.");
+    let mut vm = Vm::new();
+    // (1 2)
+    vm.push_nil();
+    vm.push_int(2);
+    vm.push_pair();
+    vm.push_int(1);
+    vm.push_pair();
+
+    vm.push_head().unwrap();
+    assert!(matches!(vm.pop().value(), ObjType::Int(1)));
+    vm.push_tail().unwrap();
+    vm.car().unwrap();
+    assert!(matches!(vm.pop().value(), ObjType::Int(2)));
+    assert!(vm.stack_size == 1, "Should have kept the list.");
+
+    vm.cdr().unwrap();
+    vm.cdr().unwrap();
+    assert!(
+        vm.car()
+            == Err(GcError::TypeMismatch {
+                expected: "pair",
+                found: "nil"
+            }),
+        "Should have reached the end of the list."
+    );
+    assert!(vm.push_head().is_err() && vm.stack_size == 1);
+    drop(vm);
+}
+
+#[test]
+fn set_pair_test() {
+    println!("Set Pair Test: Pairs are mutated through type-checked calls.");
+    let mut vm = Vm::new();
+    vm.push_int(1);
+    vm.push_int(2);
+    vm.push_pair();
+    let pair = vm.pop();
+    vm.push_ptr(pair.clone());
+    vm.push_str("new");
+    let s = vm.pop();
+    vm.set_tail(&pair, s.clone()).unwrap();
+    vm.gc();
+    assert!(vm.num_objs == 3, "Should have dropped the old tail.");
+    match pair.value() {
+        ObjType::Pair(p) => assert!(p.tail == Some(s.clone())),
+        _ => unreachable!(),
+    }
+
+    assert!(
+        vm.set_head(&s, pair.clone()).is_err(),
+        "Should only set pairs."
+    );
+    vm.freeze(&pair);
+    assert!(vm.set_head(&pair, s) == Err(GcError::FrozenObject));
+    drop(vm);
+}
+
+#[test]
+fn push_list_test() {
+    println!("Push List Test: Lists are built from iterators.");
+    let mut vm = Vm::new();
+    vm.push_list(1..=3);
+    assert!(vm.stack_size == 1);
+    vm.gc();
+    assert!(vm.num_objs == 6, "Should have rooted the whole list.");
+    let mut items = vec![];
+    vm.dup();
+    while vm.push_head().is_ok() {
+        items.push(vm.pop());
+        vm.cdr().unwrap();
+    }
+    assert!(matches!(vm.pop().value(), ObjType::Nil));
+    let items: Vec<_> = items
+        .iter()
+        .map(|item| match item.value() {
+            ObjType::Int(i) => *i,
+            _ => unreachable!(),
+        })
+        .collect();
+    assert!(items == [1, 2, 3], "Should have kept the iterator's order.");
+
+    let list = vm.pop();
+    vm.push_ptr(list.clone());
+    let nested = vm.push_list_of([list.clone(), list]);
+    assert!(vm.stack_size == 2 && vm.peek(0) == nested);
+    vm.swap();
+    vm.drop_top();
+    vm.gc();
+    assert!(vm.num_objs == 8, "Should share the inner list.");
+    assert!(matches!(vm.push_list([]).value(), ObjType::Nil));
+    drop(vm);
+}
+
+#[test]
+fn iter_list_test() {
+    println!("Iter List Test: Lists can be consumed as Rust iterators.");
+    let mut vm = Vm::new();
+    let list = vm.push_list(1..=4);
+    let mut sum = 0;
+    for item in vm.iter_list(&list) {
+        if let ObjType::Int(i) = item.value() {
+            sum += i;
+        }
+    }
+    assert!(sum == 10);
+
+    // close the list into a ring by pointing the last tail at the first pair
+    let last = (0..3).fold(list.clone(), |pair, _| match pair.value() {
+        ObjType::Pair(p) => p.tail.clone().unwrap(),
+        _ => unreachable!(),
+    });
+    vm.set_tail(&last, list.clone()).unwrap();
+    assert!(
+        vm.iter_list(&list).count() == 4,
+        "Should stop at the cycle."
+    );
+
+    vm.push_int(5);
+    let not_a_list = vm.pop();
+    assert!(vm.iter_list(&not_a_list).next().is_none());
+
+    let list = vm.push_list(1..=3);
+    let ObjType::Pair(p) = list.value() else {
+        unreachable!()
+    };
+    let second = p.tail.clone().unwrap();
+    if let ObjType::Pair(p) = &mut vm.object_mut(&second).value {
+        p.head = None;
+    }
+    let heads: Vec<_> = vm.iter_list(&list).collect();
+    assert!(
+        heads.len() == 3 && matches!(heads[1].value(), ObjType::Nil),
+        "Should yield a missing head as nil and carry on to the tail."
+    );
+    drop(vm);
+}
+
+#[test]
+fn accessors_test() {
+    println!("Accessors Test: Handles can be read without unsafe code.");
+    let mut vm = Vm::new();
+    vm.push_int(7);
+    vm.push_int(8);
+    vm.push_pair();
+    let pair = vm.pop();
+    let (head, tail) = pair.as_pair(&vm).unwrap();
+    assert!(head.unwrap().as_int(&vm) == Some(8));
+    assert!(tail.clone().unwrap().as_int(&vm) == Some(7));
+    assert!(pair.as_int(&vm).is_none() && tail.unwrap().as_pair(&vm).is_none());
+    drop(vm);
+}
+
+#[test]
+fn get_test() {
+    println!("Get Test: Heap borrows are tied to the VM.");
+    let mut vm = Vm::new();
+    vm.push_int(1);
+    let int = vm.pop();
+    assert!(matches!(vm.get(&int), ObjType::Int(1)));
+    *vm.get_mut::<i64>(&int).unwrap() += 1;
+    assert!(int.as_int(&vm) == Some(2));
+    assert!(
+        vm.get_mut::<String>(&int).err()
+            == Some(GcError::TypeMismatch {
+                expected: "string",
+                found: "int"
+            }),
+        "Should only lend out a value of the type asked for."
+    );
+    vm.push_list([1]);
+    let pair = vm.pop();
+    assert!(
+        vm.get_mut::<i64>(&pair).is_err(),
+        "Should not lend out a value that refers to other objects."
+    );
+    vm.freeze(&int);
+    assert!(vm.get_mut::<i64>(&int).err() == Some(GcError::FrozenObject));
+    drop(vm);
+}
+
+#[test]
+fn dead_object_test() {
+    use std::sync::atomic::AtomicUsize;
+
+    println!("Dead Object Test: Handles to collected objects are refused.");
+    let mut vm = Vm::new();
+    vm.push_str("gone");
+    let dead = vm.pop();
+    vm.push_str("kept");
+    let kept = vm.peek(0);
+    vm.gc();
+    assert!(!vm.is_live(&dead) && vm.is_live(&kept));
+    assert!(vm.get_mut::<String>(&dead).err() == Some(GcError::DeadObject));
+    assert!(vm.array_get(&dead, 0) == Err(GcError::DeadObject));
+    assert!(
+        vm.try_push(ObjType::Array(vec![kept.clone(), dead.clone()])) == Err(GcError::DeadObject),
+        "Should not let a dead handle into the heap."
+    );
+    let get = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| vm.get(&dead).size()));
+    assert!(get.is_err(), "Should panic rather than read freed memory.");
+
+    let other = Vm::new();
+    assert!(
+        !other.is_live(&kept),
+        "Should not accept another VM's objects."
+    );
+
+    #[derive(Debug)]
+    struct Refs(Vec<GcPtr<Object>>, Arc<AtomicUsize>);
+    impl Trace for Refs {
+        fn trace(&self, visitor: &mut dyn Visitor) {
+            for obj in &self.0 {
+                visitor.visit(obj);
+            }
+        }
+    }
+    impl ForeignObject for Refs {
+        fn finalize(&mut self) {
+            self.1.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    let finalized = Arc::new(AtomicUsize::new(0));
+    vm.push_foreign(Refs(vec![kept.clone()], finalized.clone()));
+    let refs = vm.peek(0);
+    vm.foreign_mut::<Refs>(&refs).unwrap().0.push(dead.clone());
+    assert!(
+        vm.get_mut::<String>(&kept).err() == Some(GcError::DeadObject),
+        "Should report the dead write when the next borrow is made."
+    );
+    vm.push_str("garbage");
+    vm.pop();
+    let num_objs = vm.num_objs;
+    vm.gc();
+    assert!(
+        vm.num_objs == num_objs,
+        "Should not collect while an object refers to a dead one."
+    );
+    assert!(vm.foreign_ref::<Refs>(&refs).unwrap().0.len() == 2);
+    vm.foreign_mut::<Refs>(&refs).unwrap().0.pop();
+    assert!(vm.get_mut::<String>(&kept).is_ok());
+    vm.gc();
+    assert!(vm.num_objs == num_objs - 1);
+
+    // a new object at a collected one's address is a different object
+    vm.push_str("first");
+    let first = vm.pop();
+    vm.gc();
+    let reused = (0..1000).find_map(|_| {
+        vm.push_str("second");
+        let second = vm.pop();
+        (second.0 == first.0).then_some(second)
+    });
+    if let Some(second) = reused {
+        assert!(vm.is_live(&second) && !vm.is_live(&first));
+        assert!(second != first, "Should tell a reused address apart.");
+    }
+
+    vm.foreign_mut::<Refs>(&refs).unwrap().0.push(dead.clone());
+    assert!(
+        vm.reset() == Err(GcError::DeadObject),
+        "Should report that it couldn't collect."
+    );
+    assert!(vm.is_live(&refs), "Should have left the VM as it was.");
+    drop(vm);
+    assert!(
+        finalized.load(Ordering::Relaxed) == 1,
+        "Should free and finalize everything on drop regardless."
+    );
+}
+
+#[test]
+fn send_test() {
+    println!("Send Test: A VM made on one thread runs and collects on another.");
+    let mut vm = Vm::new();
+    let mut rooted = vm.identity_map(KeyMode::Rooted);
+    let list = vm.push_list([1, 2, 3]);
+    rooted.insert(&mut vm, &list, "list");
+    vm.pop();
+
+    let (mut vm, rooted, list) = std::thread::spawn(move || {
+        for i in 0..100 {
+            vm.push_str(&i.to_string());
+        }
+        vm.clear_stack();
+        vm.gc();
+        assert!(vm.is_live(&list), "Should have kept the rooted list.");
+        let sum = vm
+            .iter_list(&list)
+            .map(|obj| obj.as_int(&vm).unwrap())
+            .sum::<i64>();
+        assert!(sum == 6);
+        (vm, rooted, list)
+    })
+    .join()
+    .unwrap();
+
+    assert!(rooted.get(&vm, &list) == Some(&"list"));
+    drop(rooted);
+    vm.gc();
+    assert!(
+        vm.num_objs == 0,
+        "Should collect normally back on this thread."
+    );
+    drop(vm);
+}
+
+#[test]
+fn deep_eq_test() {
+    println!("Deep Eq Test: Graphs compare by structure, cycles included.");
+    let mut vm = Vm::new();
+    let a = vm.push_list([1, 2, 3]);
+    let b = vm.push_list([1, 2, 3]);
+    let c = vm.push_list([1, 2]);
+    assert!(
+        a != b && vm.deep_eq(&a, &b),
+        "Should compare lists by value."
+    );
+    assert!(!vm.deep_eq(&a, &c));
+
+    // two rings of different lengths holding the same element unfold into
+    // the same infinite list
+    let one = vm.push_list([7]);
+    vm.set_tail(&one, one.clone()).unwrap();
+    let two = vm.push_list([7, 7]);
+    let last = match two.as_pair(&vm) {
+        Some((_, Some(tail))) => tail,
+        _ => unreachable!(),
+    };
+    vm.set_tail(&last, two.clone()).unwrap();
+    assert!(vm.deep_eq(&one, &two), "Should terminate on cycles.");
+    assert!(!vm.deep_eq(&one, &a));
+
+    vm.push_ptr(a.clone());
+    vm.push_array(1);
+    vm.push_ptr(b.clone());
+    vm.push_array(1);
+    let (x, y) = (vm.peek(0), vm.peek(1));
+    assert!(vm.deep_eq(&x, &y), "Should look inside arrays.");
+    drop(vm);
+}
+
+#[test]
+fn obj_type_eq_test() {
+    println!("ObjType Eq Test: Heap values compare and hash by value.");
+    let mut vm = Vm::new();
+    let a = vm.push_list([1, 2]);
+    let b = vm.push_list([1, 2]);
+    let c = vm.push_list([2, 1]);
+    assert!(vm.get(&a) == vm.get(&b) && vm.get(&a) != vm.get(&c));
+
+    vm.push_str("ab");
+    vm.push_str("a");
+    vm.push_str("b");
+    vm.concat().unwrap();
+    let (flat, rope) = (vm.peek(1), vm.peek(0));
+    assert!(
+        vm.get(&flat) == vm.get(&rope),
+        "Should compare ropes by content."
+    );
+
+    let keys: HashSet<&ObjType> = [&a, &b, &c, &flat, &rope]
+        .into_iter()
+        .map(|obj| vm.get(obj))
+        .collect();
+    assert!(keys.len() == 3, "Should hash equal values alike.");
+
+    vm.push_float(1.0);
+    vm.push_int(1);
+    let (int, float) = (vm.peek(0), vm.peek(1));
+    assert!(vm.get(&int) != vm.get(&float));
+    vm.map_new();
+    vm.map_new();
+    let (x, y) = (vm.peek(0), vm.peek(1));
+    assert!(vm.get(&x) == vm.get(&x) && vm.get(&x) != vm.get(&y));
+    drop(vm);
+}
+
+#[test]
+fn identity_hash_test() {
+    println!("Identity Hash Test: Identity hashes stick to their objects.");
+    let mut vm = Vm::new();
+    vm.push_int(1);
+    vm.push_int(1);
+    let (a, b) = (vm.peek(0), vm.peek(1));
+    let hash = vm.identity_hash(&a);
+    assert!(
+        hash != vm.identity_hash(&b),
+        "Should tell equal values apart."
+    );
+    vm.gc();
+    assert!(vm.identity_hash(&a) == hash, "Should survive collections.");
+
+    let copy = vm.deep_clone(&a);
+    assert!(
+        vm.identity_hash(&copy) != hash,
+        "Should not copy identities."
+    );
+    let nil = vm.nil.clone();
+    assert!(vm.identity_hash(&nil) == vm.identity_hash(&nil));
+    drop(vm);
+}
+
+#[test]
+fn stack_inspection_test() {
+    println!("Stack Inspection Test: The stack can be read without popping.");
+    let mut vm = Vm::with_config(VmConfig {
+        unboxed_ints: true,
+        ..VmConfig::default()
+    });
+    vm.push_int(1);
+    vm.push_str("two");
+    assert!(vm.peek_value(1).and_then(Value::as_int) == Some(1));
+    assert!(vm.peek_value(2).is_none());
+    assert!(vm.num_objs == 1, "Should not have boxed the int.");
+
+    let ints: Vec<_> = vm.stack_values().map(Value::as_int).collect();
+    assert!(
+        ints == [Some(1), None],
+        "Should list the slots bottom first."
+    );
+    let top = vm.peek(0);
+    assert!(
+        vm.stack_size == 2 && vm.peek(0) == top,
+        "Should leave it in place."
+    );
+    drop(vm);
+}
+
+#[test]
+fn try_pop_test() {
+    println!("Try Pop Test: Popping an empty stack is an error, not a crash.");
+    let mut vm = Vm::new();
+    vm.push_int(1);
+    assert!(vm.try_pop().unwrap().as_int(&vm) == Some(1));
+    assert!(vm.try_pop() == Err(GcError::StackUnderflow));
+    assert!(vm.stack_size == 0, "Should have left the stack alone.");
+    assert!(vm.execute(&[Op::PushInt(1), Op::Add]) == Err(GcError::StackUnderflow));
+    drop(vm);
+}
+
+#[test]
+fn error_test() {
+    println!("Error Test: Fallible operations report errors instead of panicking.");
+    let mut vm = Vm::new();
+    assert!(vm.div() == Err(GcError::StackUnderflow));
+    assert!(vm.add() == Err(GcError::StackUnderflow));
+    assert!(vm.car() == Err(GcError::StackUnderflow));
+    vm.register_native("nothing", |ctx| Ok(ctx.alloc(ObjType::Nil)));
+    assert!(vm.call_native("nothing", 1) == Err(GcError::StackUnderflow));
+    assert!(vm.stack_size == 0, "Should have left the stack alone.");
+
+    let err: Box<dyn std::error::Error> = Box::new(GcError::TypeMismatch {
+        expected: "int",
+        found: "pair",
+    });
+    assert!(err.to_string() == "expected int, found pair");
+    assert!(GcError::OutOfMemory.to_string() == "out of memory");
+    drop(vm);
+}
+
+#[test]
+fn try_push_test() {
+    println!("Try Push Test: Pushing onto a full stack is an error, not a crash.");
+    let mut vm = Vm::new();
+    for i in 0..STACK_MAX {
+        vm.try_push(ObjType::Int(i as i64)).unwrap();
+    }
+    assert!(vm.try_push(ObjType::Nil) == Err(GcError::StackOverflow));
+    assert!(vm.stack_size == STACK_MAX);
+
+    vm.pop();
+    assert!(vm.execute(&[Op::PushNil, Op::PushNil]) == Err(GcError::StackOverflow));
+    assert!(
+        vm.stack_size == STACK_MAX,
+        "Should have stopped at the limit."
+    );
+    drop(vm);
+}
+
+#[test]
+fn max_stack_test() {
+    println!("Max Stack Test: The stack grows on demand up to its configured limit.");
+    let mut vm = Vm::with_config(VmConfig {
+        max_stack: Some(10_000),
+        ..VmConfig::default()
+    });
+    for i in 0..10_000 {
+        vm.push_int(i);
+    }
+    assert!(vm.try_push(ObjType::Nil) == Err(GcError::StackOverflow));
+    vm.gc();
+    assert!(vm.num_objs == 10_000, "Should scan the whole stack.");
+    for _ in 0..5_000 {
+        vm.pop();
+    }
+    vm.gc();
+    assert!(vm.num_objs == 5_000, "Should only scan the live slots.");
+    drop(vm);
+}
+
+#[test]
+fn stack_warning_test() {
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    static WARNED_AT: AtomicUsize = AtomicUsize::new(0);
+    fn warn(depth: usize, max: usize) {
+        assert!(max == 100);
+        WARNED_AT.store(depth, Ordering::SeqCst);
+    }
+
+    println!("Stack Warning Test: Deep stacks are reported before they overflow.");
+    let mut vm = Vm::with_config(VmConfig {
+        max_stack: Some(100),
+        ..VmConfig::default()
+    });
+    vm.set_stack_warning(0.75, Some(warn));
+    for _ in 0..74 {
+        vm.push_nil();
+    }
+    assert!(WARNED_AT.load(Ordering::SeqCst) == 0);
+    vm.push_nil();
+    assert!(
+        WARNED_AT.load(Ordering::SeqCst) == 75,
+        "Should warn at 75%."
+    );
+    for _ in 0..10 {
+        vm.pop();
+    }
+    assert!(
+        vm.stack_high_water() == 75,
+        "Should remember the deepest point."
+    );
+    drop(vm);
+}
+
+#[test]
+fn bulk_stack_test() {
+    println!("Bulk Stack Test: Values can be pushed and popped in batches.");
+    let mut vm = Vm::new();
+    vm.push_str("below");
+    vm.push_ints(&[1, 2, 3]);
+    assert!(vm.stack_size == 4);
+    let ints: Vec<_> = vm
+        .pop_n(3)
+        .iter()
+        .map(|obj| obj.as_int(&vm).unwrap())
+        .collect();
+    assert!(ints == [1, 2, 3], "Should return the deepest first.");
+    assert!(vm.stack_size == 1 && vm.pop_n(0).is_empty());
+    drop(vm);
+
+    let mut vm = Vm::with_config(VmConfig {
+        unboxed_ints: true,
+        max_stack: Some(1000),
+        ..VmConfig::default()
+    });
+    vm.push_int(-1);
+    let values: Vec<i64> = (0..600).chain([i64::MAX]).collect();
+    vm.push_ints(&values);
+    assert!(
+        vm.stack_size == 602 && vm.stack_high_water() == 602,
+        "Should fill slots across segments."
+    );
+    vm.gc();
+    assert!(vm.num_objs == 1, "Should have boxed only what doesn't fit.");
+    let ints: Vec<_> = vm
+        .pop_n(601)
+        .iter()
+        .map(|obj| obj.as_int(&vm).unwrap())
+        .collect();
+    assert!(ints == values, "Should pop across segments in order.");
+    assert!(vm.stack_size == 1 && vm.pop_value().as_int() == Some(-1));
+    drop(vm);
+}
+
+#[test]
+fn clear_stack_test() {
+    println!("Clear Stack Test: Clearing the stack unroots everything on it.");
+    let mut vm = Vm::new();
+    vm.push_list([1, 2, 3]);
+    vm.push_str("kept");
+    let kept = vm.pop();
+    vm.define_global("kept", kept);
+    vm.clear_stack();
+    assert!(vm.stack_size == 0);
+    vm.gc();
+    assert!(
+        vm.num_objs == 1,
+        "Should only keep what's rooted elsewhere."
+    );
+    drop(vm);
+}
+
+#[test]
+fn reset_test() {
+    println!("Reset Test: A reset VM is empty but keeps its capacity.");
+    let mut vm = Vm::new();
+    for i in 0..200 {
+        vm.push_int(i);
+    }
+    vm.push_str("global");
+    let global = vm.pop();
+    vm.define_global("global", global);
+    vm.push_int(7);
+    vm.store_local(0);
+    vm.gc();
+    vm.reset().unwrap();
+    assert!(
+        vm.stack_size == 0 && vm.num_objs == 0,
+        "Should have collected everything."
+    );
+    assert!(vm.get_global("global").is_none() && vm.max_objs == INITIAL_GC_THRESHOLD);
+    assert!(
+        vm.stack.capacity() >= 200,
+        "Should keep the stack's memory."
+    );
+    assert!(vm.stack_high_water() == 0);
+
+    vm.push_int(1);
+    vm.push_int(2);
+    vm.add().unwrap();
+    assert!(vm.pop().as_int(&vm) == Some(3), "Should work as before.");
+    drop(vm);
+}
+
+#[test]
+fn shrink_test() {
+    println!("Shrink Test: Memory held since the peak is given back.");
+    let mut vm = Vm::with_config(VmConfig {
+        max_stack: Some(2000),
+        ..VmConfig::default()
+    });
+    for i in 0..1000 {
+        vm.push_int(i);
+    }
+    vm.clear_stack();
+    vm.push_int(1);
+    vm.gc();
+    assert!(
+        vm.shrink_to_fit() > 0,
+        "Should give back the peak's memory."
+    );
+    assert!(vm.heap.capacity() == vm.heap.len() && vm.stack.capacity() == 256);
+    assert!(vm.quarantined() == 0);
+    assert!(
+        vm.shrink_to_fit() == 0,
+        "Should have nothing left to give back."
+    );
+
+    vm.set_auto_shrink(true);
+    for i in 0..1000 {
+        vm.push_int(i);
+    }
+    vm.pop_n(1000);
+    vm.gc();
+    assert!(
+        vm.heap.capacity() == vm.heap.len(),
+        "Should shrink after collecting most of the heap."
+    );
+    drop(vm);
+}
+
+#[test]
+#[cfg(debug_assertions)]
+fn double_free_test() {
+    println!("Double Free Test: A handle in the heap twice is caught before it's freed.");
+    let mut vm = Vm::new();
+    vm.push_str("once");
+    let obj = vm.pop();
+    vm.heap.push(obj);
+    let sweep = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| vm.gc()));
+    assert!(sweep.is_err(), "Should have refused to sweep.");
+    assert!(vm.num_objs == 1, "Should not have freed anything.");
+    vm.heap.pop();
+    vm.gc();
+    assert!(vm.num_objs == 0 && vm.heap.is_empty());
+    drop(vm);
+}
+
+#[test]
+#[cfg(debug_assertions)]
+#[cfg_attr(
+    feature = "asan",
+    ignore = "reads quarantined memory, which the sanitizer reports"
+)]
+fn poison_test() {
+    println!("Poison Test: Using a freed object fails loudly.");
+    let mut vm = Vm::new();
+    vm.push_str("freed");
+    let stale = vm.pop();
+    vm.gc();
+    assert!(
+        allocation::is_poisoned(stale.0),
+        "Should have poisoned the object."
+    );
+    let read = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| stale.value().type_name()));
+    assert!(read.is_err(), "Should not read a freed object.");
+    for _ in 0..allocation::QUARANTINE_LEN {
+        vm.push_str("garbage");
+        vm.pop();
+    }
+    vm.gc();
+    drop(vm);
+}
+
+#[test]
+#[cfg_attr(
+    feature = "asan",
+    ignore = "reads quarantined memory, which the sanitizer reports"
+)]
+fn quarantine_test() {
+    println!("Quarantine Test: Swept objects stay trapped for a set number of collections.");
+    let mut vm = Vm::new();
+    vm.set_quarantine(Some(2));
+    vm.push_str("stale");
+    let stale = vm.pop();
+    vm.gc();
+    let read =
+        || std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| stale.value().type_name()));
+    let message = read().unwrap_err();
+    assert!(
+        message
+            .downcast_ref::<String>()
+            .unwrap()
+            .ends_with("after collection 1 freed it"),
+        "Should name the collection that freed it."
+    );
+    vm.gc();
+    assert!(
+        allocation::is_poisoned(stale.0),
+        "Should still be quarantined."
+    );
+    vm.gc();
+    assert!(vm.quarantined() == 0, "Should have released it.");
+    vm.set_quarantine(None);
+    drop(vm);
+}
+
+#[test]
+#[cfg(feature = "canaries")]
+fn canary_test() {
+    println!("Canary Test: Writes past the end of an object are caught by sweep.");
+    let mut vm = Vm::new();
+    vm.push_str("guarded");
+    let obj = vm.peek(0);
+    vm.gc();
+    let past_end = unsafe { obj.0.as_ptr().add(1).cast::<u8>() };
+    let byte = unsafe { past_end.read() };
+    unsafe { past_end.write(!byte) };
+    let sweep = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| vm.gc()));
+    assert!(sweep.is_err(), "Should have noticed the overrun.");
+    unsafe { past_end.write(byte) };
+    vm.clear_stack();
+    vm.gc();
+    drop(vm);
+}
+
+#[test]
+#[cfg(debug_assertions)]
+fn verify_heap_test() {
+    println!("Verify Heap Test: Freeing a reachable object is caught right after the sweep.");
+    let mut vm = Vm::new();
+    vm.push_str("child");
+    vm.push_nil();
+    vm.push_pair();
+    vm.gc();
+    let child = vm
+        .heap
+        .iter()
+        .position(|obj| obj.value().is_string())
+        .unwrap();
+    let child = vm.heap.remove(child);
+    let collect = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| vm.gc()));
+    assert!(collect.is_err(), "Should have noticed the missing child.");
+    vm.heap.push(child);
+    vm.gc();
+    assert!(vm.num_objs == 2);
+    drop(vm);
+}
+
+#[test]
+fn panic_safety_test() {
+    println!("Panic Safety Test: A panic while allocating leaves the VM consistent.");
+    fn size(_: &dyn Any) -> usize {
+        panic!("size");
+    }
+    let mut vm = Vm::new();
+    let kind = vm.register_kind(ObjKind {
+        name: "broken",
+        trace: |_, _| {},
+        size,
+        finalize: |_| {},
+    });
+    vm.push_int(1);
+    let (objs, bytes, heap) = (vm.num_objs, vm.num_bytes, vm.heap.len());
+    let push = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| {
+        vm.push_custom(kind, Box::new(()))
+    }));
+    assert!(push.is_err());
+    assert!(
+        vm.num_objs == objs && vm.num_bytes == bytes && vm.heap.len() == heap,
+        "Should not have allocated anything."
+    );
+    assert!(vm.stack_size == 1, "Should not have pushed anything.");
+    vm.pop();
+    vm.gc();
+    assert!(vm.num_objs == 0 && vm.num_bytes == 0);
+    drop(vm);
+}
+
+#[test]
+fn parallel_sweep_test() {
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    #[derive(Debug)]
+    struct Counted(Arc<AtomicUsize>);
+    impl Trace for Counted {
+        fn trace(&self, _: &mut dyn Visitor) {}
+    }
+    impl ForeignObject for Counted {
+        fn finalize(&mut self) {
+            self.0.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    println!("Parallel Sweep Test: Sweeping on many threads frees what one would.");
+    let finalized = Arc::new(AtomicUsize::new(0));
+    let build = |vm: &mut Vm| {
+        let mut kept = 0;
+        for i in 0..5 * SWEEP_REGION {
+            match i % 3 {
+                0 => vm.push_foreign(Counted(finalized.clone())),
+                1 => vm.push_str("some garbage"),
+                _ => vm.push_float(i as f64),
+            }
+            if i % 2 == 0 {
+                vm.drop_top();
+            } else {
+                kept += 1;
+            }
+            // keeps the survivors in arrays, so they don't fill the stack
+            if kept == 100 {
+                vm.push_array(kept);
+                kept = 0;
+            }
+        }
+    };
+    let mut serial = Vm::new();
+    build(&mut serial);
+    serial.gc();
+    let expected = finalized.load(Ordering::Relaxed);
+
+    for quarantine in [None, Some(1)] {
+        finalized.store(0, Ordering::Relaxed);
+        let mut vm = Vm::new();
+        vm.set_sweep_threads(4);
+        vm.set_quarantine(quarantine);
+        build(&mut vm);
+        vm.gc();
+        assert!(
+            vm.num_objs == serial.num_objs && vm.num_bytes == serial.num_bytes,
+            "Should have freed the same objects."
+        );
+        assert!(finalized.load(Ordering::Relaxed) == expected);
+        assert!(vm.heap.iter().all(|obj| !obj.is_marked()));
+        vm.clear_stack();
+        vm.gc();
+        assert!(vm.num_objs == 0 && vm.num_bytes == 0);
+        drop(vm);
+    }
+    drop(serial);
+}
+
+#[test]
+fn globals_test() {
+    println!("Globals Test: Objects bound to globals survive collections.");
+    let mut vm = Vm::new();
+    vm.push_int(1);
+    vm.push_int(2);
+    vm.push_pair();
+    let pair = vm.pop();
+    vm.define_global("point", pair.clone());
+    assert!(vm.stack_size == 0);
+
+    vm.gc();
+    assert!(vm.num_objs == 3, "Should have kept the pair and its ints.");
+    assert!(
+        vm.get_global("point") == Some(pair),
+        "Should find the binding."
+    );
+    assert!(vm.get_global("missing").is_none());
+
+    vm.push_str("other");
+    let other = vm.pop();
+    vm.define_global("point", other);
+    vm.gc();
+    assert!(vm.num_objs == 1, "Should have collected the unbound pair.");
+    drop(vm);
+}
+
+#[test]
+fn symbol_test() {
+    println!("Symbol Test: Symbols are unique per name and weakly held.");
+    let mut vm = Vm::new();
+    let a = vm.symbol("car");
+    let b = vm.symbol("car");
+    vm.intern("car");
+    let s = vm.pop();
+    assert!(a == b, "Should have returned the same symbol.");
+    assert!(!vm.values_eq(&a, &s), "Symbols should differ from strings.");
+
+    vm.map_new();
+    let map = vm.pop();
+    vm.push_ptr(map.clone());
+    vm.push_int(1);
+    vm.map_set(&map, &a, vm.stack[3].as_obj().unwrap()).unwrap();
+    assert!(
+        vm.map_get(&map, &s).unwrap().is_none(),
+        "Should key symbols apart."
+    );
+
+    vm.pop();
+    vm.pop();
+    vm.pop();
+    vm.pop();
+    vm.gc();
+    assert!(vm.symbols.is_empty(), "Should have dropped dead symbols.");
+    drop(vm);
+}
+
+#[test]
+fn bigint_promotion_test() {
+    println!("BigInt Promotion Test: Overflowing arithmetic promotes and demotes.");
+    let mut vm = Vm::new();
+    vm.push_int(i64::MAX);
+    vm.push_int(1);
+    vm.add().unwrap();
+    let sum = vm.stack[0].as_obj().unwrap();
+    assert!(matches!(sum.value(), ObjType::BigInt(n) if n.to_string() == "9223372036854775808"));
+
+    vm.push_int(2);
+    vm.sub().unwrap();
+    assert!(
+        matches!(vm.pop().value(), ObjType::Int(n) if *n == i64::MAX - 1),
+        "Should demote."
+    );
+
+    vm.push_int(i64::MIN);
+    vm.push_int(-1);
+    vm.mul().unwrap();
+    assert!(
+        matches!(vm.pop().value(), ObjType::BigInt(_)),
+        "Should promote."
+    );
+
+    vm.push_int(1);
+    vm.push_str("one");
+    assert!(vm.add().is_err(), "Should have rejected a string.");
+    drop(vm);
+}
+
+#[test]
+fn closure_test() {
+    println!("Closure Test: Captured upvalues are traced.");
+    let mut vm = Vm::new();
+    vm.push_int(1);
+    vm.push_int(2);
+    vm.push_pair();
+    vm.push_int(3);
+    vm.push_closure(42, 2);
+
+    vm.gc();
+    assert!(vm.num_objs == 5, "Should have reached upvalues.");
+
+    let closure = vm.pop();
+    assert!(vm.closure_code_id(&closure) == Ok(42));
+    let third = vm.closure_upvalue(&closure, 1).unwrap();
+    assert!(
+        matches!(third.value(), ObjType::Int(3)),
+        "Should keep push order."
+    );
+    assert!(
+        vm.closure_upvalue(&closure, 2).is_err(),
+        "Should have bounds checked."
+    );
+
+    vm.gc();
+    assert!(vm.num_objs == 0, "Should have collected the closure.");
+    drop(vm);
+}
+
+#[test]
+fn foreign_test() {
+    println!("Foreign Test: Foreign objects trace their references and finalize.");
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    #[derive(Debug)]
+    struct Holder {
+        refs: Vec<GcPtr<Object>>,
+        finalized: Arc<AtomicUsize>,
+    }
+
+    impl Trace for Holder {
+        fn trace(&self, visitor: &mut dyn Visitor) {
+            for obj in &self.refs {
+                visitor.visit(obj);
+            }
+        }
+    }
+
+    impl ForeignObject for Holder {
+        fn finalize(&mut self) {
+            self.finalized.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    let finalized = Arc::new(AtomicUsize::new(0));
+    let mut vm = Vm::new();
+    vm.push_int(1);
+    let one = vm.pop();
+    vm.push_foreign(Holder {
+        refs: vec![one],
+        finalized: finalized.clone(),
+    });
+
+    vm.gc();
+    assert!(
+        vm.num_objs == 2,
+        "Should have reached the traced reference."
+    );
+
+    let holder = vm.stack[0].as_obj().unwrap();
+    vm.foreign_mut::<Holder>(&holder).unwrap().refs.clear();
+    vm.gc();
+    assert!(
+        vm.num_objs == 1,
+        "Should have collected the dropped reference."
+    );
+    assert!(vm.foreign_ref::<Holder>(&holder).is_some());
+
+    vm.pop();
+    vm.gc();
+    assert!(
+        finalized.load(Ordering::Relaxed) == 1,
+        "Should have finalized exactly once."
+    );
+    drop(vm);
+}
+
+#[test]
+fn custom_kind_test() {
+    println!("Custom Kind Test: Runtime-registered kinds trace, size and finalize.");
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    static FINALIZED: AtomicUsize = AtomicUsize::new(0);
+
+    fn trace(data: &dyn Any, visitor: &mut dyn Visitor) {
+        for obj in data.downcast_ref::<Vec<GcPtr<Object>>>().unwrap() {
+            visitor.visit(obj);
+        }
+    }
+    fn size(data: &dyn Any) -> usize {
+        data.downcast_ref::<Vec<GcPtr<Object>>>()
+            .unwrap()
+            .capacity()
+            * 8
+    }
+    fn finalize(_: &mut dyn Any) {
+        FINALIZED.fetch_add(1, Ordering::SeqCst);
+    }
+
+    let mut vm = Vm::new();
+    let list = vm.register_kind(ObjKind {
+        name: "list",
+        trace,
+        size,
+        finalize,
+    });
+    vm.push_int(1);
+    let one = vm.pop();
+    vm.push_custom(list, Box::new(vec![one])).unwrap();
+    assert!(vm.push_custom(KindId(7), Box::new(())).is_err());
+
+    vm.gc();
+    assert!(
+        vm.num_objs == 2,
+        "Should have reached the traced reference."
+    );
+    let obj = vm.pop();
+    assert!(vm.custom_kind(&obj) == Some(list));
+    assert!(obj.value().type_name() == "list");
+    assert!(vm.custom_ref::<Vec<GcPtr<Object>>>(&obj).unwrap().len() == 1);
+
+    vm.gc();
+    assert!(
+        FINALIZED.load(Ordering::SeqCst) == 1,
+        "Should have finalized."
+    );
+    assert!(vm.num_bytes == 0, "Should have released all bytes.");
+    drop(vm);
+}
+
+#[test]
+fn visitor_test() {
+    println!("Visitor Test: Tracing enumerates the children of every kind.");
+    struct Collect(Vec<GcPtr<Object>>);
+    impl Visitor for Collect {
+        fn visit(&mut self, obj: &GcPtr<Object>) {
+            self.0.push(obj.clone());
+        }
+    }
+
+    let mut vm = Vm::new();
+    vm.push_int(1);
+    vm.push_int(2);
+    vm.push_pair();
+    vm.push_int(3);
+    vm.push_array(2);
+    let array = vm.pop();
+
+    let mut children = Collect(vec![]);
+    array.value().trace(&mut children);
+    assert!(children.0.len() == 2, "Should have visited both elements.");
+    let mut grandchildren = Collect(vec![]);
+    children.0[0].value().trace(&mut grandchildren);
+    assert!(
+        grandchildren.0.len() == 2,
+        "Should have visited head and tail."
+    );
+    drop(vm);
+}
+
+#[test]
+fn immediate_test() {
+    println!("Immediate Test: Immediate values stay off the heap.");
+    let mut vm = Vm::new();
+    for i in 0..1000 {
+        for _j in 0..20 {
+            vm.push_value(Value::int(i).unwrap());
+        }
+        for _k in 0..20 {
+            vm.pop_value();
+        }
+    }
+    assert!(vm.num_objs == 0, "Should not have allocated.");
+
+    vm.push_value(Value::int(1).unwrap());
+    vm.push_value(Value::float(2.5));
+    vm.push_pair();
+    vm.gc();
+    assert!(vm.num_objs == 3, "Should have boxed the pair's children.");
+
+    vm.push_value(Value::int(-7).unwrap());
+    let boxed = vm.pop();
+    assert!(
+        matches!(boxed.value(), ObjType::Int(-7)),
+        "Should have boxed the int."
+    );
+    vm.push_value(Value::TRUE);
+    assert!(
+        vm.pop() == vm.true_obj,
+        "Should have boxed to the singleton."
+    );
+    drop(vm);
+}
+
+#[test]
+fn unboxed_ints_test() {
+    println!("Unboxed Ints Test: Ints and their arithmetic stay off the heap.");
+    let mut vm = Vm::with_config(VmConfig {
+        unboxed_ints: true,
+        ..VmConfig::default()
+    });
+    for i in 0..1000 {
+        for _j in 0..20 {
+            vm.push_int(i);
+        }
+        for _k in 0..20 {
+            vm.pop_value();
+        }
+    }
+    assert!(vm.num_objs == 0, "Should not have allocated.");
+
+    vm.push_int(20);
+    vm.push_int(22);
+    vm.add().unwrap();
+    assert!(vm.num_objs == 0, "Should have added unboxed.");
+    assert!(vm.pop_value().as_int() == Some(42));
+
+    vm.push_int(i64::MAX);
+    vm.push_int(1);
+    vm.sub().unwrap();
+    vm.gc();
+    assert!(
+        vm.num_objs == 1,
+        "Should box ints too wide for an immediate."
+    );
+    drop(vm);
+}
+
+#[test]
+fn small_int_cache_test() {
+    println!("Small Int Cache Test: Small ints reuse immortal objects.");
+    let mut vm = Vm::with_config(VmConfig {
+        small_int_cache: true,
+        ..VmConfig::default()
+    });
+    for i in 0..1000 {
+        vm.push_int(i);
+        vm.pop();
+    }
+    assert!(vm.num_objs == 0, "Should have reused cached ints.");
+
+    vm.push_int(7);
+    vm.push_int(7);
+    assert!(vm.pop() == vm.pop(), "Should have reused the same object.");
+
+    vm.push_int(1000);
+    vm.push_int(25);
+    vm.add().unwrap();
+    vm.push_int(SMALL_INT_MAX + 1);
+    vm.gc();
+    assert!(vm.num_objs == 2, "Should only allocate uncached ints.");
+    assert!(matches!(vm.pop().value(), ObjType::Int(1025)));
+    assert!(matches!(vm.pop().value(), ObjType::Int(1025)));
+    drop(vm);
+}
+
+#[test]
+fn hashcons_test() {
+    println!("Hash-consing Test: Identical pairs are shared and weakly held.");
+    let mut vm = Vm::new();
+    for _ in 0..2 {
+        vm.push_int(2);
+        vm.push_int(1);
+        vm.push_pair_hashconsed();
+        vm.push_int(3);
+        vm.push_pair_hashconsed();
+    }
+    let a = vm.pop();
+    let b = vm.pop();
+    assert!(a == b, "Should have shared the identical trees.");
+
+    vm.push_ptr(a);
+    vm.gc();
+    assert!(vm.num_objs == 5, "Should have collected the duplicates.");
+
+    vm.pop();
+    vm.gc();
+    assert!(
+        vm.conses.is_empty(),
+        "Should have dropped dead table entries."
+    );
+    drop(vm);
+}
+
+#[test]
+fn freeze_test() {
+    println!("Freeze Test: Frozen objects reject mutation.");
+    let mut vm = Vm::new();
+    vm.push_int(1);
+    vm.push_array(1);
+    vm.map_new();
+    vm.push_array(2);
+    let outer = vm.pop();
+    let array = vm.array_get(&outer, 0).unwrap();
+    let map = vm.array_get(&outer, 1).unwrap();
+
+    vm.freeze(&array);
+    assert!(vm.array_set(&array, 0, map.clone()) == Err(GcError::FrozenObject));
+    assert!(
+        vm.array_set(&outer, 0, map.clone()).is_ok(),
+        "Should be shallow."
+    );
+
+    vm.freeze_deep(&outer);
+    assert!(vm.is_frozen(&map), "Should have frozen reachable objects.");
+    let key = vm.intern("key");
+    assert!(vm.map_set(&map, &key, key.clone()) == Err(GcError::FrozenObject));
+
+    vm.push_int(1);
+    vm.push_int(2);
+    vm.push_pair_hashconsed();
+    let pair = vm.pop();
+    assert!(vm.is_frozen(&pair), "Hash-consed pairs should be frozen.");
+    drop(vm);
+}
+
+#[test]
+fn shared_objects_immutable_test() {
+    println!("Shared Objects Immutable Test: Immortals and interned objects can't be changed.");
+    let mut vm = Vm::with_config(VmConfig {
+        small_int_cache: true,
+        ..VmConfig::default()
+    });
+    vm.push_nil();
+    vm.push_bool(true);
+    vm.push_int(7);
+    let (int, t, nil) = (vm.peek(0), vm.peek(1), vm.peek(2));
+    let hello = vm.intern("hello");
+    let name = vm.symbol("name");
+    for obj in [&nil, &t, &int, &hello, &name] {
+        assert!(
+            vm.get_mut::<i64>(obj).err() == Some(GcError::FrozenObject),
+            "Should refuse to change {obj:?}."
+        );
+    }
+    vm.push_nil();
+    let pushed = vm.peek(0);
+    assert!(matches!(vm.get(&pushed), ObjType::Nil));
+    vm.push_str("hello");
+    let copy = vm.pop();
+    assert!(
+        vm.get_mut::<String>(&copy).is_ok(),
+        "Should leave strings that aren't interned alone."
+    );
+    drop(vm);
+}
+
+#[test]
+fn user_tag_test() {
+    println!("User Tag Test: Tags are stored per object and survive GC.");
+    let mut vm = Vm::new();
+    vm.push_str("a");
+    vm.push_str("b");
+    let b = vm.pop();
+    let a = vm.pop();
+    vm.push_ptr(a.clone());
+    vm.push_ptr(b.clone());
+    assert!(vm.user_tag(&a) == 0, "Should start out untagged.");
+
+    vm.set_user_tag(&a, 0xdead);
+    vm.freeze(&b);
+    vm.set_user_tag(&b, u32::MAX);
+    vm.gc();
+    assert!(vm.user_tag(&a) == 0xdead && vm.user_tag(&b) == u32::MAX);
+    assert!(
+        vm.is_frozen(&b) && !vm.is_frozen(&a),
+        "Should keep tags and flags apart in the header."
+    );
+    let hash = vm.identity_hash(&a);
+    vm.set_user_tag(&a, 0);
+    assert!(vm.identity_hash(&a) == hash && vm.user_tag(&a) == 0);
+
+    let header = unsafe { a.0.as_ref() }.header;
+    assert!(
+        header.type_tag() == TypeTag::Str && header.user_tag() == 0,
+        "Should keep the type apart from the user tag."
+    );
+    vm.push_list([1]);
+    let list = vm.pop();
+    assert!(unsafe { list.0.as_ref() }.header.type_tag() == TypeTag::Pair);
+    #[cfg(target_pointer_width = "64")]
+    assert!(std::mem::size_of::<Object>() == 80);
+    drop(vm);
+}
+
+#[test]
+fn arithmetic_test() {
+    println!("Arithmetic Test: Mixed int and float arithmetic, checked division.");
+    let mut vm = Vm::new();
+    vm.push_int(7);
+    vm.push_int(-2);
+    vm.div().unwrap();
+    assert!(
+        matches!(vm.pop().value(), ObjType::Int(-3)),
+        "Should truncate."
+    );
+
+    vm.push_int(3);
+    vm.push_float(0.5);
+    vm.mul().unwrap();
+    assert!(matches!(vm.pop().value(), ObjType::Float(f) if *f == 1.5));
+
+    vm.push_int(i64::MIN);
+    vm.push_int(-1);
+    vm.div().unwrap();
+    assert!(
+        matches!(vm.pop().value(), ObjType::BigInt(_)),
+        "Should promote."
+    );
+
+    vm.push_int(1);
+    vm.push_int(0);
+    assert!(vm.div() == Err(GcError::DivisionByZero));
+    vm.push_float(0.0);
+    assert!(vm.div() == Err(GcError::DivisionByZero));
+    vm.push_nil();
+    assert!(vm.sub().is_err(), "Should have rejected nil.");
+    drop(vm);
+}
+
+#[test]
+fn stack_ops_test() {
+    println!("Stack Ops Test: Shuffling keeps every value rooted exactly once.");
+    let mut vm = Vm::new();
+    vm.push_str("a");
+    vm.push_str("b");
+    vm.push_str("c");
+    let c = vm.stack[2].as_obj().unwrap();
+    let a = vm.stack[0].as_obj().unwrap();
+
+    vm.rot();
+    assert!(
+        vm.stack[2].as_obj() == Some(a.clone()),
+        "Should move a to the top."
+    );
+    vm.swap();
+    assert!(
+        vm.stack[2].as_obj() == Some(c.clone()),
+        "Should swap c back up."
+    );
+    vm.over();
+    vm.dup();
+    assert!(vm.stack_size == 5 && vm.stack[4] == vm.stack[3]);
+
+    vm.gc();
+    assert!(vm.num_objs == 3, "Should not have allocated copies.");
+    vm.drop_top();
+    vm.drop_top();
+    vm.drop_top();
+    vm.gc();
+    assert!(vm.num_objs == 2, "Should have collected the dropped value.");
     drop(vm);
 }
 
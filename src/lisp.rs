@@ -0,0 +1,628 @@
+//! A minimal Lisp on top of the VM: a reader that builds s-expressions out of
+//! heap pairs and symbols, and an evaluator that keeps everything it works
+//! on in stack slots, so it can collect whenever the allocation threshold is
+//! hit.
+//!
+//! The special forms are `quote`, `if`, `define`, `lambda` and `begin`, and
+//! [`install`] binds the builtins `+ - * / < = cons car cdr list null?` as
+//! globals. Calls in tail position reuse the caller's slots, so tail-recursive
+//! loops run in constant stack space.
+
+use std::collections::HashSet;
+
+use crate::{Closure, GcError, GcPtr, ObjType, Object, Vm};
+
+/// the code id of closures made by `lambda`; builtins are `1 + ` their index
+/// in `BUILTINS`
+const LAMBDA: usize = 0;
+const BUILTINS: &[&str] = &[
+    "+", "-", "*", "/", "<", "=", "cons", "car", "cdr", "list", "null?",
+];
+/// slots an evaluation step may need on top of what's already pushed
+const STACK_SLACK: usize = 16;
+/// deepest the reader, the evaluator and the printer recurse, whatever the
+/// stack limit, so nesting can't overflow the native stack
+const MAX_DEPTH: usize = 256;
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum LispError {
+    Syntax(&'static str),
+    Unbound(String),
+    NotCallable(&'static str),
+    Arity { expected: usize, found: usize },
+    TooDeep,
+    Vm(GcError),
+}
+
+impl From<GcError> for LispError {
+    fn from(err: GcError) -> Self {
+        LispError::Vm(err)
+    }
+}
+
+/// Binds the builtins as globals.
+pub fn install(vm: &mut Vm) {
+    for (index, name) in BUILTINS.iter().enumerate() {
+        let builtin = vm.alloc(ObjType::Closure(Closure {
+            code_id: 1 + index,
+            upvalues: vec![],
+        }));
+        vm.define_global(name, builtin);
+    }
+}
+
+/// Parses one datum from `src` and pushes it.
+pub fn read(vm: &mut Vm, src: &str) -> Result<(), LispError> {
+    let mut reader = Reader::new(src);
+    let stack_size = vm.stack_size;
+    let result = reader.datum(vm, 0).and_then(|()| match reader.peek() {
+        None => Ok(()),
+        Some(_) => Err(LispError::Syntax("more than one datum")),
+    });
+    if result.is_err() {
+        vm.truncate_stack(stack_size);
+    }
+    result
+}
+
+/// Pops an expression and pushes its value.
+pub fn eval(vm: &mut Vm) -> Result<(), LispError> {
+    let Some(stack_size) = vm.stack_size.checked_sub(1) else {
+        return Err(GcError::StackUnderflow.into());
+    };
+    vm.push_nil();
+    vm.swap();
+    let result = eval_in(vm, 0);
+    if result.is_err() {
+        vm.truncate_stack(stack_size);
+    }
+    result
+}
+
+/// Reads and evaluates every form in `src`, returning the printed value of
+/// the last one. The stack is left as it was.
+pub fn run(vm: &mut Vm, src: &str) -> Result<String, LispError> {
+    let stack_size = vm.stack_size;
+    let mut reader = Reader::new(src);
+    let mut out = "nil".to_owned();
+    while reader.peek().is_some() {
+        let result = reader.datum(vm, 0).and_then(|()| eval(vm));
+        if let Err(err) = result {
+            vm.truncate_stack(stack_size);
+            return Err(err);
+        }
+        let result = vm.pop();
+        out = print(vm, &result);
+    }
+    Ok(out)
+}
+
+/// Formats a value the way the reader would read it back, as far as it can.
+/// A list that contains itself is printed as `...` where it comes round
+/// again, as are lists nested more than `MAX_DEPTH` deep. Panics if `obj`
+/// isn't live in `vm`.
+pub fn print(vm: &Vm, obj: &GcPtr<Object>) -> String {
+    let obj = &vm.assert_live(obj);
+    let mut out = String::new();
+    print_obj(obj, 0, &mut HashSet::new(), &mut out);
+    out
+}
+
+/// Appends `obj`, nested `depth` lists deep, to `out`. `open` holds the
+/// pairs of the lists being printed around it, which reaching again means
+/// going round a cycle.
+fn print_obj(
+    obj: &GcPtr<Object>,
+    depth: usize,
+    open: &mut HashSet<GcPtr<Object>>,
+    out: &mut String,
+) {
+    match obj.value() {
+        ObjType::Nil => out.push_str("nil"),
+        ObjType::Bool(true) => out.push_str("#t"),
+        ObjType::Bool(false) => out.push_str("#f"),
+        ObjType::Int(i) => out.push_str(&i.to_string()),
+        ObjType::BigInt(n) => out.push_str(&n.to_string()),
+        ObjType::Float(f) => out.push_str(&format!("{:?}", f)),
+        ObjType::Str(_) | ObjType::Rope(_) => {
+            out.push_str(&format!("{:?}", crate::string_contents(obj)))
+        }
+        ObjType::Symbol(name) => out.push_str(name),
+        ObjType::Pair(_) if open.contains(obj) || depth >= MAX_DEPTH => out.push_str("..."),
+        ObjType::Pair(_) => {
+            out.push('(');
+            let mut spine = vec![];
+            let mut cur = obj.clone();
+            while let ObjType::Pair(pair) = cur.value() {
+                if !open.insert(cur.clone()) {
+                    break;
+                }
+                if !spine.is_empty() {
+                    out.push(' ');
+                }
+                print_obj(pair.head.as_ref().unwrap(), depth + 1, open, out);
+                spine.push(cur.clone());
+                cur = pair.tail.clone().unwrap();
+            }
+            if !matches!(cur.value(), ObjType::Nil) {
+                out.push_str(" . ");
+                print_obj(&cur, depth + 1, open, out);
+            }
+            for pair in &spine {
+                open.remove(pair);
+            }
+            out.push(')');
+        }
+        ObjType::Closure(_) => out.push_str("#<procedure>"),
+        value => out.push_str(&format!("#<{}>", value.type_name())),
+    }
+}
+
+struct Reader<'a> {
+    src: &'a str,
+}
+
+impl<'a> Reader<'a> {
+    fn new(src: &'a str) -> Self {
+        Self { src }
+    }
+
+    /// Skips whitespace and comments and returns the next character.
+    fn peek(&mut self) -> Option<char> {
+        loop {
+            self.src = self.src.trim_start();
+            if !self.src.starts_with(';') {
+                return self.src.chars().next();
+            }
+            self.src = self.src.find('\n').map_or("", |end| &self.src[end..]);
+        }
+    }
+
+    fn bump(&mut self) {
+        let c = self.src.chars().next().unwrap();
+        self.src = &self.src[c.len_utf8()..];
+    }
+
+    /// Parses a datum nested `depth` lists deep and pushes it.
+    fn datum(&mut self, vm: &mut Vm, depth: usize) -> Result<(), LispError> {
+        if vm.stack_size + 2 >= vm.stack_max {
+            return Err(GcError::StackOverflow.into());
+        }
+        if depth >= MAX_DEPTH {
+            return Err(LispError::Syntax("nested too deeply"));
+        }
+        match self.peek() {
+            None => Err(LispError::Syntax("unexpected end of input")),
+            Some(')') => Err(LispError::Syntax("unexpected ')'")),
+            Some('(') => {
+                self.bump();
+                let mut len = 0;
+                while self.peek() != Some(')') {
+                    self.datum(vm, depth + 1)?;
+                    len += 1;
+                }
+                self.bump();
+                vm.push_nil();
+                push_list(vm, len);
+                Ok(())
+            }
+            Some('\'') => {
+                self.bump();
+                vm.symbol("quote");
+                self.datum(vm, depth + 1)?;
+                vm.push_nil();
+                push_list(vm, 2);
+                Ok(())
+            }
+            Some('"') => {
+                self.bump();
+                let mut s = String::new();
+                let mut chars = self.src.char_indices();
+                loop {
+                    match chars.next() {
+                        None => return Err(LispError::Syntax("unterminated string")),
+                        Some((end, '"')) => {
+                            self.src = &self.src[end + 1..];
+                            break;
+                        }
+                        Some((_, '\\')) => match chars.next() {
+                            Some((_, c @ ('"' | '\\'))) => s.push(c),
+                            Some((_, 'n')) => s.push('\n'),
+                            _ => return Err(LispError::Syntax("bad escape")),
+                        },
+                        Some((_, c)) => s.push(c),
+                    }
+                }
+                vm.push_str(&s);
+                Ok(())
+            }
+            Some(_) => {
+                let end = self
+                    .src
+                    .find(|c: char| c.is_whitespace() || "()';\"".contains(c))
+                    .unwrap_or(self.src.len());
+                let atom = &self.src[..end];
+                self.src = &self.src[end..];
+                if let Ok(i) = atom.parse() {
+                    vm.push_int(i);
+                } else if let Ok(f) = atom.parse::<f64>() {
+                    vm.push_float(f);
+                } else {
+                    match atom {
+                        "nil" => vm.push_nil(),
+                        "#t" => vm.push_bool(true),
+                        "#f" => vm.push_bool(false),
+                        _ => {
+                            vm.symbol(atom);
+                        }
+                    }
+                }
+                Ok(())
+            }
+        }
+    }
+}
+
+/// Turns `len` values pushed on top of a tail into a list of them, the
+/// deepest first.
+fn push_list(vm: &mut Vm, len: usize) {
+    for _ in 0..len {
+        vm.swap();
+        vm.push_pair();
+    }
+}
+
+fn pair(obj: &GcPtr<Object>) -> Result<(GcPtr<Object>, GcPtr<Object>), LispError> {
+    match obj.value() {
+        ObjType::Pair(pair) => Ok((pair.head.clone().unwrap(), pair.tail.clone().unwrap())),
+        _ => Err(LispError::Syntax("expected a list")),
+    }
+}
+
+/// Returns element `index` of the list `obj`.
+fn nth(obj: &GcPtr<Object>, index: usize) -> Result<GcPtr<Object>, LispError> {
+    let (mut head, mut tail) = pair(obj)?;
+    for _ in 0..index {
+        (head, tail) = pair(&tail)?;
+    }
+    Ok(head)
+}
+
+fn list_len(obj: &GcPtr<Object>) -> usize {
+    let mut len = 0;
+    let mut cur = obj.clone();
+    while let Ok((_, tail)) = pair(&cur) {
+        len += 1;
+        cur = tail;
+    }
+    len
+}
+
+fn is_truthy(obj: &GcPtr<Object>) -> bool {
+    !matches!(obj.value(), ObjType::Nil | ObjType::Bool(false))
+}
+
+/// Looks `name` up in the environment `env`, an association list of
+/// `(symbol . value)` pairs, and then in the globals.
+fn lookup(vm: &Vm, env: &GcPtr<Object>, name: &str) -> Result<GcPtr<Object>, LispError> {
+    let mut cur = env.clone();
+    while let Ok((binding, rest)) = pair(&cur) {
+        let (symbol, value) = pair(&binding)?;
+        if matches!(symbol.value(), ObjType::Symbol(s) if s == name) {
+            return Ok(value);
+        }
+        cur = rest;
+    }
+    vm.get_global(name)
+        .ok_or_else(|| LispError::Unbound(name.to_owned()))
+}
+
+/// Evaluates the expression on top of the stack in the environment below
+/// it, replacing both with the value. `depth` counts the evaluations this
+/// one is nested in.
+fn eval_in(vm: &mut Vm, depth: usize) -> Result<(), LispError> {
+    if depth >= MAX_DEPTH {
+        return Err(LispError::TooDeep);
+    }
+    loop {
+        vm.collect_if_due();
+        if vm.stack_size + STACK_SLACK > vm.stack_max {
+            return Err(GcError::StackOverflow.into());
+        }
+        let base = vm.stack_size - 2;
+        let env = vm.peek(1);
+        let expr = vm.peek(0);
+        let value = match expr.value() {
+            ObjType::Symbol(name) => lookup(vm, &env, name)?,
+            ObjType::Pair(_) => {
+                let (head, args) = pair(&expr)?;
+                let form = match head.value() {
+                    ObjType::Symbol(name) => name.as_str(),
+                    _ => "",
+                };
+                match form {
+                    "quote" => nth(&expr, 1)?,
+                    "if" => {
+                        vm.push_slot(vm.stack[base]);
+                        vm.push_ptr(nth(&expr, 1)?);
+                        eval_in(vm, depth + 1)?;
+                        let cond = vm.pop();
+                        let branch = if is_truthy(&cond) {
+                            nth(&expr, 2)?
+                        } else if list_len(&expr) > 3 {
+                            nth(&expr, 3)?
+                        } else {
+                            vm.nil.clone()
+                        };
+                        vm.stack[base + 1] = vm.to_value(branch);
+                        continue;
+                    }
+                    "define" => {
+                        let name = match nth(&expr, 1)?.value() {
+                            ObjType::Symbol(name) => name.clone(),
+                            _ => return Err(LispError::Syntax("define needs a symbol")),
+                        };
+                        vm.push_slot(vm.stack[base]);
+                        vm.push_ptr(nth(&expr, 2)?);
+                        eval_in(vm, depth + 1)?;
+                        let value = vm.pop();
+                        vm.define_global(&name, value.clone());
+                        value
+                    }
+                    "lambda" => {
+                        vm.push_ptr(nth(&expr, 1)?);
+                        vm.push_ptr(nth(&expr, 2)?);
+                        vm.push_slot(vm.stack[base]);
+                        vm.push_closure(LAMBDA, 3);
+                        vm.pop()
+                    }
+                    "begin" => {
+                        let mut body = args;
+                        let mut last = vm.nil.clone();
+                        while let Ok((form, rest)) = pair(&body) {
+                            if pair(&rest).is_err() {
+                                last = form;
+                                break;
+                            }
+                            vm.push_slot(vm.stack[base]);
+                            vm.push_ptr(form);
+                            eval_in(vm, depth + 1)?;
+                            vm.pop_value();
+                            body = rest;
+                        }
+                        vm.stack[base + 1] = vm.to_value(last);
+                        continue;
+                    }
+                    _ => {
+                        // the callee and the arguments land above the
+                        // expression, which keeps the argument list alive
+                        let mut argc = 0;
+                        let mut rest = args;
+                        vm.push_slot(vm.stack[base]);
+                        vm.push_ptr(head);
+                        eval_in(vm, depth + 1)?;
+                        while let Ok((arg, tail)) = pair(&rest) {
+                            vm.push_slot(vm.stack[base]);
+                            vm.push_ptr(arg);
+                            eval_in(vm, depth + 1)?;
+                            argc += 1;
+                            rest = tail;
+                        }
+                        if apply(vm, base, argc)? {
+                            continue;
+                        }
+                        return Ok(());
+                    }
+                }
+            }
+            _ => expr,
+        };
+        vm.truncate_stack(base);
+        vm.push_ptr(value);
+        return Ok(());
+    }
+}
+
+/// Calls the callee at `base + 2` on the `argc` values above it. Builtins
+/// leave their result in place of everything from `base` up and return
+/// `false`; lambdas set up their body and environment at `base` for the
+/// caller to evaluate and return `true`.
+fn apply(vm: &mut Vm, base: usize, argc: usize) -> Result<bool, LispError> {
+    let callee = vm.box_value(vm.stack[base + 2]);
+    let closure = match callee.value() {
+        ObjType::Closure(closure) => closure,
+        value => return Err(LispError::NotCallable(value.type_name())),
+    };
+    if closure.code_id != LAMBDA {
+        builtin(vm, closure.code_id - 1, argc)?;
+        let result = vm.pop_value();
+        vm.truncate_stack(base);
+        vm.push_slot(result);
+        return Ok(false);
+    }
+
+    let (params, body, env) = match &closure.upvalues[..] {
+        [params, body, env] => (params.clone(), body.clone(), env.clone()),
+        _ => unreachable!("lambdas capture their params, body and env"),
+    };
+    let expected = list_len(&params);
+    if expected != argc {
+        return Err(LispError::Arity {
+            expected,
+            found: argc,
+        });
+    }
+    vm.push_ptr(env);
+    let mut cur = params;
+    for i in 0..argc {
+        let (param, rest) = pair(&cur)?;
+        if !matches!(param.value(), ObjType::Symbol(_)) {
+            return Err(LispError::Syntax("parameters must be symbols"));
+        }
+        vm.push_slot(vm.stack[base + 3 + i]);
+        vm.push_ptr(param);
+        vm.push_pair();
+        vm.push_pair();
+        cur = rest;
+    }
+    let env = vm.pop_value();
+    vm.stack[base] = env;
+    vm.stack[base + 1] = vm.to_value(body);
+    vm.truncate_stack(base + 2);
+    Ok(true)
+}
+
+/// Runs builtin `index` on the top `argc` values, pushing its result above
+/// them.
+fn builtin(vm: &mut Vm, index: usize, argc: usize) -> Result<(), LispError> {
+    let name = BUILTINS[index];
+    let expected = match name {
+        "list" => argc,
+        "car" | "cdr" | "null?" => 1,
+        _ => 2,
+    };
+    if argc != expected {
+        return Err(LispError::Arity {
+            expected,
+            found: argc,
+        });
+    }
+    // work on copies of the arguments, which stay where they are
+    for _ in 0..argc {
+        vm.push_slot(vm.stack[vm.stack_size - argc]);
+    }
+    match name {
+        "+" => vm.add()?,
+        "-" => vm.sub()?,
+        "*" => vm.mul()?,
+        "/" => vm.div()?,
+        "<" => vm.less_than()?,
+        "=" => {
+            let b = vm.peek(0);
+            let a = vm.peek(1);
+            let eq = vm.values_eq(&a, &b);
+            vm.truncate_stack(vm.stack_size - 2);
+            vm.push_bool(eq);
+        }
+        "cons" => {
+            vm.swap();
+            vm.push_pair();
+        }
+        "car" | "cdr" => {
+            let obj = vm.pop();
+            let (head, tail) = pair(&obj).map_err(|_| GcError::TypeMismatch {
+                expected: "pair",
+                found: obj.value().type_name(),
+            })?;
+            vm.push_ptr(if name == "car" { head } else { tail });
+        }
+        "list" => {
+            vm.push_nil();
+            push_list(vm, argc);
+        }
+        "null?" => {
+            let nil = matches!(vm.pop().value(), ObjType::Nil);
+            vm.push_bool(nil);
+        }
+        _ => unreachable!(),
+    }
+    Ok(())
+}
+
+#[test]
+fn lisp_test() {
+    println!("Lisp Test: S-expressions evaluate while the collector runs.");
+    let mut vm = Vm::new();
+    install(&mut vm);
+    let run = |vm: &mut Vm, src| run(vm, src).unwrap();
+    assert!(run(&mut vm, "(car (cdr '(1 2 3)))") == "2");
+    assert!(run(&mut vm, "(list 1 \"a\" 'b (cons 2 3))") == "(1 \"a\" b (2 . 3))");
+    assert!(run(&mut vm, "(define sq (lambda (x) (* x x))) (sq 7)") == "49");
+    assert!(
+        run(
+            &mut vm,
+            "; factorial
+            (define fact (lambda (n) (if (< n 2) 1 (* n (fact (- n 1))))))
+            (fact 20)"
+        ) == "2432902008176640000"
+    );
+
+    // a tail-recursive loop allocating cons cells as it goes
+    let out = run(
+        &mut vm,
+        "(define count (lambda (n acc)
+           (if (= n 0) (car acc) (count (- n 1) (cons (+ (car acc) 1) nil)))))
+         (count 2000 '(0))",
+    );
+    assert!(out == "2000", "Should have looped in constant stack space.");
+    assert!(vm.stack_size == 0);
+    vm.gc();
+    assert!(
+        vm.num_objs < 100,
+        "Should have collected the loop's garbage."
+    );
+
+    assert!(
+        super::lisp::run(&mut vm, "(undefined 1)") == Err(LispError::Unbound("undefined".into()))
+    );
+    assert!(super::lisp::run(&mut vm, "(sq 1 2)").is_err());
+    assert!(super::lisp::run(&mut vm, "(1 2)") == Err(LispError::NotCallable("int")));
+    assert!(super::lisp::run(&mut vm, "(car 1)").is_err());
+    assert!(read(&mut vm, "(1 2").is_err() && read(&mut vm, "1 2").is_err());
+    assert!(
+        vm.stack_size == 0,
+        "Errors should leave the stack as it was."
+    );
+    drop(vm);
+}
+
+#[test]
+fn lisp_limits_test() {
+    use crate::VmConfig;
+
+    println!("Lisp Limits Test: Deep nesting and cycles fail or print, never crash.");
+    let mut vm = Vm::with_config(VmConfig {
+        max_stack: Some(1_000_000),
+        ..VmConfig::default()
+    });
+    install(&mut vm);
+    assert!(
+        eval(&mut vm) == Err(LispError::Vm(GcError::StackUnderflow)),
+        "Should not evaluate what isn't there."
+    );
+
+    let deep = "(".repeat(100_000) + &")".repeat(100_000);
+    assert!(read(&mut vm, &deep) == Err(LispError::Syntax("nested too deeply")));
+    let quotes = "'".repeat(100_000) + "x";
+    assert!(read(&mut vm, &quotes).is_err());
+    assert!(
+        run(
+            &mut vm,
+            "(define down (lambda (n) (if (= n 0) 0 (+ 1 (down (- n 1))))))
+             (down 100)"
+        ) == Ok("100".to_owned())
+    );
+    assert!(run(&mut vm, "(down 100000)") == Err(LispError::TooDeep));
+    assert!(vm.stack_size == 0);
+
+    let ring = vm.push_list([3]);
+    vm.set_tail(&ring, ring.clone()).unwrap();
+    assert!(print(&vm, &ring) == "(3 . ...)");
+    let inner = vm.push_list([4]);
+    vm.set_head(&inner, ring.clone()).unwrap();
+    assert!(print(&vm, &inner) == "((3 . ...))");
+    vm.set_head(&inner, inner.clone()).unwrap();
+    assert!(print(&vm, &inner) == "(...)");
+    let pairs: Vec<_> = (0..300).map(|i| vm.push_list([i])).collect();
+    let long = vm.push_list_of(pairs);
+    assert!(
+        print(&vm, &long).ends_with("(298) (299))"),
+        "Should print long lists whole."
+    );
+    let shared = vm.push_list_of([ring.clone(), ring.clone()]);
+    assert!(
+        print(&vm, &shared) == "((3 . ...) (3 . ...))",
+        "Should only cut off cycles, not sharing."
+    );
+    drop(vm);
+}
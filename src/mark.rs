@@ -0,0 +1,75 @@
+//! Marking: everything an object reaches is marked by working through a
+//! list of objects still to visit, rather than by recursing, so a long chain
+//! of pairs can't overflow the native stack. Objects are marked as they're
+//! taken off the list, not as they're put on it, so putting one on doesn't
+//! touch it; the one a few places down is prefetched meanwhile, so its
+//! header is in cache by the time it's taken off.
+
+use crate::{GcPtr, Object, Trace, Visitor};
+
+/// How many objects down the list the one prefetched is. Far enough for
+/// the load to land before it's taken off, near enough that it's still in
+/// cache then.
+const PREFETCH_DISTANCE: usize = 4;
+
+/// Objects reached but not visited yet.
+struct Pending(Vec<GcPtr<Object>>);
+
+impl Visitor for Pending {
+    fn visit(&mut self, obj: &GcPtr<Object>) {
+        self.0.push(obj.clone());
+    }
+}
+
+impl GcPtr<Object> {
+    /// Marks the object and everything it reaches that isn't marked yet.
+    pub(crate) unsafe fn mark(&mut self) {
+        let mut pending = Pending(vec![self.clone()]);
+        while let Some(mut obj) = pending.0.pop() {
+            if let Some(ahead) = pending.0.len().checked_sub(PREFETCH_DISTANCE) {
+                prefetch(&pending.0[ahead]);
+            }
+            // marked objects include those of mounted segments, which other
+            // threads read meanwhile, so only unmarked ones are written to
+            if obj.is_marked() {
+                continue;
+            }
+            obj.set_marked();
+            // the header says whether there's anything to trace, so leaves'
+            // values are never read
+            if obj.0.as_ref().header.type_tag().traces() {
+                obj.value().trace(&mut pending);
+            }
+        }
+    }
+}
+
+/// Asks for the cache line `obj`'s header is in, without waiting for it.
+#[inline(always)]
+fn prefetch(obj: &GcPtr<Object>) {
+    #[cfg(target_arch = "x86_64")]
+    unsafe {
+        use std::arch::x86_64::{_mm_prefetch, _MM_HINT_T0};
+        _mm_prefetch::<_MM_HINT_T0>(obj.0.as_ptr().cast());
+    }
+    // elsewhere the intrinsics aren't stable yet
+    #[cfg(not(target_arch = "x86_64"))]
+    let _ = obj;
+}
+
+#[test]
+fn deep_mark_test() {
+    use crate::Vm;
+
+    println!("Deep Mark Test: Long chains are marked without recursing.");
+    let mut vm = Vm::new();
+    vm.push_list(0..200_000);
+    vm.gc();
+    assert!(
+        vm.num_objs == 400_000,
+        "Should have kept every pair and its int."
+    );
+    vm.clear_stack();
+    vm.gc();
+    assert!(vm.num_objs == 0);
+}
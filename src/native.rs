@@ -0,0 +1,132 @@
+use crate::{GcError, GcPtr, ObjType, Object, Vm};
+
+/// A host function callable from VM code. It returns the object the call
+/// evaluates to.
+pub type NativeFn = fn(&mut NativeCtx) -> Result<GcPtr<Object>, GcError>;
+
+/// What a native function gets to work with while it runs.
+///
+/// The arguments stay in their stack slots for the whole call and everything
+/// allocated through the context is pushed above them, so all of it is rooted
+/// even when an allocation triggers a collection. Those slots are released
+/// once the call returns.
+pub struct NativeCtx<'a> {
+    vm: &'a mut Vm,
+    base: usize,
+    argc: usize,
+}
+
+impl NativeCtx<'_> {
+    pub fn argc(&self) -> usize {
+        self.argc
+    }
+
+    /// Returns argument `index`, the first one being the deepest on the stack.
+    pub fn arg(&mut self, index: usize) -> Result<GcPtr<Object>, GcError> {
+        if index >= self.argc {
+            return Err(GcError::IndexOutOfBounds {
+                index,
+                len: self.argc,
+            });
+        }
+        let depth = self.vm.stack_size - 1 - (self.base + index);
+        Ok(self.vm.peek(depth))
+    }
+
+    /// Allocates an object that stays rooted until the call returns,
+    /// collecting first if the allocation threshold has been reached or the
+    /// VM's memory budget asks for a collection.
+    pub fn alloc(&mut self, value: ObjType) -> GcPtr<Object> {
+        self.vm.collect_if_due();
+        self.vm.push(value);
+        self.vm.peek(0)
+    }
+}
+
+impl Vm {
+    /// Makes `f` callable as `name`, replacing any native already registered
+    /// under that name.
+    pub fn register_native(&mut self, name: &str, f: NativeFn) {
+        self.natives.insert(name.to_owned(), f);
+    }
+
+    /// Calls the native `name` with the top `argc` values as its arguments,
+    /// replacing them with its result. Natives can do anything, so a
+    /// recording VM refuses with `GcError::Unrecordable`.
+    pub fn call_native(&mut self, name: &str, argc: usize) -> Result<(), GcError> {
+        self.refuse_recording("call_native")?;
+        let f = *self
+            .natives
+            .get(name)
+            .ok_or_else(|| GcError::UnknownNative(name.to_owned()))?;
+        if argc > self.stack_size {
+            return Err(GcError::StackUnderflow);
+        }
+        let base = self.stack_size - argc;
+        let result = f(&mut NativeCtx {
+            vm: self,
+            base,
+            argc,
+        });
+        self.truncate_stack(base);
+        self.push_ptr(result?);
+        Ok(())
+    }
+}
+
+#[test]
+fn native_test() {
+    fn list(ctx: &mut NativeCtx) -> Result<GcPtr<Object>, GcError> {
+        // builds a fresh list of the arguments and a pile of garbage, so the
+        // allocations collect while the arguments are still in use
+        let mut list = ctx.alloc(ObjType::Nil);
+        for index in (0..ctx.argc()).rev() {
+            for i in 0..20 {
+                ctx.alloc(ObjType::Int(i));
+            }
+            let value = ctx.arg(index)?;
+            list = ctx.alloc(ObjType::Pair(crate::Pair {
+                head: Some(value),
+                tail: Some(list),
+            }));
+        }
+        Ok(list)
+    }
+
+    println!("Native Test: Natives keep their arguments alive across GCs.");
+    let mut vm = Vm::new();
+    vm.register_native("list", list);
+    vm.push_str("a");
+    vm.push_str("b");
+    vm.push_str("c");
+    vm.call_native("list", 3).unwrap();
+    assert!(vm.stack_size == 1, "Should have replaced the arguments.");
+
+    let list = vm.pop();
+    let mut names = vec![];
+    let mut cur = list.clone();
+    while let ObjType::Pair(pair) = cur.value() {
+        names.push(crate::string_contents(pair.head.as_ref().unwrap()));
+        cur = pair.tail.clone().unwrap();
+    }
+    assert!(names == ["a", "b", "c"], "Should have kept every argument.");
+
+    vm.execute(&[
+        crate::Op::PushInt(1),
+        crate::Op::CallNative {
+            name: "list".into(),
+            argc: 1,
+        },
+    ])
+    .unwrap();
+    assert!(matches!(vm.pop().value(), ObjType::Pair(_)));
+    assert!(vm.call_native("nope", 0) == Err(GcError::UnknownNative("nope".into())));
+
+    let mut ctx = NativeCtx {
+        vm: &mut vm,
+        base: 0,
+        argc: 0,
+    };
+    assert!(ctx.arg(0).is_err(), "Should not reach below the arguments.");
+    drop(vm);
+}
@@ -0,0 +1,91 @@
+//! Collecting when the host is short of memory, so a VM embedded in a
+//! daemon gives back what it can before a container's limit is hit, rather
+//! than only once its own heap has doubled.
+
+use crate::Vm;
+
+/// Returns whether the host is under memory pressure. [`cgroup_pressure`]
+/// is one; embedders with other signals, such as `sysinfo` or a host
+/// runtime's notifications, can pass their own.
+pub type PressureCheck = fn() -> bool;
+
+/// Allocations between two pressure checks, since a check may read files.
+const PRESSURE_INTERVAL: usize = 1024;
+
+/// Whether the cgroup this process runs in is using nine tenths or more of
+/// its memory limit. Understands cgroup v2, falling back to v1; without a
+/// cgroup, or with no limit set, there's never pressure.
+pub fn cgroup_pressure() -> bool {
+    let read =
+        |path: &str| -> Option<u64> { std::fs::read_to_string(path).ok()?.trim().parse().ok() };
+    let usage = read("/sys/fs/cgroup/memory.current")
+        .zip(read("/sys/fs/cgroup/memory.max"))
+        .or_else(|| {
+            read("/sys/fs/cgroup/memory/memory.usage_in_bytes")
+                .zip(read("/sys/fs/cgroup/memory/memory.limit_in_bytes"))
+        });
+    // an unlimited v2 cgroup's limit is "max", which doesn't parse
+    usage.is_some_and(|(current, limit)| current >= limit / 10 * 9)
+}
+
+impl Vm {
+    /// Polls `check` every 1024 allocations at safepoints, including
+    /// [`Vm::safepoint`] for embedders pushing and popping directly,
+    /// collecting when it reports pressure. Such a collection also gives back the memory of
+    /// everything quarantined, which the VM would otherwise hold on to for a
+    /// while. `None`, the default, stops checking.
+    pub fn set_pressure_check(&mut self, check: Option<PressureCheck>) {
+        self.pressure_check = check.map(|check| (check, self.allocations));
+    }
+
+    /// Polls the pressure check if it's due, returning whether it reported
+    /// pressure.
+    pub(crate) fn under_pressure(&mut self) -> bool {
+        let Some((check, checked_at)) = &mut self.pressure_check else {
+            return false;
+        };
+        if self.allocations - *checked_at < PRESSURE_INTERVAL {
+            return false;
+        }
+        *checked_at = self.allocations;
+        self.pressured |= check();
+        self.pressured
+    }
+
+    /// After a collection asked for by pressure, gives back what the VM was
+    /// holding on to.
+    pub(crate) fn relieve_pressure(&mut self) {
+        if std::mem::take(&mut self.pressured) {
+            self.quarantine.clear(&mut self.chunks);
+        }
+    }
+}
+
+#[test]
+fn pressure_test() {
+    use std::sync::atomic::{AtomicBool, Ordering};
+
+    static PRESSURE: AtomicBool = AtomicBool::new(false);
+
+    println!("Pressure Test: Collections happen when the host runs short.");
+    let mut vm = Vm::new();
+    vm.set_pressure_check(Some(|| PRESSURE.load(Ordering::Relaxed)));
+    vm.max_objs = usize::MAX;
+    for _ in 0..PRESSURE_INTERVAL {
+        vm.push_str("garbage");
+        vm.drop_top();
+    }
+    assert!(!vm.safepoint(), "Should not collect without pressure.");
+
+    PRESSURE.store(true, Ordering::Relaxed);
+    assert!(!vm.safepoint(), "Should only check every so often.");
+    for _ in 0..PRESSURE_INTERVAL {
+        vm.push_str("garbage");
+        vm.drop_top();
+    }
+    assert!(vm.safepoint(), "Should collect under pressure.");
+    assert!(
+        vm.num_objs == 0 && vm.quarantined() == 0,
+        "Should have given everything back."
+    );
+}
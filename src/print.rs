@@ -0,0 +1,156 @@
+use std::collections::{HashMap, HashSet};
+
+use crate::{GcPtr, ObjType, Object, Vm};
+
+/// Writes a graph out, labelling every object it reaches more than once.
+struct Printer {
+    /// the pairs and arrays reachable along more than one path
+    shared: HashSet<GcPtr<Object>>,
+    /// label of each shared object written so far
+    labels: HashMap<GcPtr<Object>, usize>,
+    out: String,
+}
+
+impl Printer {
+    fn new(root: &GcPtr<Object>) -> Self {
+        let mut seen = HashSet::new();
+        let mut shared = HashSet::new();
+        let mut pending = vec![root.clone()];
+        while let Some(obj) = pending.pop() {
+            let children: Vec<_> = match obj.value() {
+                ObjType::Pair(pair) => pair.head.iter().chain(&pair.tail).cloned().collect(),
+                ObjType::Array(elems) => elems.clone(),
+                _ => continue,
+            };
+            if !seen.insert(obj.clone()) {
+                shared.insert(obj);
+                continue;
+            }
+            pending.extend(children);
+        }
+        Self {
+            shared,
+            labels: HashMap::new(),
+            out: String::new(),
+        }
+    }
+
+    fn write(&mut self, obj: &GcPtr<Object>) {
+        if self.shared.contains(obj) {
+            if let Some(label) = self.labels.get(obj) {
+                self.out += &format!("#{}#", label);
+                return;
+            }
+            let label = self.labels.len();
+            self.labels.insert(obj.clone(), label);
+            self.out += &format!("#{}=", label);
+        }
+        match obj.value() {
+            ObjType::Nil => self.out += "nil",
+            ObjType::Bool(true) => self.out += "#t",
+            ObjType::Bool(false) => self.out += "#f",
+            ObjType::Int(i) => self.out += &i.to_string(),
+            ObjType::BigInt(n) => self.out += &n.to_string(),
+            ObjType::Float(f) => self.out += &format!("{:?}", f),
+            ObjType::Str(_) | ObjType::Rope(_) => {
+                self.out += &format!("{:?}", crate::string_contents(obj))
+            }
+            ObjType::Symbol(name) => self.out += name,
+            ObjType::Pair(_) => self.write_list(obj),
+            ObjType::Array(elems) => {
+                self.out += "#(";
+                for (i, elem) in elems.iter().enumerate() {
+                    if i > 0 {
+                        self.out.push(' ');
+                    }
+                    self.write(elem);
+                }
+                self.out.push(')');
+            }
+            value => self.out += &format!("#<{}>", value.type_name()),
+        }
+    }
+
+    /// Writes the list starting at `pair`, following its tail for as long as
+    /// that's an unshared pair; anything else ends it in dotted notation.
+    fn write_list(&mut self, pair: &GcPtr<Object>) {
+        self.out.push('(');
+        let mut cur = pair.clone();
+        loop {
+            let (head, tail) = match cur.value() {
+                ObjType::Pair(pair) => (pair.head.clone(), pair.tail.clone()),
+                _ => unreachable!(),
+            };
+            match head {
+                Some(head) => self.write(&head),
+                None => self.out += "nil",
+            }
+            match tail {
+                Some(tail)
+                    if matches!(tail.value(), ObjType::Pair(_)) && !self.shared.contains(&tail) =>
+                {
+                    self.out.push(' ');
+                    cur = tail;
+                }
+                Some(tail) if !matches!(tail.value(), ObjType::Nil) => {
+                    self.out += " . ";
+                    self.write(&tail);
+                    break;
+                }
+                _ => break,
+            }
+        }
+        self.out.push(')');
+    }
+}
+
+impl Vm {
+    /// Formats `obj` in Lisp notation, such as `(1 2 . 3)` for a list
+    /// ending in 3 and `#(1 2)` for an array. Pairs and arrays reachable more
+    /// than once are labelled `#n=` where they first appear and written as
+    /// `#n#` afterwards, so shared and cyclic structure prints in full and in
+    /// finite space.
+    pub fn display(&self, obj: &GcPtr<Object>) -> String {
+        let obj = &self.assert_live(obj);
+        let mut printer = Printer::new(obj);
+        printer.write(obj);
+        printer.out
+    }
+}
+
+#[test]
+fn display_test() {
+    println!("Display Test: Shared and cyclic structure prints with labels.");
+    let mut vm = Vm::new();
+    vm.push_int(3);
+    vm.push_int(2);
+    vm.push_pair();
+    vm.push_int(1);
+    vm.push_pair();
+    let dotted = vm.peek(0);
+    assert!(vm.display(&dotted) == "(1 2 . 3)");
+
+    let list = vm.push_list([1, 2]);
+    vm.push_ptr(list.clone());
+    vm.push_array(2);
+    let array = vm.peek(0);
+    assert!(
+        vm.display(&array) == "#(#0=(1 2) #0#)",
+        "Should label sharing."
+    );
+
+    let ring = vm.push_list([1, 2, 3]);
+    let mut last = ring.clone();
+    for _ in 0..2 {
+        last = last.as_pair(&vm).unwrap().1.unwrap();
+    }
+    vm.set_tail(&last, ring.clone()).unwrap();
+    assert!(
+        vm.display(&ring) == "#0=(1 2 3 . #0#)",
+        "Should end cycles."
+    );
+
+    vm.set_head(&ring, ring.clone()).unwrap();
+    assert!(vm.display(&ring) == "#0=(#0# 2 3 . #0#)");
+    drop(vm);
+}
@@ -0,0 +1,470 @@
+use crate::{GcError, Op, Vm};
+
+const MAGIC: &[u8; 4] = b"GCBC";
+const VERSION: u8 = 1;
+/// Deepest a decoded constant may nest pairs. Constants are trees of boxes,
+/// so decoding and dropping one recurses once per level.
+const MAX_CONSTANT_DEPTH: usize = 1024;
+
+/// A literal in a program's constant pool, described without reference to
+/// any heap, so programs can be built and shipped before there's a VM.
+#[derive(Clone, Debug, PartialEq)]
+pub enum Constant {
+    Nil,
+    Bool(bool),
+    Int(i64),
+    Float(f64),
+    Str(String),
+    Symbol(String),
+    Pair(Box<Constant>, Box<Constant>),
+}
+
+/// Compiled bytecode plus the constants its `Op::PushConst`s index into.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Program {
+    pub code: Vec<Op>,
+    pub constants: Vec<Constant>,
+}
+
+impl Program {
+    /// Encodes the program as a magic number and format version followed by
+    /// the constants and the ops. Integers are LEB128 varints (zigzagged if
+    /// signed), floats their little-endian bits and strings length-prefixed
+    /// UTF-8.
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut out = Writer(MAGIC.to_vec());
+        out.0.push(VERSION);
+        out.uint(self.constants.len());
+        for constant in &self.constants {
+            out.constant(constant);
+        }
+        out.uint(self.code.len());
+        for op in &self.code {
+            out.op(op);
+        }
+        out.0
+    }
+
+    /// Decodes a program written by [`Program::to_bytes`], rejecting
+    /// anything malformed: bad headers, truncated or trailing data, jumps
+    /// past the end of the code, constant indices past the end of the pool
+    /// and constants nesting pairs more than 1024 deep.
+    pub fn from_bytes(bytes: &[u8]) -> Result<Program, GcError> {
+        let mut input = Reader(bytes);
+        if input.take(4)? != MAGIC {
+            return Err(GcError::InvalidBytecode("bad magic number"));
+        }
+        if input.byte()? != VERSION {
+            return Err(GcError::InvalidBytecode("unsupported version"));
+        }
+        let mut program = Program::default();
+        for _ in 0..input.uint()? {
+            program.constants.push(input.constant(0)?);
+        }
+        for _ in 0..input.uint()? {
+            program.code.push(input.op()?);
+        }
+        if !input.0.is_empty() {
+            return Err(GcError::InvalidBytecode("trailing bytes"));
+        }
+        program.validate()?;
+        Ok(program)
+    }
+
+    fn validate(&self) -> Result<(), GcError> {
+        for op in &self.code {
+            match op {
+                Op::Jump(target)
+                | Op::JumpIfFalse(target)
+                | Op::Call { target, .. }
+                | Op::TailCall { target, .. }
+                | Op::Try(target)
+                    if *target > self.code.len() =>
+                {
+                    return Err(GcError::InvalidBytecode("jump out of range"));
+                }
+                Op::PushConst(index) if *index >= self.constants.len() => {
+                    return Err(GcError::InvalidBytecode("constant out of range"));
+                }
+                _ => {}
+            }
+        }
+        Ok(())
+    }
+}
+
+impl Vm {
+    /// Allocates the program's constants into the constant pool and returns
+    /// its code with `Op::PushConst` indices pointing at them, ready for
+    /// [`Vm::execute`].
+    pub fn load(&mut self, program: &Program) -> Vec<Op> {
+        let base = self.constants.len();
+        for constant in &program.constants {
+            self.push_constant(constant);
+            let obj = self.pop();
+            self.add_constant(obj);
+        }
+        program
+            .code
+            .iter()
+            .map(|op| match op {
+                Op::PushConst(index) => Op::PushConst(base + index),
+                op => op.clone(),
+            })
+            .collect()
+    }
+
+    /// Pushes the object for `constant`. Pairs are built from a list of
+    /// what's left to do rather than by recursing, since a program built in
+    /// Rust can nest them arbitrarily deep.
+    fn push_constant(&mut self, constant: &Constant) {
+        enum Task<'a> {
+            Push(&'a Constant),
+            MakePair,
+        }
+
+        let mut tasks = vec![Task::Push(constant)];
+        while let Some(task) = tasks.pop() {
+            match task {
+                Task::Push(Constant::Nil) => self.push_nil(),
+                Task::Push(Constant::Bool(b)) => self.push_bool(*b),
+                Task::Push(Constant::Int(i)) => self.push_int(*i),
+                Task::Push(Constant::Float(f)) => self.push_float(*f),
+                Task::Push(Constant::Str(s)) => {
+                    self.intern(s);
+                }
+                Task::Push(Constant::Symbol(name)) => {
+                    self.symbol(name);
+                }
+                // the tail comes off the list first and the head next, as
+                // `push_pair` wants them
+                Task::Push(Constant::Pair(head, tail)) => {
+                    tasks.extend([Task::MakePair, Task::Push(head), Task::Push(tail)]);
+                }
+                Task::MakePair => self.push_pair(),
+            }
+        }
+    }
+}
+
+pub(crate) struct Writer(pub(crate) Vec<u8>);
+
+impl Writer {
+    pub(crate) fn uint(&mut self, value: usize) {
+        let mut value = value as u64;
+        loop {
+            let byte = (value & 0x7f) as u8;
+            value >>= 7;
+            if value == 0 {
+                self.0.push(byte);
+                return;
+            }
+            self.0.push(byte | 0x80);
+        }
+    }
+
+    pub(crate) fn int(&mut self, value: i64) {
+        self.uint(((value << 1) ^ (value >> 63)) as usize);
+    }
+
+    pub(crate) fn str(&mut self, s: &str) {
+        self.bytes(s.as_bytes());
+    }
+
+    pub(crate) fn bytes(&mut self, bytes: &[u8]) {
+        self.uint(bytes.len());
+        self.0.extend_from_slice(bytes);
+    }
+
+    fn constant(&mut self, constant: &Constant) {
+        match constant {
+            Constant::Nil => self.0.push(0),
+            Constant::Bool(b) => self.0.extend([1, *b as u8]),
+            Constant::Int(i) => {
+                self.0.push(2);
+                self.int(*i);
+            }
+            Constant::Float(f) => {
+                self.0.push(3);
+                self.0.extend(f.to_bits().to_le_bytes());
+            }
+            Constant::Str(s) => {
+                self.0.push(4);
+                self.str(s);
+            }
+            Constant::Symbol(name) => {
+                self.0.push(5);
+                self.str(name);
+            }
+            Constant::Pair(head, tail) => {
+                self.0.push(6);
+                self.constant(head);
+                self.constant(tail);
+            }
+        }
+    }
+
+    pub(crate) fn op(&mut self, op: &Op) {
+        self.0.push(opcode(op));
+        match op {
+            Op::PushBool(b) => self.0.push(*b as u8),
+            Op::PushInt(i) => self.int(*i),
+            Op::PushFloat(f) => self.0.extend(f.to_bits().to_le_bytes()),
+            Op::PushStr(s) => self.str(s),
+            Op::PushConst(n)
+            | Op::MakeArray(n)
+            | Op::Jump(n)
+            | Op::JumpIfFalse(n)
+            | Op::LoadLocal(n)
+            | Op::StoreLocal(n)
+            | Op::Try(n) => self.uint(*n),
+            Op::Call { target, argc } | Op::TailCall { target, argc } => {
+                self.uint(*target);
+                self.uint(*argc);
+            }
+            Op::CallNative { name, argc } => {
+                self.str(name);
+                self.uint(*argc);
+            }
+            _ => {}
+        }
+    }
+}
+
+fn opcode(op: &Op) -> u8 {
+    match op {
+        Op::PushNil => 0,
+        Op::PushBool(_) => 1,
+        Op::PushInt(_) => 2,
+        Op::PushFloat(_) => 3,
+        Op::PushStr(_) => 4,
+        Op::PushConst(_) => 5,
+        Op::Pop => 6,
+        Op::Dup => 7,
+        Op::Over => 8,
+        Op::Swap => 9,
+        Op::Rot => 10,
+        Op::MakePair => 11,
+        Op::MakeArray(_) => 12,
+        Op::Add => 13,
+        Op::Sub => 14,
+        Op::Mul => 15,
+        Op::Div => 16,
+        Op::Eq => 17,
+        Op::Lt => 18,
+        Op::Jump(_) => 19,
+        Op::JumpIfFalse(_) => 20,
+        Op::Call { .. } => 21,
+        Op::TailCall { .. } => 22,
+        Op::Return => 23,
+        Op::LoadLocal(_) => 24,
+        Op::StoreLocal(_) => 25,
+        Op::CallNative { .. } => 26,
+        Op::Try(_) => 27,
+        Op::EndTry => 28,
+        Op::Throw => 29,
+        Op::Car => 30,
+        Op::Cdr => 31,
+    }
+}
+
+pub(crate) struct Reader<'a>(pub(crate) &'a [u8]);
+
+impl<'a> Reader<'a> {
+    pub(crate) fn take(&mut self, len: usize) -> Result<&'a [u8], GcError> {
+        if len > self.0.len() {
+            return Err(GcError::InvalidBytecode("unexpected end of input"));
+        }
+        let (head, rest) = self.0.split_at(len);
+        self.0 = rest;
+        Ok(head)
+    }
+
+    pub(crate) fn byte(&mut self) -> Result<u8, GcError> {
+        Ok(self.take(1)?[0])
+    }
+
+    pub(crate) fn bool(&mut self) -> Result<bool, GcError> {
+        match self.byte()? {
+            0 => Ok(false),
+            1 => Ok(true),
+            _ => Err(GcError::InvalidBytecode("bad bool")),
+        }
+    }
+
+    pub(crate) fn uint(&mut self) -> Result<usize, GcError> {
+        let mut value = 0u64;
+        for shift in (0..64).step_by(7) {
+            let byte = self.byte()?;
+            value |= ((byte & 0x7f) as u64) << shift;
+            if byte & 0x80 == 0 {
+                return usize::try_from(value)
+                    .map_err(|_| GcError::InvalidBytecode("integer too large"));
+            }
+        }
+        Err(GcError::InvalidBytecode("integer too large"))
+    }
+
+    pub(crate) fn int(&mut self) -> Result<i64, GcError> {
+        let value = self.uint()? as u64;
+        Ok((value >> 1) as i64 ^ -((value & 1) as i64))
+    }
+
+    pub(crate) fn float(&mut self) -> Result<f64, GcError> {
+        let bits = self.take(8)?.try_into().unwrap();
+        Ok(f64::from_bits(u64::from_le_bytes(bits)))
+    }
+
+    pub(crate) fn str(&mut self) -> Result<String, GcError> {
+        String::from_utf8(self.bytes()?).map_err(|_| GcError::InvalidBytecode("invalid UTF-8"))
+    }
+
+    pub(crate) fn bytes(&mut self) -> Result<Vec<u8>, GcError> {
+        let len = self.uint()?;
+        Ok(self.take(len)?.to_vec())
+    }
+
+    /// Decodes a constant nested `depth` pairs deep.
+    fn constant(&mut self, depth: usize) -> Result<Constant, GcError> {
+        if depth > MAX_CONSTANT_DEPTH {
+            return Err(GcError::InvalidBytecode("constant nested too deeply"));
+        }
+        Ok(match self.byte()? {
+            0 => Constant::Nil,
+            1 => Constant::Bool(self.bool()?),
+            2 => Constant::Int(self.int()?),
+            3 => Constant::Float(self.float()?),
+            4 => Constant::Str(self.str()?),
+            5 => Constant::Symbol(self.str()?),
+            6 => Constant::Pair(
+                Box::new(self.constant(depth + 1)?),
+                Box::new(self.constant(depth + 1)?),
+            ),
+            _ => return Err(GcError::InvalidBytecode("unknown constant tag")),
+        })
+    }
+
+    pub(crate) fn op(&mut self) -> Result<Op, GcError> {
+        Ok(match self.byte()? {
+            0 => Op::PushNil,
+            1 => Op::PushBool(self.bool()?),
+            2 => Op::PushInt(self.int()?),
+            3 => Op::PushFloat(self.float()?),
+            4 => Op::PushStr(self.str()?),
+            5 => Op::PushConst(self.uint()?),
+            6 => Op::Pop,
+            7 => Op::Dup,
+            8 => Op::Over,
+            9 => Op::Swap,
+            10 => Op::Rot,
+            11 => Op::MakePair,
+            12 => Op::MakeArray(self.uint()?),
+            13 => Op::Add,
+            14 => Op::Sub,
+            15 => Op::Mul,
+            16 => Op::Div,
+            17 => Op::Eq,
+            18 => Op::Lt,
+            19 => Op::Jump(self.uint()?),
+            20 => Op::JumpIfFalse(self.uint()?),
+            21 => Op::Call {
+                target: self.uint()?,
+                argc: self.uint()?,
+            },
+            22 => Op::TailCall {
+                target: self.uint()?,
+                argc: self.uint()?,
+            },
+            23 => Op::Return,
+            24 => Op::LoadLocal(self.uint()?),
+            25 => Op::StoreLocal(self.uint()?),
+            26 => Op::CallNative {
+                name: self.str()?,
+                argc: self.uint()?,
+            },
+            27 => Op::Try(self.uint()?),
+            28 => Op::EndTry,
+            29 => Op::Throw,
+            30 => Op::Car,
+            31 => Op::Cdr,
+            _ => return Err(GcError::InvalidBytecode("unknown opcode")),
+        })
+    }
+}
+
+#[test]
+fn program_test() {
+    println!("Program Test: Bytecode round-trips and is validated on load.");
+    let program = Program {
+        code: vec![
+            Op::PushConst(0),
+            Op::PushInt(-300),
+            Op::PushFloat(0.5),
+            Op::PushStr("é".into()),
+            Op::MakeArray(3),
+            Op::Call { target: 8, argc: 1 },
+            Op::PushConst(1),
+            Op::Return,
+            Op::LoadLocal(0),
+            Op::Return,
+        ],
+        constants: vec![
+            Constant::Pair(Box::new(Constant::Int(1)), Box::new(Constant::Nil)),
+            Constant::Symbol("sym".into()),
+        ],
+    };
+    let bytes = program.to_bytes();
+    assert!(Program::from_bytes(&bytes) == Ok(program.clone()));
+
+    let mut vm = Vm::new();
+    vm.push_str("first");
+    let obj = vm.pop();
+    vm.add_constant(obj);
+    let code = vm.load(&program);
+    assert!(
+        code[0] == Op::PushConst(1),
+        "Should rebase constant indices."
+    );
+    vm.execute(&code).unwrap();
+    assert!(matches!(vm.pop().value(), crate::ObjType::Symbol(_)));
+    assert!(matches!(vm.pop().value(), crate::ObjType::Array(_)));
+    let pair = vm.pop();
+    match pair.value() {
+        crate::ObjType::Pair(pair) => assert!(
+            matches!(pair.head.as_ref().unwrap().value(), crate::ObjType::Int(1)),
+            "Should have built the pair head first."
+        ),
+        _ => panic!("Should have pushed the pair constant."),
+    }
+
+    assert!(Program::from_bytes(&bytes[..bytes.len() - 1]).is_err());
+    assert!(Program::from_bytes(b"GCBD\x01\x00\x00").is_err());
+    let mut bad = program.clone();
+    bad.code.push(Op::Jump(99));
+    assert!(
+        Program::from_bytes(&bad.to_bytes()) == Err(GcError::InvalidBytecode("jump out of range"))
+    );
+    let mut bad = program;
+    bad.code.push(Op::PushConst(2));
+    assert!(Program::from_bytes(&bad.to_bytes()).is_err());
+
+    let mut nested = b"GCBC\x01\x01".to_vec();
+    nested.resize(nested.len() + 2_000_000, 0x06);
+    assert!(
+        Program::from_bytes(&nested) == Err(GcError::InvalidBytecode("constant nested too deeply")),
+        "Should refuse deep nesting instead of overflowing the stack."
+    );
+    let mut list = Constant::Nil;
+    for i in 0..5000 {
+        list = Constant::Pair(Box::new(Constant::Int(i)), Box::new(list));
+    }
+    vm.load(&Program {
+        code: vec![],
+        constants: vec![list],
+    });
+    assert!(
+        vm.iter_list(vm.constants.last().unwrap()).count() == 5000,
+        "Should build long list constants without recursing."
+    );
+    drop(vm);
+}
@@ -0,0 +1,1019 @@
+use std::collections::HashMap;
+
+use crate::program::{Reader, Writer};
+use crate::{GcError, GcPtr, Graph, LayoutPolicy, Object, Op, Value, Vm, VmConfig};
+
+const MAGIC: &[u8; 4] = b"GCLG";
+const VERSION: u8 = 4;
+
+/// One recorded operation. Objects are referred to by their id: each object
+/// gets the next one when it's allocated, the immortals first, so a replay
+/// hands out the same ids to the same objects.
+///
+/// Every public operation that changes the VM has an event, except those
+/// whose effects can't be reproduced from a log: pushing foreign and custom
+/// objects, calling natives, writing through [`Vm::get_mut`] and the like.
+/// While recording those fail with `GcError::Unrecordable`, or panic if they
+/// can't fail.
+#[derive(Clone, Debug, PartialEq)]
+pub enum Event {
+    PushNil,
+    PushBool(bool),
+    PushInt(i64),
+    PushFloat(f64),
+    PushStr(String),
+    PushPair,
+    PushPairWith {
+        head: u64,
+        tail: u64,
+    },
+    PushArray(usize),
+    PopPair,
+    SetHead {
+        pair: u64,
+        value: u64,
+    },
+    SetTail {
+        pair: u64,
+        value: u64,
+    },
+    /// `Vm::pop`, which boxes immediates
+    Pop,
+    /// `Vm::pop_value` or `Vm::drop_top`
+    Drop,
+    Dup,
+    Over,
+    Swap,
+    Rot,
+    Gc,
+    Reset,
+    ArraySet {
+        array: u64,
+        index: usize,
+        value: u64,
+    },
+    /// `Vm::peek`, which boxes an immediate in its slot
+    Peek(usize),
+    /// `Vm::push_value` of an immediate, as its encoding
+    PushValue(u64),
+    /// `Vm::push_value` of an object
+    PushObject(u64),
+    Concat,
+    Flatten,
+    Add,
+    Sub,
+    Mul,
+    Div,
+    PushClosure {
+        code_id: usize,
+        upvalues: usize,
+    },
+    PushPairHashconsed,
+    MapNew,
+    MapSet {
+        map: u64,
+        key: u64,
+        value: u64,
+    },
+    MapRemove {
+        map: u64,
+        key: u64,
+    },
+    PushBytes(Vec<u8>),
+    BytesSet {
+        bytes: u64,
+        index: usize,
+        value: u8,
+    },
+    BytesSlice {
+        bytes: u64,
+        start: usize,
+        end: usize,
+    },
+    BytesExtend {
+        bytes: u64,
+        extra: Vec<u8>,
+    },
+    Intern(String),
+    Symbol(String),
+    DefineGlobal {
+        name: String,
+        value: u64,
+    },
+    AddConstant(u64),
+    PushConst(usize),
+    LoadLocal(usize),
+    StoreLocal(usize),
+    /// `Vm::execute`; the instructions it runs aren't logged on their own
+    Execute(Vec<Op>),
+    Start(Vec<Op>),
+    Step,
+    Freeze(u64),
+    SetUserTag {
+        obj: u64,
+        tag: u32,
+    },
+    IdentityHash(u64),
+    RegisterHandle(u64),
+    ReleaseHandle(u64),
+    DeepClone(u64),
+    FromGraph(Graph),
+}
+
+impl Event {
+    /// How many values the event needs on the stack, and how many it leaves
+    /// in their place.
+    fn stack_effect(&self) -> (usize, usize) {
+        match self {
+            Event::PushPair
+            | Event::Concat
+            | Event::Add
+            | Event::Sub
+            | Event::Mul
+            | Event::Div
+            | Event::PushPairHashconsed => (2, 1),
+            Event::Swap => (2, 2),
+            Event::Over => (2, 3),
+            Event::Rot => (3, 3),
+            Event::Dup | Event::PopPair => (1, 2),
+            Event::Flatten => (1, 1),
+            Event::Pop | Event::Drop | Event::StoreLocal(_) => (1, 0),
+            Event::PushArray(n) | Event::PushClosure { upvalues: n, .. } => (*n, 1),
+            Event::Peek(depth) => (depth.saturating_add(1), depth.saturating_add(1)),
+            Event::PushNil
+            | Event::PushBool(_)
+            | Event::PushInt(_)
+            | Event::PushFloat(_)
+            | Event::PushStr(_)
+            | Event::PushPairWith { .. }
+            | Event::PushValue(_)
+            | Event::PushObject(_)
+            | Event::MapNew
+            | Event::PushBytes(_)
+            | Event::BytesSlice { .. }
+            | Event::Intern(_)
+            | Event::Symbol(_)
+            | Event::PushConst(_)
+            | Event::LoadLocal(_)
+            | Event::DeepClone(_)
+            | Event::FromGraph(_) => (0, 1),
+            // the rest leave the stack alone, or check it themselves
+            _ => (0, 0),
+        }
+    }
+}
+
+/// Everything a VM created with [`VmConfig::record`] did, which [`replay`]
+/// performs again on a fresh VM.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Log {
+    pub config: VmConfig,
+    pub events: Vec<Event>,
+}
+
+/// Tracks what's logged and which id each live object has.
+pub(crate) struct Recorder {
+    events: Vec<Event>,
+    /// how many recorded operations are running, so operations built out of
+    /// other ones are only logged once
+    depth: usize,
+    ids: HashMap<GcPtr<Object>, u64>,
+    objects: HashMap<u64, GcPtr<Object>>,
+    next_id: u64,
+}
+
+impl Recorder {
+    pub(crate) fn new(immortals: &[GcPtr<Object>]) -> Self {
+        let mut recorder = Self {
+            events: vec![],
+            depth: 0,
+            ids: HashMap::new(),
+            objects: HashMap::new(),
+            next_id: 0,
+        };
+        for obj in immortals {
+            recorder.track(obj);
+        }
+        recorder
+    }
+
+    pub(crate) fn track(&mut self, obj: &GcPtr<Object>) {
+        self.ids.insert(obj.clone(), self.next_id);
+        self.objects.insert(self.next_id, obj.clone());
+        self.next_id += 1;
+    }
+
+    pub(crate) fn forget(&mut self, obj: &GcPtr<Object>) {
+        if let Some(id) = self.ids.remove(obj) {
+            self.objects.remove(&id);
+        }
+    }
+
+    pub(crate) fn id(&self, obj: &GcPtr<Object>) -> u64 {
+        self.ids[obj]
+    }
+}
+
+impl Vm {
+    /// Runs `f`, first logging the event `event` builds if this VM records
+    /// and `f` isn't part of another recorded operation.
+    pub(crate) fn recorded<R>(
+        &mut self,
+        event: impl FnOnce(&Recorder) -> Event,
+        f: impl FnOnce(&mut Self) -> R,
+    ) -> R {
+        let Some(recorder) = &mut self.recorder else {
+            return f(self);
+        };
+        if recorder.depth == 0 {
+            let event = event(recorder);
+            recorder.events.push(event);
+        }
+        recorder.depth += 1;
+        let result = f(self);
+        self.recorder.as_mut().unwrap().depth -= 1;
+        result
+    }
+
+    /// Whether an operation called now would be logged: this VM records, and
+    /// the call isn't part of another recorded operation.
+    pub(crate) fn recording(&self) -> bool {
+        self.recorder
+            .as_ref()
+            .is_some_and(|recorder| recorder.depth == 0)
+    }
+
+    /// Fails with `GcError::Unrecordable` if `op`, which has no event, would
+    /// otherwise run unlogged.
+    pub(crate) fn refuse_recording(&self, op: &'static str) -> Result<(), GcError> {
+        if self.recording() {
+            return Err(GcError::Unrecordable(op));
+        }
+        Ok(())
+    }
+
+    /// Returns what's been recorded so far, if this VM records.
+    pub fn log(&self) -> Option<Log> {
+        self.recorder.as_ref().map(|recorder| Log {
+            config: self.config.clone(),
+            events: recorder.events.clone(),
+        })
+    }
+
+    fn object(&self, id: u64) -> Result<GcPtr<Object>, GcError> {
+        self.recorder
+            .as_ref()
+            .and_then(|recorder| recorder.objects.get(&id).cloned())
+            .ok_or(GcError::InvalidLog("unknown object id"))
+    }
+}
+
+/// Performs every operation in `log` on a fresh VM with the configuration it
+/// was recorded with, returning the VM in the state the recorded one was.
+/// The new VM records as well.
+pub fn replay(log: &Log) -> Result<Vm, GcError> {
+    let mut vm = Vm::with_config(VmConfig {
+        record: true,
+        ..log.config.clone()
+    });
+    for event in &log.events {
+        let (pops, pushes) = event.stack_effect();
+        if pops > vm.stack_size {
+            return Err(GcError::InvalidLog("stack underflow"));
+        }
+        if vm.stack_size - pops + pushes > vm.stack_max {
+            return Err(GcError::InvalidLog("stack overflow"));
+        }
+        match event {
+            Event::PushNil => vm.push_nil(),
+            Event::PushBool(b) => vm.push_bool(*b),
+            Event::PushInt(i) => vm.push_int(*i),
+            Event::PushFloat(f) => vm.push_float(*f),
+            Event::PushStr(s) => vm.push_str(s),
+            Event::PushPair => vm.push_pair(),
+            Event::PopPair => {
+                vm.pop_pair()?;
+            }
+            Event::SetHead { pair, value } => {
+                let pair = vm.object(*pair)?;
+                let value = vm.object(*value)?;
+                vm.set_head(&pair, value)?;
+            }
+            Event::SetTail { pair, value } => {
+                let pair = vm.object(*pair)?;
+                let value = vm.object(*value)?;
+                vm.set_tail(&pair, value)?;
+            }
+            Event::PushPairWith { head, tail } => {
+                let head = vm.object(*head)?;
+                let tail = vm.object(*tail)?;
+                vm.push_pair_with(head, tail);
+            }
+            Event::PushArray(n) => vm.push_array(*n),
+            Event::Pop => {
+                vm.pop();
+            }
+            Event::Drop => {
+                vm.pop_value();
+            }
+            Event::Dup => vm.dup(),
+            Event::Over => vm.over(),
+            Event::Swap => vm.swap(),
+            Event::Rot => vm.rot(),
+            Event::Gc => vm.gc(),
+            Event::Reset => vm.reset()?,
+            Event::ArraySet {
+                array,
+                index,
+                value,
+            } => {
+                let array = vm.object(*array)?;
+                let value = vm.object(*value)?;
+                vm.array_set(&array, *index, value)?;
+            }
+            Event::Peek(depth) => {
+                vm.peek(*depth);
+            }
+            Event::PushValue(bits) => {
+                let value = Value::immediate_from_bits(*bits)
+                    .ok_or(GcError::InvalidLog("not an immediate"))?;
+                vm.push_value(value);
+            }
+            Event::PushObject(obj) => {
+                let obj = vm.object(*obj)?;
+                vm.push_value(Value::from_obj(obj));
+            }
+            Event::Concat => vm.concat()?,
+            Event::Flatten => vm.flatten()?,
+            Event::Add => vm.add()?,
+            Event::Sub => vm.sub()?,
+            Event::Mul => vm.mul()?,
+            Event::Div => vm.div()?,
+            Event::PushClosure { code_id, upvalues } => vm.push_closure(*code_id, *upvalues),
+            Event::PushPairHashconsed => vm.push_pair_hashconsed(),
+            Event::MapNew => vm.map_new(),
+            Event::MapSet { map, key, value } => {
+                let map = vm.object(*map)?;
+                let key = vm.object(*key)?;
+                let value = vm.object(*value)?;
+                vm.map_set(&map, &key, value)?;
+            }
+            Event::MapRemove { map, key } => {
+                let map = vm.object(*map)?;
+                let key = vm.object(*key)?;
+                vm.map_remove(&map, &key)?;
+            }
+            Event::PushBytes(bytes) => vm.push_bytes(bytes),
+            Event::BytesSet {
+                bytes,
+                index,
+                value,
+            } => {
+                let bytes = vm.object(*bytes)?;
+                vm.bytes_set(&bytes, *index, *value)?;
+            }
+            Event::BytesSlice { bytes, start, end } => {
+                let bytes = vm.object(*bytes)?;
+                vm.bytes_slice(&bytes, *start..*end)?;
+            }
+            Event::BytesExtend { bytes, extra } => {
+                let bytes = vm.object(*bytes)?;
+                vm.bytes_extend(&bytes, extra)?;
+            }
+            Event::Intern(s) => {
+                vm.intern(s);
+            }
+            Event::Symbol(name) => {
+                vm.symbol(name);
+            }
+            Event::DefineGlobal { name, value } => {
+                let value = vm.object(*value)?;
+                vm.define_global(name, value);
+            }
+            Event::AddConstant(obj) => {
+                let obj = vm.object(*obj)?;
+                vm.add_constant(obj);
+            }
+            Event::PushConst(index) => vm.push_const(*index)?,
+            Event::LoadLocal(index) => vm.load_local(*index)?,
+            Event::StoreLocal(index) => vm.store_local(*index),
+            Event::Execute(code) => {
+                if calls_native(code) {
+                    return Err(GcError::InvalidLog("native call"));
+                }
+                // a run that failed when it was recorded fails the same way
+                // again, having done the same before failing
+                let _ = vm.execute(code);
+            }
+            Event::Start(code) => {
+                if calls_native(code) {
+                    return Err(GcError::InvalidLog("native call"));
+                }
+                vm.start(code.clone());
+            }
+            Event::Step => {
+                let _ = vm.step();
+            }
+            Event::Freeze(obj) => {
+                let obj = vm.object(*obj)?;
+                vm.freeze(&obj);
+            }
+            Event::SetUserTag { obj, tag } => {
+                let obj = vm.object(*obj)?;
+                vm.set_user_tag(&obj, *tag);
+            }
+            Event::IdentityHash(obj) => {
+                let obj = vm.object(*obj)?;
+                vm.identity_hash(&obj);
+            }
+            Event::RegisterHandle(obj) => {
+                let obj = vm.object(*obj)?;
+                vm.register_handle(obj);
+            }
+            Event::ReleaseHandle(handle) => {
+                vm.release_handle(*handle);
+            }
+            Event::DeepClone(obj) => {
+                let obj = vm.object(*obj)?;
+                vm.deep_clone(&obj);
+            }
+            Event::FromGraph(graph) => {
+                vm.from_graph(graph)?;
+            }
+        }
+    }
+    Ok(vm)
+}
+
+/// Whether `code` calls a native, which a replay has no way to call again.
+pub(crate) fn calls_native(code: &[Op]) -> bool {
+    code.iter().any(|op| matches!(op, Op::CallNative { .. }))
+}
+
+impl Log {
+    /// Encodes the log like [`crate::Program::to_bytes`] encodes programs:
+    /// a header, the configuration flags and stack limit (0 if unset), then
+    /// one tag byte per event followed by its varint and string operands.
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut out = Writer(MAGIC.to_vec());
+        out.0.push(VERSION);
+        out.0.push(
+            self.config.unboxed_ints as u8
+                | (self.config.small_int_cache as u8) << 1
+                | ((self.config.layout == LayoutPolicy::Clustered) as u8) << 2,
+        );
+        out.uint(self.config.max_stack.unwrap_or(0));
+        out.uint(self.events.len());
+        for event in &self.events {
+            match event {
+                Event::PushNil => out.0.push(0),
+                Event::PushBool(b) => out.0.extend([1, *b as u8]),
+                Event::PushInt(i) => {
+                    out.0.push(2);
+                    out.int(*i);
+                }
+                Event::PushFloat(f) => {
+                    out.0.push(3);
+                    out.0.extend(f.to_bits().to_le_bytes());
+                }
+                Event::PushStr(s) => {
+                    out.0.push(4);
+                    out.str(s);
+                }
+                Event::PushPair => out.0.push(5),
+                Event::PushArray(n) => {
+                    out.0.push(6);
+                    out.uint(*n);
+                }
+                Event::Pop => out.0.push(7),
+                Event::Drop => out.0.push(8),
+                Event::Dup => out.0.push(9),
+                Event::Swap => out.0.push(10),
+                Event::Gc => out.0.push(11),
+                Event::Reset => out.0.push(17),
+                Event::Over => out.0.push(18),
+                Event::Rot => out.0.push(19),
+                Event::ArraySet {
+                    array,
+                    index,
+                    value,
+                } => {
+                    out.0.push(12);
+                    out.uint(*array as usize);
+                    out.uint(*index);
+                    out.uint(*value as usize);
+                }
+                Event::PopPair => out.0.push(14),
+                Event::SetHead { pair, value } | Event::SetTail { pair, value } => {
+                    out.0.push(if matches!(event, Event::SetHead { .. }) {
+                        15
+                    } else {
+                        16
+                    });
+                    out.uint(*pair as usize);
+                    out.uint(*value as usize);
+                }
+                Event::PushPairWith { head, tail } => {
+                    out.0.push(13);
+                    out.uint(*head as usize);
+                    out.uint(*tail as usize);
+                }
+                Event::Peek(depth) => {
+                    out.0.push(20);
+                    out.uint(*depth);
+                }
+                Event::PushValue(bits) => {
+                    out.0.push(21);
+                    out.0.extend(bits.to_le_bytes());
+                }
+                Event::PushObject(obj) => {
+                    out.0.push(22);
+                    out.uint(*obj as usize);
+                }
+                Event::Concat => out.0.push(23),
+                Event::Flatten => out.0.push(24),
+                Event::Add => out.0.push(25),
+                Event::Sub => out.0.push(26),
+                Event::Mul => out.0.push(27),
+                Event::Div => out.0.push(28),
+                Event::PushClosure { code_id, upvalues } => {
+                    out.0.push(29);
+                    out.uint(*code_id);
+                    out.uint(*upvalues);
+                }
+                Event::PushPairHashconsed => out.0.push(30),
+                Event::MapNew => out.0.push(31),
+                Event::MapSet { map, key, value } => {
+                    out.0.push(32);
+                    out.uint(*map as usize);
+                    out.uint(*key as usize);
+                    out.uint(*value as usize);
+                }
+                Event::MapRemove { map, key } => {
+                    out.0.push(33);
+                    out.uint(*map as usize);
+                    out.uint(*key as usize);
+                }
+                Event::PushBytes(bytes) => {
+                    out.0.push(34);
+                    out.bytes(bytes);
+                }
+                Event::BytesSet {
+                    bytes,
+                    index,
+                    value,
+                } => {
+                    out.0.push(35);
+                    out.uint(*bytes as usize);
+                    out.uint(*index);
+                    out.0.push(*value);
+                }
+                Event::BytesSlice { bytes, start, end } => {
+                    out.0.push(36);
+                    out.uint(*bytes as usize);
+                    out.uint(*start);
+                    out.uint(*end);
+                }
+                Event::BytesExtend { bytes, extra } => {
+                    out.0.push(37);
+                    out.uint(*bytes as usize);
+                    out.bytes(extra);
+                }
+                Event::Intern(s) => {
+                    out.0.push(38);
+                    out.str(s);
+                }
+                Event::Symbol(name) => {
+                    out.0.push(39);
+                    out.str(name);
+                }
+                Event::DefineGlobal { name, value } => {
+                    out.0.push(40);
+                    out.str(name);
+                    out.uint(*value as usize);
+                }
+                Event::AddConstant(obj) => {
+                    out.0.push(41);
+                    out.uint(*obj as usize);
+                }
+                Event::PushConst(index) => {
+                    out.0.push(42);
+                    out.uint(*index);
+                }
+                Event::LoadLocal(index) => {
+                    out.0.push(43);
+                    out.uint(*index);
+                }
+                Event::StoreLocal(index) => {
+                    out.0.push(44);
+                    out.uint(*index);
+                }
+                Event::Execute(code) | Event::Start(code) => {
+                    out.0.push(if matches!(event, Event::Execute(_)) {
+                        45
+                    } else {
+                        46
+                    });
+                    out.uint(code.len());
+                    for op in code {
+                        out.op(op);
+                    }
+                }
+                Event::Step => out.0.push(47),
+                Event::Freeze(obj) => {
+                    out.0.push(48);
+                    out.uint(*obj as usize);
+                }
+                Event::SetUserTag { obj, tag } => {
+                    out.0.push(49);
+                    out.uint(*obj as usize);
+                    out.uint(*tag as usize);
+                }
+                Event::IdentityHash(obj) => {
+                    out.0.push(50);
+                    out.uint(*obj as usize);
+                }
+                Event::RegisterHandle(obj) => {
+                    out.0.push(51);
+                    out.uint(*obj as usize);
+                }
+                Event::ReleaseHandle(handle) => {
+                    out.0.push(52);
+                    out.uint(*handle as usize);
+                }
+                Event::DeepClone(obj) => {
+                    out.0.push(53);
+                    out.uint(*obj as usize);
+                }
+                Event::FromGraph(graph) => {
+                    out.0.push(54);
+                    out.bytes(&graph.to_bytes());
+                }
+            }
+        }
+        out.0
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> Result<Log, GcError> {
+        Self::decode(&mut Reader(bytes)).map_err(|err| match err {
+            GcError::InvalidBytecode(reason) => GcError::InvalidLog(reason),
+            err => err,
+        })
+    }
+
+    fn decode(input: &mut Reader) -> Result<Log, GcError> {
+        if input.take(4)? != MAGIC {
+            return Err(GcError::InvalidLog("bad magic number"));
+        }
+        if input.byte()? != VERSION {
+            return Err(GcError::InvalidLog("unsupported version"));
+        }
+        let flags = input.byte()?;
+        let max_stack = input.uint()?;
+        let mut log = Log {
+            config: VmConfig {
+                unboxed_ints: flags & 1 != 0,
+                small_int_cache: flags & 2 != 0,
+                record: true,
+                max_stack: (max_stack != 0).then_some(max_stack),
+                layout: if flags & 4 != 0 {
+                    LayoutPolicy::Clustered
+                } else {
+                    LayoutPolicy::Individual
+                },
+            },
+            events: vec![],
+        };
+        for _ in 0..input.uint()? {
+            log.events.push(match input.byte()? {
+                0 => Event::PushNil,
+                1 => Event::PushBool(input.bool()?),
+                2 => Event::PushInt(input.int()?),
+                3 => Event::PushFloat(input.float()?),
+                4 => Event::PushStr(input.str()?),
+                5 => Event::PushPair,
+                6 => Event::PushArray(input.uint()?),
+                7 => Event::Pop,
+                8 => Event::Drop,
+                9 => Event::Dup,
+                10 => Event::Swap,
+                11 => Event::Gc,
+                12 => Event::ArraySet {
+                    array: input.uint()? as u64,
+                    index: input.uint()?,
+                    value: input.uint()? as u64,
+                },
+                13 => Event::PushPairWith {
+                    head: input.uint()? as u64,
+                    tail: input.uint()? as u64,
+                },
+                14 => Event::PopPair,
+                15 => Event::SetHead {
+                    pair: input.uint()? as u64,
+                    value: input.uint()? as u64,
+                },
+                16 => Event::SetTail {
+                    pair: input.uint()? as u64,
+                    value: input.uint()? as u64,
+                },
+                17 => Event::Reset,
+                18 => Event::Over,
+                19 => Event::Rot,
+                20 => Event::Peek(input.uint()?),
+                21 => Event::PushValue(u64::from_le_bytes(input.take(8)?.try_into().unwrap())),
+                22 => Event::PushObject(input.uint()? as u64),
+                23 => Event::Concat,
+                24 => Event::Flatten,
+                25 => Event::Add,
+                26 => Event::Sub,
+                27 => Event::Mul,
+                28 => Event::Div,
+                29 => Event::PushClosure {
+                    code_id: input.uint()?,
+                    upvalues: input.uint()?,
+                },
+                30 => Event::PushPairHashconsed,
+                31 => Event::MapNew,
+                32 => Event::MapSet {
+                    map: input.uint()? as u64,
+                    key: input.uint()? as u64,
+                    value: input.uint()? as u64,
+                },
+                33 => Event::MapRemove {
+                    map: input.uint()? as u64,
+                    key: input.uint()? as u64,
+                },
+                34 => Event::PushBytes(input.bytes()?),
+                35 => Event::BytesSet {
+                    bytes: input.uint()? as u64,
+                    index: input.uint()?,
+                    value: input.byte()?,
+                },
+                36 => Event::BytesSlice {
+                    bytes: input.uint()? as u64,
+                    start: input.uint()?,
+                    end: input.uint()?,
+                },
+                37 => Event::BytesExtend {
+                    bytes: input.uint()? as u64,
+                    extra: input.bytes()?,
+                },
+                38 => Event::Intern(input.str()?),
+                39 => Event::Symbol(input.str()?),
+                40 => Event::DefineGlobal {
+                    name: input.str()?,
+                    value: input.uint()? as u64,
+                },
+                41 => Event::AddConstant(input.uint()? as u64),
+                42 => Event::PushConst(input.uint()?),
+                43 => Event::LoadLocal(input.uint()?),
+                44 => Event::StoreLocal(input.uint()?),
+                tag @ (45 | 46) => {
+                    let code = (0..input.uint()?)
+                        .map(|_| input.op())
+                        .collect::<Result<_, _>>()?;
+                    if tag == 45 {
+                        Event::Execute(code)
+                    } else {
+                        Event::Start(code)
+                    }
+                }
+                47 => Event::Step,
+                48 => Event::Freeze(input.uint()? as u64),
+                49 => Event::SetUserTag {
+                    obj: input.uint()? as u64,
+                    tag: u32::try_from(input.uint()?)
+                        .map_err(|_| GcError::InvalidLog("tag out of range"))?,
+                },
+                50 => Event::IdentityHash(input.uint()? as u64),
+                51 => Event::RegisterHandle(input.uint()? as u64),
+                52 => Event::ReleaseHandle(input.uint()? as u64),
+                53 => Event::DeepClone(input.uint()? as u64),
+                54 => Event::FromGraph(
+                    Graph::from_bytes(&input.bytes()?)
+                        .map_err(|_| GcError::InvalidLog("invalid graph"))?,
+                ),
+                _ => return Err(GcError::InvalidLog("unknown event")),
+            });
+        }
+        if !input.0.is_empty() {
+            return Err(GcError::InvalidLog("trailing bytes"));
+        }
+        Ok(log)
+    }
+}
+
+#[test]
+fn replay_test() {
+    println!("Replay Test: A replayed log reproduces the recorded heap.");
+    let mut vm = Vm::with_config(VmConfig {
+        record: true,
+        ..VmConfig::default()
+    });
+    vm.push_int(1);
+    vm.push_str("two");
+    vm.push_pair();
+    vm.push_float(3.0);
+    vm.push_array(1);
+    let array = vm.pop();
+    vm.dup();
+    let pair = vm.pop();
+    vm.push_array(0);
+    vm.swap();
+    vm.over();
+    vm.rot();
+    vm.drop_top();
+    vm.drop_top();
+    vm.array_set(&array, 0, pair.clone()).unwrap();
+    vm.push_pair_with(pair, array.clone());
+    let (head, _) = vm.pop_pair().unwrap();
+    vm.set_tail(&head, array.clone()).unwrap();
+    vm.gc();
+    vm.push_int(4);
+    vm.reset().unwrap();
+    vm.push_str("after");
+    let log = vm.log().unwrap();
+    assert!(
+        log.events
+            .iter()
+            .filter(|event| **event == Event::Pop)
+            .count()
+            == 2,
+        "Should not log the pops done by other operations."
+    );
+
+    let bytes = log.to_bytes();
+    let decoded = Log::from_bytes(&bytes).unwrap();
+    assert!(decoded.events == log.events);
+    let replayed = replay(&decoded).unwrap();
+    assert!(replayed.num_objs == vm.num_objs && replayed.num_bytes == vm.num_bytes);
+    assert!(replayed.stack_size == vm.stack_size);
+    assert!(
+        replayed.log().unwrap().events == log.events,
+        "Should log the same events again."
+    );
+
+    assert!(Log::from_bytes(&bytes[..bytes.len() - 1]).is_err());
+    let bad = Log {
+        config: VmConfig::default(),
+        events: vec![Event::PushPair],
+    };
+    assert!(matches!(replay(&bad), Err(GcError::InvalidLog(_))));
+    drop(vm);
+}
+
+#[test]
+fn replay_everything_test() {
+    println!("Replay Everything Test: Every operation that changes the VM is logged.");
+    let mut vm = Vm::with_config(VmConfig {
+        record: true,
+        unboxed_ints: true,
+        ..VmConfig::default()
+    });
+    // used to leave a replayed stack 2 deep where the original was 4
+    vm.push_str("a");
+    vm.push_str("b");
+    vm.concat().unwrap();
+    vm.push_int(1);
+    vm.push_int(2);
+    vm.over();
+    vm.rot();
+    assert!(vm.stack_size == 4);
+
+    vm.add().unwrap();
+    vm.mul().unwrap();
+    vm.push_float(0.5);
+    vm.sub().unwrap();
+    vm.push_int(2);
+    vm.div().unwrap();
+    vm.drop_top();
+    vm.flatten().unwrap();
+    let text = vm.peek(0);
+    vm.push_int(7);
+    vm.peek(0);
+    vm.drop_top();
+    vm.push_value(Value::int(5).unwrap());
+    vm.push_value(Value::from_obj(text.clone()));
+    vm.push_closure(3, 2);
+    let closure = vm.pop();
+    vm.define_global("closure", closure.clone());
+
+    vm.map_new();
+    let map = vm.peek(0);
+    vm.define_global("map", map.clone());
+    let key = vm.intern("key");
+    let gone = vm.symbol("gone");
+    vm.map_set(&map, &key, text.clone()).unwrap();
+    vm.map_set(&map, &gone, key.clone()).unwrap();
+    vm.map_remove(&map, &gone).unwrap();
+    vm.push_bytes(b"abc");
+    let bytes = vm.peek(0);
+    vm.bytes_set(&bytes, 0, b'x').unwrap();
+    vm.bytes_extend(&bytes, b"de").unwrap();
+    vm.bytes_slice(&bytes, 1..4).unwrap();
+    vm.push_int(1);
+    vm.push_int(2);
+    vm.push_pair_hashconsed();
+
+    vm.add_constant(bytes.clone());
+    vm.push_const(0).unwrap();
+    vm.store_local(0);
+    vm.load_local(0).unwrap();
+    vm.execute(&[Op::PushInt(10), Op::PushInt(20), Op::Add])
+        .unwrap();
+    vm.start(vec![Op::PushStr("s".into()), Op::Dup]);
+    while vm.step().unwrap().is_some() {}
+
+    vm.freeze(&map);
+    vm.set_user_tag(&bytes, 7);
+    vm.identity_hash(&text);
+    let released = vm.register_handle(closure);
+    let kept = vm.register_handle(text.clone());
+    vm.release_handle(released);
+    vm.deep_clone(&map);
+    let graph = vm.to_graph(&map).unwrap();
+    vm.from_graph(&graph).unwrap();
+    vm.gc();
+
+    assert!(matches!(
+        vm.get_mut::<String>(&text),
+        Err(GcError::Unrecordable("get_mut"))
+    ));
+    assert!(matches!(
+        vm.execute(&[Op::CallNative {
+            name: "f".into(),
+            argc: 0
+        }]),
+        Err(GcError::Unrecordable(_))
+    ));
+    assert!(matches!(
+        vm.call_native("f", 0),
+        Err(GcError::Unrecordable(_))
+    ));
+    assert!(matches!(
+        vm.try_push(crate::ObjType::Int(1)),
+        Err(GcError::Unrecordable(_))
+    ));
+    let mut other = Vm::with_config(VmConfig {
+        record: true,
+        ..VmConfig::default()
+    });
+    assert!(matches!(
+        vm.transfer_to(&mut other, &text),
+        Err(GcError::Unrecordable(_))
+    ));
+
+    let log = Log::from_bytes(&vm.log().unwrap().to_bytes()).unwrap();
+    assert!(log == vm.log().unwrap(), "Should decode what it encoded.");
+    let mut replayed = replay(&log).unwrap();
+    assert!(replayed.num_objs == vm.num_objs && replayed.num_bytes == vm.num_bytes);
+    assert!(
+        replayed.stack_size == vm.stack_size,
+        "Should leave the stack as deep."
+    );
+    let shown = |vm: &Vm, value: Value| match value.as_obj() {
+        Some(obj) => vm.display(&obj),
+        None => format!("{value:?}"),
+    };
+    for (a, b) in vm.stack_values().zip(replayed.stack_values()) {
+        assert!(
+            shown(&vm, a) == shown(&replayed, b),
+            "Should leave the same stack."
+        );
+    }
+    for name in ["closure", "map"] {
+        let (a, b) = (
+            vm.get_global(name).unwrap(),
+            replayed.get_global(name).unwrap(),
+        );
+        assert!(vm.display(&a) == replayed.display(&b));
+    }
+    assert!(replayed.is_frozen(&replayed.get_global("map").unwrap()));
+    assert!(replayed.user_tag(&replayed.constants[0]) == 7);
+    assert!(replayed.handle(released).is_none());
+    let (a, b) = (vm.handle(kept).unwrap(), replayed.handle(kept).unwrap());
+    assert!(a.identity_hash() == b.identity_hash() && a.identity_hash().is_some());
+    assert!(replayed.log().unwrap().events == log.events);
+    replayed.gc();
+}
+
+#[test]
+fn replay_stack_limit_test() {
+    println!("Replay Stack Limit Test: Logs pushing past the stack limit are invalid.");
+    let log = Log {
+        config: VmConfig {
+            max_stack: Some(2),
+            ..VmConfig::default()
+        },
+        events: vec![Event::PushNil, Event::PushNil, Event::Over],
+    };
+    assert!(
+        matches!(replay(&log), Err(GcError::InvalidLog("stack overflow"))),
+        "Should refuse instead of panicking."
+    );
+    let log = Log {
+        events: vec![Event::PushNil, Event::Peek(usize::MAX)],
+        ..log
+    };
+    assert!(matches!(replay(&log), Err(GcError::InvalidLog(_))));
+}
@@ -0,0 +1,278 @@
+//! Experimental relocation in steps, to weigh against `Vm::compact`.
+//! Compacting stops everything until the whole heap has been looked at and
+//! every object moved; relocating moves the same objects a few chunks at a
+//! time, and the mutator keeps running between steps. Steps run on the VM's
+//! thread, since objects are only ever reached through `&mut Vm`, but
+//! nothing else waits for the relocation to finish.
+//!
+//! Handles the mutator holds are followed to where their objects went by
+//! the read barrier in `Vm::resolve`, as after a compaction. References the
+//! VM holds itself are put right as each step moves their objects: those
+//! outside the heap are few enough to look at every step, and objects in
+//! the heap are only looked at when they refer to a chunk the step emptied.
+//! Which do is worked out once, when the relocation starts, and kept up to
+//! date by the write barrier in `Vm::object_mut` and by allocation, which
+//! note every object written or made since. New objects don't go in the
+//! chunks being emptied.
+//!
+//! A collection ends the relocation where it is, since it frees objects the
+//! relocation has noted, and so does a compaction.
+
+use std::collections::{BTreeMap, HashMap, HashSet};
+
+use crate::allocation::{self, Evacuation};
+use crate::compact::forwarder;
+use crate::copy::remap_children;
+use crate::{GcPtr, LayoutPolicy, Object, Trace, TypeTag, Visitor, Vm};
+
+/// A relocation under way, see [`Vm::start_relocation`].
+pub(crate) struct Relocation {
+    plan: Evacuation,
+    /// heap indices of the objects left to move, by the start of the chunk
+    /// they're in, emptied lowest first
+    pending: BTreeMap<usize, Vec<usize>>,
+    /// objects referring to one in each chunk being emptied, by its start
+    referrers: HashMap<usize, HashSet<GcPtr<Object>>>,
+    /// objects written or allocated since the last step, which may refer to
+    /// objects still to move without being among `referrers` yet
+    written: HashSet<GcPtr<Object>>,
+}
+
+impl Relocation {
+    /// Whether the object at `ptr` is in a chunk being emptied, which new
+    /// objects shouldn't go in.
+    pub(crate) fn moves(&self, ptr: std::ptr::NonNull<Object>) -> bool {
+        self.plan.moves(ptr)
+    }
+
+    /// Notes that `obj` is being written, or was just allocated.
+    pub(crate) fn written(&mut self, obj: &GcPtr<Object>) {
+        self.written.insert(obj.clone());
+    }
+
+    /// Adds `obj` to the referrers of every chunk being emptied it refers
+    /// to. A foreign or custom object keeps those chunks where they are
+    /// instead, since the objects it refers to can't be pointed elsewhere.
+    fn note(&mut self, obj: &GcPtr<Object>) {
+        struct Chunks<'a>(&'a Relocation, Vec<usize>);
+        impl Visitor for Chunks<'_> {
+            fn visit(&mut self, child: &GcPtr<Object>) {
+                if self.0.plan.moves(child.0) {
+                    self.1.push(allocation::chunk_start(child.0));
+                }
+            }
+        }
+
+        let mut chunks = Chunks(self, vec![]);
+        obj.value().trace(&mut chunks);
+        let tag = unsafe { obj.0.as_ref() }.header.type_tag();
+        for start in chunks.1 {
+            if matches!(tag, TypeTag::Foreign | TypeTag::Custom) {
+                self.plan.keep(start);
+                self.pending.remove(&start);
+                self.referrers.remove(&start);
+            } else {
+                self.referrers.entry(start).or_default().insert(obj.clone());
+            }
+        }
+    }
+}
+
+impl Vm {
+    /// Starts relocating the objects [`Vm::compact`] would move, a few
+    /// chunks at each [`Vm::relocation_step`], returning whether there's
+    /// anything to move. **Experimental**: it's here to measure against
+    /// compacting, which takes one long pause where this takes several
+    /// short ones.
+    ///
+    /// Handles keep working across steps like across a compaction, see
+    /// [`Vm::resolve`]. A collection or a compaction ends the relocation
+    /// wherever it's got to, and it doesn't start while the VM records, or
+    /// while a borrow has left an object referring to one that isn't live.
+    pub fn start_relocation(&mut self) -> bool {
+        self.relocation = None;
+        if self.config.layout != LayoutPolicy::Clustered
+            || self.recorder.is_some()
+            || !self.settle_writes()
+        {
+            return false;
+        }
+        let plan = self.chunks.evacuation(&self.pinned_chunks());
+        if plan.is_empty() {
+            return false;
+        }
+        let mut relocation = Relocation {
+            plan,
+            pending: BTreeMap::new(),
+            referrers: HashMap::new(),
+            written: HashSet::new(),
+        };
+        for (i, obj) in self.heap.iter().enumerate() {
+            if relocation.moves(obj.0) {
+                let start = allocation::chunk_start(obj.0);
+                relocation.pending.entry(start).or_default().push(i);
+            }
+            relocation.note(obj);
+        }
+        self.relocation = Some(relocation);
+        true
+    }
+
+    /// Empties up to `chunks` more of the chunks the relocation started by
+    /// [`Vm::start_relocation`] empties, pointing everything the VM holds
+    /// at where their objects went, and returns how many objects moved.
+    /// The relocation ends with the step that empties the last of them, or
+    /// that finds the room it planned to move them to has been allocated
+    /// since. Does nothing without a relocation under way, or while a borrow
+    /// has left an object referring to one that isn't live, and ends the
+    /// relocation if the VM has started recording since.
+    ///
+    /// [`Vm::safepoint`] takes a step of one chunk while a relocation is
+    /// under way.
+    pub fn relocation_step(&mut self, chunks: usize) -> usize {
+        let Some(mut relocation) = self.relocation.take() else {
+            return 0;
+        };
+        if self.recorder.is_some() {
+            return 0;
+        }
+        if !self.settle_writes() {
+            self.relocation = Some(relocation);
+            return 0;
+        }
+        for obj in std::mem::take(&mut relocation.written) {
+            if let Some(obj) = self.resolve(&obj) {
+                relocation.note(&obj);
+            }
+        }
+
+        let mut moved = HashMap::new();
+        let mut referrers = HashSet::new();
+        let mut room = true;
+        for _ in 0..chunks {
+            let Some((start, objects)) = relocation.pending.pop_first() else {
+                break;
+            };
+            room = objects
+                .into_iter()
+                .all(|i| self.move_object(i, &mut relocation.plan, &mut moved));
+            referrers.extend(relocation.referrers.remove(&start).unwrap_or_default());
+            if !room {
+                break;
+            }
+        }
+
+        self.forward_roots(&moved);
+        for obj in referrers {
+            // referrers that moved too are found where they went
+            let obj = self.resolve(&obj).expect("a referrer is live");
+            remap_children(&mut self.object_mut(&obj).value, forwarder(&moved));
+        }
+        #[cfg(debug_assertions)]
+        self.verify_heap();
+        if room && !relocation.pending.is_empty() {
+            self.relocation = Some(relocation);
+        }
+        moved.len()
+    }
+
+    /// Whether a relocation [`Vm::start_relocation`] started is under way.
+    pub fn is_relocating(&self) -> bool {
+        self.relocation.is_some()
+    }
+}
+
+#[test]
+fn relocation_test() {
+    use crate::VmConfig;
+    use std::time::{Duration, Instant};
+
+    println!("Relocation Test: Objects move in steps while the mutator runs.");
+    // an array of every eighth int, leaving each chunk about an eighth full
+    let sparse = || {
+        let mut vm = Vm::with_config(VmConfig {
+            layout: LayoutPolicy::Clustered,
+            max_stack: Some(1000),
+            ..VmConfig::default()
+        });
+        for i in 0..4096 {
+            vm.push_int(i);
+            if i % 8 != 0 {
+                vm.drop_top();
+            }
+        }
+        vm.push_array(512);
+        vm.gc();
+        vm
+    };
+
+    let mut compacted = sparse();
+    let start = Instant::now();
+    let moved = compacted.compact();
+    let pause = start.elapsed();
+
+    let mut vm = sparse();
+    let array = vm.peek(0);
+    let ints: Vec<_> = (0..512).map(|i| vm.array_get(&array, i).unwrap()).collect();
+    let pair = vm.push_pair_with(array.clone(), array.clone());
+    let before = vm.heap_stats();
+    assert!(vm.start_relocation() && vm.is_relocating());
+    let (mut steps, mut relocated, mut longest) = (0, 0, Duration::ZERO);
+    while vm.is_relocating() {
+        let start = Instant::now();
+        relocated += vm.relocation_step(1);
+        longest = longest.max(start.elapsed());
+        steps += 1;
+
+        // the mutator keeps going between steps, through handles taken
+        // before anything moved, storing them where nothing referred to
+        // them before
+        let int = &ints[steps * 37 % ints.len()];
+        assert!(int.as_int(&vm) == Some((steps * 37 % ints.len()) as i64 * 8));
+        vm.set_head(&pair, int.clone()).unwrap();
+    }
+    println!(
+        "Compacting moved {moved} objects in {pause:?}; relocating moved \
+         {relocated} in {steps} steps, the longest {longest:?}."
+    );
+    assert!(
+        steps > 1,
+        "Should have moved the objects over several steps."
+    );
+    assert!(relocated == moved, "Should move what compacting moves.");
+    assert!(
+        vm.heap_stats().chunks == compacted.heap_stats().chunks,
+        "Should give back as many chunks as compacting."
+    );
+    assert!(vm.heap_stats().chunks < before.chunks);
+
+    let stale = ints
+        .iter()
+        .filter(|obj| vm.resolve(obj).unwrap().0 != obj.0);
+    assert!(stale.count() > 0, "Should have moved some of the ints.");
+    assert!(
+        ints.iter().enumerate().all(|(i, int)| {
+            vm.array_get(&array, i).unwrap().0 == vm.resolve(int).unwrap().0
+                && int.as_int(&vm) == Some(i as i64 * 8)
+        }),
+        "Should have pointed the array at the moved ints."
+    );
+    let (head, _) = pair.as_pair(&vm).unwrap();
+    assert!(head.unwrap().0 == vm.resolve(&ints[steps * 37 % ints.len()]).unwrap().0);
+    vm.gc();
+    assert!(ints[511].as_int(&vm) == Some(4088));
+
+    println!("Relocation Test: New objects stay out of the chunks being emptied.");
+    let mut vm = sparse();
+    let array = vm.peek(0);
+    let int = vm.array_get(&array, 0).unwrap();
+    assert!(vm.start_relocation());
+    let pair = vm.push_pair_with(int.clone(), int);
+    assert!(!vm.relocation.as_ref().unwrap().moves(pair.0));
+    vm.relocation_step(1);
+    vm.gc();
+    assert!(
+        !vm.is_relocating() && vm.relocation_step(1) == 0,
+        "Should end the relocation at a collection."
+    );
+}
@@ -0,0 +1,313 @@
+//! Read-only heap segments: an object graph built once, such as a standard
+//! library's constants and symbols, and mounted into any number of VMs,
+//! which share its objects instead of each allocating their own.
+//!
+//! A segment's objects are frozen, come pre-marked so marking stops at them,
+//! and have their identity hashes assigned up front, so nothing ever writes
+//! to them once the segment is built. They aren't in any VM's heap, so no
+//! collection sweeps them or counts them; they're freed when the last VM
+//! and [`Segment`] holding on to them are dropped.
+
+use std::collections::{HashMap, HashSet};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+
+use crate::copy::{remap_children, shallow_copy};
+use crate::{allocation, mix_hash, GcError, GcPtr, Header, ObjType, Object, Trace, Visitor, Vm};
+
+/// A frozen object graph that VMs mount with [`Vm::mount`]. Cloning it is
+/// cheap, and clones, like the VMs it's mounted in, share the same objects.
+#[derive(Clone)]
+pub struct Segment {
+    inner: Arc<Frozen>,
+}
+
+struct Frozen {
+    objects: HashSet<GcPtr<Object>>,
+    /// bound as globals by each VM mounting the segment
+    globals: HashMap<String, GcPtr<Object>>,
+    /// added to the intern table of each VM mounting the segment
+    strings: HashMap<String, GcPtr<Object>>,
+    /// added to the symbol table of each VM mounting the segment
+    symbols: HashMap<String, GcPtr<Object>>,
+}
+
+// SAFETY: nothing writes to a segment's objects after it's built: they're
+// frozen and pre-marked, their identity hashes are already assigned, no VM
+// sweeps them, and VMs refuse to set their user tags. Building refuses
+// foreign and custom objects, whose payloads are only `Send`, so everything
+// left is plain data that can be read from any thread.
+unsafe impl Sync for Frozen {}
+
+/// Identity hashes handed out to segment objects so far, across all
+/// segments. Their counts have the top bit set, which a VM's own count never
+/// reaches, so they never collide with the hashes of a VM's own objects.
+static SEGMENT_HASHES: AtomicU64 = AtomicU64::new(0);
+
+impl Segment {
+    /// Every object of the segment.
+    pub(crate) fn objects(&self) -> impl Iterator<Item = &GcPtr<Object>> {
+        self.inner.objects.iter()
+    }
+
+    /// Returns the object the segment binds to the global `name`, if any.
+    pub fn global(&self, name: &str) -> Option<GcPtr<Object>> {
+        self.inner.globals.get(name).cloned()
+    }
+
+    /// Number of objects in the segment.
+    pub fn len(&self) -> usize {
+        self.inner.objects.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.inner.objects.is_empty()
+    }
+}
+
+impl Drop for Frozen {
+    fn drop(&mut self) {
+        for obj in &self.objects {
+            unsafe { allocation::deallocate(obj.0) }
+        }
+    }
+}
+
+impl Vm {
+    /// Builds a segment out of this VM's globals, interned strings and
+    /// symbols: everything they reach is copied, keeping its sharing and
+    /// cycles, and frozen. The VM itself is left as it was. Fails with
+    /// `GcError::TypeMismatch` if a foreign or custom object is reachable,
+    /// since those can't be shared between threads.
+    pub fn segment(&self) -> Result<Segment, GcError> {
+        struct Pending(Vec<GcPtr<Object>>);
+        impl Visitor for Pending {
+            fn visit(&mut self, obj: &GcPtr<Object>) {
+                self.0.push(obj.clone());
+            }
+        }
+
+        // everything is checked before anything is allocated, so failing
+        // leaves nothing to clean up
+        let tables = self.strings.values().chain(self.symbols.values());
+        let roots = self.globals.values().chain(tables).cloned().collect();
+        let mut pending = Pending(roots);
+        let mut reachable = HashSet::new();
+        while let Some(obj) = pending.0.pop() {
+            if !reachable.insert(obj.clone()) {
+                continue;
+            }
+            if let ObjType::Foreign(_) | ObjType::Custom(_) = obj.value() {
+                return Err(GcError::TypeMismatch {
+                    expected: "value that can be shared between VMs",
+                    found: obj.value().type_name(),
+                });
+            }
+            obj.value().trace(&mut pending);
+        }
+
+        let mut replacements = HashMap::new();
+        for original in reachable {
+            let value = match original.value() {
+                ObjType::Nil => ObjType::Nil,
+                ObjType::Bool(b) => ObjType::Bool(*b),
+                ObjType::Symbol(name) => ObjType::Symbol(name.clone()),
+                value => shallow_copy(value).unwrap(),
+            };
+            let count = SEGMENT_HASHES.fetch_add(1, Ordering::Relaxed) | 1 << 63;
+            let mut header = Header::default();
+            header.set_marked(true);
+            header.set_frozen(true);
+            header.set_user_tag(original.user_tag());
+            let mut object = Object::new(header, value);
+            object.set_identity_hash(mix_hash(count));
+            let copy = GcPtr::from_live(allocation::allocate(object));
+            replacements.insert(original, copy);
+        }
+        for copy in replacements.values() {
+            // the copies aren't shared with anything yet
+            remap_children(unsafe { &mut (*copy.0.as_ptr()).value }, |child| {
+                *child = replacements[child].clone()
+            });
+        }
+
+        let copy = |table: &HashMap<String, GcPtr<Object>>| {
+            table
+                .iter()
+                .map(|(name, obj)| (name.clone(), replacements[obj].clone()))
+                .collect()
+        };
+        let (globals, strings, symbols) = (
+            copy(&self.globals),
+            copy(&self.strings),
+            copy(&self.symbols),
+        );
+        Ok(Segment {
+            inner: Arc::new(Frozen {
+                objects: replacements.into_values().collect(),
+                globals,
+                strings,
+                symbols,
+            }),
+        })
+    }
+
+    /// Mounts `segment`: its objects become live in this VM for as long as
+    /// it lives, without counting towards its heap or ever being collected
+    /// by it, its globals are bound, replacing any previous bindings, and
+    /// its symbols and interned strings go into the VM's tables. A string
+    /// the VM had already interned keeps its own object.
+    ///
+    /// Symbols have to stay unique, so this fails with
+    /// `GcError::DuplicateSymbol`, mounting nothing, if the VM already has
+    /// a symbol the segment has too; mounting before making symbols avoids
+    /// that. Mounting a segment again does nothing. Panics if the VM
+    /// records, since its log couldn't refer to the segment's objects.
+    pub fn mount(&mut self, segment: &Segment) -> Result<(), GcError> {
+        assert!(
+            self.recorder.is_none(),
+            "A recording VM can't mount segments"
+        );
+        if self.mounted(segment) {
+            return Ok(());
+        }
+        if let Some(name) = segment
+            .inner
+            .symbols
+            .keys()
+            .find(|name| self.symbols.contains_key(*name))
+        {
+            return Err(GcError::DuplicateSymbol(name.clone()));
+        }
+        self.attach(segment);
+        for (name, obj) in &segment.inner.globals {
+            self.globals.insert(name.clone(), obj.clone());
+        }
+        Ok(())
+    }
+
+    /// Makes `segment`'s objects live and adds its tables to the VM's,
+    /// without binding its globals.
+    pub(crate) fn attach(&mut self, segment: &Segment) {
+        self.live.extend(segment.objects().cloned());
+        for (text, obj) in &segment.inner.strings {
+            self.strings
+                .entry(text.clone())
+                .or_insert_with(|| obj.clone());
+        }
+        for (name, obj) in &segment.inner.symbols {
+            self.symbols.insert(name.clone(), obj.clone());
+        }
+        self.segments.push(segment.clone());
+    }
+
+    fn mounted(&self, segment: &Segment) -> bool {
+        self.segments
+            .iter()
+            .any(|mounted| Arc::ptr_eq(&mounted.inner, &segment.inner))
+    }
+
+    /// Whether `obj` belongs to a segment this VM has mounted, and so may be
+    /// read by other VMs at any time.
+    pub(crate) fn is_shared(&self, obj: &GcPtr<Object>) -> bool {
+        self.segments
+            .iter()
+            .any(|segment| segment.inner.objects.contains(obj))
+    }
+}
+
+#[test]
+fn segment_test() {
+    #[derive(Debug)]
+    struct Texture;
+    impl Trace for Texture {
+        fn trace(&self, _: &mut dyn Visitor) {}
+    }
+    impl crate::ForeignObject for Texture {}
+
+    println!("Segment Test: VMs on many threads share one frozen segment.");
+    let mut builder = Vm::new();
+    let list = builder.push_list([1, 2, 3]);
+    builder.define_global("list", list);
+    let greeting = builder.intern("hello");
+    builder.define_global("greeting", greeting);
+    builder.symbol("stdlib");
+    builder.clear_stack();
+    let segment = builder.segment().unwrap();
+    drop(builder);
+    assert!(
+        segment.len() == 9,
+        "Should have copied the list, its nil, the string and the symbol."
+    );
+
+    let threads: Vec<_> = (0..4)
+        .map(|_| {
+            let segment = segment.clone();
+            std::thread::spawn(move || {
+                let mut vm = Vm::new();
+                vm.mount(&segment).unwrap();
+                let list = vm.get_global("list").unwrap();
+                assert!(
+                    list == segment.global("list").unwrap(),
+                    "Should bind the segment's own objects."
+                );
+                for _ in 0..100 {
+                    vm.push_str("garbage");
+                    vm.drop_top();
+                }
+                vm.gc();
+                assert!(vm.num_objs == 0, "Should not count the segment's objects.");
+                assert!(
+                    vm.iter_list(&list)
+                        .map(|i| i.as_int(&vm))
+                        .collect::<Vec<_>>()
+                        == [Some(1), Some(2), Some(3)],
+                    "Should have kept the list."
+                );
+                assert!(
+                    vm.set_head(&list, list.clone()) == Err(GcError::FrozenObject),
+                    "Should not mutate segment objects."
+                );
+                let greeting = vm.intern("hello");
+                assert!(
+                    Some(greeting) == vm.get_global("greeting"),
+                    "Should intern to the segment's string."
+                );
+                let symbol = vm.symbol("stdlib");
+                assert!(
+                    vm.is_shared(&symbol),
+                    "Should make symbols from the segment's."
+                );
+                vm.identity_hash(&list)
+            })
+        })
+        .collect();
+    let hashes: Vec<_> = threads
+        .into_iter()
+        .map(|thread| thread.join().unwrap())
+        .collect();
+    assert!(
+        hashes.iter().all(|&hash| hash == hashes[0]),
+        "Should see the same identity hash everywhere."
+    );
+
+    let mut vm = Vm::new();
+    vm.symbol("stdlib");
+    assert!(
+        vm.mount(&segment) == Err(GcError::DuplicateSymbol("stdlib".to_owned())),
+        "Should refuse to make a second symbol of the same name."
+    );
+    assert!(
+        vm.get_global("list").is_none(),
+        "Should have mounted nothing."
+    );
+
+    let mut builder = Vm::new();
+    builder.push_foreign(Texture);
+    let texture = builder.pop();
+    builder.define_global("texture", texture);
+    assert!(
+        builder.segment().is_err(),
+        "Should not share foreign objects."
+    );
+}
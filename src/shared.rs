@@ -0,0 +1,226 @@
+use std::sync::{Arc, Condvar, Mutex, MutexGuard};
+
+use crate::{Vm, VmConfig};
+
+/// A heap several threads allocate into, each through its own [`Mutator`].
+///
+/// Collections stop the world: one starts only once every other mutator has
+/// reached a safepoint, and they stay parked there until it's done. At a
+/// safepoint a mutator must have rooted everything it still needs, on the
+/// stack, in a global or behind a handle, since a handle on its own doesn't
+/// keep its object alive.
+pub struct SharedHeap {
+    inner: Arc<Inner>,
+}
+
+struct Inner {
+    vm: Mutex<Vm>,
+    world: Mutex<World>,
+    /// signalled whenever a mutator parks or leaves, and when a collection
+    /// finishes
+    changed: Condvar,
+}
+
+#[derive(Default)]
+struct World {
+    /// mutators registered with the heap
+    mutators: usize,
+    /// how many of them are parked at a safepoint, or running `blocking`
+    parked: usize,
+    /// whether a collection is waiting for the world to stop, or running
+    stopping: bool,
+    /// collections finished so far, so a parked mutator can tell when the
+    /// one it's waiting on is over
+    collections: u64,
+}
+
+/// One thread's access to a [`SharedHeap`]. Operations on the heap run one
+/// at a time, whichever mutator they come from.
+pub struct Mutator {
+    inner: Arc<Inner>,
+}
+
+impl SharedHeap {
+    pub fn new(config: VmConfig) -> Self {
+        SharedHeap {
+            inner: Arc::new(Inner {
+                vm: Mutex::new(Vm::with_config(config)),
+                world: Mutex::new(World::default()),
+                changed: Condvar::new(),
+            }),
+        }
+    }
+
+    /// Registers a new mutator. Collections wait for it from now on, until
+    /// it's dropped.
+    pub fn mutator(&self) -> Mutator {
+        lock(&self.inner.world).mutators += 1;
+        Mutator {
+            inner: self.inner.clone(),
+        }
+    }
+
+    /// Returns how many stop-the-world collections have finished.
+    pub fn collections(&self) -> u64 {
+        lock(&self.inner.world).collections
+    }
+}
+
+impl Mutator {
+    /// Stops at a safepoint, then runs `f` on the heap. `f` holds the heap
+    /// while it runs, so it should be short, and it mustn't call back into a
+    /// mutator.
+    pub fn with<R>(&self, f: impl FnOnce(&mut Vm) -> R) -> R {
+        self.safepoint();
+        f(&mut lock(&self.inner.vm))
+    }
+
+    /// Polls for a collection: parks until it's over if another mutator is
+    /// waiting to collect, and collects if the heap has reached its
+    /// threshold or its memory budget asks it to. Every mutator has to get
+    /// here every so often, since a collection waits for all of them;
+    /// [`Mutator::with`] does on each call.
+    pub fn safepoint(&self) {
+        let world = lock(&self.inner.world);
+        if world.stopping {
+            self.park(world);
+            return;
+        }
+        drop(world);
+        let due = lock(&self.inner.vm).collection_due();
+        if due {
+            self.collect();
+        }
+    }
+
+    /// Stops the world and collects, whatever the threshold says. If another
+    /// mutator is already collecting, waits for its collection instead.
+    pub fn collect(&self) {
+        let mut world = lock(&self.inner.world);
+        if world.stopping {
+            self.park(world);
+            return;
+        }
+        world.stopping = true;
+        while world.parked + 1 < world.mutators {
+            world = wait(&self.inner.changed, world);
+        }
+        drop(world);
+
+        lock(&self.inner.vm).gc();
+
+        let mut world = lock(&self.inner.world);
+        world.stopping = false;
+        world.collections += 1;
+        self.inner.changed.notify_all();
+    }
+
+    /// Runs `f`, which mustn't touch the heap, counting this mutator as
+    /// parked meanwhile so collections don't wait for it. For blocking on
+    /// I/O or on other threads.
+    pub fn blocking<R>(&self, f: impl FnOnce() -> R) -> R {
+        lock(&self.inner.world).parked += 1;
+        self.inner.changed.notify_all();
+        let result = f();
+        let mut world = lock(&self.inner.world);
+        // a collection that started meanwhile is counting on this mutator
+        // staying put until it's over
+        while world.stopping {
+            world = wait(&self.inner.changed, world);
+        }
+        world.parked -= 1;
+        result
+    }
+
+    fn park(&self, mut world: MutexGuard<'_, World>) {
+        let collection = world.collections;
+        world.parked += 1;
+        self.inner.changed.notify_all();
+        while world.collections == collection {
+            world = wait(&self.inner.changed, world);
+        }
+        world.parked -= 1;
+    }
+}
+
+impl Drop for Mutator {
+    fn drop(&mut self) {
+        lock(&self.inner.world).mutators -= 1;
+        self.inner.changed.notify_all();
+    }
+}
+
+/// Nothing panics while holding these locks but `Vm` operations, which
+/// leave the VM usable, so poisoning is ignored.
+fn lock<T>(mutex: &Mutex<T>) -> MutexGuard<'_, T> {
+    mutex.lock().unwrap_or_else(|err| err.into_inner())
+}
+
+fn wait<'a, T>(changed: &Condvar, guard: MutexGuard<'a, T>) -> MutexGuard<'a, T> {
+    changed.wait(guard).unwrap_or_else(|err| err.into_inner())
+}
+
+#[test]
+fn shared_heap_test() {
+    println!("Shared Heap Test: Mutators on many threads share one heap.");
+    let heap = SharedHeap::new(VmConfig::default());
+    let main = heap.mutator();
+    let threads: Vec<_> = (0..4)
+        .map(|thread| {
+            let mutator = heap.mutator();
+            std::thread::spawn(move || {
+                let name = format!("list{thread}");
+                mutator.with(|vm| {
+                    vm.push_nil();
+                    let nil = vm.pop();
+                    vm.define_global(&name, nil);
+                });
+                for i in 0..300 {
+                    mutator.with(|vm| {
+                        for _ in 0..10 {
+                            vm.push_str("garbage");
+                            vm.drop_top();
+                        }
+                        // nothing collects within one operation, so the new
+                        // pair only has to be rooted by the end of it
+                        vm.push_int(i);
+                        let head = vm.pop();
+                        let list = vm.get_global(&name).unwrap();
+                        let pair = vm.push_pair_with(head, list);
+                        vm.drop_top();
+                        vm.define_global(&name, pair);
+                    });
+                }
+                mutator.with(|vm| {
+                    let list = vm.get_global(&name).unwrap();
+                    vm.iter_list(&list).count()
+                })
+            })
+        })
+        .collect();
+
+    // the main thread's mutator would hold up every collection if it
+    // didn't count as parked while it waits
+    let lengths: Vec<_> = main.blocking(|| {
+        threads
+            .into_iter()
+            .map(|thread| thread.join().unwrap())
+            .collect()
+    });
+    assert!(
+        lengths.iter().all(|&len| len == 300),
+        "Should have kept every thread's rooted list."
+    );
+    assert!(
+        heap.collections() > 0,
+        "Should have collected along the way."
+    );
+
+    main.collect();
+    main.with(|vm| {
+        assert!(
+            vm.num_objs == 4 * 300 * 2,
+            "Should have freed all the garbage."
+        );
+    });
+}
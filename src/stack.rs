@@ -0,0 +1,145 @@
+use std::ops::{Index, IndexMut, Range};
+
+use crate::{GcError, Value};
+
+/// number of slots in each segment
+const SEGMENT_SLOTS: usize = 256;
+
+/// Storage for the operand stack, made of fixed-size segments allocated as
+/// the stack first reaches them. Growing never moves the slots already in
+/// use, and how deep the stack gets is only bounded by `VmConfig::max_stack`
+/// and memory. Slots that aren't in use hold nil.
+pub(crate) struct Stack {
+    segments: Vec<Box<[Value]>>,
+}
+
+impl Stack {
+    pub(crate) fn new() -> Self {
+        Self { segments: vec![] }
+    }
+
+    /// Number of slots allocated so far.
+    pub(crate) fn capacity(&self) -> usize {
+        self.segments.len() * SEGMENT_SLOTS
+    }
+
+    /// Makes sure slot `index` exists, allocating the segment it's in.
+    pub(crate) fn reserve(&mut self, index: usize) {
+        while index >= self.capacity() {
+            self.segments
+                .push(vec![Value::NIL; SEGMENT_SLOTS].into_boxed_slice());
+        }
+    }
+
+    /// Like `reserve`, but fails with `GcError::OutOfMemory` instead of
+    /// aborting when a segment can't be allocated.
+    pub(crate) fn try_reserve(&mut self, index: usize) -> Result<(), GcError> {
+        while index >= self.capacity() {
+            let mut segment = vec![];
+            segment
+                .try_reserve_exact(SEGMENT_SLOTS)
+                .and_then(|()| self.segments.try_reserve(1))
+                .map_err(|_| GcError::OutOfMemory)?;
+            segment.resize(SEGMENT_SLOTS, Value::NIL);
+            self.segments.push(segment.into_boxed_slice());
+        }
+        Ok(())
+    }
+
+    /// The values in `range`, which has to be allocated, lowest first.
+    pub(crate) fn slots(&self, range: Range<usize>) -> impl DoubleEndedIterator<Item = Value> + '_ {
+        range.map(|index| self[index])
+    }
+
+    /// The slots in `range`, which has to be allocated, lowest first, finding
+    /// each segment once rather than once per slot.
+    pub(crate) fn slots_mut(
+        &mut self,
+        range: Range<usize>,
+    ) -> impl Iterator<Item = &mut Value> + '_ {
+        let (start, end) = (range.start, range.end);
+        let first = start / SEGMENT_SLOTS;
+        self.segments[first..end.div_ceil(SEGMENT_SLOTS)]
+            .iter_mut()
+            .zip((first..).map(|segment| segment * SEGMENT_SLOTS))
+            .flat_map(move |(segment, offset)| {
+                let from = start.saturating_sub(offset);
+                let to = (end - offset).min(SEGMENT_SLOTS);
+                segment[from..to].iter_mut()
+            })
+    }
+
+    pub(crate) fn swap(&mut self, a: usize, b: usize) {
+        let value = self[a];
+        self[a] = self[b];
+        self[b] = value;
+    }
+
+    /// Frees the segments past the one slot `len - 1` is in, returning how
+    /// many bytes that gave back.
+    pub(crate) fn shrink(&mut self, len: usize) -> usize {
+        let (segments, capacity) = (self.segments.len(), self.segments.capacity());
+        self.segments.truncate(len.div_ceil(SEGMENT_SLOTS));
+        self.segments.shrink_to_fit();
+        let slots = (segments - self.segments.len()) * SEGMENT_SLOTS;
+        let entries = capacity - self.segments.capacity();
+        slots * std::mem::size_of::<Value>() + entries * std::mem::size_of::<Box<[Value]>>()
+    }
+
+    /// Frees every segment.
+    pub(crate) fn clear(&mut self) {
+        self.segments.clear();
+    }
+}
+
+impl Index<usize> for Stack {
+    type Output = Value;
+
+    fn index(&self, index: usize) -> &Value {
+        &self.segments[index / SEGMENT_SLOTS][index % SEGMENT_SLOTS]
+    }
+}
+
+impl IndexMut<usize> for Stack {
+    fn index_mut(&mut self, index: usize) -> &mut Value {
+        &mut self.segments[index / SEGMENT_SLOTS][index % SEGMENT_SLOTS]
+    }
+}
+
+#[test]
+fn segmented_stack_test() {
+    println!("Segmented Stack Test: Deep stacks span segments, all of them roots.");
+    let mut vm = crate::Vm::with_config(crate::VmConfig {
+        max_stack: Some(usize::MAX),
+        ..crate::VmConfig::default()
+    });
+    assert!(
+        vm.stack.capacity() == 0,
+        "Should allocate segments on demand."
+    );
+    for i in 0..1000 {
+        vm.push_int(i);
+    }
+    assert!(vm.stack.segments.len() == 4);
+    vm.gc();
+    assert!(vm.num_objs == 1000, "Should scan every segment.");
+    vm.swap();
+    vm.rot();
+    let ints: Vec<_> = vm
+        .stack_values()
+        .rev()
+        .take(3)
+        .map(|value| value.as_obj().unwrap().as_int(&vm).unwrap())
+        .collect();
+    assert!(ints == [997, 998, 999]);
+
+    let mut depth = 0;
+    while vm.stack_size > 0 {
+        vm.pop();
+        depth += 1;
+    }
+    assert!(depth == 1000);
+    vm.gc();
+    assert!(vm.num_objs == 0);
+    drop(vm);
+}
@@ -0,0 +1,164 @@
+use std::fmt;
+
+use crate::{GcPtr, Object};
+
+/// A NaN-boxed value: one 64-bit word that's either a float, an immediate
+/// (nil, a bool, or an int that fits in 48 bits), or a reference to a heap
+/// object. Immediates never touch the heap.
+///
+/// Floats are stored as their own bits. Every other kind lives in the quiet
+/// NaN space, with a non-zero tag in bits 48..=50 and a 48-bit payload below
+/// it; float NaNs are canonicalized so they never collide with a tag.
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub struct Value(u64);
+
+const QNAN: u64 = 0x7ff8 << 48;
+const TAG_MASK: u64 = 0xffff << 48;
+const PAYLOAD_MASK: u64 = (1 << 48) - 1;
+
+const TAG_NIL: u64 = QNAN | 1 << 48;
+const TAG_BOOL: u64 = QNAN | 2 << 48;
+const TAG_INT: u64 = QNAN | 3 << 48;
+const TAG_REF: u64 = QNAN | 4 << 48;
+
+impl Value {
+    pub const NIL: Value = Value(TAG_NIL);
+    pub const TRUE: Value = Value(TAG_BOOL | 1);
+    pub const FALSE: Value = Value(TAG_BOOL);
+
+    /// Smallest and largest ints that can be stored as immediates.
+    pub const INT_MIN: i64 = -(1 << 47);
+    pub const INT_MAX: i64 = (1 << 47) - 1;
+
+    pub fn bool(value: bool) -> Self {
+        if value {
+            Self::TRUE
+        } else {
+            Self::FALSE
+        }
+    }
+
+    /// Returns an immediate int, or `None` if `value` needs more than 48 bits.
+    pub fn int(value: i64) -> Option<Self> {
+        (Self::INT_MIN..=Self::INT_MAX)
+            .contains(&value)
+            .then_some(Value(TAG_INT | value as u64 & PAYLOAD_MASK))
+    }
+
+    pub fn float(value: f64) -> Self {
+        if value.is_nan() {
+            Value(f64::NAN.to_bits())
+        } else {
+            Value(value.to_bits())
+        }
+    }
+
+    pub fn from_obj(obj: GcPtr<Object>) -> Self {
+        let addr = crate::allocation::to_addr(obj.0);
+        debug_assert!(addr & !PAYLOAD_MASK == 0, "pointer doesn't fit in 48 bits");
+        Value(TAG_REF | addr)
+    }
+
+    /// The value's encoding, for logs.
+    pub(crate) fn to_bits(self) -> u64 {
+        self.0
+    }
+
+    /// Decodes an immediate written by `to_bits`, or returns `None` if the
+    /// bits are a reference or no value at all.
+    pub(crate) fn immediate_from_bits(bits: u64) -> Option<Self> {
+        let value = Value(bits);
+        let immediate = value.is_nil()
+            || value.as_bool().is_some()
+            || value.as_int().is_some()
+            || value.as_float().is_some();
+        immediate.then_some(value)
+    }
+
+    fn tag(self) -> u64 {
+        self.0 & TAG_MASK
+    }
+
+    pub fn is_nil(self) -> bool {
+        self.0 == TAG_NIL
+    }
+
+    pub fn as_bool(self) -> Option<bool> {
+        (self.tag() == TAG_BOOL).then_some(self.0 & 1 == 1)
+    }
+
+    pub fn as_int(self) -> Option<i64> {
+        // shifting back down sign-extends the 48-bit payload
+        (self.tag() == TAG_INT).then_some(((self.0 << 16) as i64) >> 16)
+    }
+
+    pub fn as_float(self) -> Option<f64> {
+        let top = self.0 >> 48;
+        let tagged = top & 0xfff8 == 0x7ff8 && top & 0x7 != 0;
+        (!tagged).then_some(f64::from_bits(self.0))
+    }
+
+    /// Name of the value's type, as `ObjType::type_name` would give it once
+    /// boxed.
+    pub(crate) fn type_name(self) -> &'static str {
+        match self.as_obj() {
+            Some(obj) => obj.value().type_name(),
+            None if self.is_nil() => "nil",
+            None if self.as_bool().is_some() => "bool",
+            None if self.as_int().is_some() => "int",
+            None => "float",
+        }
+    }
+
+    /// Address of the heap object this value refers to, if it's a reference.
+    pub(crate) fn addr(self) -> Option<u64> {
+        (self.tag() == TAG_REF).then_some(self.0 & PAYLOAD_MASK)
+    }
+
+    /// Returns the heap object this value refers to, if it's a reference.
+    /// A value carries no epoch, so this reads it from the object, which
+    /// has to be live: take values from the VM, or check ones from outside
+    /// with [`crate::Vm::value_obj`].
+    pub(crate) fn as_obj(self) -> Option<GcPtr<Object>> {
+        self.addr()
+            .map(|addr| GcPtr::from_live(crate::allocation::from_addr(addr)))
+    }
+}
+
+impl From<GcPtr<Object>> for Value {
+    fn from(obj: GcPtr<Object>) -> Self {
+        Self::from_obj(obj)
+    }
+}
+
+impl fmt::Debug for Value {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.is_nil() {
+            write!(f, "nil")
+        } else if let Some(b) = self.as_bool() {
+            write!(f, "{}", b)
+        } else if let Some(i) = self.as_int() {
+            write!(f, "{}", i)
+        } else if let Some(addr) = self.addr() {
+            write!(f, "{:?}", crate::allocation::from_addr(addr))
+        } else {
+            write!(f, "{:?}", self.as_float().unwrap())
+        }
+    }
+}
+
+#[test]
+fn value_test() {
+    println!("Value Test: Immediates round-trip through the boxed encoding.");
+    assert!(Value::NIL.is_nil() && Value::NIL.as_float().is_none());
+    assert!(Value::TRUE.as_bool() == Some(true));
+    assert!(Value::FALSE.as_bool() == Some(false));
+    assert!(Value::int(-5).unwrap().as_int() == Some(-5));
+    assert!(Value::int(Value::INT_MAX).unwrap().as_int() == Some(Value::INT_MAX));
+    assert!(Value::int(Value::INT_MIN).unwrap().as_int() == Some(Value::INT_MIN));
+    assert!(Value::int(Value::INT_MAX + 1).is_none(), "Should not fit.");
+    assert!(Value::float(1.5).as_float() == Some(1.5));
+    assert!(Value::float(-f64::NAN).as_float().unwrap().is_nan());
+    assert!(Value::float(f64::INFINITY).as_int().is_none());
+    assert!(Value::int(3).unwrap().as_float().is_none());
+}